@@ -0,0 +1,120 @@
+use image::DynamicImage;
+
+/// Rectangle of an image, in pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Region {
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+///
+/// Finds the image shown in a pixel exact (1:1) screenshot, as the bounding box of all pixels
+/// which differ from the background. The background is the color of the top left pixel,
+/// so the screenshot has to show the image on a plain background.
+/// Returns `None` if the screenshot only shows the background.
+pub(crate) fn locate_embedded_image(screenshot: &DynamicImage) -> Option<Region> {
+    let bytes_per_pixel = screenshot.color().bytes_per_pixel() as usize;
+    let width = screenshot.width() as usize;
+    let samples = screenshot.as_bytes();
+    let background = samples.get(..bytes_per_pixel)?;
+
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for (index, pixel) in samples.chunks_exact(bytes_per_pixel).enumerate() {
+        if pixel == background {
+            continue;
+        }
+        let (x, y) = (index % width, index / width);
+        bounds = Some(match bounds {
+            None => (x, y, x, y),
+            Some((left, top, right, _)) => (left.min(x), top, right.max(x), y),
+        });
+    }
+
+    let (left, top, right, bottom) = bounds?;
+    Some(Region {
+        x: left as u32,
+        y: top as u32,
+        width: (right - left + 1) as u32,
+        height: (bottom - top + 1) as u32,
+    })
+}
+
+///
+/// Crops a screenshot to the image it shows, see [`locate_embedded_image`]
+pub(crate) fn crop_to_embedded_image(
+    screenshot: &DynamicImage,
+) -> Result<(Region, DynamicImage), String> {
+    let region = locate_embedded_image(screenshot).ok_or_else(|| {
+        "The screenshot only shows its background color, no image was found".to_string()
+    })?;
+    let cropped = screenshot.crop_imm(region.x, region.y, region.width, region.height);
+
+    Ok((region, cropped))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{imageops, ImageBuffer, Rgb, Rgba, RgbaImage};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        buffer_modify::convert_dynamic_image_to_png_image,
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header},
+        payload::{read_payload, write_payload},
+    };
+
+    #[test]
+    fn pasted_image_is_located_and_decoded() {
+        let mut stego: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 48);
+        rand::thread_rng().fill_bytes(&mut stego);
+        let payload = b"found on a larger canvas".to_vec();
+        let header = generate_v3_header(
+            64 * 48,
+            &payload,
+            image::ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        write_payload(&mut stego, &header, &payload, None).unwrap();
+
+        // Screenshots are usually RGBA, while the pasted image is RGB
+        let mut screenshot = RgbaImage::from_pixel(200, 150, Rgba([30, 30, 30, 255]));
+        imageops::overlay(
+            &mut screenshot,
+            &DynamicImage::ImageRgb8(stego).to_rgba8(),
+            37,
+            21,
+        );
+        let screenshot = DynamicImage::ImageRgba8(screenshot);
+
+        let (region, mut cropped) = crop_to_embedded_image(&screenshot).unwrap();
+        assert_eq!(
+            region,
+            Region {
+                x: 37,
+                y: 21,
+                width: 64,
+                height: 48
+            }
+        );
+        let cropped = convert_dynamic_image_to_png_image(&mut cropped).unwrap();
+        let header = try_get_header(cropped).unwrap();
+        assert_eq!(read_payload(cropped, &header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn plain_screenshot_has_no_image() {
+        let screenshot =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(20, 10, Rgba([255, 255, 255, 255])));
+
+        assert_eq!(locate_embedded_image(&screenshot), None);
+        assert!(crop_to_embedded_image(&screenshot).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use image::{DynamicImage, GenericImageView};
+
+/// Upper bound of pixels looked at when estimating the entropy of an image
+const MAX_SAMPLES: u64 = 1 << 16;
+
+/// Covers with a color entropy (in bits) below this value are considered flat.
+/// LSB noise would be clearly visible in them.
+pub(crate) const LOW_ENTROPY_THRESHOLD: f64 = 4.0;
+
+///
+/// Estimates the Shannon entropy of the colors in the image, in bits.
+/// Only an evenly spread sample of the pixels is looked at for large images.
+pub(crate) fn estimate_color_entropy(image: &DynamicImage) -> f64 {
+    let (width, height) = image.dimensions();
+    let pixel_count = width as u64 * height as u64;
+    if pixel_count == 0 {
+        return 0.0;
+    }
+    let step = pixel_count.div_ceil(MAX_SAMPLES);
+
+    let mut histogram: HashMap<[u8; 4], u64> = HashMap::new();
+    let mut samples = 0u64;
+    for index in (0..pixel_count).step_by(step as usize) {
+        let x = (index % width as u64) as u32;
+        let y = (index / width as u64) as u32;
+        *histogram.entry(image.get_pixel(x, y).0).or_insert(0) += 1;
+        samples += 1;
+    }
+
+    histogram
+        .values()
+        .map(|count| {
+            let probability = *count as f64 / samples as f64;
+            -probability * probability.log2()
+        })
+        .sum()
+}
+
+///
+/// Checks whether the image is busy enough to hide data in it without the noise being obvious.
+/// Returns a message suggesting a different cover if it is not.
+pub(crate) fn check_cover_entropy(image: &DynamicImage) -> Result<(), String> {
+    let entropy = estimate_color_entropy(image);
+    if entropy < LOW_ENTROPY_THRESHOLD {
+        return Err(format!(
+            "The image has very few distinct colors (entropy {:.2} bits, threshold {:.2} bits). \
+            Hidden data will be easy to spot. Consider using a busier image, e.g. a photo.",
+            entropy, LOW_ENTROPY_THRESHOLD
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+    use rand::RngCore;
+
+    use super::*;
+
+    #[test]
+    fn solid_color_image_is_flat() {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(200, 100, Rgb([30, 60, 90])));
+
+        assert_eq!(estimate_color_entropy(&image), 0.0);
+        assert!(check_cover_entropy(&image).is_err());
+    }
+
+    #[test]
+    fn photo_like_image_is_busy() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(300, 300);
+        rand::thread_rng().fill_bytes(&mut image);
+        let image = DynamicImage::ImageRgb8(image);
+
+        assert!(estimate_color_entropy(&image) > LOW_ENTROPY_THRESHOLD);
+        assert!(check_cover_entropy(&image).is_ok());
+    }
+
+    #[test]
+    fn two_color_image_has_one_bit_entropy() {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::from_fn(64, 64, |x, _| {
+            if x % 2 == 0 {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            }
+        }));
+
+        assert!((estimate_color_entropy(&image) - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,167 @@
+//! Several files packed into one payload, see `encode --message-file` and `decode --extract-all`.
+//!
+//! The archive is the bincode encoding of the file names and contents, in the order they were given.
+//! Headers of such payloads carry [`crate::header::HeaderExtension::Container`], so decoding knows to unpack them.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use bincode::{config, Decode, Encode};
+
+use crate::io_errors::{cannot_read, cannot_write};
+
+/// A single file inside the archive
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub(crate) struct ArchiveEntry {
+    /// File name without any directories
+    pub(crate) name: String,
+    pub(crate) data: Vec<u8>,
+}
+
+pub(crate) fn pack(entries: &[ArchiveEntry]) -> Result<Vec<u8>, String> {
+    bincode::encode_to_vec(entries, config::standard()).map_err(|x| x.to_string())
+}
+
+pub(crate) fn unpack(data: &[u8]) -> Result<Vec<ArchiveEntry>, String> {
+    let (entries, read): (Vec<ArchiveEntry>, usize) =
+        bincode::decode_from_slice(data, config::standard())
+            .map_err(|x| format!("The payload is not a valid archive: {}", x))?;
+    if read != data.len() {
+        return Err(format!(
+            "The payload is not a valid archive: {} trailing bytes",
+            data.len() - read
+        ));
+    }
+    Ok(entries)
+}
+
+///
+/// Reads the files and packs them under their file names. Names have to be unique, as they are extracted side by side.
+pub(crate) fn pack_files(paths: &[String]) -> Result<Vec<u8>, String> {
+    let mut entries: Vec<ArchiveEntry> = Vec::with_capacity(paths.len());
+    for path in paths {
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .ok_or_else(|| format!("{} does not name a file", path))?;
+        if entries.iter().any(|entry| entry.name == name) {
+            return Err(format!("More than one file is named {}", name));
+        }
+        let data = fs::read(path).map_err(|x| cannot_read(path, &x))?;
+        entries.push(ArchiveEntry { name, data });
+    }
+    pack(&entries)
+}
+
+///
+/// Writes every file of the archive into `out_dir`. Existing files are never overwritten.
+/// Returns the paths of the created files.
+pub(crate) fn extract_all(archive: &[u8], out_dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = unpack(archive)?;
+    fs::create_dir_all(out_dir).map_err(|x| cannot_write(out_dir.display(), &x))?;
+
+    entries
+        .iter()
+        .map(|entry| {
+            // Only use the last component, so a crafted archive can not write outside of the output directory
+            let name = Path::new(&entry.name)
+                .file_name()
+                .filter(|name| !name.is_empty())
+                .ok_or_else(|| format!("The archive contains the invalid name {:?}", entry.name))?;
+            let out_path = out_dir.join(name);
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&out_path)
+                .map_err(|x| cannot_write(out_path.display(), &x))?;
+            file.write_all(&entry.data)
+                .map_err(|x| cannot_write(out_path.display(), &x))?;
+            Ok(out_path)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use image::{ImageBuffer, Rgba};
+    use pretty_assertions::assert_eq;
+    use rand::{thread_rng, Rng, RngCore};
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{
+            try_get_header, HeaderExtension, PayloadContainer, V1DataStuffingOptions,
+            VersionedHeader,
+        },
+        payload::{read_payload, write_payload},
+    };
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("ihm-archive-{:x}", thread_rng().gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn two_files_round_trip_through_an_image() {
+        let dir = temp_dir();
+        let notes = dir.join("notes.txt");
+        let key = dir.join("key.bin");
+        fs::write(&notes, b"meet at noon").unwrap();
+        fs::write(&key, [0x00, 0xFF, 0x10, 0x80]).unwrap();
+        let payload = pack_files(&[
+            notes.to_string_lossy().into_owned(),
+            key.to_string_lossy().into_owned(),
+        ])
+        .unwrap();
+
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        thread_rng().fill_bytes(&mut image);
+        let header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 1024 },
+            data_mask: 0x03_03_03_03_00_00_00_00u64,
+            data_len: payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(&payload),
+            extensions: vec![HeaderExtension::Container(PayloadContainer::Archive)],
+        };
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        let header = try_get_header(&image).unwrap();
+        assert_eq!(header.container(), Some(PayloadContainer::Archive));
+        let out_dir = dir.join("out");
+        let extracted =
+            extract_all(&read_payload(&image, &header, None).unwrap(), &out_dir).unwrap();
+
+        assert_eq!(
+            extracted,
+            [out_dir.join("notes.txt"), out_dir.join("key.bin")]
+        );
+        assert_eq!(fs::read(&extracted[0]).unwrap(), b"meet at noon");
+        assert_eq!(fs::read(&extracted[1]).unwrap(), [0x00, 0xFF, 0x10, 0x80]);
+        // Nothing is overwritten
+        assert!(extract_all(&payload, &out_dir).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn names_stay_inside_the_output_directory() {
+        let dir = temp_dir();
+        let archive = pack(&[ArchiveEntry {
+            name: "../../escape.txt".to_string(),
+            data: b"contained".to_vec(),
+        }])
+        .unwrap();
+
+        let extracted = extract_all(&archive, &dir).unwrap();
+
+        assert_eq!(extracted, [dir.join("escape.txt")]);
+        assert!(unpack(b"not an archive").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
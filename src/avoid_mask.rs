@@ -0,0 +1,95 @@
+use std::path::Path;
+
+use crc::{Crc, CRC_32_CKSUM};
+
+/// Pixels with a luma below this value count as black, i.e. off-limits
+const BLACK_THRESHOLD: u8 = 128;
+
+///
+/// A per-pixel map of where payload data may be written.
+/// Loaded from a PNG of the same size as the cover, where black pixels mark forbidden regions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvoidMask {
+    allowed: Vec<bool>,
+}
+
+impl AvoidMask {
+    pub fn from_allowed(allowed: Vec<bool>) -> AvoidMask {
+        AvoidMask { allowed }
+    }
+
+    ///
+    /// Loads the mask and validates that it matches the dimensions of the cover image
+    pub fn load(path: &Path, cover_dimensions: (u32, u32)) -> Result<AvoidMask, String> {
+        let mask = image::open(path)
+            .map_err(|x| format!("Failed to load the avoid mask: {}", x))?
+            .into_luma8();
+
+        if mask.dimensions() != cover_dimensions {
+            return Err(format!(
+                "Avoid mask is {} × {}px, but the image is {} × {}px",
+                mask.width(),
+                mask.height(),
+                cover_dimensions.0,
+                cover_dimensions.1
+            ));
+        }
+
+        Ok(AvoidMask::from_allowed(
+            mask.pixels()
+                .map(|pixel| pixel.0[0] >= BLACK_THRESHOLD)
+                .collect(),
+        ))
+    }
+
+    ///
+    /// Indices of all pixels which may carry payload data, in ascending order
+    pub fn allowed_pixels(&self) -> Vec<usize> {
+        self.allowed
+            .iter()
+            .enumerate()
+            .filter(|(_, allowed)| **allowed)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    ///
+    /// Checksum over the mask, stored in the header so decoding with a different mask is detected
+    pub fn checksum(&self) -> u32 {
+        let packed: Vec<u8> = self
+            .allowed
+            .chunks(8)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u8, |byte, (i, allowed)| byte | (*allowed as u8) << (7 - i))
+            })
+            .collect();
+
+        Crc::<u32>::new(&CRC_32_CKSUM).checksum(&packed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn allowed_pixels_skips_masked() {
+        let mask = AvoidMask::from_allowed(vec![true, false, false, true, true, false]);
+
+        assert_eq!(mask.allowed_pixels(), vec![0, 3, 4]);
+    }
+
+    #[test]
+    fn checksum_depends_on_mask() {
+        let a = AvoidMask::from_allowed(vec![true; 100]);
+        let mut b_allowed = vec![true; 100];
+        b_allowed[42] = false;
+        let b = AvoidMask::from_allowed(b_allowed);
+
+        assert_ne!(a.checksum(), b.checksum());
+    }
+}
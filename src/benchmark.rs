@@ -0,0 +1,91 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// How long embedding or extracting a payload took, printed by `--benchmark`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Benchmark {
+    /// What was measured, e.g. "embedding"
+    pub(crate) operation: &'static str,
+    pub(crate) payload_bytes: u64,
+    /// Pixels of the whole image, whether they carry payload or not
+    pub(crate) pixels: u64,
+    pub(crate) elapsed: Duration,
+}
+
+impl Benchmark {
+    /// Runs `f` and measures how long it takes
+    pub(crate) fn measure<T>(
+        operation: &'static str,
+        payload_bytes: u64,
+        pixels: u64,
+        f: impl FnOnce() -> T,
+    ) -> (T, Benchmark) {
+        let start = Instant::now();
+        let result = f();
+        let benchmark = Benchmark {
+            operation,
+            payload_bytes,
+            pixels,
+            elapsed: start.elapsed(),
+        };
+        (result, benchmark)
+    }
+
+    /// Payload throughput in MB (10^6 bytes) per second
+    pub(crate) fn payload_throughput(&self) -> f64 {
+        self.payload_bytes as f64 / 1e6 / self.seconds()
+    }
+
+    /// Image throughput in megapixels per second
+    pub(crate) fn pixel_throughput(&self) -> f64 {
+        self.pixels as f64 / 1e6 / self.seconds()
+    }
+
+    /// Never 0, so very fast runs give large rather than infinite throughputs
+    fn seconds(&self) -> f64 {
+        self.elapsed.as_secs_f64().max(1e-9)
+    }
+}
+
+impl fmt::Display for Benchmark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Benchmark: {} {} B with {} px took {:.3} ms, {:.2} MB/s of payload, {:.2} MP/s of image, rayon thread pool of {}",
+            self.operation,
+            self.payload_bytes,
+            self.pixels,
+            self.elapsed.as_secs_f64() * 1e3,
+            self.payload_throughput(),
+            self.pixel_throughput(),
+            rayon::current_num_threads()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throughput_is_per_second() {
+        let benchmark = Benchmark {
+            operation: "embedding",
+            payload_bytes: 3_000_000,
+            pixels: 8_000_000,
+            elapsed: Duration::from_millis(2000),
+        };
+
+        assert_eq!(benchmark.payload_throughput(), 1.5);
+        assert_eq!(benchmark.pixel_throughput(), 4.0);
+        assert!(benchmark
+            .to_string()
+            .starts_with("Benchmark: embedding 3000000 B with 8000000 px took 2000.000 ms, 1.50 MB/s of payload, 4.00 MP/s of image"));
+
+        let (value, instant) = Benchmark::measure("extracting", 1, 1, || 42);
+        assert_eq!(value, 42);
+        assert!(instant.payload_throughput().is_finite());
+    }
+}
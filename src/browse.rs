@@ -0,0 +1,328 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    buffer_modify::convert_dynamic_image_to_png_image,
+    extract::extract_to_file,
+    header::{try_get_header, VersionedHeader},
+    io_errors::cannot_read,
+    payload::read_payload,
+    size_format::format_byte_size,
+};
+
+/// Extensions of the image formats this tool can decode
+const IMAGE_EXTENSIONS: [&str; 4] = ["png", "ff", "farbfeld", "qoi"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PayloadStatus {
+    Payload(VersionedHeader),
+    NoPayload,
+    /// The file could not be loaded as an image
+    Unreadable(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BrowseEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) status: PayloadStatus,
+}
+
+///
+/// State of the `browse` command, kept apart from the terminal so it can be tested
+#[derive(Debug)]
+pub(crate) struct BrowseModel {
+    pub(crate) dir: PathBuf,
+    pub(crate) entries: Vec<BrowseEntry>,
+    pub(crate) selected: usize,
+    /// Result of the last decode or extract of the selected entry
+    message: Option<String>,
+}
+
+impl BrowseModel {
+    ///
+    /// Lists the images in the directory, sorted by file name, and checks each for a header
+    pub(crate) fn load(dir: &Path) -> Result<BrowseModel, String> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|x| cannot_read(dir.display(), &x))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && has_image_extension(path))
+            .collect();
+        paths.sort();
+
+        let entries = paths
+            .into_iter()
+            .map(|path| BrowseEntry {
+                status: scan_image(&path),
+                path,
+            })
+            .collect();
+
+        Ok(BrowseModel {
+            dir: dir.to_path_buf(),
+            entries,
+            selected: 0,
+            message: None,
+        })
+    }
+
+    pub(crate) fn select_next(&mut self) {
+        if self.selected + 1 < self.entries.len() {
+            self.selected += 1;
+            self.message = None;
+        }
+    }
+
+    pub(crate) fn select_previous(&mut self) {
+        if self.selected > 0 {
+            self.selected -= 1;
+            self.message = None;
+        }
+    }
+
+    ///
+    /// Reads the payload of the selected image. Text payloads are shown as they are.
+    pub(crate) fn decode_selected(&mut self) {
+        let Some(entry) = self.entries.get(self.selected) else {
+            return;
+        };
+        let PayloadStatus::Payload(header) = &entry.status else {
+            return;
+        };
+        let payload = load_image(&entry.path).and_then(|mut image| {
+            read_payload(
+                convert_dynamic_image_to_png_image(&mut image)?,
+                header,
+                None,
+            )
+        });
+
+        self.message = Some(match payload {
+            Ok(payload) => match String::from_utf8(payload) {
+                Ok(text) => text,
+                Err(err) => format!(
+                    "{} of binary data. Press x to extract it.",
+                    format_byte_size(err.as_bytes().len() as u64)
+                ),
+            },
+            Err(err) => format!("Failed to read payload: {}", err),
+        });
+    }
+
+    ///
+    /// Writes the payload of the selected image into the browsed directory, like `extract`
+    pub(crate) fn extract_selected(&mut self) {
+        let Some(entry) = self.entries.get(self.selected) else {
+            return;
+        };
+        let PayloadStatus::Payload(header) = &entry.status else {
+            return;
+        };
+        let written = load_image(&entry.path).and_then(|mut image| {
+            extract_to_file(
+                convert_dynamic_image_to_png_image(&mut image)?,
+                header,
+                None,
+                &entry.path,
+                &self.dir,
+                true,
+            )
+        });
+
+        self.message = Some(match written {
+            Ok(path) => format!("Extracted to {}", path.display()),
+            Err(err) => format!("Failed to extract: {}", err),
+        });
+    }
+
+    ///
+    /// Text of the preview pane for the selected image
+    pub(crate) fn preview(&self) -> String {
+        if let Some(message) = &self.message {
+            return message.clone();
+        }
+        match self.entries.get(self.selected).map(|entry| &entry.status) {
+            None => format!("No images in {}", self.dir.display()),
+            Some(PayloadStatus::Payload(header)) => format!(
+                "Carries a payload of {}. Press Enter to decode, x to extract.",
+                format_byte_size(header.data_len())
+            ),
+            Some(PayloadStatus::NoPayload) => "No payload found".to_string(),
+            Some(PayloadStatus::Unreadable(err)) => format!("Failed to load the image: {}", err),
+        }
+    }
+}
+
+fn has_image_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        })
+}
+
+fn load_image(path: &Path) -> Result<image::DynamicImage, String> {
+    let data = fs::read(path).map_err(|x| x.to_string())?;
+    image::load_from_memory(&data).map_err(|x| x.to_string())
+}
+
+fn scan_image(path: &Path) -> PayloadStatus {
+    let mut image = match load_image(path) {
+        Ok(val) => val,
+        Err(err) => return PayloadStatus::Unreadable(err),
+    };
+    match convert_dynamic_image_to_png_image(&mut image).and_then(|image| try_get_header(image)) {
+        Ok(header) => PayloadStatus::Payload(header),
+        Err(_) => PayloadStatus::NoPayload,
+    }
+}
+
+///
+/// Runs the interactive browser until the user quits
+pub(crate) fn run_browse(dir: &Path) -> Result<(), String> {
+    let mut model = BrowseModel::load(dir)?;
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &mut model);
+    ratatui::restore();
+
+    result.map_err(|x| x.to_string())
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, model: &mut BrowseModel) -> io::Result<()> {
+    loop {
+        terminal.draw(|frame| draw(frame, model))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Down | KeyCode::Char('j') => model.select_next(),
+            KeyCode::Up | KeyCode::Char('k') => model.select_previous(),
+            KeyCode::Enter => model.decode_selected(),
+            KeyCode::Char('x') => model.extract_selected(),
+            _ => {}
+        }
+    }
+}
+
+fn draw(frame: &mut Frame, model: &BrowseModel) {
+    let [list_area, preview_area] =
+        Layout::horizontal([Constraint::Percentage(40), Constraint::Fill(1)]).areas(frame.area());
+
+    let items: Vec<ListItem> = model
+        .entries
+        .iter()
+        .map(|entry| {
+            let name = entry
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            ListItem::new(match entry.status {
+                PayloadStatus::Payload(_) => Line::from(format!("● {}", name)).green(),
+                PayloadStatus::NoPayload => Line::from(format!("  {}", name)),
+                PayloadStatus::Unreadable(_) => Line::from(format!("! {}", name)).red(),
+            })
+        })
+        .collect();
+    let list = List::new(items)
+        .block(
+            Block::bordered()
+                .title(model.dir.display().to_string())
+                .title_bottom("↑/↓ select, Enter decode, x extract, q quit"),
+        )
+        .highlight_style(Style::new().reversed());
+    let mut state = ListState::default().with_selected(Some(model.selected));
+    frame.render_stateful_widget(list, list_area, &mut state);
+
+    let preview = Paragraph::new(model.preview())
+        .wrap(Wrap { trim: false })
+        .block(Block::bordered().title("Payload"));
+    frame.render_widget(preview, preview_area);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use image::{ImageBuffer, ImageOutputFormat, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::{thread_rng, Rng, RngCore};
+
+    use super::*;
+    use crate::{
+        buffer_modify::PngImageSaveable, crc_spec::CrcSpec, header::generate_v3_header,
+        payload::write_payload,
+    };
+
+    fn noisy_image() -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        thread_rng().fill_bytes(&mut image);
+        image
+    }
+
+    #[test]
+    fn browse_marks_images_carrying_a_payload() {
+        let dir = env::temp_dir().join(format!("ihm-browse-{:x}", thread_rng().gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let payload = b"found while browsing".to_vec();
+        let mut encoded = noisy_image();
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            image::ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        write_payload(&mut encoded, &header, &payload, None).unwrap();
+        let png = |image: &ImageBuffer<Rgb<u8>, Vec<u8>>| {
+            image.save_to_buffer(ImageOutputFormat::Png).unwrap()
+        };
+        fs::write(dir.join("a-encoded.png"), png(&encoded)).unwrap();
+        fs::write(dir.join("b-plain.png"), png(&noisy_image())).unwrap();
+        fs::write(dir.join("c-broken.png"), b"not an image").unwrap();
+        fs::write(dir.join("notes.txt"), b"skipped").unwrap();
+
+        let mut model = BrowseModel::load(&dir).unwrap();
+        let names: Vec<_> = model
+            .entries
+            .iter()
+            .map(|entry| {
+                entry
+                    .path
+                    .file_name()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        assert_eq!(names, ["a-encoded.png", "b-plain.png", "c-broken.png"]);
+        assert_eq!(model.entries[0].status, PayloadStatus::Payload(header));
+        assert_eq!(model.entries[1].status, PayloadStatus::NoPayload);
+        assert!(matches!(
+            model.entries[2].status,
+            PayloadStatus::Unreadable(_)
+        ));
+
+        model.decode_selected();
+        assert_eq!(model.preview(), "found while browsing");
+        model.select_next();
+        assert_eq!(model.preview(), "No payload found");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use crate::header::{V1DataStuffingOptions, VersionedHeader};
+
+///
+/// How the pixels of an image are split between the header, the payload and what is left,
+/// e.g. to decide whether another payload fits next to the embedded one.
+/// Header and payload pixels never overlap, so the three counts add up to the total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelBudget {
+    pub total_pixels: u64,
+    /// Pixels at the start of the image carrying the header, one bit each
+    pub header_pixels: u64,
+    /// Pixels carrying the payload, starting at `start_offset`. The last one may be used partially.
+    pub payload_pixels: u64,
+    /// Pixels carrying neither, before and after the payload
+    pub free_pixels: u64,
+    pub bits_per_pixel: u32,
+    /// Index of the first payload pixel
+    pub start_offset: u64,
+}
+
+impl PixelBudget {
+    ///
+    /// Accounts for a payload stored sequentially after the header. Other placements spread the payload
+    /// over pixels which are not known from the header alone, so they are rejected.
+    pub fn new(header: &VersionedHeader, total_pixels: u64) -> Result<PixelBudget, String> {
+        let V1DataStuffingOptions::None { start_offset } = header.stuffing_opts() else {
+            return Err(
+                "Pixel budgets are only available for payloads stored right after the header"
+                    .to_string(),
+            );
+        };
+        let bits_per_pixel = header.data_mask().count_ones();
+        let header_pixels = header.pixel_span()?;
+        let payload_pixels = match bits_per_pixel {
+            0 => 0,
+            bits => (header.data_len() * 8).div_ceil(bits as u64),
+        };
+        let used_pixels = header_pixels + payload_pixels;
+        if start_offset < header_pixels || start_offset + payload_pixels > total_pixels {
+            return Err(format!(
+                "The payload pixels {}..{} do not fit between the header and the end of the image at {}",
+                start_offset,
+                start_offset + payload_pixels,
+                total_pixels
+            ));
+        }
+
+        Ok(PixelBudget {
+            total_pixels,
+            header_pixels,
+            payload_pixels,
+            free_pixels: total_pixels - used_pixels,
+            bits_per_pixel,
+            start_offset,
+        })
+    }
+}
@@ -1,121 +1,376 @@
-use std::io::{BufWriter, Cursor, Read};
-
-use image::{ColorType, DynamicImage, ImageBuffer, ImageOutputFormat};
-
-pub(crate) trait WriteImageBinary {
-    fn write_data_with_mask(&mut self, data: &[u8], writing_mask: u64, pixel_offset: usize);
+use std::io::{BufWriter, Cursor};
+
+use image::{
+    flat::SampleLayout, ColorType, DynamicImage, EncodableLayout, FlatSamples, ImageBuffer,
+    ImageOutputFormat, Pixel, PixelWithColorType,
+};
+
+use crate::{
+    header::HEADER_MASK,
+    raw::{
+        read_from_buffer, read_from_buffer_at_pixels, write_to_buffer, write_to_buffer_at_pixels,
+        PixelFormat, WriteStats,
+    },
+};
+
+pub trait WriteImageBinary {
+    fn write_data_with_mask(
+        &mut self,
+        data: &[u8],
+        writing_mask: u64,
+        pixel_offset: usize,
+    ) -> WriteStats;
+    fn write_data_at_pixels(
+        &mut self,
+        data: &[u8],
+        writing_mask: u64,
+        pixels: &[usize],
+    ) -> WriteStats;
+    /// See the function `compensate_mean_shift` below. `original` holds the raw bytes of the image before embedding.
+    fn compensate_mean_shift(&mut self, original: &[u8], data_mask: u64);
+    /// See the function `preserve_luma` below. `original` holds the raw bytes of the image before embedding.
+    fn preserve_luma(&mut self, original: &[u8], data_mask: u64);
 }
 
-pub(crate) trait ReadImageBinary {
+pub trait ReadImageBinary {
     fn read_data_with_mask(&self, reading_mask: u64, pixel_offset: usize, length: usize)
         -> Vec<u8>;
+    fn read_data_at_pixels(&self, reading_mask: u64, pixels: &[usize], length: usize) -> Vec<u8>;
+    fn pixel_count(&self) -> u64;
+    fn width(&self) -> u32;
+    fn color_type(&self) -> ColorType;
+    /// All pixels as 8-bit RGBA, 16-bit channels are reduced to their high byte
+    fn rgba8_pixels(&self) -> Vec<[u8; 4]>;
 }
 
-pub(crate) trait PngImageSaveable {
-    fn save_to_png_buffer(&self) -> Result<Vec<u8>, String>;
+pub trait PngImageSaveable {
+    fn save_to_buffer(&self, format: ImageOutputFormat) -> Result<Vec<u8>, String>;
 }
 
-impl ReadImageBinary for ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-    fn read_data_with_mask(
-        &self,
-        reading_mask: u64,
-        pixel_offset: usize,
-        length: usize,
-    ) -> Vec<u8> {
-        let image_buf = self.as_raw();
-
-        read_from_buffer(
-            image_buf,
-            pixel_offset,
-            length,
-            reading_mask,
-            ColorType::Rgb8,
-        )
+fn save_image_buffer<P>(
+    image: &ImageBuffer<P, Vec<P::Subpixel>>,
+    format: ImageOutputFormat,
+) -> Result<Vec<u8>, String>
+where
+    P: PixelWithColorType,
+    [P::Subpixel]: EncodableLayout,
+{
+    let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+    {
+        let mut writer = BufWriter::new(&mut cursor);
+        image
+            .write_to(&mut writer, format)
+            .map_err(|x| x.to_string())?;
     }
+    Ok(cursor.into_inner())
 }
 
-impl WriteImageBinary for ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-    fn write_data_with_mask(&mut self, data: &[u8], writing_mask: u64, pixel_offset: usize) {
-        // TODO: Check if we can somehow get "as_raw_mut" of sth like that.
-        // Copying the image buffer to be able to do modifications smells a lot.
-        let mut image_buf: image::FlatSamples<&mut [u8]> = self.as_flat_samples_mut();
-
-        write_to_buffer(
-            &mut image_buf.as_mut_slice(),
-            pixel_offset,
-            writing_mask,
-            ColorType::Rgb8,
-            data,
-        )
+///
+/// The bit masks address the bytes of a channel in big-endian order (MSB first).
+/// 16-bit buffers are converted to that layout before reading/writing.
+fn u16_samples_to_be_bytes(samples: &[u16]) -> Vec<u8> {
+    samples
+        .iter()
+        .flat_map(|sample| sample.to_be_bytes())
+        .collect()
+}
+
+fn be_bytes_to_u16_samples(bytes: &[u8], samples: &mut [u16]) {
+    for (sample, bytes) in samples.iter_mut().zip(bytes.chunks_exact(2)) {
+        *sample = u16::from_be_bytes([bytes[0], bytes[1]]);
     }
 }
 
-impl PngImageSaveable for ImageBuffer<image::Rgb<u8>, Vec<u8>> {
-    fn save_to_png_buffer(&self) -> Result<Vec<u8>, String> {
-        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        {
-            let mut writer = BufWriter::new(&mut cursor);
-            self.write_to(&mut writer, ImageOutputFormat::Png)
-                .map_err(|x| x.to_string())?;
+macro_rules! impl_png_image_u8 {
+    ($pixel:ty, $color_type:expr) => {
+        impl ReadImageBinary for ImageBuffer<$pixel, Vec<u8>> {
+            fn read_data_with_mask(
+                &self,
+                reading_mask: u64,
+                pixel_offset: usize,
+                length: usize,
+            ) -> Vec<u8> {
+                read_from_buffer(
+                    packed_samples(self),
+                    pixel_offset,
+                    length,
+                    reading_mask,
+                    $color_type.into(),
+                )
+            }
+
+            fn read_data_at_pixels(
+                &self,
+                reading_mask: u64,
+                pixels: &[usize],
+                length: usize,
+            ) -> Vec<u8> {
+                read_from_buffer_at_pixels(
+                    packed_samples(self),
+                    pixels.iter().copied(),
+                    length,
+                    reading_mask,
+                    $color_type.into(),
+                )
+            }
+
+            fn pixel_count(&self) -> u64 {
+                self.width() as u64 * self.height() as u64
+            }
+
+            fn width(&self) -> u32 {
+                ImageBuffer::width(self)
+            }
+
+            fn color_type(&self) -> ColorType {
+                $color_type
+            }
+
+            fn rgba8_pixels(&self) -> Vec<[u8; 4]> {
+                self.pixels().map(|pixel| pixel.to_rgba().0).collect()
+            }
         }
-        let mut result_vec: Vec<u8> = Vec::new();
-        cursor
-            .read_to_end(&mut result_vec)
-            .map_err(|x| x.to_string())?;
 
-        Ok(result_vec)
-    }
+        impl WriteImageBinary for ImageBuffer<$pixel, Vec<u8>> {
+            fn write_data_with_mask(
+                &mut self,
+                data: &[u8],
+                writing_mask: u64,
+                pixel_offset: usize,
+            ) -> WriteStats {
+                write_to_buffer(
+                    packed_samples_mut(self),
+                    pixel_offset,
+                    writing_mask,
+                    $color_type.into(),
+                    data,
+                )
+            }
+
+            fn write_data_at_pixels(
+                &mut self,
+                data: &[u8],
+                writing_mask: u64,
+                pixels: &[usize],
+            ) -> WriteStats {
+                write_to_buffer_at_pixels(
+                    packed_samples_mut(self),
+                    pixels.iter().copied(),
+                    writing_mask,
+                    $color_type.into(),
+                    data,
+                )
+            }
+
+            fn compensate_mean_shift(&mut self, original: &[u8], data_mask: u64) {
+                compensate_mean_shift(original, packed_samples_mut(self), data_mask, $color_type)
+            }
+
+            fn preserve_luma(&mut self, original: &[u8], data_mask: u64) {
+                preserve_luma(original, packed_samples_mut(self), data_mask, $color_type)
+            }
+        }
+
+        impl PngImageSaveable for ImageBuffer<$pixel, Vec<u8>> {
+            fn save_to_buffer(&self, format: ImageOutputFormat) -> Result<Vec<u8>, String> {
+                save_image_buffer(self, format)
+            }
+        }
+    };
 }
 
-impl PngImageSaveable for ImageBuffer<image::Rgba<u8>, Vec<u8>> {
-    fn save_to_png_buffer(&self) -> Result<Vec<u8>, String> {
-        let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        {
-            let mut writer = BufWriter::new(&mut cursor);
-            self.write_to(&mut writer, ImageOutputFormat::Png)
-                .map_err(|x| x.to_string())?;
+macro_rules! impl_png_image_u16 {
+    ($pixel:ty, $color_type:expr) => {
+        impl ReadImageBinary for ImageBuffer<$pixel, Vec<u16>> {
+            fn read_data_with_mask(
+                &self,
+                reading_mask: u64,
+                pixel_offset: usize,
+                length: usize,
+            ) -> Vec<u8> {
+                let image_buf = u16_samples_to_be_bytes(packed_samples(self));
+
+                read_from_buffer(
+                    &image_buf,
+                    pixel_offset,
+                    length,
+                    reading_mask,
+                    $color_type.into(),
+                )
+            }
+
+            fn read_data_at_pixels(
+                &self,
+                reading_mask: u64,
+                pixels: &[usize],
+                length: usize,
+            ) -> Vec<u8> {
+                let image_buf = u16_samples_to_be_bytes(packed_samples(self));
+
+                read_from_buffer_at_pixels(
+                    &image_buf,
+                    pixels.iter().copied(),
+                    length,
+                    reading_mask,
+                    $color_type.into(),
+                )
+            }
+
+            fn pixel_count(&self) -> u64 {
+                self.width() as u64 * self.height() as u64
+            }
+
+            fn width(&self) -> u32 {
+                ImageBuffer::width(self)
+            }
+
+            fn color_type(&self) -> ColorType {
+                $color_type
+            }
+
+            fn rgba8_pixels(&self) -> Vec<[u8; 4]> {
+                self.pixels()
+                    .map(|pixel| pixel.to_rgba().0.map(|sample| (sample >> 8) as u8))
+                    .collect()
+            }
         }
-        Ok(cursor.into_inner())
-    }
+
+        impl WriteImageBinary for ImageBuffer<$pixel, Vec<u16>> {
+            fn write_data_with_mask(
+                &mut self,
+                data: &[u8],
+                writing_mask: u64,
+                pixel_offset: usize,
+            ) -> WriteStats {
+                let mut image_buf = u16_samples_to_be_bytes(packed_samples(self));
+
+                let stats = write_to_buffer(
+                    &mut image_buf,
+                    pixel_offset,
+                    writing_mask,
+                    $color_type.into(),
+                    data,
+                );
+
+                be_bytes_to_u16_samples(&image_buf, packed_samples_mut(self));
+                stats
+            }
+
+            fn write_data_at_pixels(
+                &mut self,
+                data: &[u8],
+                writing_mask: u64,
+                pixels: &[usize],
+            ) -> WriteStats {
+                let mut image_buf = u16_samples_to_be_bytes(packed_samples(self));
+
+                let stats = write_to_buffer_at_pixels(
+                    &mut image_buf,
+                    pixels.iter().copied(),
+                    writing_mask,
+                    $color_type.into(),
+                    data,
+                );
+
+                be_bytes_to_u16_samples(&image_buf, packed_samples_mut(self));
+                stats
+            }
+
+            fn compensate_mean_shift(&mut self, original: &[u8], data_mask: u64) {
+                let original: Vec<u16> = original
+                    .chunks_exact(2)
+                    .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+                    .collect();
+                compensate_mean_shift(&original, packed_samples_mut(self), data_mask, $color_type)
+            }
+
+            fn preserve_luma(&mut self, original: &[u8], data_mask: u64) {
+                let original: Vec<u16> = original
+                    .chunks_exact(2)
+                    .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+                    .collect();
+                preserve_luma(&original, packed_samples_mut(self), data_mask, $color_type)
+            }
+        }
+
+        impl PngImageSaveable for ImageBuffer<$pixel, Vec<u16>> {
+            fn save_to_buffer(&self, format: ImageOutputFormat) -> Result<Vec<u8>, String> {
+                save_image_buffer(self, format)
+            }
+        }
+    };
 }
 
-impl ReadImageBinary for ImageBuffer<image::Rgba<u8>, Vec<u8>> {
-    fn read_data_with_mask(
-        &self,
-        reading_mask: u64,
-        pixel_offset: usize,
-        length: usize,
-    ) -> Vec<u8> {
-        let image_buf = self.as_raw();
-
-        read_from_buffer(
-            image_buf,
-            pixel_offset,
-            length,
-            reading_mask,
-            ColorType::Rgba8,
+impl_png_image_u8!(image::Rgb<u8>, ColorType::Rgb8);
+impl_png_image_u8!(image::Rgba<u8>, ColorType::Rgba8);
+impl_png_image_u16!(image::Rgb<u16>, ColorType::Rgb16);
+impl_png_image_u16!(image::Rgba<u16>, ColorType::Rgba16);
+
+impl From<ColorType> for PixelFormat {
+    fn from(color_type: ColorType) -> PixelFormat {
+        PixelFormat::new(
+            color_type.channel_count(),
+            color_type.bytes_per_pixel() / color_type.channel_count(),
         )
     }
 }
 
-impl WriteImageBinary for ImageBuffer<image::Rgba<u8>, Vec<u8>> {
-    fn write_data_with_mask(&mut self, data: &[u8], writing_mask: u64, pixel_offset: usize) {
-        let mut image_buf: image::FlatSamples<&mut [u8]> = self.as_flat_samples_mut();
+pub trait PngImage: ReadImageBinary + WriteImageBinary + PngImageSaveable {}
+impl<T> PngImage for T where T: ReadImageBinary + WriteImageBinary + PngImageSaveable {}
 
-        write_to_buffer(
-            &mut image_buf.as_mut_slice(),
-            pixel_offset,
-            writing_mask,
-            ColorType::Rgba8,
-            data,
-        )
+///
+/// The buffers are indexed as `pixel_index * bytes_per_pixel`, which only holds for
+/// row-major samples without any padding between channels, pixels or rows.
+fn check_tightly_packed<B: AsRef<[T]>, T>(samples: &FlatSamples<B>) -> Result<(), String> {
+    let layout = samples.layout;
+    let expected = SampleLayout::row_major_packed(layout.channels, layout.width, layout.height);
+    if layout != expected {
+        return Err(format!(
+            "Image buffer is not tightly packed: expected strides (channel {}, pixel {}, row {}), got ({}, {}, {})",
+            expected.channel_stride,
+            expected.width_stride,
+            expected.height_stride,
+            layout.channel_stride,
+            layout.width_stride,
+            layout.height_stride
+        ));
+    }
+    match expected.min_length() {
+        Some(length) if samples.samples.as_ref().len() >= length => Ok(()),
+        _ => Err("Image buffer is shorter than its dimensions require".to_string()),
     }
 }
 
-pub(crate) trait PngImage: ReadImageBinary + WriteImageBinary + PngImageSaveable {}
-impl<T> PngImage for T where T: ReadImageBinary + WriteImageBinary + PngImageSaveable {}
+///
+/// The samples the read path reads. `as_raw` and `as_flat_samples_mut` both hand out the whole sample
+/// container of an `ImageBuffer`, so reads and writes see the same layout as long as it is tightly packed.
+fn packed_samples<P: Pixel>(buffer: &ImageBuffer<P, Vec<P::Subpixel>>) -> &[P::Subpixel] {
+    let samples = buffer.as_flat_samples();
+    if let Err(err) = check_tightly_packed(&samples) {
+        panic!("{}", err);
+    }
+    samples.samples
+}
+
+///
+/// The samples the write path modifies, the same ones [`packed_samples`] reads
+fn packed_samples_mut<P: Pixel>(
+    buffer: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+) -> &mut [P::Subpixel] {
+    if let Err(err) = check_tightly_packed(&buffer.as_flat_samples()) {
+        panic!("{}", err);
+    }
+    buffer.as_flat_samples_mut().samples
+}
+
+fn packed<P: Pixel>(
+    buffer: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+) -> Result<&mut ImageBuffer<P, Vec<P::Subpixel>>, String> {
+    check_tightly_packed(&buffer.as_flat_samples())?;
+    Ok(buffer)
+}
 
-pub(crate) fn convert_dynamic_image_to_png_image(
+pub fn convert_dynamic_image_to_png_image(
     image: &mut DynamicImage,
 ) -> Result<&mut dyn PngImage, String> {
     match image.color() {
@@ -123,10 +378,12 @@ pub(crate) fn convert_dynamic_image_to_png_image(
         | image::ColorType::La8
         | image::ColorType::L16
         | image::ColorType::La16 => Err("Luma-type Images are currently not supported".to_string()),
-        image::ColorType::Rgb8 => Ok(image.as_mut_rgb8().unwrap() as &mut dyn PngImage),
-        image::ColorType::Rgba8 => Ok(image.as_mut_rgba8().unwrap() as &mut dyn PngImage),
-        //image::ColorType::Rgb16 => Ok(image.as_mut_rgb16().unwrap()),
-        //image::ColorType::Rgba16 => Ok(image.as_rgba16().unwrap()),
+        image::ColorType::Rgb8 => Ok(packed(image.as_mut_rgb8().unwrap())? as &mut dyn PngImage),
+        image::ColorType::Rgba8 => Ok(packed(image.as_mut_rgba8().unwrap())? as &mut dyn PngImage),
+        image::ColorType::Rgb16 => Ok(packed(image.as_mut_rgb16().unwrap())? as &mut dyn PngImage),
+        image::ColorType::Rgba16 => {
+            Ok(packed(image.as_mut_rgba16().unwrap())? as &mut dyn PngImage)
+        }
         image::ColorType::Rgb32F | image::ColorType::Rgba32F => {
             Err("Floating-Type Images are currently not supported".to_string())
         }
@@ -135,130 +392,169 @@ pub(crate) fn convert_dynamic_image_to_png_image(
 }
 
 ///
-/// read_mask is a right-padded mask defining which bits in a pixel are relevant.
-pub(crate) fn read_from_buffer(
-    image_buf: &[u8],
-    pixels_offset_start: usize,
-    bytes_len_read: usize,
-    read_mask: u64,
-    color_type: ColorType,
-) -> Vec<u8> {
-    let offset_map = create_offset_map(read_mask, color_type.bits_per_pixel() as usize);
-    if offset_map.len() == 0 {
-        panic!("offset-map is empty. Cannot continue.");
-    }
+/// Bits of a sample which are read when decoding, and the step a sample may be moved by instead.
+#[derive(Debug, Clone, Copy)]
+struct AdjustableChannel {
+    /// Payload bits of the channel
+    data_bits: i64,
+    /// Payload bits and the bit which may carry the header. Moves changing any of them are not allowed.
+    read_bits: i64,
+    /// Lowest bit above the payload bits which is not read
+    step: i64,
+}
 
-    let mut return_data: Vec<u8> = Vec::new();
-
-    let mut current_byte_vec: Vec<bool> = Vec::with_capacity(8);
-
-    let mut current_pixel_index = pixels_offset_start;
-    // Loop over all pixels. This will break out once bytes_len_read is finished
-    loop {
-        let current_pixel_slice =
-            get_pixel_slice(image_buf, color_type.bytes_per_pixel(), current_pixel_index);
-
-        for in_pixel_offset in &offset_map {
-            let bit_value = current_pixel_slice[in_pixel_offset / 8]
-                & (0b1u8 << 7 >> (in_pixel_offset % 8))
-                != 0;
-            current_byte_vec.push(bit_value);
-
-            if current_byte_vec.len() == 8 {
-                // Now build the byte
-                let mut byte = 0u8;
-                for i in 0..8 {
-                    if !current_byte_vec[i] {
-                        continue;
-                    }
-                    byte |= 0b1 << 7 >> i;
+impl AdjustableChannel {
+    fn for_channels(data_mask: u64, color_type: ColorType) -> Vec<AdjustableChannel> {
+        let channels = color_type.channel_count() as usize;
+        let bits_per_channel = color_type.bits_per_pixel() as usize / channels;
+        let channel_bits = |mask: u64, channel: usize| -> i64 {
+            ((mask << (channel * bits_per_channel)) >> (u64::BITS as usize - bits_per_channel))
+                as i64
+        };
+
+        (0..channels)
+            .map(|channel| {
+                let data_bits = channel_bits(data_mask, channel);
+                let read_bits = data_bits | channel_bits(HEADER_MASK, channel);
+                let above_data = i64::BITS - data_bits.leading_zeros();
+                let step = 1i64 << ((!read_bits >> above_data).trailing_zeros() + above_data);
+                AdjustableChannel {
+                    data_bits,
+                    read_bits,
+                    step,
                 }
-                return_data.push(byte);
-                current_byte_vec.clear();
-                if return_data.len() == bytes_len_read {
-                    return return_data;
-                }
-            }
-        }
-        current_pixel_index += 1;
+            })
+            .collect()
     }
-}
 
-pub(crate) fn write_to_buffer(
-    image_buf: &mut [u8],
-    pixels_offset_start: usize,
-    write_mask: u64,
-    color_type: ColorType,
-    data_to_write: &[u8],
-) {
-    let offset_map = create_offset_map(write_mask, color_type.bits_per_pixel() as usize);
-    if offset_map.len() == 0 {
-        panic!("offset-map is empty. Cannot continue.");
+    ///
+    /// Whether the sample may take `candidate` instead of the `written` value.
+    /// Moving by a step can carry into higher bits, so the read bits are checked as a whole.
+    fn allows(&self, written: i64, candidate: i64, max_value: i64) -> bool {
+        (0..=max_value).contains(&candidate) && (written ^ candidate) & self.read_bits == 0
     }
-    let mut current_byte_to_write: Vec<bool> = Vec::with_capacity(8);
-    let mut data_to_write_index = 0usize;
-    let mut current_pixel_index = pixels_offset_start;
+}
 
-    let current_byte = data_to_write[data_to_write_index];
-    for i in 0..8 {
-        current_byte_to_write.push(current_byte & (0b1u8 << 7 >> i) != 0);
-    }
-    current_byte_to_write.reverse(); // Reversed as we will just "pop" from the back
-
-    loop {
-        let current_pixel_slice =
-            get_pixel_slice_mut(image_buf, color_type.bytes_per_pixel(), current_pixel_index);
-
-        for in_pixel_offset in &offset_map {
-            let local_pixel_offset = in_pixel_offset / 8;
-            let local_mask = 0b1u8 << 7 >> in_pixel_offset % 8;
-            // inverted mask causes the value bit to be set to 0
-            current_pixel_slice[local_pixel_offset] &= !local_mask;
-            if current_byte_to_write.pop().unwrap() {
-                // set the value bit to 1
-                current_pixel_slice[local_pixel_offset] |= local_mask;
-            }
-            if current_byte_to_write.len() == 0 {
-                data_to_write_index += 1;
-                if data_to_write_index >= data_to_write.len() {
-                    return;
-                }
-                let current_byte = data_to_write[data_to_write_index];
-                for i in 0..8 {
-                    current_byte_to_write.push((current_byte & (0b1u8 << 7 >> i)) != 0);
-                }
-                current_byte_to_write.reverse() // Reversed as we will just "pop" from the back
-            }
+///
+/// Counters the brightness bias of overwriting the masked bits with the payload.
+/// Every changed sample may also be moved by one step above its highest masked bit,
+/// which leaves all bits read when decoding as they are.
+/// Per channel, the option keeping the running sum of changes closest to zero is picked.
+/// Channels without masked bits are left alone.
+fn compensate_mean_shift<T>(
+    original: &[T],
+    modified: &mut [T],
+    data_mask: u64,
+    color_type: ColorType,
+) where
+    T: Copy + PartialEq + Into<i64> + TryFrom<i64>,
+{
+    let channels = color_type.channel_count() as usize;
+    let bits_per_channel = color_type.bits_per_pixel() as usize / channels;
+    let adjustable = AdjustableChannel::for_channels(data_mask, color_type);
+    let max_value = (1i64 << bits_per_channel) - 1;
+
+    let mut shifts = vec![0i64; channels];
+    for (index, (old, new)) in original.iter().zip(modified.iter_mut()).enumerate() {
+        let channel = index % channels;
+        let channel_info = adjustable[channel];
+        if channel_info.data_bits == 0 || old == new {
+            continue;
+        }
+        let (old, value): (i64, i64) = ((*old).into(), (*new).into());
+        let shift = shifts[channel];
+        let best = [value, value - channel_info.step, value + channel_info.step]
+            .into_iter()
+            .filter(|candidate| channel_info.allows(value, *candidate, max_value))
+            .min_by_key(|candidate| ((shift + candidate - old).abs(), (candidate - old).abs()))
+            .expect("the written value is always allowed");
+        shifts[channel] += best - old;
+        if let Ok(best) = T::try_from(best) {
+            *new = best;
         }
-        current_pixel_index += 1;
     }
 }
 
-fn get_pixel_slice(image_buf: &[u8], pixel_len_bytes: u8, current_pixel_index: usize) -> &[u8] {
-    &image_buf[current_pixel_index * pixel_len_bytes as usize
-        ..(current_pixel_index + 1) * pixel_len_bytes as usize]
-}
-
-fn get_pixel_slice_mut(
-    image_buf: &mut [u8],
-    pixel_len_bytes: u8,
-    current_pixel_index: usize,
-) -> &mut [u8] {
-    &mut image_buf[current_pixel_index * pixel_len_bytes as usize
-        ..(current_pixel_index + 1) * pixel_len_bytes as usize]
-}
+/// Rec. 601 luma weights of R, G and B, in thousandths
+const LUMA_WEIGHTS: [i64; 3] = [299, 587, 114];
 
 ///
-/// Returns a vec containing an "offset map" which defines the offsets of all value-bits
-fn create_offset_map(write_mask: u64, pixel_size: usize) -> Vec<usize> {
-    let mut return_map = Vec::new();
-    for i in 0..pixel_size {
-        if ((0b1 << 63 >> i) & write_mask) > 0 {
-            return_map.push(i)
+/// Keeps the luma of every changed pixel close to the original, by also moving its color channels
+/// by one step above their highest masked bit, without changing any bit read when decoding.
+/// Among all combinations of moves, the one with the smallest luma deviation is picked,
+/// preferring smaller changes. Images without color channels are left alone.
+fn preserve_luma<T>(original: &[T], modified: &mut [T], data_mask: u64, color_type: ColorType)
+where
+    T: Copy + PartialEq + Into<i64> + TryFrom<i64>,
+{
+    let channels = color_type.channel_count() as usize;
+    if channels < 3 {
+        return;
+    }
+    let bits_per_channel = color_type.bits_per_pixel() as usize / channels;
+    let adjustable = AdjustableChannel::for_channels(data_mask, color_type);
+    let max_value = (1i64 << bits_per_channel) - 1;
+    let luma = |pixel: &[i64; 3]| -> i64 {
+        LUMA_WEIGHTS
+            .iter()
+            .zip(pixel)
+            .map(|(weight, value)| weight * value)
+            .sum()
+    };
+
+    for (old, new) in original
+        .chunks_exact(channels)
+        .zip(modified.chunks_exact_mut(channels))
+    {
+        if old == new {
+            continue;
+        }
+        let old: [i64; 3] = std::array::from_fn(|channel| old[channel].into());
+        let written: [i64; 3] = std::array::from_fn(|channel| new[channel].into());
+        let target = luma(&old);
+
+        // Every channel moves down a step, stays or moves up a step
+        let best = (0..27)
+            .map(|combination: i64| {
+                std::array::from_fn(|channel| {
+                    let direction = combination / 3i64.pow(channel as u32) % 3 - 1;
+                    written[channel] + direction * adjustable[channel].step
+                })
+            })
+            .filter(|candidate: &[i64; 3]| {
+                (0..3).all(|channel| {
+                    adjustable[channel].allows(written[channel], candidate[channel], max_value)
+                })
+            })
+            .min_by_key(|candidate| {
+                let change: i64 = candidate
+                    .iter()
+                    .zip(&old)
+                    .map(|(value, old)| (value - old).abs())
+                    .sum();
+                ((luma(candidate) - target).abs(), change)
+            })
+            .expect("the written pixel is always allowed");
+
+        for (sample, value) in new.iter_mut().zip(best) {
+            if let Ok(value) = T::try_from(value) {
+                *sample = value;
+            }
         }
     }
+}
 
-    return_map
+///
+/// Converts a pixel index or count (stored as u64 in the header) into a usize.
+/// On 32-bit targets this fails instead of silently truncating the value.
+pub fn checked_pixel_index(value: u64) -> Result<usize, String> {
+    usize::try_from(value).map_err(|_| {
+        format!(
+            "Image too large for this platform: {} does not fit into a {}-bit index",
+            value,
+            usize::BITS
+        )
+    })
 }
 
 #[cfg(test)]
@@ -268,37 +564,182 @@ mod tests {
     use super::*;
 
     #[test]
-    fn create_offset_map_test() {
-        let input =
-            0b1000_0100_0010_0001__0000_0000_0000_0000__0000_0000_0000_0000__0000_0000_0000_0001u64;
+    fn checked_pixel_index_in_range() {
+        assert_eq!(checked_pixel_index(1234).unwrap(), 1234usize);
+    }
 
-        let output = create_offset_map(input, 64);
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn checked_pixel_index_beyond_usize_errors() {
+        let result = checked_pixel_index(u32::MAX as u64 + 1);
+        assert!(result.unwrap_err().contains("too large for this platform"));
+    }
 
-        assert_eq!(output, vec![0usize, 5usize, 10usize, 15usize, 63usize])
+    #[test]
+    fn mean_shift_compensation_keeps_payload() {
+        let cover: ImageBuffer<image::Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        });
+        // Only set bits, which shifts every changed sample up
+        let payload = vec![0xFF; 1000];
+        let mask = 0x01_01_01_00_00_00_00_00;
+        let mean_shift = |image: &ImageBuffer<image::Rgb<u8>, Vec<u8>>| -> i64 {
+            image
+                .iter()
+                .zip(cover.iter())
+                .map(|(new, old)| *new as i64 - *old as i64)
+                .sum::<i64>()
+                .abs()
+        };
+
+        let mut plain = cover.clone();
+        plain.write_data_with_mask(&payload, mask, 100);
+        let mut compensated = plain.clone();
+        compensated.compensate_mean_shift(cover.as_raw(), mask);
+
+        assert_eq!(
+            compensated.read_data_with_mask(mask, 100, payload.len()),
+            payload
+        );
+        assert!(mean_shift(&plain) > 1000, "{}", mean_shift(&plain));
+        assert!(
+            mean_shift(&compensated) <= 3,
+            "{}",
+            mean_shift(&compensated)
+        );
     }
 
     #[test]
-    fn encode_and_decode_into_byte_buffer() {
-        let mut image_buf = vec![0u8; 200];
-        rand::thread_rng().fill_bytes(&mut image_buf);
-
-        let data: Vec<u8> = vec![0x12, 0x34, 0x56, 0x78];
-        write_to_buffer(
-            &mut image_buf,
-            0,
-            0x01_01_01_00_00_00_00_00u64,
-            ColorType::Rgba8,
-            &data,
+    fn luma_preservation_keeps_payload() {
+        let cover: ImageBuffer<image::Rgb<u8>, Vec<u8>> = ImageBuffer::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        });
+        let mut payload = vec![0u8; 1200];
+        rand::thread_rng().fill_bytes(&mut payload);
+        let mask = 0x01_01_01_00_00_00_00_00;
+        let luma_deviation = |image: &ImageBuffer<image::Rgb<u8>, Vec<u8>>| -> i64 {
+            image
+                .pixels()
+                .zip(cover.pixels())
+                .map(|(new, old)| {
+                    (0..3)
+                        .map(|channel| {
+                            LUMA_WEIGHTS[channel] * (new[channel] as i64 - old[channel] as i64)
+                        })
+                        .sum::<i64>()
+                        .abs()
+                })
+                .sum()
+        };
+
+        let mut plain = cover.clone();
+        plain.write_data_with_mask(&payload, mask, 100);
+        let mut preserved = plain.clone();
+        preserved.preserve_luma(cover.as_raw(), mask);
+
+        assert_eq!(
+            preserved.read_data_with_mask(mask, 100, payload.len()),
+            payload
+        );
+        assert_eq!(
+            preserved.read_data_with_mask(HEADER_MASK, 0, 100),
+            plain.read_data_with_mask(HEADER_MASK, 0, 100)
         );
+        assert!(
+            luma_deviation(&preserved) * 4 < luma_deviation(&plain),
+            "{} vs {}",
+            luma_deviation(&preserved),
+            luma_deviation(&plain)
+        );
+    }
+
+    #[test]
+    fn adjustments_keep_the_header_bit_of_wide_samples() {
+        // Samples just below the header bit, so moving a step up would carry into it
+        let cover: ImageBuffer<image::Rgb<u16>, Vec<u16>> = ImageBuffer::from_fn(32, 32, |x, y| {
+            image::Rgb([0x00FC + (x % 4) as u16, 0x01FC + (y % 4) as u16, 0x00FE])
+        });
+        let mut payload = vec![0u8; 300];
+        rand::thread_rng().fill_bytes(&mut payload);
+        let mask = 0x0001_0001_0001_0000;
+
+        let mut plain = cover.clone();
+        plain.write_data_with_mask(&payload, mask, 100);
+        let mut compensated = plain.clone();
+        compensated.compensate_mean_shift(cover.as_bytes(), mask);
+        let mut preserved = plain.clone();
+        preserved.preserve_luma(cover.as_bytes(), mask);
+
+        for adjusted in [&compensated, &preserved] {
+            assert_ne!(adjusted, &plain);
+            assert_eq!(
+                adjusted.read_data_with_mask(HEADER_MASK, 0, 100),
+                plain.read_data_with_mask(HEADER_MASK, 0, 100)
+            );
+            assert_eq!(
+                adjusted.read_data_with_mask(mask, 100, payload.len()),
+                payload
+            );
+        }
+    }
 
-        let result = read_from_buffer(
-            &image_buf,
-            0,
-            4,
-            0x01_01_01_00_00_00_00_00u64,
-            ColorType::Rgba8,
+    #[test]
+    fn padded_rows_are_rejected() {
+        let mut layout = SampleLayout::row_major_packed(3, 4, 2);
+        // 2 bytes of padding after every row
+        layout.height_stride = 4 * 3 + 2;
+        let samples = FlatSamples {
+            samples: vec![0u8; layout.height_stride * 2],
+            layout,
+            color_hint: Some(ColorType::Rgb8),
+        };
+
+        let result = check_tightly_packed(&samples);
+        assert!(result.unwrap_err().contains("not tightly packed"));
+    }
+
+    #[test]
+    fn image_buffers_are_tightly_packed() {
+        let mut image = DynamicImage::new_rgba16(5, 3);
+
+        assert!(convert_dynamic_image_to_png_image(&mut image).is_ok());
+    }
+
+    #[test]
+    fn rgb8_trait_reads_what_it_writes() {
+        // An odd width, so rows are not a multiple of 4 bytes long
+        let mut image = DynamicImage::new_rgb8(33, 7);
+        rand::thread_rng().fill_bytes(image.as_mut_rgb8().unwrap());
+        let mask = 0x03_01_03_00_00_00_00_00u64;
+        let unused_bit = 0x00_02_00_00_00_00_00_00u64;
+        let data: Vec<u8> = (0..80).collect();
+        let pixels: Vec<usize> = (0..33 * 7).rev().step_by(2).collect();
+
+        let rgb8 = convert_dynamic_image_to_png_image(&mut image).unwrap();
+        assert_eq!(rgb8.color_type(), ColorType::Rgb8);
+        rgb8.write_data_with_mask(&data, mask, 5);
+        rgb8.write_data_at_pixels(&data[..10], unused_bit, &pixels);
+
+        assert_eq!(rgb8.read_data_with_mask(mask, 5, data.len()), data);
+        assert_eq!(
+            rgb8.read_data_at_pixels(unused_bit, &pixels, 10),
+            &data[..10]
+        );
+        // The raw samples hold the same bits the trait reads
+        let raw = image.as_rgb8().unwrap().as_raw();
+        assert_eq!(
+            read_from_buffer(raw, 5, data.len(), mask, ColorType::Rgb8.into()),
+            data
         );
 
-        assert_eq!(data, result);
+        // And so does the image after a round trip through PNG
+        let png = convert_dynamic_image_to_png_image(&mut image)
+            .unwrap()
+            .save_to_buffer(ImageOutputFormat::Png)
+            .unwrap();
+        let mut reloaded = image::load_from_memory(&png).unwrap();
+        let reloaded = convert_dynamic_image_to_png_image(&mut reloaded).unwrap();
+        assert_eq!(reloaded.color_type(), ColorType::Rgb8);
+        assert_eq!(reloaded.read_data_with_mask(mask, 5, data.len()), data);
     }
 }
@@ -0,0 +1,258 @@
+use std::str::FromStr;
+
+use image::ColorType;
+
+use crate::header::{bit_mask_for_channels, check_low_bits_only, VersionedHeader};
+
+///
+/// Number of bits the payload uses in one channel, given as `r=2`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ChannelBits {
+    pub(crate) channel: char,
+    pub(crate) bits: u8,
+}
+
+impl FromStr for ChannelBits {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Expected CHANNEL=BITS like r=2, got \"{}\"", value);
+        let (channel, bits) = value.split_once('=').ok_or_else(invalid)?;
+        let mut chars = channel.trim().chars();
+        let (Some(channel), None) = (chars.next(), chars.next()) else {
+            return Err(invalid());
+        };
+
+        Ok(ChannelBits {
+            channel: channel.to_ascii_lowercase(),
+            bits: bits.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Names of the channels of the color type, in the order they are stored
+fn channel_names(color_type: ColorType) -> &'static str {
+    match color_type.channel_count() {
+        1 => "l",
+        2 => "la",
+        3 => "rgb",
+        _ => "rgba",
+    }
+}
+
+///
+/// Builds the data mask from the bits given per channel. Channels which are not given carry no payload.
+pub(crate) fn channel_bits_mask(
+    entries: &[ChannelBits],
+    color_type: ColorType,
+) -> Result<u64, String> {
+    let names = channel_names(color_type);
+    let bits_per_channel = color_type.bits_per_pixel() / color_type.channel_count() as u16;
+    let mut data_bits_per_channel = vec![None; names.len()];
+
+    for entry in entries {
+        let index = names.find(entry.channel).ok_or_else(|| {
+            format!(
+                "Channel {} is not part of the image, which has the channels {}",
+                entry.channel, names
+            )
+        })?;
+        if entry.bits as u16 > bits_per_channel {
+            return Err(format!(
+                "Channel {} only has {} bits, but {} were given",
+                entry.channel, bits_per_channel, entry.bits
+            ));
+        }
+        if data_bits_per_channel[index]
+            .replace(entry.bits as usize)
+            .is_some()
+        {
+            return Err(format!("Channel {} is given more than once", entry.channel));
+        }
+    }
+
+    let data_bits_per_channel: Vec<usize> = data_bits_per_channel
+        .into_iter()
+        .map(|bits| bits.unwrap_or(0))
+        .collect();
+    if data_bits_per_channel.iter().all(|bits| *bits == 0) {
+        return Err("At least one channel has to carry payload bits".to_string());
+    }
+
+    Ok(bit_mask_for_channels(&data_bits_per_channel, color_type))
+}
+
+///
+/// Replaces the data mask picked for the header with the one given per channel.
+/// The start offset is kept, so the mask has to provide at least as many bits per pixel as the picked one.
+pub(crate) fn with_channel_bits(
+    header: VersionedHeader,
+    entries: &[ChannelBits],
+    color_type: ColorType,
+    max_bits_per_channel: Option<u8>,
+) -> Result<VersionedHeader, String> {
+    let data_mask = channel_bits_mask(entries, color_type)?;
+    let needed_bits = header.data_mask().count_ones();
+    if data_mask.count_ones() < needed_bits {
+        return Err(format!(
+            "The payload needs {} bits per pixel, but --channel-bits only gives {}",
+            needed_bits,
+            data_mask.count_ones()
+        ));
+    }
+    check_low_bits_only(data_mask, color_type, max_bits_per_channel)?;
+
+    Ok(header.with_data_mask(data_mask))
+}
+
+///
+/// Channel bits which confine the payload to the alpha channel. The header follows it there,
+/// so the color channels stay bit-exact.
+pub(crate) fn alpha_channel_bits(
+    bits: u8,
+    color_type: ColorType,
+) -> Result<Vec<ChannelBits>, String> {
+    if !color_type.has_alpha() {
+        return Err(format!(
+            "--alpha-only needs an image with an alpha channel, but the image is {:?}",
+            color_type
+        ));
+    }
+
+    Ok(vec![ChannelBits { channel: 'a', bits }])
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb, Rgba};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header, DEFAULT_MAX_BITS_PER_CHANNEL},
+        payload::{read_payload, write_payload},
+    };
+
+    fn parse(entries: &[&str]) -> Vec<ChannelBits> {
+        entries.iter().map(|entry| entry.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_channel_bits() {
+        assert_eq!(
+            "G=3".parse::<ChannelBits>().unwrap(),
+            ChannelBits {
+                channel: 'g',
+                bits: 3
+            }
+        );
+        assert!("g3".parse::<ChannelBits>().is_err());
+        assert!("rg=3".parse::<ChannelBits>().is_err());
+        assert!("g=x".parse::<ChannelBits>().is_err());
+    }
+
+    #[test]
+    fn entries_are_order_independent() {
+        let mask = channel_bits_mask(&parse(&["r=2", "g=1", "b=3"]), ColorType::Rgb8).unwrap();
+        let reordered = channel_bits_mask(&parse(&["b=3", "r=2", "g=1"]), ColorType::Rgb8).unwrap();
+
+        assert_eq!(mask, 0x03_01_07_00_00_00_00_00);
+        assert_eq!(mask, reordered);
+    }
+
+    #[test]
+    fn missing_channels_carry_no_payload() {
+        assert_eq!(
+            channel_bits_mask(&parse(&["g=2"]), ColorType::Rgba8).unwrap(),
+            0x00_03_00_00_00_00_00_00
+        );
+        assert_eq!(
+            channel_bits_mask(&parse(&["a=1"]), ColorType::Rgba16).unwrap(),
+            0x0000_0000_0000_0001
+        );
+        assert!(channel_bits_mask(&parse(&["r=0"]), ColorType::Rgb8).is_err());
+    }
+
+    #[test]
+    fn over_specification_is_rejected() {
+        assert!(channel_bits_mask(&parse(&["r=1", "r=2"]), ColorType::Rgb8).is_err());
+        assert!(channel_bits_mask(&parse(&["a=1"]), ColorType::Rgb8).is_err());
+        assert!(channel_bits_mask(&parse(&["r=9"]), ColorType::Rgb8).is_err());
+        assert!(channel_bits_mask(&parse(&["l=1", "r=1"]), ColorType::L8).is_err());
+    }
+
+    #[test]
+    fn payload_round_trips_with_channel_bits() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        let payload = vec![0xA5; 600];
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        let max_bits = Some(DEFAULT_MAX_BITS_PER_CHANNEL);
+
+        assert!(
+            with_channel_bits(header.clone(), &parse(&["g=1"]), ColorType::Rgb8, max_bits).is_err()
+        );
+        assert!(with_channel_bits(
+            header.clone(),
+            &parse(&["r=1", "g=3"]),
+            ColorType::Rgb8,
+            max_bits
+        )
+        .is_err());
+        let header =
+            with_channel_bits(header, &parse(&["r=2", "b=2"]), ColorType::Rgb8, max_bits).unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        let header = try_get_header(&image).unwrap();
+        assert_eq!(header.data_mask(), 0x03_00_03_00_00_00_00_00);
+        assert_eq!(read_payload(&image, &header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn alpha_only_leaves_the_color_channels_untouched() {
+        let mut cover: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut cover);
+        for pixel in cover.pixels_mut() {
+            pixel[3] = 255;
+        }
+        let payload = vec![0x3C; 300];
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            ColorType::Rgba8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        let header = with_channel_bits(
+            header,
+            &alpha_channel_bits(2, ColorType::Rgba8).unwrap(),
+            ColorType::Rgba8,
+            Some(DEFAULT_MAX_BITS_PER_CHANNEL),
+        )
+        .unwrap();
+        let mut image = cover.clone();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        for (encoded, original) in image.pixels().zip(cover.pixels()) {
+            assert_eq!(encoded.0[..3], original.0[..3]);
+        }
+        let decoded = try_get_header(&image).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.data_mask(), 0x00_00_00_03_00_00_00_00);
+        assert_eq!(read_payload(&image, &decoded, None).unwrap(), payload);
+
+        assert!(alpha_channel_bits(2, ColorType::Rgb8).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::Cursor;
+
+use arboard::{Clipboard, ImageData};
+use image::{DynamicImage, ImageOutputFormat, RgbaImage};
+
+///
+/// Where the clipboard image comes from. Only the system clipboard is used outside of tests.
+pub(crate) trait ClipboardSource {
+    fn get_image(&mut self) -> Result<ImageData<'static>, arboard::Error>;
+}
+
+impl ClipboardSource for Clipboard {
+    fn get_image(&mut self) -> Result<ImageData<'static>, arboard::Error> {
+        Clipboard::get_image(self)
+    }
+}
+
+///
+/// Reads the clipboard image as RGBA.
+pub(crate) fn read_clipboard_image(
+    source: &mut dyn ClipboardSource,
+) -> Result<DynamicImage, String> {
+    let data = source.get_image().map_err(|err| match err {
+        arboard::Error::ContentNotAvailable => {
+            "The clipboard does not contain an image. Copy one first, e.g. take a screenshot"
+                .to_string()
+        }
+        err => format!("Failed to read the clipboard: {}", err),
+    })?;
+
+    let width = u32::try_from(data.width).map_err(|x| x.to_string())?;
+    let height = u32::try_from(data.height).map_err(|x| x.to_string())?;
+    RgbaImage::from_raw(width, height, data.bytes.into_owned())
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| "The clipboard image is smaller than its dimensions".to_string())
+}
+
+///
+/// Reads the clipboard image as PNG file, so it goes through the same decode path as images from STDIN.
+pub(crate) fn read_clipboard_png(source: &mut dyn ClipboardSource) -> Result<Vec<u8>, String> {
+    let image = read_clipboard_image(source)?;
+    let mut cursor = Cursor::new(Vec::new());
+    image
+        .write_to(&mut cursor, ImageOutputFormat::Png)
+        .map_err(|x| x.to_string())?;
+    Ok(cursor.into_inner())
+}
+
+///
+/// Reads the image from the system clipboard
+pub(crate) fn read_system_clipboard_png() -> Result<Vec<u8>, String> {
+    let mut clipboard =
+        Clipboard::new().map_err(|err| format!("Failed to access the clipboard: {}", err))?;
+    read_clipboard_png(&mut clipboard)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use image::{ImageBuffer, Rgba};
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        buffer_modify::convert_dynamic_image_to_png_image,
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header},
+        payload::{read_payload, write_payload},
+    };
+
+    struct MockClipboard(Option<ImageData<'static>>);
+
+    impl ClipboardSource for MockClipboard {
+        fn get_image(&mut self) -> Result<ImageData<'static>, arboard::Error> {
+            self.0.clone().ok_or(arboard::Error::ContentNotAvailable)
+        }
+    }
+
+    #[test]
+    fn payload_is_read_from_clipboard_image() {
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        let payload = b"copied from a screenshot".to_vec();
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            image::ColorType::Rgba8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+        let mut clipboard = MockClipboard(Some(ImageData {
+            width: 64,
+            height: 64,
+            bytes: Cow::Owned(image.into_raw()),
+        }));
+
+        let png = read_clipboard_png(&mut clipboard).unwrap();
+        let mut decoded = image::load_from_memory(&png).unwrap();
+        let decoded = convert_dynamic_image_to_png_image(&mut decoded).unwrap();
+        let header = try_get_header(decoded).unwrap();
+        assert_eq!(read_payload(decoded, &header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn missing_clipboard_image_is_reported() {
+        let err = read_clipboard_png(&mut MockClipboard(None)).unwrap_err();
+
+        assert!(err.contains("does not contain an image"), "{}", err);
+    }
+}
@@ -0,0 +1,179 @@
+use std::str::FromStr;
+
+use bincode::{Decode, Encode};
+
+use crate::{avoid_mask::AvoidMask, header::DEFAULT_MAX_BITS_PER_CHANNEL};
+
+/// Bits ignored when matching, as the payload may change them
+const IGNORED_BITS: u8 = !0 >> (8 - DEFAULT_MAX_BITS_PER_CHANNEL) as u32;
+
+///
+/// Selects the pixels carrying the payload by their color, e.g. a green screen background.
+/// Recorded in the header, so decoding selects the same pixels again.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct ColorKey {
+    /// RGBA, 16-bit images are compared by the high byte of each channel
+    pub color: [u8; 4],
+    /// Whether alpha is compared as well, i.e. the key was given as RRGGBBAA
+    pub compare_alpha: bool,
+    /// Largest difference per channel which still counts as a match
+    pub tolerance: u8,
+    /// Select the pixels which do not match instead
+    pub invert: bool,
+}
+
+impl ColorKey {
+    ///
+    /// Whether the pixel is selected. The low bits which may carry the payload are ignored,
+    /// so the selection stays the same after embedding.
+    pub fn selects(&self, pixel: [u8; 4]) -> bool {
+        let channels = if self.compare_alpha { 4 } else { 3 };
+        let matches = pixel
+            .iter()
+            .zip(self.color)
+            .take(channels)
+            .all(|(value, key)| {
+                (value & !IGNORED_BITS).abs_diff(key & !IGNORED_BITS) <= self.tolerance
+            });
+        matches != self.invert
+    }
+
+    ///
+    /// The selected pixels, as a mask of the pixels allowed to carry the payload
+    pub fn selection(&self, pixels: &[[u8; 4]]) -> AvoidMask {
+        AvoidMask::from_allowed(pixels.iter().map(|pixel| self.selects(*pixel)).collect())
+    }
+}
+
+impl FromStr for ColorKey {
+    type Err = String;
+
+    ///
+    /// Parses `RRGGBB` or `RRGGBBAA`, optionally prefixed with `#`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let hex = value.strip_prefix('#').unwrap_or(value);
+        if !matches!(hex.len(), 6 | 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "Invalid color key {}, expected RRGGBB or RRGGBBAA",
+                value
+            ));
+        }
+
+        let mut color = [0xFF; 4];
+        for (i, channel) in color.iter_mut().enumerate().take(hex.len() / 2) {
+            *channel = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|x| x.to_string())?;
+        }
+        Ok(ColorKey {
+            color,
+            compare_alpha: hex.len() == 8,
+            tolerance: 0,
+            invert: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        buffer_modify::ReadImageBinary,
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header, V1DataStuffingOptions},
+        payload::{read_payload, write_payload},
+    };
+
+    #[test]
+    fn parses_rgb_and_rgba_keys() {
+        let key: ColorKey = "#00ff00".parse().unwrap();
+        assert_eq!(key.color, [0, 0xFF, 0, 0xFF]);
+        assert!(!key.compare_alpha);
+
+        let key: ColorKey = "11223344".parse().unwrap();
+        assert_eq!(key.color, [0x11, 0x22, 0x33, 0x44]);
+        assert!(key.compare_alpha);
+
+        assert!("12345".parse::<ColorKey>().is_err());
+        assert!("gg0000".parse::<ColorKey>().is_err());
+    }
+
+    #[test]
+    fn payload_only_lands_on_the_keyed_background() {
+        // Green background, with a gray subject in the middle
+        let subject = |x: u32, y: u32| (16..48).contains(&x) && (16..48).contains(&y);
+        let cover: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(64, 64, |x, y| match subject(x, y) {
+                true => Rgb([120, 120, 120]),
+                false => Rgb([10, 200, 12]),
+            });
+        let key = ColorKey {
+            tolerance: 8,
+            ..ColorKey::from_str("00c810").unwrap()
+        };
+        let selection = key.selection(&cover.rgba8_pixels());
+        let payload = vec![0x5Au8; 400];
+
+        let header = generate_v3_header(
+            selection.allowed_pixels().len() as u64,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        let start_offset = header.start_offset();
+        let header = header.with_stuffing_opts(V1DataStuffingOptions::ColorKey {
+            start_offset,
+            key,
+            selection_checksum: selection.checksum(),
+        });
+        let mut image = cover.clone();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        for (x, y, pixel) in image.enumerate_pixels() {
+            if subject(x, y) {
+                assert_eq!(pixel, cover.get_pixel(x, y), "subject pixel {},{}", x, y);
+            }
+        }
+        assert_eq!(key.selection(&image.rgba8_pixels()), selection);
+        let header = try_get_header(&image).unwrap();
+        assert_eq!(read_payload(&image, &header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn keyed_header_ends_before_an_unrandomized_payload() {
+        // Every pixel is selected, and the payload starts right after the reserved header span
+        let cover: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(64, 64, Rgb([0x80, 0x80, 0x80]));
+        let key = ColorKey::from_str("808080").unwrap();
+        let selection = key.selection(&cover.rgba8_pixels());
+
+        for payload in [vec![0x5Au8; 16], vec![0xA5u8; 1300]] {
+            let header = generate_v3_header(
+                selection.allowed_pixels().len() as u64,
+                &payload,
+                ColorType::Rgb8,
+                CrcSpec::default(),
+                Vec::new(),
+                false,
+            )
+            .unwrap();
+            let start_offset = header.start_offset();
+            assert_eq!(start_offset, header.max_pixel_span().unwrap());
+            let header = header.with_stuffing_opts(V1DataStuffingOptions::ColorKey {
+                start_offset,
+                key,
+                selection_checksum: selection.checksum(),
+            });
+            assert!(header.pixel_span().unwrap() <= start_offset);
+
+            let mut image = cover.clone();
+            write_payload(&mut image, &header, &payload, None).unwrap();
+            let header = try_get_header(&image).unwrap();
+            assert_eq!(read_payload(&image, &header, None).unwrap(), payload);
+        }
+    }
+}
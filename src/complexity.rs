@@ -0,0 +1,374 @@
+//! Embedding which spends more payload bits in busy parts of the image and fewer in flat ones.
+//!
+//! Every sample gets a budget of low bits from how much its channel varies across the surrounding 3×3 pixels.
+//! The variation is measured on the bits above the largest budget, leaving out the header bit, so neither
+//! the payload nor the header change it. Decoding rebuilds the same budgets from the modified image,
+//! which is why the header only records the largest budget and not the budgets themselves.
+
+use rand::Rng;
+
+use crate::{
+    buffer_modify::{checked_pixel_index, PngImage},
+    header::{V1DataStuffingOptions, VersionedHeader, HEADER_MASK},
+    prng::tool_rng,
+    raw::WriteStats,
+};
+
+/// Largest number of low bits a sample can carry
+pub const MAX_COMPLEXITY_BITS: u8 = 4;
+
+/// Every bit of a pixel, for up to 64 bits per pixel
+fn pixel_mask(image: &dyn PngImage) -> u64 {
+    u64::MAX << (64 - image.color_type().bits_per_pixel().min(64))
+}
+
+/// The samples of the given pixels, as stored in the image
+fn read_samples(image: &dyn PngImage, pixels: &[usize]) -> Vec<u16> {
+    let color_type = image.color_type();
+    let bytes_per_pixel = color_type.bytes_per_pixel() as usize;
+    let bytes =
+        image.read_data_at_pixels(pixel_mask(image), pixels, pixels.len() * bytes_per_pixel);
+    bytes_to_samples(
+        &bytes,
+        bytes_per_pixel / color_type.channel_count() as usize,
+    )
+}
+
+fn bytes_to_samples(bytes: &[u8], bytes_per_channel: usize) -> Vec<u16> {
+    match bytes_per_channel {
+        1 => bytes.iter().map(|byte| *byte as u16).collect(),
+        _ => bytes
+            .chunks_exact(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .collect(),
+    }
+}
+
+fn samples_to_bytes(samples: &[u16], bytes_per_channel: usize) -> Vec<u8> {
+    match bytes_per_channel {
+        1 => samples.iter().map(|sample| *sample as u8).collect(),
+        _ => samples
+            .iter()
+            .flat_map(|sample| sample.to_be_bytes())
+            .collect(),
+    }
+}
+
+fn check_max_bits(max_bits: u8) -> Result<(), String> {
+    match (1..=MAX_COMPLEXITY_BITS).contains(&max_bits) {
+        true => Ok(()),
+        false => Err(format!(
+            "Complexity weighted embedding uses 1 to {} bits per channel, but {} were requested",
+            MAX_COMPLEXITY_BITS, max_bits
+        )),
+    }
+}
+
+///
+/// The number of low bits every sample of the image carries, in sample order.
+/// A sample whose channel spans less than `2^max_bits` across its neighbourhood carries nothing,
+/// every doubling of the span adds a bit, up to `max_bits`.
+pub fn sample_budgets(image: &dyn PngImage, max_bits: u8) -> Result<Vec<u8>, String> {
+    let color_type = image.color_type();
+    let channels = color_type.channel_count() as usize;
+    let bytes_per_pixel = color_type.bytes_per_pixel() as usize;
+    let width = image.width() as usize;
+    let pixel_count = checked_pixel_index(image.pixel_count())?;
+    let height = pixel_count / width.max(1);
+
+    let mut bytes = image.read_data_with_mask(pixel_mask(image), 0, pixel_count * bytes_per_pixel);
+    // The header bit lies above the low bits of 16-bit samples
+    let header_bit = (HEADER_MASK >> 56) as u8;
+    for pixel in bytes.chunks_exact_mut(bytes_per_pixel) {
+        pixel[0] &= !header_bit;
+    }
+    let coarse: Vec<u16> = bytes_to_samples(&bytes, bytes_per_pixel / channels)
+        .into_iter()
+        .map(|sample| sample >> max_bits)
+        .collect();
+
+    let mut budgets = vec![0u8; coarse.len()];
+    let coarse = &coarse;
+    for y in 0..height {
+        for x in 0..width {
+            for channel in 0..channels {
+                let neighbourhood = (y.saturating_sub(1)..(y + 2).min(height)).flat_map(|y| {
+                    (x.saturating_sub(1)..(x + 2).min(width))
+                        .map(move |x| coarse[(y * width + x) * channels + channel])
+                });
+                let (low, high) = neighbourhood.fold((u16::MAX, u16::MIN), |(low, high), value| {
+                    (low.min(value), high.max(value))
+                });
+                let span_bits = (u16::BITS - (high - low).leading_zeros()) as u8;
+                budgets[(y * width + x) * channels + channel] = span_bits.min(max_bits);
+            }
+        }
+    }
+    Ok(budgets)
+}
+
+///
+/// Pixels with a budget of at least one bit, in order, together with how many bits they carry.
+/// The pixels the header may cover are left out.
+fn weighted_pixels(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    max_bits: u8,
+) -> Result<Vec<(usize, u64)>, String> {
+    check_max_bits(max_bits)?;
+    let channels = image.color_type().channel_count() as usize;
+    let reserved = checked_pixel_index(header.max_pixel_span()?)?;
+
+    Ok(sample_budgets(image, max_bits)?
+        .chunks_exact(channels)
+        .map(|budgets| budgets.iter().map(|bits| *bits as u64).sum::<u64>())
+        .enumerate()
+        .skip(reserved)
+        .filter(|(_, bits)| *bits > 0)
+        .collect())
+}
+
+///
+/// Moves the payload of the header into the complexity weighted pixels of the image.
+/// The header itself stays where it is.
+pub fn with_complexity_placement(
+    image: &dyn PngImage,
+    header: VersionedHeader,
+    max_bits: u8,
+    randomize_offset: bool,
+) -> Result<VersionedHeader, String> {
+    let pixels = weighted_pixels(image, &header, max_bits)?;
+    let needed = header.data_len().saturating_mul(8);
+    let capacity: u64 = pixels.iter().map(|(_, bits)| bits).sum();
+    if needed > capacity {
+        return Err(format!(
+            "The payload needs {} bits, but the textured parts of the image only carry {} at up to {} bits per channel",
+            needed, capacity, max_bits
+        ));
+    }
+
+    // The last pixel the payload can start at, so the pixels after it still carry all of it
+    let mut remaining = capacity;
+    let last_start = pixels
+        .iter()
+        .take_while(|(_, bits)| {
+            let fits = remaining >= needed;
+            remaining -= bits;
+            fits
+        })
+        .count()
+        .saturating_sub(1) as u64;
+    let start_offset = match randomize_offset {
+        true => tool_rng().gen_range(0..=last_start),
+        false => 0,
+    };
+    Ok(header
+        .with_data_mask(0)
+        .with_stuffing_opts(V1DataStuffingOptions::Complexity {
+            start_offset,
+            max_bits,
+        }))
+}
+
+///
+/// Pixels carrying the payload, see [`with_complexity_placement`]
+pub fn payload_pixels(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    max_bits: u8,
+) -> Result<Vec<usize>, String> {
+    let pixels = weighted_pixels(image, header, max_bits)?;
+    let start_offset = checked_pixel_index(header.start_offset())?;
+    let needed = header.data_len().saturating_mul(8);
+
+    let mut carried = 0;
+    let used: Vec<usize> = pixels
+        .iter()
+        .skip(start_offset)
+        .take_while(|(_, bits)| {
+            let more = carried < needed;
+            carried += bits;
+            more
+        })
+        .map(|(pixel, _)| *pixel)
+        .collect();
+    if carried < needed {
+        return Err(
+            "Header describes more data than the textured parts of the image can hold".to_string(),
+        );
+    }
+    Ok(used)
+}
+
+///
+/// Writes the payload into the low bits of the given pixels, as many per sample as its budget allows
+pub fn write_weighted(
+    image: &mut dyn PngImage,
+    payload: &[u8],
+    max_bits: u8,
+    pixels: &[usize],
+) -> Result<WriteStats, String> {
+    let budgets = sample_budgets(image, max_bits)?;
+    let channels = image.color_type().channel_count() as usize;
+    let bytes_per_channel = image.color_type().bytes_per_pixel() as usize / channels;
+    let mut samples = read_samples(image, pixels);
+    let total_bits = payload.len() * 8;
+
+    let mut index = 0;
+    for (position, pixel) in pixels.iter().enumerate() {
+        for channel in 0..channels {
+            let sample = &mut samples[position * channels + channel];
+            for shift in (0..budgets[pixel * channels + channel]).rev() {
+                // The last pixel keeps its own low bits where the payload runs out
+                if index < total_bits {
+                    let bit = ((payload[index / 8] >> (7 - index % 8)) & 1) as u16;
+                    *sample = (*sample & !(1 << shift)) | bit << shift;
+                }
+                index += 1;
+            }
+        }
+    }
+    Ok(image.write_data_at_pixels(
+        &samples_to_bytes(&samples, bytes_per_channel),
+        pixel_mask(image),
+        pixels,
+    ))
+}
+
+///
+/// Reads `data_len` bytes from the low bits of the given pixels
+pub fn read_weighted(
+    image: &dyn PngImage,
+    max_bits: u8,
+    pixels: &[usize],
+    data_len: usize,
+) -> Result<Vec<u8>, String> {
+    let budgets = sample_budgets(image, max_bits)?;
+    let channels = image.color_type().channel_count() as usize;
+    let samples = read_samples(image, pixels);
+    let bits = pixels.iter().enumerate().flat_map(|(position, pixel)| {
+        let (budgets, samples) = (&budgets, &samples);
+        (0..channels).flat_map(move |channel| {
+            let sample = samples[position * channels + channel];
+            (0..budgets[pixel * channels + channel])
+                .rev()
+                .map(move |shift| ((sample >> shift) & 1) as u8)
+        })
+    });
+
+    let mut payload = vec![0u8; data_len];
+    for (index, bit) in bits.take(data_len * 8).enumerate() {
+        payload[index / 8] |= bit << (7 - index % 8);
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, ImageOutputFormat, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header},
+        payload::{read_payload, write_payload},
+    };
+
+    /// Flat on the left, a faint checkerboard in the middle and noise on the right
+    fn mixed_cover() -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let mut noise = vec![0u8; 64 * 64 * 3];
+        rand::thread_rng().fill_bytes(&mut noise);
+        ImageBuffer::from_fn(64, 64, |x, y| match x {
+            0..=20 => Rgb([120, 120, 120]),
+            21..=41 => Rgb([120 + ((x + y) % 2 * 4) as u8; 3]),
+            _ => {
+                let index = ((y * 64 + x) * 3) as usize;
+                Rgb([noise[index], noise[index + 1], noise[index + 2]])
+            }
+        })
+    }
+
+    fn budget_of(budgets: &[u8], x: usize, y: usize) -> &[u8] {
+        &budgets[(y * 64 + x) * 3..(y * 64 + x + 1) * 3]
+    }
+
+    #[test]
+    fn bits_follow_the_complexity_and_decode_exactly() {
+        let mut image = mixed_cover();
+        let budgets = sample_budgets(&image, 2).unwrap();
+        // Columns next to another region see that region in their neighbourhood
+        for y in 0..64 {
+            assert_eq!(budget_of(&budgets, 10, y), [0, 0, 0]);
+            assert_eq!(budget_of(&budgets, 30, y), [1, 1, 1]);
+        }
+        // Noise mostly spans enough for the full 2 bits
+        let noisy: u64 = (0..64)
+            .flat_map(|y| &budgets[(y * 64 + 43) * 3..(y + 1) * 64 * 3])
+            .map(|bits| *bits as u64)
+            .sum();
+        assert!(noisy > 21 * 64 * 3 * 3 / 2, "{}", noisy);
+
+        let payload: Vec<u8> = (0..1000).map(|_| rand::random()).collect();
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+        let header = with_complexity_placement(&image, header, 2, false).unwrap();
+        let cover = image.clone();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        // Embedding leaves the budgets as they are, so decoding finds the same bits
+        assert_eq!(sample_budgets(&image, 2).unwrap(), budgets);
+        let reserved = header.max_pixel_span().unwrap() as usize;
+        for (index, (pixel, original)) in image.pixels().zip(cover.pixels()).enumerate() {
+            if index < reserved {
+                continue;
+            }
+            let (x, y) = (index % 64, index / 64);
+            for channel in 0..3 {
+                let budget = budget_of(&budgets, x, y)[channel];
+                let changed = pixel[channel] ^ original[channel];
+                assert!(
+                    changed < 1 << budget,
+                    "pixel {} changed by {}",
+                    index,
+                    changed
+                );
+            }
+        }
+
+        let mut png = Vec::new();
+        image
+            .write_to(&mut std::io::Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().into_rgb8();
+
+        let read_header = try_get_header(&decoded).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(read_payload(&decoded, &read_header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn flat_covers_carry_nothing() {
+        let image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::from_pixel(64, 64, Rgb([7, 7, 7]));
+        let header = generate_v3_header(
+            64 * 64,
+            b"nowhere to go",
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+
+        assert!(with_complexity_placement(&image, header.clone(), 2, true).is_err());
+        assert!(with_complexity_placement(&image, header, MAX_COMPLEXITY_BITS + 1, true).is_err());
+    }
+}
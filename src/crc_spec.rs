@@ -0,0 +1,194 @@
+use std::{fmt, str::FromStr};
+
+use bincode::{Decode, Encode};
+use crc::{Algorithm, CRC_32_CKSUM};
+
+///
+/// Parameters of a 32-bit CRC algorithm (see the "Rocksoft" model used by [`crc::Algorithm`]).
+/// Stored in the header, so payloads can be checked with the same algorithm an external format uses.
+#[derive(Encode, Decode, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct CrcSpec {
+    pub poly: u32,
+    pub init: u32,
+    pub refin: bool,
+    pub refout: bool,
+    pub xorout: u32,
+}
+
+impl CrcSpec {
+    pub const fn from_algorithm(algorithm: &Algorithm<u32>) -> CrcSpec {
+        CrcSpec {
+            poly: algorithm.poly,
+            init: algorithm.init,
+            refin: algorithm.refin,
+            refout: algorithm.refout,
+            xorout: algorithm.xorout,
+        }
+    }
+
+    ///
+    /// Builds the algorithm description. `check` and `residue` are not part of the spec and left at 0.
+    pub const fn to_algorithm(self) -> Algorithm<u32> {
+        Algorithm {
+            width: 32,
+            poly: self.poly,
+            init: self.init,
+            refin: self.refin,
+            refout: self.refout,
+            xorout: self.xorout,
+            check: 0,
+            residue: 0,
+        }
+    }
+
+    ///
+    /// Computes the checksum bit by bit. `crc::Crc` needs a `'static` algorithm,
+    /// which a spec read from a header at runtime cannot provide.
+    pub fn checksum(&self, data: &[u8]) -> u32 {
+        let algorithm = self.to_algorithm();
+        let mut register = algorithm.init;
+
+        for byte in data {
+            let byte = if algorithm.refin {
+                byte.reverse_bits()
+            } else {
+                *byte
+            };
+            register ^= (byte as u32) << 24;
+            for _ in 0..8 {
+                register = if register & 0x8000_0000 != 0 {
+                    (register << 1) ^ algorithm.poly
+                } else {
+                    register << 1
+                };
+            }
+        }
+
+        if algorithm.refout {
+            register = register.reverse_bits();
+        }
+        register ^ algorithm.xorout
+    }
+}
+
+impl Default for CrcSpec {
+    fn default() -> Self {
+        CrcSpec::from_algorithm(&CRC_32_CKSUM)
+    }
+}
+
+///
+/// Parses a spec of the form `poly=0x04c11db7,init=0xffffffff,refin=true,refout=true,xorout=0xffffffff`.
+/// Omitted keys keep the value of the default spec (CRC-32/CKSUM).
+impl FromStr for CrcSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_u32 = |value: &str| {
+            let digits = value.trim_start_matches("0x").trim_start_matches("0X");
+            u32::from_str_radix(digits, 16).map_err(|x| format!("Invalid value {}: {}", value, x))
+        };
+        let parse_bool = |value: &str| {
+            value
+                .parse::<bool>()
+                .map_err(|x| format!("Invalid value {}: {}", value, x))
+        };
+
+        let mut spec = CrcSpec::default();
+        for pair in s.split(',').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, but got {}", pair))?;
+            match key.trim() {
+                "poly" => spec.poly = parse_u32(value.trim())?,
+                "init" => spec.init = parse_u32(value.trim())?,
+                "refin" => spec.refin = parse_bool(value.trim())?,
+                "refout" => spec.refout = parse_bool(value.trim())?,
+                "xorout" => spec.xorout = parse_u32(value.trim())?,
+                other => return Err(format!("Unknown CRC parameter {}", other)),
+            }
+        }
+
+        Ok(spec)
+    }
+}
+
+///
+/// Formats the spec in the form accepted by [`CrcSpec::from_str`]
+impl fmt::Display for CrcSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "poly={:#010x},init={:#010x},refin={},refout={},xorout={:#010x}",
+            self.poly, self.init, self.refin, self.refout, self.xorout
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crc::{Crc, CRC_32_BZIP2, CRC_32_ISO_HDLC};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const CHECK_INPUT: &[u8] = b"123456789";
+
+    #[test]
+    fn default_matches_crc_crate() {
+        let data = b"some payload which needs a checksum";
+
+        assert_eq!(
+            CrcSpec::default().checksum(data),
+            Crc::<u32>::new(&CRC_32_CKSUM).checksum(data)
+        );
+    }
+
+    #[test]
+    fn catalog_check_values() {
+        for algorithm in [&CRC_32_CKSUM, &CRC_32_ISO_HDLC, &CRC_32_BZIP2] {
+            assert_eq!(
+                CrcSpec::from_algorithm(algorithm).checksum(CHECK_INPUT),
+                algorithm.check
+            );
+        }
+    }
+
+    #[test]
+    fn parse_custom_spec() {
+        let spec: CrcSpec =
+            "poly=0x04c11db7,init=0xffffffff,refin=true,refout=true,xorout=0xffffffff"
+                .parse()
+                .unwrap();
+
+        assert_eq!(spec, CrcSpec::from_algorithm(&CRC_32_ISO_HDLC));
+        assert_eq!(spec.checksum(CHECK_INPUT), 0xCBF43926);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let spec = CrcSpec::from_algorithm(&CRC_32_BZIP2);
+
+        assert_eq!(spec.to_string().parse::<CrcSpec>().unwrap(), spec);
+    }
+
+    #[test]
+    fn parse_partial_spec_keeps_defaults() {
+        let spec: CrcSpec = "init=0x12345678".parse().unwrap();
+
+        assert_eq!(
+            spec,
+            CrcSpec {
+                init: 0x12345678,
+                ..CrcSpec::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unknown_keys() {
+        assert!("width=16".parse::<CrcSpec>().is_err());
+        assert!("poly".parse::<CrcSpec>().is_err());
+        assert!("refin=maybe".parse::<CrcSpec>().is_err());
+    }
+}
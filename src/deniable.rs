@@ -0,0 +1,380 @@
+use std::{collections::HashSet, mem::size_of};
+
+use pbkdf2::pbkdf2_hmac;
+use rand::{Rng, RngCore};
+use sha2::Sha256;
+
+use crate::{
+    buffer_modify::{checked_pixel_index, PngImage},
+    crc_spec::CrcSpec,
+    header::{
+        check_low_bits_only, generate_v3_header, HeaderRaw, V1DataStuffingOptions, VersionedHeader,
+        HEADER_MASK,
+    },
+    payload::check_payload_crc,
+    prng::{shuffle, tool_rng},
+};
+
+/// The salt is stored like the regular header, 1 bit per pixel at the start of the image
+const SALT_PIXELS: usize = u64::BITS as usize;
+/// Number of regions a password can map to. Unused regions are filled with noise.
+const SLOT_COUNT: usize = 2;
+/// Upper bound for the serialized header. Pixels for this many bytes are reserved in each slot.
+const MAX_PASSWORD_HEADER_BYTES: usize = 128;
+/// The header is stored 1 bit per pixel
+const HEADER_PIXELS: usize = MAX_PASSWORD_HEADER_BYTES * 8;
+
+/// PBKDF2 iterations, so guessing a password costs noticeably more than checking a CRC
+const KDF_ROUNDS: u32 = 100_000;
+
+/// Pixels needed for the salt and the header of every slot, before any payload
+const MIN_PIXELS: usize = SALT_PIXELS + HEADER_PIXELS * SLOT_COUNT;
+/// Unlike [`NO_PAYLOAD`], this tells nothing about the password
+const TOO_SMALL: &str = "Image is too small to hold a password protected payload";
+
+/// Reported for every kind of failure, so a wrong password can not be told apart from a missing payload
+const NO_PAYLOAD: &str = "No payload found for this password";
+
+///
+/// Derives the seed of a password's pixels. The salt is random for each image,
+/// so the same password does not lead to the same pixels in different images.
+///
+/// This only hides where the payload is. The payload itself is not encrypted.
+fn derive_seed(salt: u64, password: &str) -> u64 {
+    let mut seed = [0u8; size_of::<u64>()];
+    pbkdf2_hmac::<Sha256>(
+        password.as_bytes(),
+        &salt.to_be_bytes(),
+        KDF_ROUNDS,
+        &mut seed,
+    );
+    u64::from_be_bytes(seed)
+}
+
+fn slot_of(seed: u64) -> usize {
+    (seed % SLOT_COUNT as u64) as usize
+}
+
+///
+/// Pixels of the slot the seed maps to, in the order derived from the seed.
+/// Slots interleave pixel by pixel, so every slot covers the whole image.
+fn slot_pixels(seed: u64, pixel_count: u64) -> Result<Vec<usize>, String> {
+    let mut pixels: Vec<usize> = (SALT_PIXELS + slot_of(seed)..checked_pixel_index(pixel_count)?)
+        .step_by(SLOT_COUNT)
+        .collect();
+    if pixels.len() <= HEADER_PIXELS {
+        return Err(TOO_SMALL.to_string());
+    }
+
+    shuffle(seed, &mut pixels);
+    Ok(pixels)
+}
+
+///
+/// What is written into one slot: a serialized header followed by the payload
+struct SlotContent {
+    pixels: Vec<usize>,
+    header_bytes: Vec<u8>,
+    data_mask: u64,
+    start_offset: usize,
+    payload: Vec<u8>,
+}
+
+impl SlotContent {
+    /// The header goes into the first pixels of the slot, the payload follows
+    fn write(&self, image: &mut dyn PngImage) {
+        let (header_pixels, payload_pixels) = self.pixels.split_at(HEADER_PIXELS);
+        image.write_data_at_pixels(&self.header_bytes, HEADER_MASK, header_pixels);
+        if !self.payload.is_empty() {
+            image.write_data_at_pixels(
+                &self.payload,
+                self.data_mask,
+                &payload_pixels[self.start_offset..],
+            );
+        }
+    }
+}
+
+///
+/// Embeds each payload into the pixels derived from its password.
+/// Without a password, neither the payloads nor their count can be found.
+/// The salt is stored in the image. If it is not given, a random one is picked.
+pub(crate) fn write_password_payloads(
+    image: &mut dyn PngImage,
+    entries: &[(&str, &[u8])],
+    crc_spec: CrcSpec,
+    max_bits_per_channel: Option<u8>,
+    salt: Option<u64>,
+) -> Result<(), String> {
+    if entries.is_empty() || entries.len() > SLOT_COUNT {
+        return Err(format!(
+            "Between 1 and {} password protected payloads fit into one image",
+            SLOT_COUNT
+        ));
+    }
+    let passwords: HashSet<&str> = entries.iter().map(|(password, _)| *password).collect();
+    if passwords.len() != entries.len() {
+        return Err("Every payload needs a different password".to_string());
+    }
+
+    // Derived once per salt, as the KDF is slow on purpose
+    let seeds_if_slots_differ = |salt: u64| {
+        let seeds: Vec<u64> = entries
+            .iter()
+            .map(|(password, _)| derive_seed(salt, password))
+            .collect();
+        let slots: HashSet<usize> = seeds.iter().map(|seed| slot_of(*seed)).collect();
+        (slots.len() == entries.len()).then_some((salt, seeds))
+    };
+    let (salt, seeds) = match salt {
+        Some(salt) => seeds_if_slots_differ(salt).ok_or_else(|| {
+            "With this salt, the passwords collide. Pick another salt".to_string()
+        })?,
+        // Retry salts until every password maps to a different slot
+        None => loop {
+            if let Some(found) = seeds_if_slots_differ(tool_rng().gen()) {
+                break found;
+            }
+        },
+    };
+
+    let mut slots = Vec::with_capacity(SLOT_COUNT);
+    for ((_, payload), seed) in entries.iter().zip(&seeds) {
+        let pixels = slot_pixels(*seed, image.pixel_count())?;
+        let header = generate_v3_header(
+            (pixels.len() - HEADER_PIXELS) as u64,
+            payload,
+            image.color_type(),
+            crc_spec,
+            Vec::new(),
+            true,
+        )?;
+        check_low_bits_only(header.data_mask(), image.color_type(), max_bits_per_channel)?;
+        let start_offset = header.start_offset();
+        let header = header.with_stuffing_opts(V1DataStuffingOptions::Password { start_offset });
+
+        let raw_header: HeaderRaw = header.clone().try_into().map_err(|x| format!("{}", x))?;
+        let header_bytes = raw_header.to_bytes();
+        if header_bytes.len() > MAX_PASSWORD_HEADER_BYTES {
+            return Err(format!(
+                "Header is {} bytes long, but at most {} bytes fit in password mode",
+                header_bytes.len(),
+                MAX_PASSWORD_HEADER_BYTES
+            ));
+        }
+        slots.push(SlotContent {
+            pixels,
+            header_bytes,
+            data_mask: header.data_mask(),
+            start_offset: checked_pixel_index(start_offset)?,
+            payload: payload.to_vec(),
+        });
+    }
+
+    // Fill the remaining slots with noise shaped like the first payload, so they look used as well
+    let used_slots: HashSet<usize> = seeds.iter().map(|seed| slot_of(*seed)).collect();
+    for slot in (0..SLOT_COUNT).filter(|slot| !used_slots.contains(slot)) {
+        let seed = loop {
+            let seed: u64 = tool_rng().gen();
+            if slot_of(seed) == slot {
+                break seed;
+            }
+        };
+        let template = &slots[0];
+        let mut noise = SlotContent {
+            pixels: slot_pixels(seed, image.pixel_count())?,
+            header_bytes: vec![0u8; template.header_bytes.len()],
+            data_mask: template.data_mask,
+            start_offset: template.start_offset,
+            payload: vec![0u8; template.payload.len()],
+        };
+        tool_rng().fill_bytes(&mut noise.header_bytes);
+        tool_rng().fill_bytes(&mut noise.payload);
+        slots.push(noise);
+    }
+
+    image.write_data_with_mask(&salt.to_be_bytes(), HEADER_MASK, 0);
+    for slot in &slots {
+        slot.write(image);
+    }
+
+    Ok(())
+}
+
+///
+/// Reads the payload the password unlocks, along with its header.
+pub(crate) fn read_password_payload(
+    image: &dyn PngImage,
+    password: &str,
+) -> Result<(VersionedHeader, Vec<u8>), String> {
+    // The salt alone would run out of pixels on tiny images
+    if image.pixel_count() < MIN_PIXELS as u64 {
+        return Err(TOO_SMALL.to_string());
+    }
+    let mut salt = [0u8; size_of::<u64>()];
+    salt.copy_from_slice(&image.read_data_with_mask(HEADER_MASK, 0, size_of::<u64>()));
+    let pixels = slot_pixels(
+        derive_seed(u64::from_be_bytes(salt), password),
+        image.pixel_count(),
+    )?;
+    let (header_pixels, payload_pixels) = pixels.split_at(HEADER_PIXELS);
+
+    // Magic (1B), Header Len (2B), Data, CRC (4B)
+    let partial_header = image.read_data_at_pixels(HEADER_MASK, header_pixels, 3);
+    let header_len = 3 + u16::from_be_bytes([partial_header[1], partial_header[2]]) as usize + 4;
+    if header_len > MAX_PASSWORD_HEADER_BYTES {
+        return Err(NO_PAYLOAD.to_string());
+    }
+    let header: VersionedHeader =
+        HeaderRaw::from_bytes(&image.read_data_at_pixels(HEADER_MASK, header_pixels, header_len))
+            .and_then(VersionedHeader::try_from)
+            .map_err(|_| NO_PAYLOAD.to_string())?;
+
+    let start_offset = checked_pixel_index(header.start_offset())?;
+    let data_len = checked_pixel_index(header.data_len())?;
+    let bits_per_pixel = header.data_mask().count_ones() as usize;
+    let available_pixels = payload_pixels.len().saturating_sub(start_offset);
+    if bits_per_pixel == 0 || (data_len * 8).div_ceil(bits_per_pixel) > available_pixels {
+        return Err("Header describes more data than the image can hold".to_string());
+    }
+
+    let payload = match data_len {
+        0 => Vec::new(),
+        _ => image.read_data_at_pixels(
+            header.data_mask(),
+            &payload_pixels[start_offset..],
+            data_len,
+        ),
+    };
+    check_payload_crc(&header, &payload)?;
+
+    Ok((header, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::buffer_modify::ReadImageBinary;
+
+    fn noisy_image() -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(128, 128);
+        rand::thread_rng().fill_bytes(&mut image);
+        image
+    }
+
+    #[test]
+    fn two_passwords_unlock_two_payloads() {
+        let mut image = noisy_image();
+        let real = vec![0xA5; 3000];
+        let decoy = b"shopping list: milk, eggs".to_vec();
+
+        write_password_payloads(
+            &mut image,
+            &[("correct horse", &real), ("battery staple", &decoy)],
+            CrcSpec::default(),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let (_, payload) = read_password_payload(&image, "correct horse").unwrap();
+        assert_eq!(payload, real);
+        let (_, payload) = read_password_payload(&image, "battery staple").unwrap();
+        assert_eq!(payload, decoy);
+        assert!(read_password_payload(&image, "wrong").is_err());
+    }
+
+    #[test]
+    fn single_password_round_trip() {
+        let mut image = noisy_image();
+        let payload = b"only one payload".to_vec();
+
+        write_password_payloads(
+            &mut image,
+            &[("secret", &payload)],
+            CrcSpec::default(),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let (header, read) = read_password_payload(&image, "secret").unwrap();
+        assert_eq!(read, payload);
+        assert!(matches!(
+            header.stuffing_opts(),
+            V1DataStuffingOptions::Password { .. }
+        ));
+    }
+
+    #[test]
+    fn random_salt_changes_the_embedding() {
+        let cover = noisy_image();
+        let payload = b"same payload, same password".to_vec();
+        let encode = || {
+            let mut image = cover.clone();
+            write_password_payloads(
+                &mut image,
+                &[("secret", &payload)],
+                CrcSpec::default(),
+                None,
+                None,
+            )
+            .unwrap();
+            image
+        };
+        let (first, second) = (encode(), encode());
+
+        assert!(first != second);
+        assert_eq!(read_password_payload(&first, "secret").unwrap().1, payload);
+        assert_eq!(read_password_payload(&second, "secret").unwrap().1, payload);
+    }
+
+    #[test]
+    fn given_salt_is_stored() {
+        let mut image = noisy_image();
+        let salt = 0x0123_4567_89AB_CDEF;
+
+        write_password_payloads(
+            &mut image,
+            &[("secret", b"salted".as_slice())],
+            CrcSpec::default(),
+            None,
+            Some(salt),
+        )
+        .unwrap();
+
+        let stored = image.read_data_with_mask(HEADER_MASK, 0, size_of::<u64>());
+        assert_eq!(stored, salt.to_be_bytes());
+        assert_eq!(
+            read_password_payload(&image, "secret").unwrap().1,
+            b"salted"
+        );
+    }
+
+    #[test]
+    fn tiny_image_is_rejected_without_panicking() {
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(4, 4);
+
+        assert_eq!(
+            read_password_payload(&image, "secret").unwrap_err(),
+            TOO_SMALL
+        );
+    }
+
+    #[test]
+    fn passwords_must_differ() {
+        let mut image = noisy_image();
+
+        let result = write_password_payloads(
+            &mut image,
+            &[("same", b"a".as_slice()), ("same", b"b".as_slice())],
+            CrcSpec::default(),
+            None,
+            None,
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use image::ImageOutputFormat;
+
+use crate::{
+    buffer_modify::{convert_dynamic_image_to_png_image, PngImage},
+    header::{calculate_bit_mask, try_get_header},
+    io_errors::{cannot_read, cannot_write},
+    payload::{read_payload, scrub_stale_payload, write_payload},
+    verification::verify,
+};
+
+///
+/// What `diffuse` did to a single file
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum DiffuseOutcome {
+    Diffused {
+        output: PathBuf,
+        /// Whether the file carried a valid payload, which was kept
+        kept_payload: bool,
+        /// Entropy of the low bits before and after, in bits per byte, see [`low_bit_entropy`]
+        entropy_before: f64,
+        entropy_after: f64,
+    },
+    /// The file could not be read, is no supported image, or could not be written
+    Skipped(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DiffuseEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) outcome: DiffuseOutcome,
+}
+
+impl DiffuseEntry {
+    pub(crate) fn to_text(&self) -> String {
+        match &self.outcome {
+            DiffuseOutcome::Diffused {
+                output,
+                kept_payload,
+                entropy_before,
+                entropy_after,
+            } => format!(
+                "{} -> {}: {}, low bit entropy {:.2} -> {:.2} bits per byte",
+                self.path.display(),
+                output.display(),
+                match kept_payload {
+                    true => "payload kept",
+                    false => "no payload",
+                },
+                entropy_before,
+                entropy_after
+            ),
+            DiffuseOutcome::Skipped(note) => format!("{}: skipped ({})", self.path.display(), note),
+        }
+    }
+}
+
+///
+/// Randomizes the `bits_per_channel` least significant bits of every image in `dir` and writes the results
+/// as PNG into `out_dir`, under the same relative path, see [`output_path`]. A valid payload is written back
+/// unchanged, everything else in those bits is replaced with noise. Files which are not readable as an image
+/// are skipped. Fails before writing anything if two files would be written to the same path.
+/// Payloads that are only found with a key, like those of `--password` or `--avoid-mask`, count as none.
+pub(crate) fn diffuse_dir(
+    dir: &Path,
+    out_dir: &Path,
+    bits_per_channel: u8,
+    recursive: bool,
+) -> Result<Vec<DiffuseEntry>, String> {
+    // Relative and absolute paths, or ones with `..` in them, only compare equal once canonicalized
+    let canonical_out_dir = fs::canonicalize(out_dir).ok();
+    let is_in_out_dir = |path: &Path| {
+        canonical_out_dir.as_ref().is_some_and(|out_dir| {
+            fs::canonicalize(path).is_ok_and(|path| path.starts_with(out_dir))
+        })
+    };
+    if is_in_out_dir(dir) {
+        return Err(
+            "The output directory has to differ from the scanned one, and must not contain it"
+                .to_string(),
+        );
+    }
+    let mut files = Vec::new();
+    collect_files(dir, recursive, &mut files)?;
+
+    let mut outputs: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut jobs = Vec::new();
+    for path in files.into_iter().filter(|path| !is_in_out_dir(path)) {
+        let output = output_path(dir, out_dir, &path);
+        if let Some(other) = outputs.insert(output.clone(), path.clone()) {
+            return Err(format!(
+                "{} and {} would both be written to {}",
+                other.display(),
+                path.display(),
+                output.display()
+            ));
+        }
+        jobs.push((path, output));
+    }
+
+    Ok(jobs
+        .into_iter()
+        .map(|(path, output)| {
+            let outcome = diffuse_file(&path, &output, bits_per_channel)
+                .unwrap_or_else(DiffuseOutcome::Skipped);
+            DiffuseEntry { path, outcome }
+        })
+        .collect())
+}
+
+///
+/// Where the diffused copy of `path` goes. Files which are no PNG keep their extension in front of `.png`,
+/// so `a.jpg` and `a.png` are not written to the same file.
+fn output_path(dir: &Path, out_dir: &Path, path: &Path) -> PathBuf {
+    let output = out_dir.join(path.strip_prefix(dir).unwrap_or(path));
+    match output.extension() {
+        Some(extension) if extension.eq_ignore_ascii_case("png") => output,
+        _ => {
+            let mut name = output.into_os_string();
+            name.push(".png");
+            PathBuf::from(name)
+        }
+    }
+}
+
+fn collect_files(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    let mut children: Vec<(PathBuf, bool)> = fs::read_dir(dir)
+        .map_err(|x| cannot_read(dir.display(), &x))?
+        .filter_map(|entry| entry.ok())
+        // Symbolic links to directories are not followed, so the walk always ends
+        .map(|entry| {
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            (entry.path(), is_dir)
+        })
+        .collect();
+    children.sort();
+
+    for (path, is_dir) in children {
+        match is_dir {
+            true if recursive => collect_files(&path, recursive, files)?,
+            true => {}
+            false => files.push(path),
+        }
+    }
+    Ok(())
+}
+
+fn diffuse_file(
+    path: &Path,
+    output: &Path,
+    bits_per_channel: u8,
+) -> Result<DiffuseOutcome, String> {
+    let data = fs::read(path).map_err(|x| cannot_read(path.display(), &x))?;
+    let mut image = image::load_from_memory(&data).map_err(|x| x.to_string())?;
+    let image = convert_dynamic_image_to_png_image(&mut image)?;
+
+    let entropy_before = low_bit_entropy(image, bits_per_channel);
+    let kept_payload = diffuse_image(image, bits_per_channel)?;
+    let entropy_after = low_bit_entropy(image, bits_per_channel);
+
+    let png = image.save_to_buffer(ImageOutputFormat::Png)?;
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).map_err(|x| cannot_write(parent.display(), &x))?;
+    }
+    fs::write(output, png).map_err(|x| cannot_write(output.display(), &x))?;
+
+    Ok(DiffuseOutcome::Diffused {
+        output: output.to_path_buf(),
+        kept_payload,
+        entropy_before,
+        entropy_after,
+    })
+}
+
+///
+/// Scrubs the low bits of the image like `encode --clean-slate`, then writes a valid payload back.
+/// Returns whether there was one.
+pub(crate) fn diffuse_image(
+    image: &mut dyn PngImage,
+    bits_per_channel: u8,
+) -> Result<bool, String> {
+    let payload = match try_get_header(image) {
+        Ok(header) if verify(image, None).is_valid() => {
+            Some((read_payload(image, &header, None)?, header))
+        }
+        _ => None,
+    };
+    scrub_stale_payload(image, bits_per_channel);
+
+    let Some((payload, header)) = payload else {
+        return Ok(false);
+    };
+    write_payload(image, &header, &payload, None)?;
+    match read_payload(image, &header, None)? == payload {
+        true => Ok(true),
+        false => Err("The payload read back differs from the one before".to_string()),
+    }
+}
+
+///
+/// Shannon entropy of the `bits_per_channel` least significant bits of all pixels, packed into bytes.
+/// Close to 8 bits per byte for noise, low for flat or structured low bits.
+pub(crate) fn low_bit_entropy(image: &dyn PngImage, bits_per_channel: u8) -> f64 {
+    let color_type = image.color_type();
+    let mask = calculate_bit_mask(bits_per_channel * color_type.channel_count(), color_type);
+    let len = mask.count_ones() as u64 * image.pixel_count() / 8;
+    let bytes = image.read_data_with_mask(mask, 0, len as usize);
+    if bytes.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0u64; 256];
+    for byte in &bytes {
+        counts[*byte as usize] += 1;
+    }
+    counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / bytes.len() as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use image::{ColorType, ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::{thread_rng, Rng, RngCore};
+
+    use super::*;
+    use crate::{buffer_modify::PngImageSaveable, crc_spec::CrcSpec, header::generate_v3_header};
+
+    /// A smooth gradient, its low bits repeat in long runs
+    fn gradient() -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(96, 96, |x, y| Rgb([(x / 3) as u8, (y / 3) as u8, 128]))
+    }
+
+    #[test]
+    fn low_bit_entropy_rises_for_every_file() {
+        let dir = env::temp_dir().join(format!("ihm-diffuse-{:x}", thread_rng().gen::<u64>()));
+        let out_dir = dir.join("out");
+        fs::create_dir_all(dir.join("nested")).unwrap();
+
+        let payload: Vec<u8> = (0..200).map(|index| index as u8).collect();
+        let mut carrier = gradient();
+        let header = generate_v3_header(
+            96 * 96,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        write_payload(&mut carrier, &header, &payload, None).unwrap();
+        fs::write(
+            dir.join("carrier.png"),
+            carrier.save_to_buffer(ImageOutputFormat::Png).unwrap(),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("nested/clean.bmp"),
+            gradient().save_to_buffer(ImageOutputFormat::Bmp).unwrap(),
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), b"not an image").unwrap();
+
+        let entries = diffuse_dir(&dir, &out_dir, 1, true).unwrap();
+
+        let outputs: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| match &entry.outcome {
+                DiffuseOutcome::Diffused {
+                    output,
+                    kept_payload,
+                    entropy_before,
+                    entropy_after,
+                } => {
+                    assert!(entropy_after > entropy_before, "{}", entry.to_text());
+                    Some((output.clone(), *kept_payload))
+                }
+                DiffuseOutcome::Skipped(_) => None,
+            })
+            .collect();
+        assert_eq!(
+            outputs,
+            [
+                (out_dir.join("carrier.png"), true),
+                (out_dir.join("nested/clean.bmp.png"), false)
+            ]
+        );
+        assert!(matches!(entries[2].outcome, DiffuseOutcome::Skipped(_)));
+
+        let mut diffused = image::open(out_dir.join("carrier.png")).unwrap();
+        let diffused = convert_dynamic_image_to_png_image(&mut diffused).unwrap();
+        let header = try_get_header(diffused).unwrap();
+        assert_eq!(read_payload(diffused, &header, None).unwrap(), payload);
+        assert!(low_bit_entropy(diffused, 1) > 7.0);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn output_dir_is_skipped_however_it_is_spelled() {
+        let dir = env::temp_dir().join(format!("ihm-diffuse-{:x}", thread_rng().gen::<u64>()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(
+            dir.join("a.png"),
+            gradient().save_to_buffer(ImageOutputFormat::Png).unwrap(),
+        )
+        .unwrap();
+
+        // Names the same directory as `dir/out`, but does not start with it
+        let out_dir = dir.join("nested/../out");
+        for _ in 0..2 {
+            let entries = diffuse_dir(&dir, &out_dir, 1, true).unwrap();
+            let paths: Vec<_> = entries.iter().map(|entry| entry.path.clone()).collect();
+            assert_eq!(paths, [dir.join("a.png")]);
+        }
+        assert!(diffuse_dir(&dir, &dir.join("nested/.."), 1, true).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn colliding_outputs_are_rejected() {
+        let dir = env::temp_dir().join(format!("ihm-diffuse-{:x}", thread_rng().gen::<u64>()));
+        let out_dir = dir.join("out");
+        fs::create_dir_all(&dir).unwrap();
+        for (name, format) in [
+            ("a.png", ImageOutputFormat::Png),
+            ("a.bmp", ImageOutputFormat::Bmp),
+        ] {
+            fs::write(dir.join(name), gradient().save_to_buffer(format).unwrap()).unwrap();
+        }
+
+        // The extensions are kept, so these two do not collide
+        let outputs: Vec<_> = diffuse_dir(&dir, &out_dir, 1, false)
+            .unwrap()
+            .into_iter()
+            .filter_map(|entry| match entry.outcome {
+                DiffuseOutcome::Diffused { output, .. } => Some(output),
+                DiffuseOutcome::Skipped(_) => None,
+            })
+            .collect();
+        assert_eq!(outputs, [out_dir.join("a.bmp.png"), out_dir.join("a.png")]);
+
+        // But a PNG named like the output of the BMP does
+        fs::copy(dir.join("a.png"), dir.join("a.bmp.png")).unwrap();
+        fs::remove_dir_all(&out_dir).unwrap();
+        let err = diffuse_dir(&dir, &out_dir, 1, false).unwrap_err();
+        assert!(err.contains("would both be written to"), "{}", err);
+        assert!(!out_dir.exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn noise_keeps_its_entropy() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        thread_rng().fill_bytes(&mut image);
+        assert!(low_bit_entropy(&image, 1) > 7.0);
+
+        assert!(!diffuse_image(&mut image, 1).unwrap());
+        assert!(low_bit_entropy(&image, 1) > 7.0);
+    }
+}
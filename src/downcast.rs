@@ -0,0 +1,181 @@
+use clap::ValueEnum;
+use image::{DynamicImage, ImageBuffer};
+
+/// How the fraction lost when reducing 16-bit samples to 8 bits is spread over the image
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub(crate) enum Dither {
+    /// Drop the low byte. Smooth gradients turn into visible bands.
+    None,
+    /// Add a fixed 4x4 Bayer pattern before rounding
+    Ordered,
+    /// Floyd-Steinberg error diffusion, which carries the rounding error over to the neighbouring pixels
+    #[default]
+    ErrorDiffusion,
+}
+
+/// 4x4 Bayer matrix, giving the threshold of a pixel in sixteenths
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+///
+/// Converts an image with 16 bits per channel to 8 bits per channel, keeping its channels.
+/// Color channels are dithered, alpha is rounded. Other images are returned as they are.
+pub(crate) fn downcast_to_8bit(image: DynamicImage, dither: Dither) -> DynamicImage {
+    let (width, height) = (image.width(), image.height());
+    let samples = |buffer: &[u16], channels: usize, has_alpha: bool| {
+        downcast_samples(buffer, width as usize, channels, has_alpha, dither)
+    };
+    let converted = match &image {
+        DynamicImage::ImageLuma16(buffer) => {
+            ImageBuffer::from_raw(width, height, samples(buffer, 1, false))
+                .map(DynamicImage::ImageLuma8)
+        }
+        DynamicImage::ImageLumaA16(buffer) => {
+            ImageBuffer::from_raw(width, height, samples(buffer, 2, true))
+                .map(DynamicImage::ImageLumaA8)
+        }
+        DynamicImage::ImageRgb16(buffer) => {
+            ImageBuffer::from_raw(width, height, samples(buffer, 3, false))
+                .map(DynamicImage::ImageRgb8)
+        }
+        DynamicImage::ImageRgba16(buffer) => {
+            ImageBuffer::from_raw(width, height, samples(buffer, 4, true))
+                .map(DynamicImage::ImageRgba8)
+        }
+        _ => None,
+    };
+
+    converted.unwrap_or(image)
+}
+
+fn downcast_samples(
+    samples: &[u16],
+    width: usize,
+    channels: usize,
+    has_alpha: bool,
+    dither: Dither,
+) -> Vec<u8> {
+    let color_channels = channels - has_alpha as usize;
+    // 8-bit values are the 16-bit values divided by 257, as 0xFFFF maps to 0xFF
+    let scaled = |sample: u16| sample as f32 / 257.0;
+    let to_u8 = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+
+    if dither != Dither::ErrorDiffusion {
+        return samples
+            .iter()
+            .enumerate()
+            .map(|(index, sample)| {
+                let pixel = index / channels;
+                match dither {
+                    _ if index % channels >= color_channels => to_u8(scaled(*sample)),
+                    Dither::Ordered => {
+                        let threshold = BAYER_4X4[pixel / width % 4][pixel % width % 4];
+                        (scaled(*sample) + (threshold as f32 + 0.5) / 16.0)
+                            .floor()
+                            .min(255.0) as u8
+                    }
+                    _ => (sample >> 8) as u8,
+                }
+            })
+            .collect();
+    }
+
+    // Rounding errors of the current and the next row, per sample
+    let row_len = width * channels;
+    let mut errors = vec![0f32; row_len];
+    let mut next_errors = vec![0f32; row_len];
+    let mut converted = Vec::with_capacity(samples.len());
+    for row in samples.chunks(row_len) {
+        for (index, sample) in row.iter().enumerate() {
+            if index % channels >= color_channels {
+                converted.push(to_u8(scaled(*sample)));
+                continue;
+            }
+            let wanted = scaled(*sample) + errors[index];
+            let value = to_u8(wanted);
+            converted.push(value);
+
+            let error = wanted - value as f32;
+            let x = index / channels;
+            if x + 1 < width {
+                errors[index + channels] += error * 7.0 / 16.0;
+                next_errors[index + channels] += error / 16.0;
+            }
+            if x > 0 {
+                next_errors[index - channels] += error * 3.0 / 16.0;
+            }
+            next_errors[index] += error * 5.0 / 16.0;
+        }
+        errors = std::mem::replace(&mut next_errors, vec![0f32; row_len]);
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, Rgba};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// Gradient rising evenly from the 8-bit level 64 to 65, so half the pixels should end up at either
+    fn shallow_gradient() -> DynamicImage {
+        DynamicImage::ImageRgb16(ImageBuffer::from_fn(257, 32, |x, _| {
+            let value = 64 * 257 + x as u16;
+            Rgb([value, value, value])
+        }))
+    }
+
+    /// Histogram of the red channel
+    fn histogram(image: &DynamicImage) -> [u64; 256] {
+        let mut histogram = [0u64; 256];
+        for pixel in image.as_rgb8().unwrap().pixels() {
+            histogram[pixel[0] as usize] += 1;
+        }
+        histogram
+    }
+
+    /// Share of the pixels at the lower of the two levels
+    fn lower_share(histogram: &[u64; 256]) -> f64 {
+        assert_eq!(histogram[64] + histogram[65], histogram.iter().sum::<u64>());
+        histogram[64] as f64 / (histogram[64] + histogram[65]) as f64
+    }
+
+    #[test]
+    fn dithering_keeps_the_distribution_of_a_gradient() {
+        let cover = shallow_gradient();
+
+        // Truncation moves a quarter of the gradient too far down, into one wide band
+        let truncated = histogram(&downcast_to_8bit(cover.clone(), Dither::None));
+        assert!(lower_share(&truncated) > 0.7, "{}", lower_share(&truncated));
+
+        for dither in [Dither::Ordered, Dither::ErrorDiffusion] {
+            let dithered = histogram(&downcast_to_8bit(cover.clone(), dither));
+            assert!(
+                (lower_share(&dithered) - 0.5).abs() < 0.05,
+                "{:?}: {}",
+                dither,
+                lower_share(&dithered)
+            );
+        }
+    }
+
+    #[test]
+    fn only_16_bit_images_are_converted() {
+        let cover = DynamicImage::ImageRgba16(ImageBuffer::from_pixel(
+            3,
+            2,
+            Rgba([0xFFFF, 0, 0x8080, 0x7F80]),
+        ));
+        let converted = downcast_to_8bit(cover, Dither::ErrorDiffusion);
+        assert_eq!(
+            converted.as_rgba8().unwrap().get_pixel(2, 1),
+            &Rgba([0xFF, 0, 0x80, 0x7F])
+        );
+
+        let eight_bit = DynamicImage::new_rgb8(2, 2);
+        assert_eq!(
+            downcast_to_8bit(eight_bit.clone(), Dither::Ordered),
+            eight_bit
+        );
+    }
+}
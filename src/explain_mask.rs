@@ -0,0 +1,225 @@
+use std::fmt;
+
+use image::ColorType;
+
+use crate::{
+    header::{calculate_bit_mask, check_low_bits_only, DEFAULT_MAX_BITS_PER_CHANNEL},
+    raw::create_offset_map,
+    size_format::format_byte_size,
+};
+
+/// Color types `explain-mask` accepts, by their name in any case, e.g. `rgb8`
+const COLOR_TYPES: [ColorType; 10] = [
+    ColorType::L8,
+    ColorType::La8,
+    ColorType::Rgb8,
+    ColorType::Rgba8,
+    ColorType::L16,
+    ColorType::La16,
+    ColorType::Rgb16,
+    ColorType::Rgba16,
+    ColorType::Rgb32F,
+    ColorType::Rgba32F,
+];
+
+pub(crate) fn parse_color_type(name: &str) -> Result<ColorType, String> {
+    COLOR_TYPES
+        .into_iter()
+        .find(|color_type| format!("{:?}", color_type).eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            format!(
+                "Unknown color type {}, expected one of {}",
+                name,
+                COLOR_TYPES
+                    .iter()
+                    .map(|color_type| format!("{:?}", color_type).to_lowercase())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+///
+/// Parses a hex mask written from the first bit of the pixel on, in the order `stat` shows the bits.
+/// `0x0F_0F_0F` selects the 4 low bits of the first three channels of 8 bits each.
+pub(crate) fn parse_mask(hex: &str) -> Result<u64, String> {
+    let digits: String = hex
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|digit| *digit != '_')
+        .collect();
+    if digits.is_empty() || digits.len() > 16 {
+        return Err(format!("Expected 1 to 16 hex digits, got {}", hex));
+    }
+    let mask = u64::from_str_radix(&digits, 16).map_err(|_| format!("{} is no hex number", hex))?;
+    Ok(mask << (64 - digits.len() * 4))
+}
+
+///
+/// The bits of a single channel a mask selects
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ChannelBits {
+    pub(crate) name: &'static str,
+    /// Selected bits, counted from the least significant one at 0
+    pub(crate) bits: Vec<usize>,
+}
+
+///
+/// What a data mask does to the pixels of a color type, printed by `explain-mask`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MaskExplanation {
+    pub(crate) mask: u64,
+    pub(crate) color_type: ColorType,
+    pub(crate) channels: Vec<ChannelBits>,
+    pub(crate) bits_per_pixel: usize,
+    /// Why encoding refuses the mask without --allow-high-bits or --allow-destructive, if it does
+    pub(crate) refused_by_default: Option<String>,
+}
+
+impl MaskExplanation {
+    /// Payload bytes a megapixel holds with this mask
+    pub(crate) fn bytes_per_megapixel(&self) -> u64 {
+        1_000_000 * self.bits_per_pixel as u64 / 8
+    }
+
+    /// Whether encoding picks this very mask when spreading the payload over `bits_per_pixel` bits
+    fn is_default_layout(&self) -> bool {
+        u8::try_from(self.bits_per_pixel)
+            .is_ok_and(|bits| calculate_bit_mask(bits, self.color_type) == self.mask)
+    }
+}
+
+///
+/// Splits the mask into the channels of the color type. Fails if it selects bits beyond the pixel.
+pub(crate) fn explain_mask(mask: u64, color_type: ColorType) -> Result<MaskExplanation, String> {
+    let pixel_bits = color_type.bits_per_pixel() as usize;
+    let offsets = create_offset_map(mask, pixel_bits);
+    if offsets.len() != mask.count_ones() as usize {
+        return Err(format!(
+            "The mask {:#018x} selects bits beyond the {} bits of a {:?} pixel",
+            mask, pixel_bits, color_type
+        ));
+    }
+
+    let channel_bits = pixel_bits / color_type.channel_count() as usize;
+    let names: &[&'static str] = match color_type.channel_count() {
+        1 => &["L"],
+        2 => &["L", "A"],
+        3 => &["R", "G", "B"],
+        _ => &["R", "G", "B", "A"],
+    };
+    let channels = names
+        .iter()
+        .enumerate()
+        .map(|(channel, name)| ChannelBits {
+            name,
+            bits: offsets
+                .iter()
+                .filter(|offset| *offset / channel_bits == channel)
+                .map(|offset| channel_bits - 1 - offset % channel_bits)
+                .rev()
+                .collect(),
+        })
+        .collect();
+
+    Ok(MaskExplanation {
+        mask,
+        color_type,
+        channels,
+        bits_per_pixel: offsets.len(),
+        refused_by_default: check_low_bits_only(
+            mask,
+            color_type,
+            Some(DEFAULT_MAX_BITS_PER_CHANNEL),
+        )
+        .err(),
+    })
+}
+
+impl fmt::Display for MaskExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Mask {:#018x} on {:?} pixels",
+            self.mask, self.color_type
+        )?;
+        for channel in &self.channels {
+            match channel.bits.len() {
+                0 => writeln!(f, "{}: unused", channel.name)?,
+                count => writeln!(
+                    f,
+                    "{}: {} bit{} ({}), counted from the least significant bit at 0",
+                    channel.name,
+                    count,
+                    if count == 1 { "" } else { "s" },
+                    channel
+                        .bits
+                        .iter()
+                        .map(|bit| bit.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )?,
+            }
+        }
+        writeln!(f, "Bits per pixel: {}", self.bits_per_pixel)?;
+        writeln!(
+            f,
+            "Capacity: {} per megapixel",
+            format_byte_size(self.bytes_per_megapixel())
+        )?;
+        if self.is_default_layout() {
+            writeln!(
+                f,
+                "Encoding picks this mask for payloads needing {} bits per pixel",
+                self.bits_per_pixel
+            )?;
+        }
+        match &self.refused_by_default {
+            None => write!(f, "Encoding accepts it without further flags"),
+            Some(reason) => write!(f, "Encoding refuses it by default: {}", reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn low_nibbles_of_rgb8_are_4_bits_per_channel() {
+        let mask = parse_mask("0x0F_0F_0F_00").unwrap();
+        assert_eq!(mask, 0x0F_0F_0F_00_00_00_00_00);
+
+        let explanation = explain_mask(mask, parse_color_type("rgb8").unwrap()).unwrap();
+
+        for (channel, name) in explanation.channels.iter().zip(["R", "G", "B"]) {
+            assert_eq!(channel.name, name);
+            assert_eq!(channel.bits, [0, 1, 2, 3]);
+        }
+        assert_eq!(explanation.bits_per_pixel, 12);
+        assert_eq!(explanation.bytes_per_megapixel(), 1_500_000);
+        assert!(explanation.refused_by_default.is_some());
+        let text = explanation.to_string();
+        assert!(
+            text.contains("R: 4 bits (0, 1, 2, 3)") && text.contains("Bits per pixel: 12"),
+            "{}",
+            text
+        );
+        assert!(text.contains("Encoding picks this mask"), "{}", text);
+    }
+
+    #[test]
+    fn bits_beyond_the_pixel_are_rejected() {
+        let mask = parse_mask("0x01_01_01_01").unwrap();
+
+        assert!(explain_mask(mask, ColorType::Rgb8).is_err());
+        assert!(explain_mask(mask, ColorType::Rgba8)
+            .unwrap()
+            .refused_by_default
+            .is_none());
+        assert!(parse_color_type("cmyk8").is_err());
+        assert!(parse_mask("0xZZ").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    avoid_mask::AvoidMask, buffer_modify::PngImage, header::VersionedHeader,
+    io_errors::cannot_write, payload::read_payload,
+};
+
+///
+/// Picks the name of the extracted file: the file name stored in the header,
+/// or `<source-stem>.<ext>` if there is none. The extension is guessed from the magic bytes of `payload`,
+/// and is `bin` if they are unknown or no payload is given.
+pub(crate) fn output_file_name(
+    header: &VersionedHeader,
+    source: &Path,
+    payload: Option<&[u8]>,
+) -> PathBuf {
+    // Only use the last component, so a crafted header can not write outside of the output directory
+    let stored_name = header
+        .file_name()
+        .and_then(|name| Path::new(name).file_name())
+        .filter(|name| !name.is_empty());
+
+    match stored_name {
+        Some(name) => PathBuf::from(name),
+        None => {
+            let stem = source
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "payload".to_string());
+            let extension = payload
+                .and_then(infer::get)
+                .map_or("bin", |kind| kind.extension());
+            PathBuf::from(format!("{}.{}", stem, extension))
+        }
+    }
+}
+
+///
+/// Reads the payload and writes it into `out_dir`. Existing files are never overwritten.
+/// Without `sniff`, a payload without a stored file name is always written as `.bin`.
+/// Returns the path of the created file.
+pub(crate) fn extract_to_file(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    avoid_mask: Option<&AvoidMask>,
+    source: &Path,
+    out_dir: &Path,
+    sniff: bool,
+) -> Result<PathBuf, String> {
+    let payload = read_payload(image, header, avoid_mask)?;
+    let out_path = out_dir.join(output_file_name(
+        header,
+        source,
+        sniff.then_some(payload.as_slice()),
+    ));
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&out_path)
+        .map_err(|x| cannot_write(out_path.display(), &x))?;
+    file.write_all(&payload)
+        .map_err(|x| cannot_write(out_path.display(), &x))?;
+
+    Ok(out_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use image::{ImageBuffer, Rgba};
+    use pretty_assertions::assert_eq;
+    use rand::{thread_rng, Rng, RngCore};
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{HeaderExtension, V1DataStuffingOptions},
+        payload::write_payload,
+    };
+
+    fn encoded_image(
+        payload: &[u8],
+        file_name: Option<&str>,
+    ) -> (ImageBuffer<Rgba<u8>, Vec<u8>>, VersionedHeader) {
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        thread_rng().fill_bytes(&mut image);
+        let mut header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 1024 },
+            data_mask: 0x03_03_03_03_00_00_00_00u64,
+            data_len: payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(payload),
+            extensions: Vec::new(),
+        };
+        if let Some(name) = file_name {
+            header = header
+                .with_extension(HeaderExtension::FileName(name.to_string()))
+                .unwrap();
+        }
+        write_payload(&mut image, &header, payload, None).unwrap();
+        (image, header)
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = env::temp_dir().join(format!("ihm-extract-{:x}", thread_rng().gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn extract_uses_stored_file_name() {
+        let payload = b"secret notes".to_vec();
+        let (image, header) = encoded_image(&payload, Some("notes.txt"));
+        let dir = temp_dir();
+
+        let path =
+            extract_to_file(&image, &header, None, Path::new("cover.png"), &dir, true).unwrap();
+
+        assert_eq!(path, dir.join("notes.txt"));
+        assert_eq!(fs::read(&path).unwrap(), payload);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn extract_falls_back_to_source_stem() {
+        let payload = vec![0x01, 0x02, 0x03];
+        let (image, header) = encoded_image(&payload, None);
+        let dir = temp_dir();
+
+        let source = Path::new("some/dir/cover.png");
+        let path = extract_to_file(&image, &header, None, source, &dir, true).unwrap();
+
+        assert_eq!(path, dir.join("cover.bin"));
+        assert_eq!(fs::read(&path).unwrap(), payload);
+
+        // A second extraction must not overwrite the first one
+        assert!(
+            extract_to_file(&image, &header, None, Path::new("cover.png"), &dir, true).is_err()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn extension_is_sniffed_from_the_payload() {
+        let pdf = b"%PDF-1.7\n1 0 obj\n<< /Type /Catalog >>\nendobj\n".to_vec();
+        let (image, header) = encoded_image(&pdf, None);
+        let dir = temp_dir();
+
+        let path =
+            extract_to_file(&image, &header, None, Path::new("cover.png"), &dir, true).unwrap();
+
+        assert_eq!(path, dir.join("cover.pdf"));
+        assert_eq!(fs::read(&path).unwrap(), pdf);
+        assert_eq!(
+            output_file_name(&header, Path::new("cover.png"), None),
+            PathBuf::from("cover.bin")
+        );
+        assert_eq!(
+            output_file_name(&header, Path::new("cover.png"), Some(&[0x8f, 0x00, 0x13])),
+            PathBuf::from("cover.bin")
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stored_file_name_can_not_escape_output_dir() {
+        let (_, header) = encoded_image(b"x", Some("../../etc/passwd"));
+
+        assert_eq!(
+            output_file_name(&header, Path::new("cover.png"), Some(b"%PDF-1.7")),
+            PathBuf::from("passwd")
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use clap::ValueEnum;
+
+use crate::buffer_modify::PngImage;
+
+/// Payload layouts produced by external LSB tools
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ForeignFormat {
+    /// Sequential LSB of the R, G and B channels, row by row, prefixed with a 32-bit big-endian length
+    /// (as detected by `zsteg` as `b1,rgb,lsb,xy`)
+    LsbRgb,
+    /// Sequential LSB of the R, G, B and A channels, row by row, prefixed with a 32-bit big-endian length
+    /// (as detected by `zsteg` as `b1,rgba,lsb,xy`)
+    LsbRgba,
+}
+
+/// Size of the length prefix used by all supported foreign formats
+const LENGTH_PREFIX_BYTES: usize = 4;
+
+impl ForeignFormat {
+    ///
+    /// The mask in our own notation (see `VersionedHeader::V1::data_mask`) matching the foreign layout
+    fn data_mask(&self) -> u64 {
+        match self {
+            ForeignFormat::LsbRgb => 0x01_01_01_00_00_00_00_00u64,
+            ForeignFormat::LsbRgba => 0x01_01_01_01_00_00_00_00u64,
+        }
+    }
+}
+
+///
+/// Reads a payload which was embedded by an external tool, using the layout described by `format`.
+pub(crate) fn read_foreign_payload(
+    image: &dyn PngImage,
+    format: ForeignFormat,
+) -> Result<Vec<u8>, String> {
+    let data_mask = format.data_mask();
+    let bits_per_pixel = image.color_type().bits_per_pixel();
+    // Bits outside of the pixel are ignored by the reader, so only count the ones that exist
+    let data_bits_per_pixel = (data_mask >> (64 - bits_per_pixel.min(64))).count_ones() as u64;
+    let capacity_bytes = image.pixel_count() * data_bits_per_pixel / 8;
+
+    if capacity_bytes < LENGTH_PREFIX_BYTES as u64 {
+        return Err("Image is too small to contain a foreign payload".to_string());
+    }
+
+    let prefix = image.read_data_with_mask(data_mask, 0, LENGTH_PREFIX_BYTES);
+    let payload_len = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as u64;
+
+    if payload_len + LENGTH_PREFIX_BYTES as u64 > capacity_bytes {
+        return Err(format!(
+            "Length prefix claims {} bytes, but the image can only hold {} bytes in this format",
+            payload_len,
+            capacity_bytes - LENGTH_PREFIX_BYTES as u64
+        ));
+    }
+
+    // The prefix does not necessarily end on a pixel boundary, so read from the start and skip it
+    let mut data =
+        image.read_data_with_mask(data_mask, 0, LENGTH_PREFIX_BYTES + payload_len as usize);
+
+    Ok(data.split_off(LENGTH_PREFIX_BYTES))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb, Rgba};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    ///
+    /// Embeds data the way a simple external LSB tool would: every channel byte gets one bit,
+    /// MSB of the data first, prefixed with the big-endian u32 length.
+    fn embed_like_external_tool(
+        raw: &mut [u8],
+        channels: usize,
+        used_channels: usize,
+        data: &[u8],
+    ) {
+        let mut framed = (data.len() as u32).to_be_bytes().to_vec();
+        framed.extend_from_slice(data);
+
+        let bits = framed
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1));
+        let targets = raw
+            .iter_mut()
+            .enumerate()
+            .filter(|(i, _)| i % channels < used_channels)
+            .map(|(_, channel)| channel);
+
+        for (channel, bit) in targets.zip(bits) {
+            *channel = (*channel & 0xFE) | bit;
+        }
+    }
+
+    #[test]
+    fn decode_lsb_rgb_fixture() {
+        let message = b"Hello from somewhere else";
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(32, 32, |x, y| Rgb([x as u8 * 7, y as u8 * 5, 0xAA]));
+        embed_like_external_tool(&mut image, 3, 3, message);
+
+        let result = read_foreign_payload(&image, ForeignFormat::LsbRgb).unwrap();
+
+        assert_eq!(result, message.to_vec());
+    }
+
+    #[test]
+    fn decode_lsb_rgba_fixture() {
+        let message = b"Hello from somewhere else, with alpha";
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(32, 32, |x, y| Rgba([x as u8, y as u8, 0x55, 0xFF]));
+        embed_like_external_tool(&mut image, 4, 4, message);
+
+        let result = read_foreign_payload(&image, ForeignFormat::LsbRgba).unwrap();
+
+        assert_eq!(result, message.to_vec());
+    }
+
+    #[test]
+    fn lsb_rgb_in_rgba_image_skips_alpha() {
+        let message = b"no alpha here";
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(16, 16, |x, y| Rgba([x as u8, y as u8, 0x55, 0xFF]));
+        embed_like_external_tool(&mut image, 4, 3, message);
+
+        let result = read_foreign_payload(&image, ForeignFormat::LsbRgb).unwrap();
+
+        assert_eq!(result, message.to_vec());
+    }
+
+    #[test]
+    fn reject_length_prefix_exceeding_capacity() {
+        // All LSBs set, so the prefix claims 0xFFFFFFFF bytes
+        let image: ImageBuffer<Rgb<u8>, Vec<u8>> =
+            ImageBuffer::from_pixel(4, 4, Rgb([0xFF, 0xFF, 0xFF]));
+
+        assert!(read_foreign_payload(&image, ForeignFormat::LsbRgb).is_err());
+    }
+}
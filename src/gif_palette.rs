@@ -0,0 +1,373 @@
+use std::ops::Range;
+
+use weezl::{decode::Decoder, BitOrder};
+
+use crate::{
+    crc_spec::CrcSpec,
+    header::{HeaderExtension, HeaderRaw, V1DataStuffingOptions, VersionedHeader},
+    payload::check_payload_crc,
+};
+
+/// Every byte of an unused palette entry carries data, as the entry is never displayed
+pub(crate) const GIF_PALETTE_MASK: u64 = 0xFFu64 << 56;
+const GIF_SIGNATURES: [&[u8; 6]; 2] = [b"GIF87a", b"GIF89a"];
+/// Signature (6B), then the logical screen descriptor: width (2B), height (2B), flags, background index, aspect ratio
+const GLOBAL_TABLE_START: usize = 13;
+const FULL_TABLE_ENTRIES: usize = 256;
+const EXTENSION_INTRODUCER: u8 = 0x21;
+const GRAPHIC_CONTROL_LABEL: u8 = 0xF9;
+const IMAGE_SEPARATOR: u8 = 0x2C;
+const TRAILER: u8 = 0x3B;
+
+/// Whether the data starts with a GIF signature
+pub(crate) fn is_gif(data: &[u8]) -> bool {
+    GIF_SIGNATURES
+        .iter()
+        .any(|signature| data.starts_with(&signature[..]))
+}
+
+///
+/// Where the global color table is, and which of its entries are displayed
+struct PaletteUsage {
+    /// The global color table, as byte range into the file
+    table: Range<usize>,
+    /// Entries referenced by a frame, as background or as transparent color
+    used: [bool; FULL_TABLE_ENTRIES],
+}
+
+impl PaletteUsage {
+    /// File offsets of the bytes of the unused entries, in order
+    fn carrier_bytes(&self) -> Vec<usize> {
+        (0..self.table.len() / 3)
+            .filter(|entry| !self.used[*entry])
+            .flat_map(|entry| {
+                let start = self.table.start + entry * 3;
+                start..start + 3
+            })
+            .collect()
+    }
+}
+
+fn table_entries(flags: u8) -> usize {
+    2 << (flags & 0x07)
+}
+
+///
+/// Concatenates the data sub-blocks starting at `offset`. Returns them and the offset after the terminator.
+fn read_sub_blocks(gif: &[u8], mut offset: usize) -> Result<(Vec<u8>, usize), String> {
+    let mut data = Vec::new();
+    loop {
+        let len = *gif.get(offset).ok_or("GIF file is truncated")? as usize;
+        if len == 0 {
+            return Ok((data, offset + 1));
+        }
+        let block = gif
+            .get(offset + 1..offset + 1 + len)
+            .ok_or("GIF file is truncated")?;
+        data.extend_from_slice(block);
+        offset += 1 + len;
+    }
+}
+
+///
+/// Walks the blocks of the GIF and decodes the frames drawn with the global color table,
+/// to tell the entries they show from the unused ones
+fn read_palette_usage(gif: &[u8]) -> Result<PaletteUsage, String> {
+    if !is_gif(gif) {
+        return Err("Only GIF files have a global color table".to_string());
+    }
+    let screen = gif
+        .get(6..GLOBAL_TABLE_START)
+        .ok_or("GIF file is truncated")?;
+    if screen[4] & 0x80 == 0 {
+        return Err("The GIF has no global color table, every frame brings its own".to_string());
+    }
+    let table = GLOBAL_TABLE_START..GLOBAL_TABLE_START + table_entries(screen[4]) * 3;
+    if table.end > gif.len() {
+        return Err("GIF file is truncated".to_string());
+    }
+
+    let mut used = [false; FULL_TABLE_ENTRIES];
+    used[screen[5] as usize] = true;
+    // A graphic control extension applies to the frame following it
+    let mut transparent = None;
+    let mut offset = table.end;
+    loop {
+        match gif.get(offset).copied() {
+            Some(TRAILER) => break,
+            Some(EXTENSION_INTRODUCER) => {
+                let label = *gif.get(offset + 1).ok_or("GIF file is truncated")?;
+                let (data, end) = read_sub_blocks(gif, offset + 2)?;
+                if label == GRAPHIC_CONTROL_LABEL && data.len() >= 4 && data[0] & 0x01 != 0 {
+                    transparent = Some(data[3]);
+                }
+                offset = end;
+            }
+            Some(IMAGE_SEPARATOR) => {
+                // Left, top, width, height (2B each), flags
+                let flags = *gif.get(offset + 9).ok_or("GIF file is truncated")?;
+                let has_local_table = flags & 0x80 != 0;
+                let mut data_start = offset + 10;
+                if has_local_table {
+                    data_start += table_entries(flags) * 3;
+                }
+                let min_code_size = *gif.get(data_start).ok_or("GIF file is truncated")?;
+                let (data, end) = read_sub_blocks(gif, data_start + 1)?;
+                if !has_local_table {
+                    if !(2..=11).contains(&min_code_size) {
+                        return Err(format!(
+                            "The frame at byte {} has an invalid code size of {}",
+                            offset, min_code_size
+                        ));
+                    }
+                    let indices = Decoder::new(BitOrder::Lsb, min_code_size)
+                        .decode(&data)
+                        .map_err(|x| format!("The frame at byte {} is damaged: {}", offset, x))?;
+                    for index in indices.into_iter().chain(transparent) {
+                        used[index as usize] = true;
+                    }
+                }
+                transparent = None;
+                offset = end;
+            }
+            Some(block) => {
+                return Err(format!(
+                    "Unknown GIF block {:#04x} at byte {}",
+                    block, offset
+                ))
+            }
+            None => return Err("GIF file is truncated".to_string()),
+        }
+    }
+
+    Ok(PaletteUsage { table, used })
+}
+
+///
+/// Grows the global color table to 256 entries. The added entries are black and unused,
+/// as no frame refers to an index beyond the original table.
+fn grow_global_table(gif: &[u8], usage: &PaletteUsage) -> (Vec<u8>, PaletteUsage) {
+    let mut grown = Vec::with_capacity(gif.len() + FULL_TABLE_ENTRIES * 3);
+    grown.extend_from_slice(&gif[..usage.table.start]);
+    grown[10] |= 0x07;
+    grown.extend_from_slice(&gif[usage.table.clone()]);
+    grown.resize(usage.table.start + FULL_TABLE_ENTRIES * 3, 0);
+    grown.extend_from_slice(&gif[usage.table.end..]);
+
+    let usage = PaletteUsage {
+        table: usage.table.start..usage.table.start + FULL_TABLE_ENTRIES * 3,
+        used: usage.used,
+    };
+    (grown, usage)
+}
+
+///
+/// Embeds the payload into the unused entries of the global color table of a GIF. The header is stored there
+/// as well, so the frames stay untouched. Returns the modified GIF file.
+pub(crate) fn write_gif_payload(
+    gif: &[u8],
+    payload: &[u8],
+    crc_spec: CrcSpec,
+) -> Result<Vec<u8>, String> {
+    let (mut gif, usage) = grow_global_table(gif, &read_palette_usage(gif)?);
+    let carrier_bytes = usage.carrier_bytes();
+
+    let mut extensions = Vec::new();
+    if crc_spec != CrcSpec::default() {
+        extensions.push(HeaderExtension::PayloadCrcSpec(crc_spec));
+    }
+    // The table holds at most 768 bytes, so the header uses the shorter variable-length encoding.
+    // Its length then depends on the offset, which follows the header. Settle on a fixed point.
+    let mut start_offset = 0;
+    let header_bytes = loop {
+        let header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::GifPalette { start_offset },
+            data_mask: GIF_PALETTE_MASK,
+            data_len: payload.len() as u64,
+            data_crc: crc_spec.checksum(payload),
+            extensions: extensions.clone(),
+        };
+        let raw_header = header.to_varint_raw().map_err(|x| format!("{}", x))?;
+        let header_bytes = raw_header.to_bytes();
+        if header_bytes.len() as u64 == start_offset {
+            break header_bytes;
+        }
+        start_offset = header_bytes.len() as u64;
+    };
+
+    if header_bytes.len() + payload.len() > carrier_bytes.len() {
+        return Err(format!(
+            "The GIF has {} unused palette entries, which hold {} bytes. The header and payload need {} bytes.",
+            carrier_bytes.len() / 3,
+            carrier_bytes.len(),
+            header_bytes.len() + payload.len()
+        ));
+    }
+
+    for (offset, byte) in carrier_bytes
+        .into_iter()
+        .zip(header_bytes.iter().chain(payload))
+    {
+        gif[offset] = *byte;
+    }
+    Ok(gif)
+}
+
+fn read_carrier_bytes(gif: &[u8]) -> Result<Vec<u8>, String> {
+    let usage = read_palette_usage(gif)?;
+    Ok(usage
+        .carrier_bytes()
+        .into_iter()
+        .map(|offset| gif[offset])
+        .collect())
+}
+
+///
+/// Reads the header stored in the palette of a GIF, if the payload was embedded there
+pub(crate) fn try_get_gif_header(gif: &[u8]) -> Result<VersionedHeader, String> {
+    read_header(&read_carrier_bytes(gif)?)
+}
+
+fn read_header(carrier: &[u8]) -> Result<VersionedHeader, String> {
+    // Magic (1B), Header Len (2B), Data, CRC (4B)
+    if carrier.len() < 3 + 4 {
+        return Err("The GIF has too few unused palette entries to hold a header".to_string());
+    }
+    let header_len = 3 + u16::from_be_bytes([carrier[1], carrier[2]]) as usize + 4;
+    let header_bytes = carrier
+        .get(..header_len)
+        .ok_or("The GIF palette does not carry a header")?;
+
+    let header: VersionedHeader =
+        HeaderRaw::from_bytes(header_bytes).and_then(VersionedHeader::try_from)?;
+    if !matches!(
+        header.stuffing_opts(),
+        V1DataStuffingOptions::GifPalette { .. }
+    ) {
+        return Err("The header does not describe a payload in the GIF palette".to_string());
+    }
+
+    Ok(header)
+}
+
+///
+/// Reads the payload embedded via [`write_gif_payload`], along with its header.
+pub(crate) fn read_gif_payload(gif: &[u8]) -> Result<(VersionedHeader, Vec<u8>), String> {
+    let carrier = read_carrier_bytes(gif)?;
+    let header = read_header(&carrier)?;
+
+    let payload = usize::try_from(header.start_offset())
+        .ok()
+        .zip(usize::try_from(header.data_len()).ok())
+        .filter(|_| header.data_mask() == GIF_PALETTE_MASK)
+        .and_then(|(start, len)| carrier.get(start..start.checked_add(len)?))
+        .ok_or("Header describes more data than the GIF palette can hold")?
+        .to_vec();
+    check_payload_crc(&header, &payload)?;
+
+    Ok((header, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use weezl::encode::Encoder;
+
+    use super::*;
+
+    const WIDTH: u16 = 8;
+    const HEIGHT: u16 = 8;
+
+    /// The frames of a GIF drawn with the global color table, as palette indices
+    fn frame_indices(gif: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        let mut offset = read_palette_usage(gif).unwrap().table.end;
+        while gif[offset] != TRAILER {
+            let (data, end) = match gif[offset] {
+                IMAGE_SEPARATOR => read_sub_blocks(gif, offset + 11).unwrap(),
+                _ => read_sub_blocks(gif, offset + 2).unwrap(),
+            };
+            if gif[offset] == IMAGE_SEPARATOR {
+                frames.push(
+                    Decoder::new(BitOrder::Lsb, gif[offset + 10])
+                        .decode(&data)
+                        .unwrap(),
+                );
+            }
+            offset = end;
+        }
+        frames
+    }
+
+    /// An 8×8 GIF with a global color table of 8 entries and three frames, which use 5 of them
+    fn animated_gif() -> Vec<u8> {
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&WIDTH.to_le_bytes());
+        gif.extend_from_slice(&HEIGHT.to_le_bytes());
+        // Global color table of 2^(2+1) entries, background index 0
+        gif.extend_from_slice(&[0x80 | 0x02, 0, 0]);
+        gif.extend((0..8u8).flat_map(|i| [i * 30, 255 - i * 30, i * 10]));
+        // Netscape looping extension
+        gif.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+
+        for (frame, colors) in [[1u8, 2], [2, 3], [3, 4]].into_iter().enumerate() {
+            // Graphic control extension with a delay of 10 ms, entry 4 is transparent in the last frame
+            let transparent = (frame == 2) as u8;
+            gif.extend_from_slice(&[0x21, 0xF9, 4, transparent, 1, 0, 4, 0]);
+            gif.push(IMAGE_SEPARATOR);
+            gif.extend_from_slice(&[0, 0, 0, 0]);
+            gif.extend_from_slice(&WIDTH.to_le_bytes());
+            gif.extend_from_slice(&HEIGHT.to_le_bytes());
+            gif.push(0);
+            let indices: Vec<u8> = (0..WIDTH * HEIGHT)
+                .map(|i| colors[(i % 3 == 0) as usize])
+                .collect();
+            let data = Encoder::new(BitOrder::Lsb, 3).encode(&indices).unwrap();
+            gif.push(3);
+            for block in data.chunks(255) {
+                gif.push(block.len() as u8);
+                gif.extend_from_slice(block);
+            }
+            gif.push(0);
+        }
+        gif.push(TRAILER);
+        gif
+    }
+
+    #[test]
+    fn gif_round_trip_leaves_frames_and_used_colors_untouched() {
+        let cover = animated_gif();
+        let payload = b"hidden between the frames of a looping animation".to_vec();
+
+        let modified = write_gif_payload(&cover, &payload, CrcSpec::default()).unwrap();
+
+        let (header, read) = read_gif_payload(&modified).unwrap();
+        assert_eq!(read, payload);
+        assert!(matches!(
+            header.stuffing_opts(),
+            V1DataStuffingOptions::GifPalette { .. }
+        ));
+        assert_eq!(try_get_gif_header(&modified).unwrap(), header);
+
+        assert_eq!(frame_indices(&modified), frame_indices(&cover));
+        let cover_table = read_palette_usage(&cover).unwrap().table;
+        let usage = read_palette_usage(&modified).unwrap();
+        assert_eq!(usage.table.len(), FULL_TABLE_ENTRIES * 3);
+        for entry in [0, 1, 2, 3, 4] {
+            let bytes = cover_table.start + entry * 3..cover_table.start + entry * 3 + 3;
+            assert_eq!(modified[bytes.clone()], cover[bytes]);
+        }
+        // Everything behind the table is unchanged
+        assert_eq!(modified[usage.table.end..], cover[cover_table.end..]);
+    }
+
+    #[test]
+    fn gif_rejects_oversized_payload() {
+        let cover = animated_gif();
+
+        // 251 unused entries of 3 bytes each
+        assert!(write_gif_payload(&cover, &[0u8; 760], CrcSpec::default()).is_err());
+        assert!(read_gif_payload(&cover).is_err());
+        assert!(write_gif_payload(b"\x89PNG\r\n\x1a\n", b"x", CrcSpec::default()).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+//! Gray coding of the payload bits, see `encode --gray`.
+//!
+//! The bits a channel carries form one symbol, which is stored as its Gray code: consecutive symbol values
+//! differ in a single stored bit. The symbols follow the data mask, so a channel with 2 data bits carries
+//! 2-bit symbols, and the last symbol is cut short where the payload ends. Decoding reverses the mapping.
+
+use image::ColorType;
+
+///
+/// Number of data bits of every channel of a pixel which carries any, in the order they are stored
+pub fn symbol_widths(data_mask: u64, color_type: ColorType) -> Vec<usize> {
+    let bytes_per_channel = (color_type.bytes_per_pixel() / color_type.channel_count()) as usize;
+    let bits_per_pixel = (color_type.bits_per_pixel() as usize).min(u64::BITS as usize);
+    let mut widths: Vec<(usize, usize)> = Vec::new();
+    for bit in (0..bits_per_pixel).filter(|bit| data_mask & 1 << (63 - bit) != 0) {
+        let channel = bit / 8 / bytes_per_channel;
+        match widths.last_mut() {
+            Some((last_channel, width)) if *last_channel == channel => *width += 1,
+            _ => widths.push((channel, 1)),
+        }
+    }
+    widths.into_iter().map(|(_, width)| width).collect()
+}
+
+fn bits_of(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .flat_map(|byte| (0..8).rev().map(move |shift| byte >> shift & 1))
+        .collect()
+}
+
+fn bytes_of(bits: &[u8]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|bits| bits.iter().fold(0, |byte, bit| byte << 1 | bit))
+        .collect()
+}
+
+///
+/// Applies `map` to every symbol of the bit stream, cycling through the symbol widths
+fn map_symbols(data: &[u8], widths: &[usize], map: fn(&mut [u8])) -> Vec<u8> {
+    if widths.is_empty() {
+        return data.to_vec();
+    }
+    let mut bits = bits_of(data);
+    let mut start = 0;
+    for width in widths.iter().cycle() {
+        if start >= bits.len() {
+            break;
+        }
+        let end = (start + width).min(bits.len());
+        map(&mut bits[start..end]);
+        start = end;
+    }
+    bytes_of(&bits)
+}
+
+///
+/// Replaces every symbol by its Gray code, most significant bit first
+pub fn gray_encode(data: &[u8], widths: &[usize]) -> Vec<u8> {
+    map_symbols(data, widths, |symbol| {
+        for index in (1..symbol.len()).rev() {
+            symbol[index] ^= symbol[index - 1];
+        }
+    })
+}
+
+/// Reverses [`gray_encode`]
+pub fn gray_decode(data: &[u8], widths: &[usize]) -> Vec<u8> {
+    map_symbols(data, widths, |symbol| {
+        for index in 1..symbol.len() {
+            symbol[index] ^= symbol[index - 1];
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{try_get_header, HeaderExtension, V1DataStuffingOptions, VersionedHeader},
+        payload::{read_payload, write_payload},
+    };
+
+    /// Leaves room for the header in front of the payload
+    const START_OFFSET: u64 = 1024;
+
+    #[test]
+    fn symbols_follow_the_data_mask() {
+        assert_eq!(symbol_widths(0x03_03_03 << 40, ColorType::Rgb8), [2, 2, 2]);
+        assert_eq!(symbol_widths(0x01_07_00 << 40, ColorType::Rgb8), [1, 3]);
+        // Both bytes of a 16-bit channel form one symbol
+        assert_eq!(
+            symbol_widths(0x01_03_00_01 << 32, ColorType::Rgba16),
+            [3, 1]
+        );
+
+        // 2-bit symbols 00 01 10 11 become 00 01 11 10
+        assert_eq!(gray_encode(&[0b00_01_10_11], &[2]), [0b00_01_11_10]);
+        assert_eq!(gray_decode(&[0b00_01_11_10], &[2]), [0b00_01_10_11]);
+        // 3-bit symbols 110 110 and the last one cut short by the end of the payload, 11
+        assert_eq!(gray_encode(&[0b1101_1011], &[3]), [0b1011_0110]);
+        assert_eq!(gray_decode(&[0b1011_0110], &[3]), [0b1101_1011]);
+    }
+
+    /// Mean absolute change of the channels carrying data, for the payload embedded with and without Gray coding
+    fn mean_deltas(cover: &ImageBuffer<Rgb<u8>, Vec<u8>>, payload: &[u8]) -> (f64, f64) {
+        let header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None {
+                start_offset: START_OFFSET,
+            },
+            data_mask: 0x03_03_03 << 40,
+            data_len: payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(payload),
+            extensions: Vec::new(),
+        };
+        let mean_delta = |header: &VersionedHeader| {
+            let mut image = cover.clone();
+            write_payload(&mut image, header, payload, None).unwrap();
+            assert_eq!(read_payload(&image, header, None).unwrap(), payload);
+            let deltas: Vec<i32> = image
+                .as_raw()
+                .iter()
+                .zip(cover.as_raw())
+                .skip(START_OFFSET as usize * 3)
+                .map(|(new, old)| (*new as i32 - *old as i32).abs())
+                .collect();
+            deltas.iter().sum::<i32>() as f64 / deltas.len() as f64
+        };
+        let gray = header
+            .clone()
+            .with_extension(HeaderExtension::GrayCode)
+            .unwrap();
+
+        (mean_delta(&header), mean_delta(&gray))
+    }
+
+    #[test]
+    fn gray_coded_payload_round_trips_at_the_same_mean_delta() {
+        let mut cover: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut cover);
+        let mut payload = vec![0u8; (64 * 64 - START_OFFSET as usize) * 6 / 8];
+        rand::thread_rng().fill_bytes(&mut payload);
+
+        let mut image = cover.clone();
+        let header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None {
+                start_offset: START_OFFSET,
+            },
+            data_mask: 0x03_03_03 << 40,
+            data_len: payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(&payload),
+            extensions: vec![HeaderExtension::GrayCode],
+        };
+        write_payload(&mut image, &header, &payload, None).unwrap();
+        let found = try_get_header(&image).unwrap();
+        assert!(found.is_gray_coded());
+        assert_eq!(read_payload(&image, &found, None).unwrap(), payload);
+
+        // Gray coding maps the symbols one to one, so random symbols stay uniformly distributed
+        // and the cover changes by as much as without it
+        let (plain, gray) = mean_deltas(&cover, &payload);
+        assert!((plain - gray).abs() < plain * 0.05, "{} vs {}", plain, gray);
+    }
+}
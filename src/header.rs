@@ -1,22 +1,201 @@
-use std::mem::size_of;
+use std::{collections::BTreeMap, str::FromStr};
 
 use bincode::{config, error::EncodeError, Decode, Encode};
 use crc::{Crc, CRC_32_CKSUM};
 use image::{ColorType, EncodableLayout};
-use rand::{thread_rng, Rng};
+use rand::Rng;
 
-use crate::buffer_modify::PngImage;
+use crate::{
+    buffer_modify::PngImage,
+    color_key::ColorKey,
+    crc_spec::CrcSpec,
+    header_copies::find_header_copy,
+    preamble::{Preamble, PREAMBLE_BYTES},
+    prng::tool_rng,
+    raw::create_offset_map,
+    records::LengthEndian,
+    scatter::{read_scattered_header, SCATTERED_MAGIC},
+    span::SpanInfo,
+};
+
+/// The header is always stored in the least significant bit of the first channel
+pub const HEADER_MASK: u64 = 0b1u64 << 63 >> 7;
+
+/// Magic of headers whose body uses fixed-width integers, so its length does not depend on the field values
+pub const HEADER_MAGIC: u8 = 0x44;
+/// Magic of headers whose body uses variable-length integers. Earlier versions wrote these, they are still read.
+pub const VARINT_HEADER_MAGIC: u8 = 0x42;
 
 #[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
-pub(crate) enum V1DataStuffingOptions {
+pub enum V1DataStuffingOptions {
     None {
         /// How many pixels offset do we start?
         start_offset: u64,
     },
+    /// Only pixels which are not masked by an avoid mask carry data
+    AvoidMask {
+        /// How many allowed pixels offset do we start?
+        start_offset: u64,
+        /// Checksum of the avoid mask. The same mask is needed to decode.
+        mask_checksum: u32,
+    },
+    /// The header is scattered among the payload pixels, see [`crate::scatter`]
+    ScatteredHeader {
+        /// How many free pixels (not used by the bootstrap or the header) offset do we start?
+        start_offset: u64,
+        /// Seed the header pixels are derived from
+        seed: u64,
+    },
+    /// The header and the payload are placed in pixels derived from a password, see `src/deniable.rs`
+    Password {
+        /// How many of the password-derived payload pixels offset do we start?
+        start_offset: u64,
+    },
+    /// The header and the payload are stored in the transparency entries of a palette PNG, see `src/trns.rs`
+    Trns {
+        /// How many transparency entries offset do we start?
+        start_offset: u64,
+    },
+    /// The header and the payload are placed following a key file, see `src/keyfile.rs`
+    Keyed {
+        /// How many pixels of the key file's traversal offset do we start?
+        start_offset: u64,
+    },
+    /// Only pixels matching a color key carry data
+    ColorKey {
+        /// How many selected pixels offset do we start?
+        start_offset: u64,
+        key: ColorKey,
+        /// Checksum of the selected pixels, so decoding notices if the image was altered
+        selection_checksum: u32,
+    },
+    /// The payload is stored in the luma (Y) of the image's YCbCr representation, see [`crate::ycbcr`].
+    /// The data mask is unused. The header is stored like for [`V1DataStuffingOptions::None`].
+    Luma {
+        /// How many pixels able to carry the luma bits offset do we start?
+        start_offset: u64,
+        /// Least significant bits of the luma carrying the payload
+        y_bits: u8,
+    },
+    /// Further copies of the header are spread across the image, see [`crate::header_copies`].
+    /// The payload skips the pixels they take.
+    HeaderCopies {
+        /// How many pixels not taken by a copy offset do we start?
+        start_offset: u64,
+        /// Number of copies, including the one at the start of the image
+        copies: u8,
+    },
+    /// Samples carry more payload bits the more their surroundings vary, see [`crate::complexity`].
+    /// The data mask is unused. The header is stored like for [`V1DataStuffingOptions::None`].
+    Complexity {
+        /// How many pixels able to carry payload bits offset do we start?
+        start_offset: u64,
+        /// Most low bits a single sample carries
+        max_bits: u8,
+    },
+    /// The header and the payload are stored in the unused entries of a GIF's global color table,
+    /// see `src/gif_palette.rs`
+    GifPalette {
+        /// How many bytes of the unused entries offset do we start?
+        start_offset: u64,
+    },
 }
 
+impl V1DataStuffingOptions {
+    ///
+    /// Every variant with all of its fields at their maximum, see [`VersionedHeader::max_pixel_span`].
+    /// New variants have to be added here, or headers using them may overlap the payload.
+    fn largest_of_each_variant() -> [V1DataStuffingOptions; 11] {
+        let start_offset = u64::MAX;
+        [
+            V1DataStuffingOptions::None { start_offset },
+            V1DataStuffingOptions::AvoidMask {
+                start_offset,
+                mask_checksum: u32::MAX,
+            },
+            V1DataStuffingOptions::ScatteredHeader {
+                start_offset,
+                seed: u64::MAX,
+            },
+            V1DataStuffingOptions::Password { start_offset },
+            V1DataStuffingOptions::Trns { start_offset },
+            V1DataStuffingOptions::Keyed { start_offset },
+            V1DataStuffingOptions::ColorKey {
+                start_offset,
+                key: ColorKey {
+                    color: [u8::MAX; 4],
+                    compare_alpha: true,
+                    tolerance: u8::MAX,
+                    invert: true,
+                },
+                selection_checksum: u32::MAX,
+            },
+            V1DataStuffingOptions::Luma {
+                start_offset,
+                y_bits: u8::MAX,
+            },
+            V1DataStuffingOptions::HeaderCopies {
+                start_offset,
+                copies: u8::MAX,
+            },
+            V1DataStuffingOptions::Complexity {
+                start_offset,
+                max_bits: u8::MAX,
+            },
+            V1DataStuffingOptions::GifPalette { start_offset },
+        ]
+    }
+}
+
+///
+/// Optional header fields. New features add variants here instead of introducing a new header version.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub enum HeaderExtension {
+    /// The CRC algorithm used for the payload checksum, if it is not CRC-32/CKSUM
+    PayloadCrcSpec(CrcSpec),
+    /// Name of the file the payload was read from
+    FileName(String),
+    /// Version of this tool which created the image
+    ToolVersion(String),
+    /// The payload is split across several images, this one carries the given chunk
+    Span(SpanInfo),
+    /// Arbitrary key/value pairs, e.g. for provenance. Sorted by key, so the encoding is stable.
+    Metadata(BTreeMap<String, String>),
+    /// A [`Preamble`] summarizing the header is stored ahead of it
+    Preamble,
+    /// Ed25519 signature over the payload, see [`crate::signature`]
+    Signature([u8; 64]),
+    /// The payload bundles several parts instead of being a single message
+    Container(PayloadContainer),
+    /// The payload bits of every channel are stored Gray-coded, see [`crate::gray_code`]
+    GrayCode,
+    /// Identifies the key needed for the payload, e.g. a key ID or a KDF parameter set. Never the key itself.
+    KeyId(String),
+    /// Bytes claimed for later appends, right after the payload at the same bits per pixel.
+    /// Placing other payloads skips them, and `append-record` only grows the payload into them.
+    Reserved(u64),
+    /// The payload is written a second time with this mask, at the same pixels as the data mask.
+    /// Copies which disagree point at the pixels that were altered, see `decode --check-tamper`.
+    TamperCopy(u64),
+}
+
+/// How a payload bundles several parts, see [`HeaderExtension::Container`]
 #[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
-pub(crate) enum VersionedHeader {
+pub enum PayloadContainer {
+    /// Named files packed by `encode --message-file`, unpacked by `decode --extract-all`
+    Archive,
+    /// Length-prefixed records, written by `encode --record` and extended by `append-record`
+    Records {
+        count: u32,
+        length_endian: LengthEndian,
+    },
+}
+
+/// Version of this tool, recorded in every header it writes
+pub const TOOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub enum VersionedHeader {
     V1 {
         stuffing_opts: V1DataStuffingOptions,
         /// A mask defining which bits inside a Pixel are used for data
@@ -30,45 +209,507 @@ pub(crate) enum VersionedHeader {
         /// This is NOT the count of pixels etc. This is the input/output length!
         data_len: u64,
     },
+    V2 {
+        stuffing_opts: V1DataStuffingOptions,
+        /// See [`VersionedHeader::V1`]
+        data_mask: u64,
+        /// See [`VersionedHeader::V1`]
+        data_len: u64,
+        /// Checksum of the payload, so a damaged payload can be told apart from a valid one
+        data_crc: u32,
+    },
+    V3 {
+        stuffing_opts: V1DataStuffingOptions,
+        /// See [`VersionedHeader::V1`]
+        data_mask: u64,
+        /// See [`VersionedHeader::V1`]
+        data_len: u64,
+        /// See [`VersionedHeader::V2`]
+        data_crc: u32,
+        extensions: Vec<HeaderExtension>,
+    },
+}
+
+impl VersionedHeader {
+    pub fn stuffing_opts(&self) -> V1DataStuffingOptions {
+        match self {
+            VersionedHeader::V1 { stuffing_opts, .. }
+            | VersionedHeader::V2 { stuffing_opts, .. }
+            | VersionedHeader::V3 { stuffing_opts, .. } => *stuffing_opts,
+        }
+    }
+
+    pub fn with_stuffing_opts(self, opts: V1DataStuffingOptions) -> VersionedHeader {
+        match self {
+            VersionedHeader::V1 {
+                data_mask,
+                data_len,
+                ..
+            } => VersionedHeader::V1 {
+                stuffing_opts: opts,
+                data_mask,
+                data_len,
+            },
+            VersionedHeader::V2 {
+                data_mask,
+                data_len,
+                data_crc,
+                ..
+            } => VersionedHeader::V2 {
+                stuffing_opts: opts,
+                data_mask,
+                data_len,
+                data_crc,
+            },
+            VersionedHeader::V3 {
+                data_mask,
+                data_len,
+                data_crc,
+                extensions,
+                ..
+            } => VersionedHeader::V3 {
+                stuffing_opts: opts,
+                data_mask,
+                data_len,
+                data_crc,
+                extensions,
+            },
+        }
+    }
+
+    ///
+    /// Pixels the header covers when stored at the start of the image, 1 bit per pixel
+    pub fn pixel_span(&self) -> Result<u64, String> {
+        Ok(self.stored_bytes()?.len() as u64 * 8)
+    }
+
+    ///
+    /// The bytes stored at the start of the image: the preamble, if requested, followed by the raw header
+    pub fn stored_bytes(&self) -> Result<Vec<u8>, String> {
+        let raw_header: HeaderRaw = self.clone().try_into().map_err(|x| format!("{}", x))?;
+        let mut bytes = match self.has_preamble() {
+            true => Preamble::for_header(self).to_bytes().to_vec(),
+            false => Vec::new(),
+        };
+        bytes.extend(raw_header.to_bytes());
+        Ok(bytes)
+    }
+
+    ///
+    /// Pixels the header covers at most, whatever stuffing options, data mask and checksum are picked later.
+    /// Only the version, the payload length and the extensions of this header are taken into account.
+    pub fn max_pixel_span(&self) -> Result<u64, String> {
+        // Every field at its maximum takes the most bytes. The stuffing options are filled in below.
+        let stuffing_opts = V1DataStuffingOptions::None {
+            start_offset: u64::MAX,
+        };
+        let worst_case = match self.clone() {
+            VersionedHeader::V1 { data_len, .. } => VersionedHeader::V1 {
+                stuffing_opts,
+                data_mask: u64::MAX,
+                data_len,
+            },
+            VersionedHeader::V2 { data_len, .. } => VersionedHeader::V2 {
+                stuffing_opts,
+                data_mask: u64::MAX,
+                data_len,
+                data_crc: u32::MAX,
+            },
+            VersionedHeader::V3 {
+                data_len,
+                extensions,
+                ..
+            } => {
+                // Record payloads grow with every record appended, so their header has to fit at any length
+                let grows = matches!(self.container(), Some(PayloadContainer::Records { .. }));
+                VersionedHeader::V3 {
+                    stuffing_opts,
+                    data_mask: u64::MAX,
+                    data_len: if grows { u64::MAX } else { data_len },
+                    data_crc: u32::MAX,
+                    extensions: extensions
+                        .into_iter()
+                        .map(|extension| match extension {
+                            HeaderExtension::Container(PayloadContainer::Records {
+                                length_endian,
+                                ..
+                            }) => HeaderExtension::Container(PayloadContainer::Records {
+                                count: u32::MAX,
+                                length_endian,
+                            }),
+                            extension => extension,
+                        })
+                        .collect(),
+                }
+            }
+        };
+        // The variants differ in size, so the largest one decides
+        let mut span = 0;
+        for stuffing_opts in V1DataStuffingOptions::largest_of_each_variant() {
+            span = span.max(
+                worst_case
+                    .clone()
+                    .with_stuffing_opts(stuffing_opts)
+                    .pixel_span()?,
+            );
+        }
+        Ok(span)
+    }
+
+    ///
+    /// Spreads the payload over `bits_per_pixel` bits of every pixel. The start offset is kept,
+    /// so this may only raise the bits per pixel the header was generated with.
+    pub fn with_bits_per_pixel(self, bits_per_pixel: u8, color_type: ColorType) -> VersionedHeader {
+        self.with_data_mask(calculate_bit_mask(bits_per_pixel, color_type))
+    }
+
+    pub fn with_data_mask(mut self, mask: u64) -> VersionedHeader {
+        match &mut self {
+            VersionedHeader::V1 { data_mask, .. }
+            | VersionedHeader::V2 { data_mask, .. }
+            | VersionedHeader::V3 { data_mask, .. } => *data_mask = mask,
+        }
+        self
+    }
+
+    /// Optional fields. Empty for headers which predate them.
+    pub fn extensions(&self) -> &[HeaderExtension] {
+        match self {
+            VersionedHeader::V1 { .. } | VersionedHeader::V2 { .. } => &[],
+            VersionedHeader::V3 { extensions, .. } => extensions,
+        }
+    }
+
+    /// Adds an optional field. Only V3 headers can carry them, others are rejected.
+    /// The header gets longer, so pass extensions to [`generate_v3_header`] instead when embedding.
+    pub fn with_extension(self, extension: HeaderExtension) -> Result<VersionedHeader, String> {
+        match self {
+            VersionedHeader::V3 {
+                stuffing_opts,
+                data_mask,
+                data_len,
+                data_crc,
+                mut extensions,
+            } => {
+                extensions.push(extension);
+                Ok(VersionedHeader::V3 {
+                    stuffing_opts,
+                    data_mask,
+                    data_len,
+                    data_crc,
+                    extensions,
+                })
+            }
+            VersionedHeader::V1 { .. } | VersionedHeader::V2 { .. } => {
+                Err("Only V3 headers can carry extensions".to_string())
+            }
+        }
+    }
+
+    /// The algorithm the payload checksum was computed with
+    pub fn payload_crc_spec(&self) -> CrcSpec {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::PayloadCrcSpec(spec) => Some(*spec),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Version of the tool which created the image, if it was recorded
+    pub fn tool_version(&self) -> Option<&str> {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::ToolVersion(version) => Some(version.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Name of the file the payload was read from, if it was recorded
+    pub fn file_name(&self) -> Option<&str> {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::FileName(name) => Some(name.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Ed25519 signature over the payload, if it was signed
+    pub fn signature(&self) -> Option<[u8; 64]> {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::Signature(signature) => Some(*signature),
+                _ => None,
+            })
+    }
+
+    /// How the payload bundles several parts, if it does
+    pub fn container(&self) -> Option<PayloadContainer> {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::Container(container) => Some(*container),
+                _ => None,
+            })
+    }
+
+    /// Whether a [`Preamble`] is stored ahead of the header
+    pub fn has_preamble(&self) -> bool {
+        self.extensions()
+            .iter()
+            .any(|extension| matches!(extension, HeaderExtension::Preamble))
+    }
+
+    /// Whether the payload bits are stored Gray-coded
+    pub fn is_gray_coded(&self) -> bool {
+        self.extensions()
+            .iter()
+            .any(|extension| matches!(extension, HeaderExtension::GrayCode))
+    }
+
+    /// Position of the payload chunk, if the payload spans several images
+    pub fn span_info(&self) -> Option<SpanInfo> {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::Span(span) => Some(*span),
+                _ => None,
+            })
+    }
+
+    /// Key/value pairs stored alongside the payload, if any were recorded
+    pub fn metadata(&self) -> Option<&BTreeMap<String, String>> {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::Metadata(metadata) => Some(metadata),
+                _ => None,
+            })
+    }
+
+    /// Identifier of the key needed for the payload, if one was recorded
+    pub fn key_id(&self) -> Option<&str> {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::KeyId(key_id) => Some(key_id.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Bytes reserved behind the payload for later appends, 0 if nothing is reserved
+    pub fn reserved_bytes(&self) -> u64 {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::Reserved(bytes) => Some(*bytes),
+                _ => None,
+            })
+            .unwrap_or(0)
+    }
+
+    /// Mask of the second payload copy, if one was written for tamper evidence
+    pub fn tamper_copy_mask(&self) -> Option<u64> {
+        self.extensions()
+            .iter()
+            .find_map(|extension| match extension {
+                HeaderExtension::TamperCopy(mask) => Some(*mask),
+                _ => None,
+            })
+    }
+
+    pub fn start_offset(&self) -> u64 {
+        match self.stuffing_opts() {
+            V1DataStuffingOptions::None { start_offset }
+            | V1DataStuffingOptions::AvoidMask { start_offset, .. }
+            | V1DataStuffingOptions::ScatteredHeader { start_offset, .. }
+            | V1DataStuffingOptions::Password { start_offset }
+            | V1DataStuffingOptions::Trns { start_offset }
+            | V1DataStuffingOptions::Keyed { start_offset }
+            | V1DataStuffingOptions::ColorKey { start_offset, .. }
+            | V1DataStuffingOptions::Luma { start_offset, .. }
+            | V1DataStuffingOptions::HeaderCopies { start_offset, .. }
+            | V1DataStuffingOptions::Complexity { start_offset, .. }
+            | V1DataStuffingOptions::GifPalette { start_offset } => start_offset,
+        }
+    }
+
+    /// Seed of the scattered header, if the header is scattered
+    pub fn scatter_seed(&self) -> Option<u64> {
+        match self.stuffing_opts() {
+            V1DataStuffingOptions::ScatteredHeader { seed, .. } => Some(seed),
+            _ => None,
+        }
+    }
+
+    /// Checksum of the avoid mask the payload was written with, if any
+    pub fn avoid_mask_checksum(&self) -> Option<u32> {
+        match self.stuffing_opts() {
+            V1DataStuffingOptions::AvoidMask { mask_checksum, .. } => Some(mask_checksum),
+            _ => None,
+        }
+    }
+
+    /// Color key selecting the payload pixels, if any
+    pub fn color_key(&self) -> Option<ColorKey> {
+        match self.stuffing_opts() {
+            V1DataStuffingOptions::ColorKey { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    pub fn data_mask(&self) -> u64 {
+        match self {
+            VersionedHeader::V1 { data_mask, .. }
+            | VersionedHeader::V2 { data_mask, .. }
+            | VersionedHeader::V3 { data_mask, .. } => *data_mask,
+        }
+    }
+
+    pub fn data_len(&self) -> u64 {
+        match self {
+            VersionedHeader::V1 { data_len, .. }
+            | VersionedHeader::V2 { data_len, .. }
+            | VersionedHeader::V3 { data_len, .. } => *data_len,
+        }
+    }
+
+    /// Version number of the header layout, as in the variant name
+    pub fn version(&self) -> u8 {
+        match self {
+            VersionedHeader::V1 { .. } => 1,
+            VersionedHeader::V2 { .. } => 2,
+            VersionedHeader::V3 { .. } => 3,
+        }
+    }
+
+    /// The payload checksum. `None` for headers which predate it.
+    pub fn data_crc(&self) -> Option<u32> {
+        match self {
+            VersionedHeader::V1 { .. } => None,
+            VersionedHeader::V2 { data_crc, .. } | VersionedHeader::V3 { data_crc, .. } => {
+                Some(*data_crc)
+            }
+        }
+    }
 }
 
 #[derive(Encode, Decode, PartialEq, Debug, Clone)]
-pub(crate) struct HeaderRaw {
-    /// Should be 0x42. Here so we can  
-    pub(crate) magic: u8,
+pub struct HeaderRaw {
+    /// [`HEADER_MAGIC`], or [`VARINT_HEADER_MAGIC`] for headers of earlier versions
+    pub magic: u8,
     /// How many bytes (=pixels*8) are used for the data segment
-    pub(crate) header_len: u16,
-    pub(crate) data: Vec<u8>,
+    pub header_len: u16,
+    pub data: Vec<u8>,
     /// Checksum of the header and data
-    pub(crate) crc: u32,
+    pub crc: u32,
 }
 
-impl TryInto<HeaderRaw> for VersionedHeader {
-    type Error = EncodeError;
+impl HeaderRaw {
+    ///
+    /// Parses the byte layout written by [`HeaderRaw::to_bytes`]
+    pub fn from_bytes(data: &[u8]) -> Result<HeaderRaw, String> {
+        if data.len() < 3 + 4 {
+            return Err("Header is too short".to_string());
+        }
+        let header_len = u16::from_be_bytes([data[1], data[2]]);
+        let data_end = 3 + header_len as usize;
+        if data.len() != data_end + 4 {
+            return Err(format!(
+                "Header claims {} bytes of data, but {} bytes are available",
+                header_len,
+                data.len() - 3 - 4
+            ));
+        }
 
-    fn try_into(self) -> Result<HeaderRaw, Self::Error> {
+        Ok(HeaderRaw {
+            magic: data[0],
+            header_len,
+            data: data[3..data_end].to_vec(),
+            crc: u32::from_be_bytes([
+                data[data_end],
+                data[data_end + 1],
+                data[data_end + 2],
+                data[data_end + 3],
+            ]),
+        })
+    }
+
+    ///
+    /// Whether the checksum matches the data
+    pub fn has_valid_crc(&self) -> bool {
+        Crc::<u32>::new(&CRC_32_CKSUM).checksum(self.data.as_bytes()) == self.crc
+    }
+
+    ///
+    /// Serializes the header into the byte layout which is written into the image:
+    /// Magic (1B), Header Len (2B, BE), Data, CRC (4B, BE)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut as_binary_data = Vec::with_capacity(3 + self.data.len() + 4);
+        as_binary_data.push(self.magic);
+        as_binary_data.extend_from_slice(&self.header_len.to_be_bytes());
+        as_binary_data.extend_from_slice(&self.data);
+        as_binary_data.extend_from_slice(&self.crc.to_be_bytes());
+        as_binary_data
+    }
+}
+
+impl VersionedHeader {
+    ///
+    /// Serializes the header with variable-length integers. Shorter, but the length depends on the field values.
+    /// Only meant for places with very little room, which settle the header length themselves.
+    pub fn to_varint_raw(&self) -> Result<HeaderRaw, EncodeError> {
         let data = bincode::encode_to_vec(self, config::standard())?;
-        let crc = Crc::<u32>::new(&CRC_32_CKSUM).checksum(&data.as_bytes());
+        HeaderRaw::from_body(VARINT_HEADER_MAGIC, data)
+    }
+}
+
+impl HeaderRaw {
+    fn from_body(magic: u8, data: Vec<u8>) -> Result<HeaderRaw, EncodeError> {
+        let crc = Crc::<u32>::new(&CRC_32_CKSUM).checksum(data.as_bytes());
+        let header_len = u16::try_from(data.len()).map_err(|_| {
+            EncodeError::OtherString(format!(
+                "Header is {} bytes long, but at most {} bytes can be stored",
+                data.len(),
+                u16::MAX
+            ))
+        })?;
 
         Ok(HeaderRaw {
-            magic: 0x42,
-            header_len: data.len() as u16,
+            magic,
+            header_len,
             data,
             crc,
         })
     }
 }
 
+impl TryInto<HeaderRaw> for VersionedHeader {
+    type Error = EncodeError;
+
+    fn try_into(self) -> Result<HeaderRaw, Self::Error> {
+        let data = bincode::encode_to_vec(self, config::standard().with_fixed_int_encoding())?;
+        HeaderRaw::from_body(HEADER_MAGIC, data)
+    }
+}
+
 impl TryFrom<HeaderRaw> for VersionedHeader {
     type Error = String;
 
     fn try_from(value: HeaderRaw) -> Result<Self, Self::Error> {
-        if value.magic != 0x42 {
-            return Err("Not a valid header: Magic Number is not 0x42".to_string());
+        if value.magic != HEADER_MAGIC && value.magic != VARINT_HEADER_MAGIC {
+            return Err(format!(
+                "Not a valid header: Magic Number is not {:#04x}",
+                HEADER_MAGIC
+            ));
         }
 
         // Check the checksum
-        let crc = Crc::<u32>::new(&CRC_32_CKSUM).checksum(&value.data.as_bytes());
+        let crc = Crc::<u32>::new(&CRC_32_CKSUM).checksum(value.data.as_bytes());
         if crc != value.crc {
             return Err(format!(
                 "Checksum Mismatch. Expected {:#01x}, but found {:#01x}",
@@ -77,45 +718,102 @@ impl TryFrom<HeaderRaw> for VersionedHeader {
         }
 
         // Try to parse Header from binary data
-        let (payload, _): (VersionedHeader, _) =
-            bincode::decode_from_slice(&value.data.as_slice(), config::standard())
-                .map_err(|x| format!("Failed to decode header payload: {}", x))?;
+        let encoding = match value.magic {
+            HEADER_MAGIC => HeaderEncoding::FixedInt,
+            _ => HeaderEncoding::Varint,
+        };
+        encoding.decode(&value.data)
+    }
+}
 
-        Ok(payload)
+/// How the integers of a header body are serialized, told apart by the magic
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderEncoding {
+    /// Fixed-width integers, marked by [`HEADER_MAGIC`]
+    FixedInt,
+    /// Variable-length integers, marked by [`VARINT_HEADER_MAGIC`]
+    Varint,
+}
+
+impl HeaderEncoding {
+    pub fn decode(self, data: &[u8]) -> Result<VersionedHeader, String> {
+        let decoded = match self {
+            HeaderEncoding::FixedInt => {
+                bincode::decode_from_slice(data, config::standard().with_fixed_int_encoding())
+            }
+            HeaderEncoding::Varint => bincode::decode_from_slice(data, config::standard()),
+        };
+        let (header, _): (VersionedHeader, _) =
+            decoded.map_err(|x| format!("Failed to decode header payload: {}", x))?;
+
+        Ok(header)
+    }
+
+    pub fn encode(self, header: &VersionedHeader) -> Result<Vec<u8>, EncodeError> {
+        match self {
+            HeaderEncoding::FixedInt => {
+                bincode::encode_to_vec(header, config::standard().with_fixed_int_encoding())
+            }
+            HeaderEncoding::Varint => bincode::encode_to_vec(header, config::standard()),
+        }
     }
 }
 
 ///
 /// This function basically determines the u64 which acts as a data mask
 ///
-fn calculate_bit_mask(bits_needed_per_pixel: u8, color_type: ColorType) -> u64 {
+pub fn calculate_bit_mask(bits_needed_per_pixel: u8, color_type: ColorType) -> u64 {
     let bit_count_on_all_channels = bits_needed_per_pixel / color_type.channel_count();
     let mut data_bits_per_channel: Vec<usize> =
         vec![bit_count_on_all_channels as usize; color_type.channel_count() as usize];
 
     let remainder = (bits_needed_per_pixel % color_type.channel_count()) as usize;
-    for i in 0..remainder {
-        data_bits_per_channel[i] += 1;
+    for bits in data_bits_per_channel.iter_mut().take(remainder) {
+        *bits += 1;
     }
 
     while data_bits_per_channel.len() < color_type.channel_count() as usize {
         data_bits_per_channel.insert(0, 0) // Right-Pad with empty data
     }
 
-    // Finally, build the u64
+    bit_mask_for_channels(&data_bits_per_channel, color_type)
+}
+
+///
+/// The bits right above the data bits of every channel, as many as the data mask uses there.
+/// A copy of the payload written with it shares the pixels, but neither a bit nor a bit plane with the original.
+pub fn tamper_copy_mask(data_mask: u64, color_type: ColorType) -> u64 {
+    let pixel_bits = (color_type.bits_per_pixel() as usize).min(u64::BITS as usize);
+    let channel_bits = color_type.bits_per_pixel() as usize / color_type.channel_count() as usize;
+    let offsets = create_offset_map(data_mask, pixel_bits);
+    let doubled_bits: Vec<usize> = (0..color_type.channel_count() as usize)
+        .map(|channel| {
+            let bits = offsets
+                .iter()
+                .filter(|offset| *offset / channel_bits == channel)
+                .count();
+            (2 * bits).min(channel_bits)
+        })
+        .collect();
+    bit_mask_for_channels(&doubled_bits, color_type) & !data_mask
+}
+
+///
+/// Builds the data mask using the given number of least significant bits of each channel, in channel order
+pub fn bit_mask_for_channels(data_bits_per_channel: &[usize], color_type: ColorType) -> u64 {
     let bits_per_channel =
         (color_type.bits_per_pixel() / color_type.channel_count() as u16) as usize;
     let bytes_per_channel = (color_type.bytes_per_pixel() / color_type.channel_count()) as usize;
 
     let mut return_vec: Vec<u8> = Vec::new();
 
-    for bits_for_current_channel in data_bits_per_channel {
-        let mut vec_for_channel = vec![0u8; bytes_per_channel as usize];
+    for &bits_for_current_channel in data_bits_per_channel {
+        let mut vec_for_channel = vec![0u8; bytes_per_channel];
 
-        let clear_bits_count = (bits_per_channel - bits_for_current_channel) as usize;
+        let clear_bits_count = bits_per_channel - bits_for_current_channel;
 
         for i in clear_bits_count..bits_per_channel {
-            vec_for_channel[i / 8] |= 0b1u8 << 7 >> i % 8;
+            vec_for_channel[i / 8] |= 0b1u8 << 7 >> (i % 8);
         }
 
         return_vec.append(&mut vec_for_channel);
@@ -123,85 +821,423 @@ fn calculate_bit_mask(bits_needed_per_pixel: u8, color_type: ColorType) -> u64 {
 
     let mut return_data: u64 = 0;
 
-    for i in 0..return_vec.len() {
-        return_data |= (return_vec[i] as u64) << 64 - 8 >> i * 8;
+    for (i, byte) in return_vec.iter().enumerate() {
+        return_data |= (*byte as u64) << (64 - 8) >> (i * 8);
     }
 
     return_data
 }
 
-pub(crate) fn generate_v1_header(
+/// Masks may only use this many of the least significant bits of each channel, unless high bits are allowed
+pub const DEFAULT_MAX_BITS_PER_CHANNEL: u8 = 2;
+
+/// Limit of `--allow-high-bits`: only the clamp to half the bit depth of a channel applies
+pub const HIGH_BITS_PER_CHANNEL: u8 = u8::MAX;
+
+///
+/// Rejects masks which touch bits above the `max_bits_per_channel` least significant bits of any channel.
+/// Changing those bits visibly alters the image. Any limit is clamped to half the bit depth of a channel,
+/// beyond that the image is destroyed rather than altered. `None` allows all bits.
+pub fn check_low_bits_only(
+    data_mask: u64,
+    color_type: ColorType,
+    max_bits_per_channel: Option<u8>,
+) -> Result<(), String> {
+    let Some(max_bits_per_channel) = max_bits_per_channel else {
+        return Ok(());
+    };
+    let bits_per_channel = color_type.bits_per_pixel() / color_type.channel_count() as u16;
+    let destructive_bits = bits_per_channel / 2;
+    let allowed_bits = (max_bits_per_channel as u16).min(destructive_bits) as u8;
+    let allowed_mask = calculate_bit_mask(allowed_bits * color_type.channel_count(), color_type);
+
+    if data_mask & !allowed_mask == 0 {
+        return Ok(());
+    }
+    let destructive_mask = calculate_bit_mask(
+        destructive_bits as u8 * color_type.channel_count(),
+        color_type,
+    );
+    match data_mask & !destructive_mask != 0 {
+        true => Err(format!(
+            "The data mask {:#018x} uses more than half of the {} bits of a channel, which destroys the image. \
+            Use a larger image, or pass --allow-destructive to embed anyway.",
+            data_mask, bits_per_channel
+        )),
+        false => Err(format!(
+            "The data mask {:#018x} uses more than the {} least significant bits of a channel, which visibly alters the image. \
+            Use a larger image, or pass --allow-high-bits to embed anyway.",
+            data_mask, allowed_bits
+        )),
+    }
+}
+
+/// Bits per pixel assumed when suggesting a cover image for a payload that does not fit
+const SUGGESTED_BITS_PER_PIXEL: u64 = 2;
+
+///
+/// Returns the minimum pixel count of a cover image which fits `data_len_bytes`
+/// at [`SUGGESTED_BITS_PER_PIXEL`], next to a header covering `header_pixels`.
+fn suggested_pixel_count(header_pixels: u64, data_len_bytes: u64) -> u64 {
+    header_pixels.saturating_add(
+        data_len_bytes
+            .saturating_mul(8)
+            .div_ceil(SUGGESTED_BITS_PER_PIXEL),
+    )
+}
+
+///
+/// How the random start offset of a payload is drawn among the offsets it fits at.
+/// Only the drawn offset is stored in the header, so decoding does not depend on it.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum OffsetBias {
+    /// Every offset is equally likely
+    #[default]
+    Uniform,
+    /// Triangular distribution peaking in the middle of the image
+    Center,
+    /// Inverted triangular distribution, most likely at the start or the end of the image
+    Edges,
+}
+
+impl OffsetBias {
+    /// Draws an index in `0..count`, `count` must not be 0
+    fn draw(&self, rng: &mut impl Rng, count: u64) -> u64 {
+        if *self == OffsetBias::Uniform {
+            return rng.gen_range(0..count);
+        }
+        let triangular = (rng.gen::<f64>() + rng.gen::<f64>()) / 2.0;
+        let position = match (self, triangular) {
+            // Mirrors both halves of the triangle, moving its peak to 0 and 1
+            (OffsetBias::Edges, t) if t < 0.5 => 0.5 - t,
+            (OffsetBias::Edges, t) => 1.5 - t,
+            (_, t) => t,
+        };
+        ((position * count as f64) as u64).min(count - 1)
+    }
+}
+
+impl FromStr for OffsetBias {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(OffsetBias::Uniform),
+            "center" => Ok(OffsetBias::Center),
+            "edges" => Ok(OffsetBias::Edges),
+            _ => Err(format!(
+                "Unknown offset bias {}, expected uniform, center or edges",
+                s
+            )),
+        }
+    }
+}
+
+///
+/// Picks the data mask and a random start offset for the payload.
+/// New images are written with [`generate_v3_header`], V1 headers are only generated to test reading them.
+pub fn generate_v1_header(
     pixel_count: u64,
     data_len_bytes: u64,
     color_type: ColorType,
 ) -> Result<VersionedHeader, String> {
-    // start_offset + data_len + worst case data_mask (4B) + CRC32
-    // Header is only using 1 bit per pixel.
-    let v1_header_len = (size_of::<u64>() * 2 + 4 + size_of::<u32>()) as u64;
-    let available_pixels = pixel_count - v1_header_len;
+    let header = VersionedHeader::V1 {
+        stuffing_opts: V1DataStuffingOptions::None { start_offset: 0 },
+        data_mask: 0,
+        data_len: data_len_bytes,
+    };
+    let (start_offset, data_mask) = place_payload(
+        pixel_count,
+        header.max_pixel_span()?,
+        data_len_bytes,
+        color_type,
+        Some(OffsetBias::Uniform),
+    )?;
+
+    Ok(header
+        .with_stuffing_opts(V1DataStuffingOptions::None { start_offset })
+        .with_data_mask(data_mask))
+}
+
+///
+/// Picks the start offset and the data mask of the payload.
+/// The first `header_pixels` pixels are left to the header, so the payload never overwrites it.
+/// Without `offset_bias`, the payload starts at the first pixel it fits at and no randomness is drawn.
+fn place_payload(
+    pixel_count: u64,
+    header_pixels: u64,
+    data_len_bytes: u64,
+    color_type: ColorType,
+    offset_bias: Option<OffsetBias>,
+) -> Result<(u64, u64), String> {
+    if pixel_count <= header_pixels {
+        return Err(format!(
+            "Cannot encode data. The image has {} pixels, but the header alone needs {}.",
+            pixel_count, header_pixels
+        ));
+    }
+    let data_len_bits = data_len_bytes.checked_mul(8).ok_or_else(|| {
+        format!(
+            "Cannot encode data. A payload of {} bytes is too large.",
+            data_len_bytes
+        )
+    })?;
+
+    let available_pixels = pixel_count - header_pixels;
 
     // How many bits would we need to be able to encode the entire payload
-    let bits_needed_per_pixel = (1 + (data_len_bytes * 8 / available_pixels)) as u8;
+    let bits_needed_per_pixel = data_len_bits.div_ceil(available_pixels).max(1);
     let available_space_bytes = color_type.bytes_per_pixel() as u64 * available_pixels;
 
-    if bits_needed_per_pixel as u16 > color_type.bits_per_pixel() {
-        return Err(format!("Cannot encode data. Would need {}bytes, but can only encode {}bytes in the given picture. (delta: {})", data_len_bytes, available_space_bytes, data_len_bytes-available_space_bytes));
+    // Checked before narrowing, as a huge payload on a tiny image could wrap around to a small value
+    let bits_needed_per_pixel = match u8::try_from(bits_needed_per_pixel) {
+        Ok(bits) if bits as u16 <= color_type.bits_per_pixel() => bits,
+        _ => {
+            let suggested_pixels = suggested_pixel_count(header_pixels, data_len_bytes);
+            return Err(format!("Cannot encode data. Would need {}bytes, but can only encode {}bytes in the given picture. (delta: {}). Try an image of at least {} pixels (~{:.2} megapixels).", data_len_bytes, available_space_bytes, data_len_bytes.saturating_sub(available_space_bytes), suggested_pixels, suggested_pixels as f64 / 1_000_000.0));
+        }
+    };
+
+    let pixels_needed_to_store_message = data_len_bits.div_ceil(bits_needed_per_pixel as u64);
+
+    // Offsets are only drawn among the ones keeping the payload inside the image,
+    // so unlike rejection sampling, no attempt can fail.
+    let offset = header_pixels
+        + match offset_bias {
+            Some(bias) => bias.draw(
+                &mut tool_rng(),
+                available_pixels - pixels_needed_to_store_message + 1,
+            ),
+            None => 0,
+        };
+
+    Ok((
+        offset,
+        calculate_bit_mask(bits_needed_per_pixel, color_type),
+    ))
+}
+
+///
+/// Generates a header for the given payload, including a checksum of the payload.
+/// The checksum is computed with `crc_spec`, which is recorded in the header if it is not the default.
+/// `extensions` are added to the header. They have to be known up front, as they make the header longer,
+/// and the payload has to start after it. A [`HeaderExtension::Reserved`] among them keeps room behind the payload.
+/// A [`HeaderExtension::TamperCopy`] among them gets the mask of the copy filled in, its value is ignored.
+/// Without `randomize_offset`, the payload starts right after the header.
+pub fn generate_v3_header(
+    pixel_count: u64,
+    payload: &[u8],
+    color_type: ColorType,
+    crc_spec: CrcSpec,
+    extensions: Vec<HeaderExtension>,
+    randomize_offset: bool,
+) -> Result<VersionedHeader, String> {
+    generate_v3_header_with_bias(
+        pixel_count,
+        payload,
+        color_type,
+        crc_spec,
+        extensions,
+        randomize_offset.then_some(OffsetBias::Uniform),
+    )
+}
+
+///
+/// Like [`generate_v3_header`], but draws the start offset following `offset_bias`.
+/// Without one, the payload starts right after the header.
+pub fn generate_v3_header_with_bias(
+    pixel_count: u64,
+    payload: &[u8],
+    color_type: ColorType,
+    crc_spec: CrcSpec,
+    extensions: Vec<HeaderExtension>,
+    offset_bias: Option<OffsetBias>,
+) -> Result<VersionedHeader, String> {
+    let mut all_extensions = vec![HeaderExtension::ToolVersion(TOOL_VERSION.to_string())];
+    if crc_spec != CrcSpec::default() {
+        all_extensions.push(HeaderExtension::PayloadCrcSpec(crc_spec));
     }
+    let tamper_copy = extensions
+        .iter()
+        .any(|extension| matches!(extension, HeaderExtension::TamperCopy(_)));
+    // The widest mask stands in for the copy mask until it is known, so the header does not grow later
+    all_extensions.extend(extensions.into_iter().map(|extension| match extension {
+        HeaderExtension::TamperCopy(_) => HeaderExtension::TamperCopy(u64::MAX),
+        extension => extension,
+    }));
 
-    let pixels_needed_to_store_message = (data_len_bytes * 8) / bits_needed_per_pixel as u64 + 1;
+    let mut header = VersionedHeader::V3 {
+        stuffing_opts: V1DataStuffingOptions::None { start_offset: 0 },
+        data_mask: 0,
+        data_len: payload.len() as u64,
+        data_crc: crc_spec.checksum(payload),
+        extensions: all_extensions,
+    };
+    // Reserved bytes are placed like more payload, so they are always left behind it
+    let claimed_bytes = (payload.len() as u64)
+        .checked_add(header.reserved_bytes())
+        .ok_or_else(|| "Cannot encode data. The reservation is too large.".to_string())?;
+    // Both copies share the pixels, so they are placed like a payload of twice the size
+    let claimed_bytes = match tamper_copy {
+        true => claimed_bytes
+            .checked_mul(2)
+            .ok_or_else(|| "Cannot encode data. The payload is too large.".to_string())?,
+        false => claimed_bytes,
+    };
+    let (start_offset, data_mask) = place_payload(
+        pixel_count,
+        header.max_pixel_span()?,
+        claimed_bytes,
+        color_type,
+        offset_bias,
+    )?;
+    let data_mask = match tamper_copy {
+        true => {
+            let data_mask =
+                calculate_bit_mask(data_mask.count_ones().div_ceil(2) as u8, color_type);
+            if let VersionedHeader::V3 { extensions, .. } = &mut header {
+                for extension in extensions.iter_mut() {
+                    if let HeaderExtension::TamperCopy(mask) = extension {
+                        *mask = tamper_copy_mask(data_mask, color_type);
+                    }
+                }
+            }
+            data_mask
+        }
+        false => data_mask,
+    };
 
-    let offset = v1_header_len
-        + thread_rng().gen_range(0..(available_pixels - pixels_needed_to_store_message));
+    Ok(header
+        .with_stuffing_opts(V1DataStuffingOptions::None { start_offset })
+        .with_data_mask(data_mask))
+}
 
-    let header = VersionedHeader::V1 {
-        stuffing_opts: V1DataStuffingOptions::None {
-            start_offset: offset,
-        },
-        data_mask: calculate_bit_mask(bits_needed_per_pixel, color_type),
-        data_len: data_len_bytes,
+///
+/// The least significant bit of the alpha channel, for images which have one
+pub fn alpha_header_mask(color_type: ColorType) -> Option<u64> {
+    color_type
+        .has_alpha()
+        .then(|| 1u64 << 63 >> (color_type.bits_per_pixel().min(u64::BITS as u16) - 1))
+}
+
+///
+/// The bit the header is stored in. Payloads confined to the alpha channel keep their header there too,
+/// so the color channels stay untouched. All other headers use [`HEADER_MASK`].
+pub fn header_mask_for(data_mask: u64, color_type: ColorType) -> u64 {
+    let Some(alpha_lsb) = alpha_header_mask(color_type) else {
+        return HEADER_MASK;
     };
+    let mut alpha_bits = vec![0; color_type.channel_count() as usize];
+    if let Some(bits) = alpha_bits.last_mut() {
+        *bits = (color_type.bits_per_pixel() / color_type.channel_count() as u16) as usize;
+    }
+    match data_mask & !bit_mask_for_channels(&alpha_bits, color_type) {
+        0 if data_mask != 0 => alpha_lsb,
+        _ => HEADER_MASK,
+    }
+}
 
-    Ok(header)
+///
+/// Finds the bit the header of the image is stored in, see [`header_mask_for`].
+/// Falls back to [`HEADER_MASK`] if no header parses in the alpha channel either.
+pub fn locate_header_mask(image: &dyn PngImage) -> u64 {
+    match alpha_header_mask(image.color_type()) {
+        Some(mask)
+            if read_header_at(image, HEADER_MASK).is_err()
+                && read_header_at(image, mask).is_ok() =>
+        {
+            mask
+        }
+        _ => HEADER_MASK,
+    }
 }
 
-pub(crate) fn try_get_header(image: &mut dyn PngImage) -> Result<VersionedHeader, String> {
+pub fn try_get_header(image: &dyn PngImage) -> Result<VersionedHeader, String> {
+    // Magic (1B), Header Len (2B), CRC (4B), each bit in its own pixel
+    if image.pixel_count() < (3 + 4) * 8 {
+        return Err("The image is too small to contain a header".to_string());
+    }
+    read_header_at(image, HEADER_MASK).or_else(|err| {
+        // Random alpha bits may well start with a magic, so only a header which parses there counts
+        alpha_header_mask(image.color_type())
+            .and_then(|mask| read_header_at(image, mask).ok())
+            .or_else(|| find_header_copy(image))
+            .ok_or(err)
+    })
+}
+
+fn read_header_at(image: &dyn PngImage, header_mask: u64) -> Result<VersionedHeader, String> {
+    read_header_from(image, header_mask, 0)
+}
+
+///
+/// Reads a header starting at the given pixel, e.g. one of the copies from [`crate::header_copies`]
+pub fn read_header_from(
+    image: &dyn PngImage,
+    header_mask: u64,
+    first_pixel: usize,
+) -> Result<VersionedHeader, String> {
+    if (first_pixel + 3 * 8) as u64 > image.pixel_count() {
+        return Err("The image ends before the header".to_string());
+    }
+    // A preamble only summarizes the header, which follows right behind it
+    let has_preamble = (first_pixel + PREAMBLE_BYTES * 8) as u64 <= image.pixel_count()
+        && Preamble::from_bytes(&image.read_data_with_mask(
+            header_mask,
+            first_pixel,
+            PREAMBLE_BYTES,
+        ))
+        .is_some();
+    let pixel_offset = match has_preamble {
+        true => first_pixel + PREAMBLE_BYTES * 8,
+        false => first_pixel,
+    };
     // Try get the header
     // First read the first 3 bytes. They contain the magic and length
-    let partial_header = image.read_data_with_mask(0b1u64 << 63 >> 7, 0, 3);
-    if partial_header[0] != 0x42 {
+    let partial_header = image.read_data_with_mask(header_mask, pixel_offset, 3);
+    if partial_header[0] == SCATTERED_MAGIC && header_mask == HEADER_MASK && first_pixel == 0 {
+        return read_scattered_header(image)?.try_into();
+    }
+    if partial_header[0] != HEADER_MAGIC && partial_header[0] != VARINT_HEADER_MAGIC {
         let error = format!(
-            "Tried to find a header in file. Magic was {:#01x}, not 0x42",
-            partial_header[0]
+            "Tried to find a header in file. Magic was {:#01x}, not {:#04x}",
+            partial_header[0], HEADER_MAGIC
         );
         return Err(error);
     }
 
     let data_length = (((partial_header[1] as u16) << 8) | (partial_header[2] as u16)) as usize;
+    // A random image may start with the magic by chance, followed by a length it cannot hold
+    if (pixel_offset + (3 + data_length + 4) * 8) as u64 > image.pixel_count() {
+        return Err(format!(
+            "Header claims {} bytes, more than the image can hold",
+            3 + data_length + 4
+        ));
+    }
 
-    let full_header = image.read_data_with_mask(0b1u64 << 63 >> 7, 0, 3 + data_length + 4);
-    let raw_payload: &[u8] = &full_header[3..3 + data_length];
-    let raw_crc: &[u8] = &full_header[data_length + 3..data_length + 3 + 4];
-
-    let crc = (raw_crc[0] as u32) << 24
-        | (raw_crc[1] as u32) << 16
-        | (raw_crc[2] as u32) << 8
-        | (raw_crc[3] as u32);
-
-    let raw_header = HeaderRaw {
-        magic: 0x42,
-        header_len: data_length as u16,
-        data: Vec::from(raw_payload),
-        crc,
-    };
+    let full_header = image.read_data_with_mask(header_mask, pixel_offset, 3 + data_length + 4);
 
-    raw_header.try_into()
+    HeaderRaw::from_bytes(&full_header)?.try_into()
 }
 
 #[cfg(test)]
 mod tests {
+    use image::{ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
 
     use super::*;
-    use pretty_assertions::assert_eq;
+    use crate::buffer_modify::WriteImageBinary;
+
+    /// Pixels reserved for a V1 header describing a payload of `data_len` bytes
+    fn v1_header_pixels(data_len: u64) -> u64 {
+        VersionedHeader::V1 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 0 },
+            data_mask: 0,
+            data_len,
+        }
+        .max_pixel_span()
+        .unwrap()
+    }
 
     #[test]
     fn calculate_bit_mask_rgb8() {
@@ -209,7 +1245,7 @@ mod tests {
         let response = calculate_bit_mask(12, ColorType::Rgb8);
         assert_eq!(
             format!("{:#01x}", response),
-            format!("{:#01x}", 0x0F_0F_0F_00__00_00_00_00u64)
+            format!("{:#01x}", 0x0F_0F_0F_00_00_00_00_00u64)
         )
     }
 
@@ -219,8 +1255,49 @@ mod tests {
         let response = calculate_bit_mask(16, ColorType::Rgba8);
         assert_eq!(
             format!("{:#01x}", response),
-            format!("{:#01x}", 0x0F_0F_0F_0F__00_00_00_00u64)
+            format!("{:#01x}", 0x0F_0F_0F_0F_00_00_00_00u64)
+        )
+    }
+
+    #[test]
+    fn high_bits_are_rejected_unless_allowed() {
+        let low_bits = calculate_bit_mask(6, ColorType::Rgb8);
+        let high_bits = calculate_bit_mask(12, ColorType::Rgb8);
+        let limit = Some(DEFAULT_MAX_BITS_PER_CHANNEL);
+
+        assert!(check_low_bits_only(low_bits, ColorType::Rgb8, limit).is_ok());
+        assert!(check_low_bits_only(high_bits, ColorType::Rgb8, limit).is_err());
+        assert!(check_low_bits_only(high_bits, ColorType::Rgb8, None).is_ok());
+        // 16-bit channels have their low bits in the second byte
+        let low_bits = calculate_bit_mask(8, ColorType::Rgba16);
+        assert!(check_low_bits_only(low_bits, ColorType::Rgba16, limit).is_ok());
+    }
+
+    #[test]
+    fn destructive_payloads_are_rejected_unless_allowed() {
+        // 3000 bytes in 2000 pixels need more than half of the 24 bits of an RGB8 pixel
+        let header = generate_v3_header(
+            2000,
+            &[0xAB; 3000],
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            false,
         )
+        .unwrap();
+        let data_mask = header.data_mask();
+        assert!(data_mask.count_ones() > 12);
+
+        let high_bits =
+            check_low_bits_only(data_mask, ColorType::Rgb8, Some(HIGH_BITS_PER_CHANNEL));
+        assert!(high_bits.unwrap_err().contains("--allow-destructive"));
+        assert!(check_low_bits_only(data_mask, ColorType::Rgb8, None).is_ok());
+        // Half the bit depth is fine with --allow-high-bits
+        let half = calculate_bit_mask(12, ColorType::Rgb8);
+        assert!(check_low_bits_only(half, ColorType::Rgb8, Some(HIGH_BITS_PER_CHANNEL)).is_ok());
+        let default =
+            check_low_bits_only(half, ColorType::Rgb8, Some(DEFAULT_MAX_BITS_PER_CHANNEL));
+        assert!(default.unwrap_err().contains("--allow-high-bits"));
     }
 
     #[test]
@@ -228,12 +1305,12 @@ mod tests {
         let response = calculate_bit_mask(5, ColorType::Rgba8);
         assert_eq!(
             format!("{:#01x}", response),
-            format!("{:#01x}", 0x03_01_01_01__00_00_00_00u64)
+            format!("{:#01x}", 0x03_01_01_01_00_00_00_00u64)
         )
     }
 
     fn util_count_bits(input: u64) -> usize {
-        let mut counter = 0 as usize;
+        let mut counter = 0usize;
 
         for i in 0..64 {
             if input & 1u64 << i != 0 {
@@ -245,7 +1322,7 @@ mod tests {
 
     #[test]
     fn generate_v1_header_test() {
-        let result = generate_v1_header(600, 100, ColorType::Rgb8).unwrap();
+        let result = generate_v1_header(1000, 100, ColorType::Rgb8).unwrap();
 
         match result {
             VersionedHeader::V1 {
@@ -262,13 +1339,343 @@ mod tests {
                         let used_pixels_data = (data_len * 8) / bits_per_pixel as u64;
 
                         assert_eq!(used_pixels_data, 400);
-                        assert!(start_offset + used_pixels_data < 600);
+                        assert!(start_offset >= v1_header_pixels(data_len));
+                        assert!(start_offset + used_pixels_data <= 1000);
+                    }
+                    V1DataStuffingOptions::AvoidMask { .. }
+                    | V1DataStuffingOptions::ScatteredHeader { .. }
+                    | V1DataStuffingOptions::Password { .. }
+                    | V1DataStuffingOptions::Trns { .. }
+                    | V1DataStuffingOptions::Keyed { .. }
+                    | V1DataStuffingOptions::ColorKey { .. }
+                    | V1DataStuffingOptions::Luma { .. }
+                    | V1DataStuffingOptions::HeaderCopies { .. }
+                    | V1DataStuffingOptions::Complexity { .. }
+                    | V1DataStuffingOptions::GifPalette { .. } => {
+                        panic!("Expected plain stuffing options")
                     }
                 }
             }
+            VersionedHeader::V2 { .. } | VersionedHeader::V3 { .. } => {
+                panic!("Expected a V1 header")
+            }
         }
     }
 
+    #[test]
+    fn tiny_payloads_fit_behind_the_header() {
+        for data_len in [0, 1] {
+            let header = generate_v1_header(1000, data_len, ColorType::Rgb8).unwrap();
+
+            assert_eq!(header.data_len(), data_len);
+            assert_eq!(header.data_mask().count_ones(), 1);
+            assert!(header.start_offset() >= v1_header_pixels(data_len));
+            assert!(header.start_offset() + data_len * 8 <= 1000);
+        }
+    }
+
+    #[test]
+    fn suggested_pixel_count_fits_payload() {
+        let data_len = 1_000_000;
+        let err = generate_v1_header(10_000, data_len, ColorType::Rgb8).unwrap_err();
+        let suggested = suggested_pixel_count(v1_header_pixels(data_len), data_len);
+        assert!(err.contains(&format!("at least {} pixels", suggested)));
+
+        let header = generate_v1_header(suggested, data_len, ColorType::Rgb8).unwrap();
+        match header {
+            VersionedHeader::V1 { data_mask, .. } => {
+                assert_eq!(
+                    util_count_bits(data_mask),
+                    SUGGESTED_BITS_PER_PIXEL as usize
+                );
+            }
+            VersionedHeader::V2 { .. } | VersionedHeader::V3 { .. } => {
+                panic!("Expected a V1 header")
+            }
+        }
+    }
+
+    #[test]
+    fn generate_v1_header_rejects_wrapping_bits_per_pixel() {
+        // 257 bits per pixel would be needed, which wraps around to 1 as a u8
+        let available_pixels = 972u64;
+        let data_len = (available_pixels * 257).div_ceil(8);
+
+        let result = generate_v1_header(
+            available_pixels + v1_header_pixels(data_len),
+            data_len,
+            ColorType::Rgb8,
+        );
+        assert!(result.unwrap_err().starts_with("Cannot encode data"));
+    }
+
+    #[test]
+    fn center_bias_clusters_offsets_around_the_middle() {
+        // The header takes the first pixels, the payload of 600 pixels at 1 bit each fits at 10_000 offsets after it
+        let header_pixels = 500;
+        let starts = 10_000;
+        let share_in_middle_half = |bias: OffsetBias| {
+            let draws = 4000;
+            let in_middle = (0..draws)
+                .map(|_| {
+                    let (offset, _) = place_payload(
+                        header_pixels + starts + 599,
+                        header_pixels,
+                        75,
+                        ColorType::Rgb8,
+                        Some(bias),
+                    )
+                    .unwrap();
+                    assert!((header_pixels..header_pixels + starts).contains(&offset));
+                    offset - header_pixels
+                })
+                .filter(|offset| (starts / 4..starts * 3 / 4).contains(offset))
+                .count();
+            in_middle as f64 / draws as f64
+        };
+
+        // A triangular distribution puts 75% of the draws into the middle half, a uniform one 50%
+        // and the inverted triangle 25%
+        let center = share_in_middle_half(OffsetBias::Center);
+        let uniform = share_in_middle_half(OffsetBias::Uniform);
+        let edges = share_in_middle_half(OffsetBias::Edges);
+        assert!((0.7..0.8).contains(&center), "center: {}", center);
+        assert!((0.45..0.55).contains(&uniform), "uniform: {}", uniform);
+        assert!((0.2..0.3).contains(&edges), "edges: {}", edges);
+
+        assert_eq!("center".parse(), Ok(OffsetBias::Center));
+        assert!("middle".parse::<OffsetBias>().is_err());
+    }
+
+    #[test]
+    fn generate_v1_header_rejects_degenerate_inputs() {
+        assert!(generate_v1_header(v1_header_pixels(1), 1, ColorType::Rgb8).is_err());
+        assert!(generate_v1_header(10, 1, ColorType::Rgb8).is_err());
+        assert!(generate_v1_header(1_000_000, u64::MAX / 4, ColorType::Rgb8).is_err());
+    }
+
+    #[test]
+    fn oversized_header_is_rejected() {
+        let header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 0 },
+            data_mask: 0,
+            data_len: 0,
+            data_crc: 0,
+            extensions: vec![HeaderExtension::FileName("x".repeat(70_000))],
+        };
+
+        let result: Result<HeaderRaw, _> = header.try_into();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn generate_v3_header_contains_payload_checksum() {
+        let payload = vec![0xAB; 100];
+        let result = generate_v3_header(
+            2000,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+
+        assert_eq!(result.data_len(), 100);
+        assert_eq!(
+            result.data_crc(),
+            Some(CrcSpec::default().checksum(&payload))
+        );
+        assert_eq!(result.payload_crc_spec(), CrcSpec::default());
+    }
+
+    #[test]
+    fn tool_version_survives_round_trip() {
+        let header = generate_v3_header(
+            2000,
+            &[1, 2, 3],
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+
+        let as_raw_header: HeaderRaw = header.try_into().unwrap();
+        let header_from_raw = VersionedHeader::try_from(as_raw_header).unwrap();
+
+        assert_eq!(
+            header_from_raw.tool_version(),
+            Some(env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn extensions_need_a_v3_header() {
+        let header = VersionedHeader::V2 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 100 },
+            data_mask: 0x01_00_00_00_00_00_00_00,
+            data_len: 3,
+            data_crc: 0,
+        };
+
+        assert!(header.with_extension(HeaderExtension::GrayCode).is_err());
+    }
+
+    #[test]
+    fn metadata_survives_round_trip_in_key_order() {
+        let header = generate_v3_header(
+            2000,
+            &[1, 2, 3],
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        let entries = [("source", "camera 2"), ("author", "jane"), ("id", "")];
+        let metadata = |entries: &[(&str, &str)]| {
+            HeaderExtension::Metadata(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect(),
+            )
+        };
+        let mut reversed = entries;
+        reversed.reverse();
+        let header_a = header.clone().with_extension(metadata(&entries)).unwrap();
+        let header_b = header.with_extension(metadata(&reversed)).unwrap();
+
+        let raw_a: HeaderRaw = header_a.clone().try_into().unwrap();
+        let raw_b: HeaderRaw = header_b.try_into().unwrap();
+        // Insertion order does not change the stored bytes
+        assert_eq!(raw_a.to_bytes(), raw_b.to_bytes());
+
+        let header_from_raw = VersionedHeader::try_from(raw_a).unwrap();
+        assert_eq!(header_from_raw, header_a);
+        let keys: Vec<&str> = header_from_raw
+            .metadata()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(keys, ["author", "id", "source"]);
+        assert_eq!(header_from_raw.metadata().unwrap()["source"], "camera 2");
+    }
+
+    #[test]
+    fn key_id_survives_round_trip() {
+        let header = generate_v3_header(
+            2000,
+            &[1, 2, 3],
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            vec![HeaderExtension::KeyId("team-a/2024-03".to_string())],
+            true,
+        )
+        .unwrap();
+
+        let as_raw_header: HeaderRaw = header.clone().try_into().unwrap();
+        let header_from_raw = VersionedHeader::try_from(as_raw_header).unwrap();
+
+        assert_eq!(header_from_raw, header);
+        assert_eq!(header_from_raw.key_id(), Some("team-a/2024-03"));
+        assert_eq!(header_from_raw.metadata(), None);
+    }
+
+    #[test]
+    fn generate_v3_header_with_custom_crc_spec() {
+        let payload = vec![0xAB; 100];
+        let crc_spec: CrcSpec = "init=0xdeadbeef,refin=true".parse().unwrap();
+        let header =
+            generate_v3_header(2000, &payload, ColorType::Rgb8, crc_spec, Vec::new(), true)
+                .unwrap();
+
+        assert_eq!(header.payload_crc_spec(), crc_spec);
+        assert_eq!(header.data_crc(), Some(crc_spec.checksum(&payload)));
+        assert_ne!(
+            header.data_crc(),
+            Some(CrcSpec::default().checksum(&payload))
+        );
+
+        // Round-trip through the raw header, the spec has to survive
+        let as_raw_header: HeaderRaw = header.clone().try_into().unwrap();
+        let header_from_raw = VersionedHeader::try_from(as_raw_header).unwrap();
+        assert_eq!(header_from_raw, header);
+        assert_eq!(header_from_raw.payload_crc_spec(), crc_spec);
+    }
+
+    #[test]
+    fn header_raw_from_bytes_round_trip() {
+        let raw = HeaderRaw {
+            magic: 0x42,
+            header_len: 2,
+            data: vec![0xAA, 0xBB],
+            crc: 0x11223344,
+        };
+
+        assert_eq!(HeaderRaw::from_bytes(&raw.to_bytes()).unwrap(), raw);
+        assert!(HeaderRaw::from_bytes(&raw.to_bytes()[..8]).is_err());
+    }
+
+    #[test]
+    fn header_raw_to_bytes_layout() {
+        let raw = HeaderRaw {
+            magic: 0x42,
+            header_len: 0x0102,
+            data: vec![0xAA, 0xBB],
+            crc: 0x11223344,
+        };
+
+        assert_eq!(
+            raw.to_bytes(),
+            vec![0x42, 0x01, 0x02, 0xAA, 0xBB, 0x11, 0x22, 0x33, 0x44]
+        );
+    }
+
+    #[test]
+    fn header_length_does_not_depend_on_field_values() {
+        let header = |value: u64| VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None {
+                start_offset: value,
+            },
+            data_mask: value,
+            data_len: value,
+            data_crc: value as u32,
+            extensions: vec![HeaderExtension::ToolVersion(TOOL_VERSION.to_string())],
+        };
+
+        let lengths: Vec<u64> = [0, 1, 300, 70_000, u32::MAX as u64, u64::MAX]
+            .into_iter()
+            .map(|value| header(value).pixel_span().unwrap())
+            .collect();
+        assert!(
+            lengths.iter().all(|length| *length == lengths[0]),
+            "{:?}",
+            lengths
+        );
+    }
+
+    #[test]
+    fn varint_headers_of_earlier_versions_are_read() {
+        let header = VersionedHeader::V2 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 500 },
+            data_mask: 0x0101_0100_0000_0000,
+            data_len: 1234,
+            data_crc: 0xDEAD_BEEF,
+        };
+        let data = bincode::encode_to_vec(&header, config::standard()).unwrap();
+        let raw = HeaderRaw {
+            magic: VARINT_HEADER_MAGIC,
+            header_len: data.len() as u16,
+            crc: Crc::<u32>::new(&CRC_32_CKSUM).checksum(&data),
+            data,
+        };
+
+        assert_eq!(VersionedHeader::try_from(raw).unwrap(), header);
+    }
+
     #[test]
     fn encode_and_decode_v1_header() {
         let header = VersionedHeader::V1 {
@@ -279,7 +1686,7 @@ mod tests {
             data_len: 0x98761234,
         };
 
-        let as_raw_header: HeaderRaw = header.try_into().unwrap();
+        let as_raw_header: HeaderRaw = header.clone().try_into().unwrap();
 
         let as_binary_data = bincode::encode_to_vec(as_raw_header, config::standard()).unwrap();
 
@@ -290,4 +1697,24 @@ mod tests {
 
         assert_eq!(header, header_from_raw);
     }
+
+    #[test]
+    fn tiny_images_are_reported_as_too_small() {
+        for side in 1..=7 {
+            let image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(side, side);
+            assert_eq!(
+                try_get_header(&image).unwrap_err(),
+                "The image is too small to contain a header"
+            );
+        }
+        // Large enough for the magic, which announces a scattered header, but not for its bootstrap
+        for magic in [HEADER_MAGIC, SCATTERED_MAGIC] {
+            for side in 8..13 {
+                let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> =
+                    ImageBuffer::from_pixel(side, side, Rgb([255, 255, 255]));
+                image.write_data_with_mask(&[magic], HEADER_MASK, 0);
+                assert!(try_get_header(&image).is_err());
+            }
+        }
+    }
 }
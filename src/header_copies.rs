@@ -0,0 +1,141 @@
+//! Copies of the header spread across the image, so damage to its first pixels does not lose the payload.
+//!
+//! The header records how many copies there are. Copy `k` of `n` starts at pixel `pixel_count * k / n`,
+//! so a decoder which cannot read the first copy tries every count until one of the copies parses.
+
+use crate::{
+    buffer_modify::{checked_pixel_index, PngImage},
+    header::{read_header_from, V1DataStuffingOptions, VersionedHeader, HEADER_MASK},
+};
+
+/// Most copies of the header, including the one at the start of the image
+pub const MAX_HEADER_COPIES: u8 = 8;
+
+///
+/// First pixel of every copy but the one at the start of the image
+pub fn copy_offsets(pixel_count: u64, copies: u8) -> Vec<u64> {
+    (1..copies as u64)
+        .map(|copy| pixel_count * copy / copies as u64)
+        .collect()
+}
+
+///
+/// All pixels which are not covered by one of the additional copies, in ascending order.
+/// Every copy gets as many pixels as the header can take at most.
+pub fn pixels_between_copies(
+    header: &VersionedHeader,
+    pixel_count: u64,
+    copies: u8,
+) -> Result<Vec<usize>, String> {
+    if !(1..=MAX_HEADER_COPIES).contains(&copies) {
+        return Err(format!(
+            "Between 1 and {} copies of the header can be stored, but {} were requested",
+            MAX_HEADER_COPIES, copies
+        ));
+    }
+    let copy_pixels = header.max_pixel_span()?;
+    if copy_pixels * copies as u64 > pixel_count {
+        return Err(format!(
+            "The image is too small for {} copies of the header, {} pixels each",
+            copies, copy_pixels
+        ));
+    }
+
+    let copy_offsets = copy_offsets(pixel_count, copies);
+    Ok((0..checked_pixel_index(pixel_count)?)
+        .filter(|pixel| {
+            let pixel = *pixel as u64;
+            !copy_offsets
+                .iter()
+                .any(|offset| (*offset..offset + copy_pixels).contains(&pixel))
+        })
+        .collect())
+}
+
+///
+/// Looks for one of the additional copies of the header.
+/// Only a copy recording the count whose offsets it was found at counts.
+pub fn find_header_copy(image: &dyn PngImage) -> Option<VersionedHeader> {
+    (2..=MAX_HEADER_COPIES).find_map(|copies| {
+        copy_offsets(image.pixel_count(), copies)
+            .into_iter()
+            .filter_map(|offset| checked_pixel_index(offset).ok())
+            .find_map(|offset| {
+                read_header_from(image, HEADER_MASK, offset)
+                    .ok()
+                    .filter(|header| {
+                        matches!(header.stuffing_opts(),
+                            V1DataStuffingOptions::HeaderCopies { copies: stored, .. } if stored == copies)
+                    })
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        buffer_modify::WriteImageBinary,
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header},
+        payload::{read_payload, write_payload},
+    };
+
+    fn header_with_copies(payload: &[u8], copies: u8) -> VersionedHeader {
+        let reserved = generate_v3_header(
+            64 * 64,
+            payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+        let free_pixels = pixels_between_copies(&reserved, 64 * 64, copies).unwrap();
+        let header = generate_v3_header(
+            free_pixels.len() as u64,
+            payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        let start_offset = header.start_offset();
+        header.with_stuffing_opts(V1DataStuffingOptions::HeaderCopies {
+            start_offset,
+            copies,
+        })
+    }
+
+    #[test]
+    fn payload_decodes_via_another_copy_when_one_is_damaged() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        let payload = b"the header is stored three times".repeat(8);
+        let header = header_with_copies(&payload, 3);
+        write_payload(&mut image, &header, &payload, None).unwrap();
+        let span = header.pixel_span().unwrap() as usize;
+
+        // Wipe the first copy, then the second one as well
+        for damaged_copy in [0, copy_offsets(64 * 64, 3)[0]] {
+            image.write_data_with_mask(&vec![0u8; span / 8], HEADER_MASK, damaged_copy as usize);
+
+            let found = try_get_header(&image).unwrap();
+            assert_eq!(found, header);
+            assert_eq!(read_payload(&image, &found, None).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn copies_have_to_fit_into_the_image() {
+        let header = header_with_copies(b"short", 2);
+
+        assert!(pixels_between_copies(&header, 64 * 64, MAX_HEADER_COPIES + 1).is_err());
+        assert!(pixels_between_copies(&header, 64, 8).is_err());
+    }
+}
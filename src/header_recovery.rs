@@ -0,0 +1,246 @@
+use std::fmt;
+
+use crate::{
+    buffer_modify::PngImage,
+    header::{
+        HeaderEncoding, HeaderRaw, VersionedHeader, HEADER_MAGIC, HEADER_MASK, VARINT_HEADER_MAGIC,
+    },
+    scatter::{read_scattered_header, SCATTERED_MAGIC},
+};
+
+/// A header found by [`try_all_headers`], together with the convention it was written under
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct HeaderMatch {
+    pub(crate) header: VersionedHeader,
+    /// The pixel bit the header, or the bootstrap of a scattered header, was read from
+    pub(crate) header_mask: u64,
+    pub(crate) magic: u8,
+    pub(crate) encoding: HeaderEncoding,
+    pub(crate) scattered: bool,
+}
+
+impl fmt::Display for HeaderMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let encoding = match self.encoding {
+            HeaderEncoding::FixedInt => "fixed-width",
+            HeaderEncoding::Varint => "variable-length",
+        };
+        write!(
+            f,
+            "V{} header with {} integers, magic {:#04x}, header bit {:#018x}",
+            self.header.version(),
+            encoding,
+            self.magic,
+            self.header_mask
+        )?;
+        if self.scattered {
+            write!(f, ", scattered")?;
+        }
+        if Some(self.encoding) != encoding_of_magic(self.magic) {
+            write!(f, " (the magic claims the other integer encoding)")?;
+        }
+        Ok(())
+    }
+}
+
+fn encoding_of_magic(magic: u8) -> Option<HeaderEncoding> {
+    match magic {
+        HEADER_MAGIC => Some(HeaderEncoding::FixedInt),
+        VARINT_HEADER_MAGIC => Some(HeaderEncoding::Varint),
+        _ => None,
+    }
+}
+
+///
+/// Looks for a header under every convention this tool has written headers with,
+/// and returns the first one which parses with a valid checksum.
+/// Besides the default, the header may sit in another bit of the pixel, or its magic may claim
+/// the wrong integer encoding for its body. The default convention is tried first.
+pub(crate) fn try_all_headers(image: &dyn PngImage) -> Result<HeaderMatch, String> {
+    all_headers(image).next().ok_or_else(|| {
+        format!(
+            "No header found in any of the {} pixel bits, with either integer encoding",
+            pixel_bits(image)
+        )
+    })
+}
+
+///
+/// Every header which parses with a valid checksum, under the conventions of [`try_all_headers`] and in
+/// the order they are tried. At most one is found per pixel bit.
+pub(crate) fn all_headers(image: &dyn PngImage) -> impl Iterator<Item = HeaderMatch> + '_ {
+    let header_masks = std::iter::once(HEADER_MASK).chain(
+        (0..pixel_bits(image))
+            .map(|bit| 1u64 << 63 >> bit)
+            .filter(|mask| *mask != HEADER_MASK),
+    );
+
+    header_masks.filter_map(|header_mask| {
+        let (raw, scattered) = read_raw_header(image, header_mask)?;
+        if !raw.has_valid_crc() {
+            return None;
+        }
+
+        // The encoding the magic claims goes first
+        let claimed = encoding_of_magic(raw.magic).unwrap_or(HeaderEncoding::FixedInt);
+        let other = match claimed {
+            HeaderEncoding::FixedInt => HeaderEncoding::Varint,
+            HeaderEncoding::Varint => HeaderEncoding::FixedInt,
+        };
+        [claimed, other].into_iter().find_map(|encoding| {
+            let header = encoding.decode(&raw.data).ok()?;
+            // A body may decode under the wrong encoding by chance, but then it does not re-encode to itself
+            if encoding.encode(&header).ok().as_ref() != Some(&raw.data) {
+                return None;
+            }
+            Some(HeaderMatch {
+                header,
+                header_mask,
+                magic: raw.magic,
+                encoding,
+                scattered,
+            })
+        })
+    })
+}
+
+fn pixel_bits(image: &dyn PngImage) -> u32 {
+    image.color_type().bits_per_pixel().min(u64::BITS as u16) as u32
+}
+
+///
+/// Reads the raw header stored in the given pixel bit, if it starts with a known magic.
+/// Also tells whether it was collected via a scattered header bootstrap.
+fn read_raw_header(image: &dyn PngImage, header_mask: u64) -> Option<(HeaderRaw, bool)> {
+    let partial_header = image.read_data_with_mask(header_mask, 0, 3);
+    if partial_header[0] == SCATTERED_MAGIC && header_mask == HEADER_MASK {
+        return read_scattered_header(image).ok().map(|raw| (raw, true));
+    }
+    encoding_of_magic(partial_header[0])?;
+
+    let data_length = u16::from_be_bytes([partial_header[1], partial_header[2]]) as usize;
+    if (3 + data_length + 4) as u64 * 8 > image.pixel_count() {
+        return None;
+    }
+    let full_header = image.read_data_with_mask(header_mask, 0, 3 + data_length + 4);
+
+    HeaderRaw::from_bytes(&full_header)
+        .ok()
+        .map(|raw| (raw, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb, Rgba};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        buffer_modify::WriteImageBinary,
+        crc_spec::CrcSpec,
+        header::{
+            alpha_header_mask, generate_v1_header, try_get_header, V1DataStuffingOptions,
+            DEFAULT_MAX_BITS_PER_CHANNEL,
+        },
+        payload::{read_payload, scrub_stale_payload, write_payload},
+    };
+
+    fn noisy_cover() -> ImageBuffer<Rgb<u8>, Vec<u8>> {
+        let mut cover = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut cover);
+        cover
+    }
+
+    #[test]
+    fn v1_header_behind_the_wrong_magic_is_identified() {
+        let mut image = noisy_cover();
+        let payload = b"written by an early version".to_vec();
+        let header =
+            generate_v1_header(64 * 64, payload.len() as u64, image::ColorType::Rgb8).unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        // Fixed-width body, but tagged as variable-length. The default path trusts the magic.
+        let mut raw: HeaderRaw = header.clone().try_into().unwrap();
+        raw.magic = VARINT_HEADER_MAGIC;
+        image.write_data_with_mask(&raw.to_bytes(), HEADER_MASK, 0);
+        assert!(try_get_header(&image) != Ok(header.clone()));
+
+        let found = try_all_headers(&image).unwrap();
+        assert_eq!(found.header, header);
+        assert_eq!(found.header.version(), 1);
+        assert_eq!(found.encoding, HeaderEncoding::FixedInt);
+        assert_eq!(found.magic, VARINT_HEADER_MAGIC);
+        assert_eq!(found.header_mask, HEADER_MASK);
+        assert_eq!(read_payload(&image, &found.header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn header_in_another_bit_is_found() {
+        let mut image = noisy_cover();
+        let header = generate_v1_header(64 * 64, 10, image::ColorType::Rgb8).unwrap();
+        let raw: HeaderRaw = header.clone().try_into().unwrap();
+        let green_lsb = 1u64 << 63 >> 15;
+        image.write_data_with_mask(&raw.to_bytes(), green_lsb, 0);
+
+        let found = try_all_headers(&image).unwrap();
+        assert_eq!(found.header, header);
+        assert_eq!(found.header_mask, green_lsb);
+        assert_eq!(found.encoding, HeaderEncoding::FixedInt);
+    }
+
+    #[test]
+    fn clean_slate_leaves_only_the_new_header() {
+        let header = |stuffing_opts, data_mask, payload: &[u8]| VersionedHeader::V2 {
+            stuffing_opts,
+            data_mask,
+            data_len: payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(payload),
+        };
+        // The old payload is confined to alpha, which keeps its header in the alpha channel as well
+        let old_payload = vec![0x3C; 300];
+        let old_header = header(
+            V1DataStuffingOptions::None { start_offset: 1024 },
+            0x00_00_00_03_00_00_00_00,
+            &old_payload,
+        );
+        let new_payload = b"the new payload".to_vec();
+        let new_header = header(
+            V1DataStuffingOptions::None { start_offset: 3000 },
+            0x01_01_01_00_00_00_00_00,
+            &new_payload,
+        );
+        let re_encode = |clean_slate: bool| {
+            let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+            rand::thread_rng().fill_bytes(&mut image);
+            write_payload(&mut image, &old_header, &old_payload, None).unwrap();
+            if clean_slate {
+                scrub_stale_payload(&mut image, DEFAULT_MAX_BITS_PER_CHANNEL);
+            }
+            write_payload(&mut image, &new_header, &new_payload, None).unwrap();
+            image
+        };
+        let found = |image: &ImageBuffer<Rgba<u8>, Vec<u8>>| -> Vec<(VersionedHeader, u64)> {
+            all_headers(image)
+                .map(|found| (found.header, found.header_mask))
+                .collect()
+        };
+        let alpha_lsb = alpha_header_mask(image::ColorType::Rgba8).unwrap();
+
+        // A plain re-encode leaves the old header for a scan to find
+        assert_eq!(
+            found(&re_encode(false)),
+            [
+                (new_header.clone(), HEADER_MASK),
+                (old_header.clone(), alpha_lsb)
+            ]
+        );
+
+        let image = re_encode(true);
+        assert_eq!(found(&image), [(new_header.clone(), HEADER_MASK)]);
+        assert_eq!(
+            read_payload(&image, &new_header, None).unwrap(),
+            new_payload
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Images inside zip and tar archives, see `scan-dir`, `decode --entry` and `stat --entry`.
+//!
+//! Entries are read into memory and loaded from there, nothing is extracted to disk.
+//! Reading needs a build with the `zip` or `tar` feature, archives are recognized without them.
+
+#[cfg(any(feature = "zip", feature = "tar"))]
+use std::io::{Cursor, Read};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ArchiveKind {
+    Zip,
+    Tar,
+}
+
+impl ArchiveKind {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            ArchiveKind::Zip => "zip",
+            ArchiveKind::Tar => "tar",
+        }
+    }
+}
+
+/// Recognizes an archive by its signature: a local file header for zip, the ustar magic for tar
+pub(crate) fn archive_kind(data: &[u8]) -> Option<ArchiveKind> {
+    if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+        return Some(ArchiveKind::Zip);
+    }
+    match data.get(257..262) {
+        Some(b"ustar") => Some(ArchiveKind::Tar),
+        _ => None,
+    }
+}
+
+///
+/// Names and contents of the files in the archive, in the order they are stored.
+/// Directories and links are left out.
+#[cfg_attr(not(all(feature = "zip", feature = "tar")), allow(unused_variables))]
+pub(crate) fn read_entries(
+    data: &[u8],
+    kind: ArchiveKind,
+) -> Result<Vec<(String, Vec<u8>)>, String> {
+    match kind {
+        #[cfg(feature = "zip")]
+        ArchiveKind::Zip => read_zip_entries(data),
+        #[cfg(feature = "tar")]
+        ArchiveKind::Tar => read_tar_entries(data),
+        #[allow(unreachable_patterns)]
+        kind => Err(format!(
+            "Reading {} archives needs a build with the {} feature",
+            kind.name(),
+            kind.name()
+        )),
+    }
+}
+
+/// The contents of the file called `name` in the archive
+pub(crate) fn read_entry(data: &[u8], kind: ArchiveKind, name: &str) -> Result<Vec<u8>, String> {
+    let entries = read_entries(data, kind)?;
+    let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+    let missing = format!(
+        "The archive has no file {}, it contains: {}",
+        name,
+        names.join(", ")
+    );
+    entries
+        .into_iter()
+        .find(|(entry_name, _)| entry_name == name)
+        .map(|(_, data)| data)
+        .ok_or(missing)
+}
+
+#[cfg(feature = "zip")]
+fn read_zip_entries(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).map_err(|x| x.to_string())?;
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(|x| x.to_string())?;
+        if !file.is_file() {
+            continue;
+        }
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .map_err(|x| format!("Failed to read {}: {}", file.name(), x))?;
+        entries.push((file.name().to_string(), contents));
+    }
+    Ok(entries)
+}
+
+#[cfg(feature = "tar")]
+fn read_tar_entries(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut archive = tar::Archive::new(Cursor::new(data));
+    let mut entries = Vec::new();
+    for entry in archive.entries().map_err(|x| x.to_string())? {
+        let mut entry = entry.map_err(|x| x.to_string())?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry
+            .path()
+            .map_err(|x| x.to_string())?
+            .to_string_lossy()
+            .into_owned();
+        let mut contents = Vec::new();
+        entry
+            .read_to_end(&mut contents)
+            .map_err(|x| format!("Failed to read {}: {}", name, x))?;
+        entries.push((name, contents));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn archives_are_recognized_by_their_signature() {
+        assert_eq!(
+            archive_kind(b"PK\x03\x04rest of a zip"),
+            Some(ArchiveKind::Zip)
+        );
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(archive_kind(&tar), Some(ArchiveKind::Tar));
+        assert_eq!(archive_kind(b"\x89PNG\r\n\x1a\n"), None);
+    }
+
+    #[cfg(feature = "tar")]
+    #[test]
+    fn tar_entries_are_read_in_order() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, contents) in [("b.png", &b"second"[..]), ("a/c.png", &b"third"[..])] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, name, contents).unwrap();
+        }
+        let data = builder.into_inner().unwrap();
+
+        assert_eq!(archive_kind(&data), Some(ArchiveKind::Tar));
+        assert_eq!(
+            read_entries(&data, ArchiveKind::Tar).unwrap(),
+            [
+                ("b.png".to_string(), b"second".to_vec()),
+                ("a/c.png".to_string(), b"third".to_vec())
+            ]
+        );
+        assert_eq!(
+            read_entry(&data, ArchiveKind::Tar, "a/c.png").unwrap(),
+            b"third"
+        );
+        assert!(read_entry(&data, ArchiveKind::Tar, "missing.png").is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use image::{ImageFormat, ImageOutputFormat};
+
+use crate::{
+    budget::PixelBudget,
+    buffer_modify::convert_dynamic_image_to_png_image,
+    crc_spec::CrcSpec,
+    header::{
+        check_low_bits_only, generate_v3_header, try_get_header, DEFAULT_MAX_BITS_PER_CHANNEL,
+    },
+    payload::{read_payload, write_payload},
+};
+
+///
+/// Hides the payload in a PNG cover and returns the modified PNG.
+/// Without `randomize_offset`, the payload starts right after the header and no randomness is drawn.
+pub fn encode_to_vec(
+    cover_png: &[u8],
+    payload: &[u8],
+    randomize_offset: bool,
+) -> Result<Vec<u8>, String> {
+    encode_to_vec_with_budget(cover_png, payload, randomize_offset).map(|(data, _)| data)
+}
+
+///
+/// Like [`encode_to_vec`], but also returns how the pixels of the cover were used
+pub fn encode_to_vec_with_budget(
+    cover_png: &[u8],
+    payload: &[u8],
+    randomize_offset: bool,
+) -> Result<(Vec<u8>, PixelBudget), String> {
+    let mut cover = image::load_from_memory_with_format(cover_png, ImageFormat::Png)
+        .map_err(|x| x.to_string())?;
+    let image = convert_dynamic_image_to_png_image(&mut cover)?;
+
+    let header = generate_v3_header(
+        image.pixel_count(),
+        payload,
+        image.color_type(),
+        CrcSpec::default(),
+        Vec::new(),
+        randomize_offset,
+    )?;
+    check_low_bits_only(
+        header.data_mask(),
+        image.color_type(),
+        Some(DEFAULT_MAX_BITS_PER_CHANNEL),
+    )?;
+    write_payload(image, &header, payload, None)?;
+    let budget = PixelBudget::new(&header, image.pixel_count())?;
+
+    Ok((image.save_to_buffer(ImageOutputFormat::Png)?, budget))
+}
+
+///
+/// Reads the payload hidden in a PNG by [`encode_to_vec`]
+pub fn decode_from_slice(image_png: &[u8]) -> Result<Vec<u8>, String> {
+    let mut image = image::load_from_memory_with_format(image_png, ImageFormat::Png)
+        .map_err(|x| x.to_string())?;
+    let image = convert_dynamic_image_to_png_image(&mut image)?;
+    let header = try_get_header(image)?;
+
+    read_payload(image, &header, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn round_trip_in_memory() {
+        let cover = ImageBuffer::from_fn(64, 64, |x, y| Rgb([x as u8, y as u8, (x ^ y) as u8]));
+        let mut cover_png = Vec::new();
+        cover
+            .write_to(
+                &mut std::io::Cursor::new(&mut cover_png),
+                ImageOutputFormat::Png,
+            )
+            .unwrap();
+        let payload = b"kept in memory".repeat(20);
+
+        let encoded = encode_to_vec(&cover_png, &payload, false).unwrap();
+
+        assert_eq!(decode_from_slice(&encoded).unwrap(), payload);
+    }
+
+    #[cfg(feature = "strict-no-panic")]
+    #[test]
+    fn oversized_length_is_an_error() {
+        use crate::{
+            buffer_modify::WriteImageBinary,
+            header::{HeaderRaw, VersionedHeader, HEADER_MASK},
+        };
+
+        let cover = ImageBuffer::from_fn(64, 64, |x, y| Rgb([x as u8, y as u8, (x ^ y) as u8]));
+        let mut cover_png = Vec::new();
+        cover
+            .write_to(
+                &mut std::io::Cursor::new(&mut cover_png),
+                ImageOutputFormat::Png,
+            )
+            .unwrap();
+        let encoded = encode_to_vec(&cover_png, b"short", false).unwrap();
+        let mut image = image::load_from_memory(&encoded).unwrap().into_rgb8();
+        let header = try_get_header(&image).unwrap();
+        let VersionedHeader::V3 {
+            stuffing_opts,
+            data_mask,
+            data_crc,
+            extensions,
+            ..
+        } = header
+        else {
+            panic!("Expected a V3 header")
+        };
+        // 64x64 pixels hold far less than that in 2 bits each
+        let header = VersionedHeader::V3 {
+            stuffing_opts,
+            data_mask,
+            data_len: 10_000,
+            data_crc,
+            extensions,
+        };
+        let raw: HeaderRaw = header.try_into().unwrap();
+        image.write_data_with_mask(&raw.to_bytes(), HEADER_MASK, 0);
+        let mut crafted = Vec::new();
+        image
+            .write_to(
+                &mut std::io::Cursor::new(&mut crafted),
+                ImageOutputFormat::Png,
+            )
+            .unwrap();
+
+        let err = decode_from_slice(&crafted).unwrap_err();
+        assert!(err.contains("needs"), "{}", err);
+    }
+
+    #[test]
+    fn pixel_budget_adds_up() {
+        let cover = ImageBuffer::from_fn(64, 64, |x, y| Rgb([x as u8, y as u8, (x ^ y) as u8]));
+        let mut cover_png = Vec::new();
+        cover
+            .write_to(
+                &mut std::io::Cursor::new(&mut cover_png),
+                ImageOutputFormat::Png,
+            )
+            .unwrap();
+
+        for (len, randomize_offset) in [(0, false), (1, true), (500, false), (2500, true)] {
+            let payload = vec![0x5A; len];
+            let (encoded, budget) =
+                encode_to_vec_with_budget(&cover_png, &payload, randomize_offset).unwrap();
+            assert_eq!(decode_from_slice(&encoded).unwrap(), payload);
+
+            assert_eq!(budget.total_pixels, 64 * 64);
+            assert_eq!(
+                budget.header_pixels + budget.payload_pixels + budget.free_pixels,
+                budget.total_pixels
+            );
+            // The payload fills all but the last of its pixels
+            let payload_bits = len as u64 * 8;
+            let bits_per_pixel = budget.bits_per_pixel as u64;
+            assert!(budget.payload_pixels * bits_per_pixel >= payload_bits);
+            assert!(budget.payload_pixels.saturating_sub(1) * bits_per_pixel < payload_bits.max(1));
+            assert!(budget.start_offset >= budget.header_pixels);
+            assert!(budget.start_offset + budget.payload_pixels <= budget.total_pixels);
+        }
+    }
+}
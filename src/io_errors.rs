@@ -0,0 +1,53 @@
+use std::{
+    fmt::Display,
+    io::{self, ErrorKind},
+};
+
+/// The reason of a failed file operation, without the OS error code
+pub(crate) fn describe_io_error(err: &io::Error) -> String {
+    match err.kind() {
+        ErrorKind::PermissionDenied => "permission denied".to_string(),
+        ErrorKind::NotFound => "no such file or directory".to_string(),
+        ErrorKind::AlreadyExists => "the file already exists".to_string(),
+        ErrorKind::IsADirectory => "it is a directory".to_string(),
+        ErrorKind::NotADirectory => "a parent of it is not a directory".to_string(),
+        ErrorKind::ReadOnlyFilesystem => "the file system is read-only".to_string(),
+        ErrorKind::StorageFull => "no space left on the device".to_string(),
+        _ => err.to_string(),
+    }
+}
+
+/// E.g. "Cannot read cover.png: permission denied"
+pub(crate) fn cannot_read(path: impl Display, err: &io::Error) -> String {
+    format!("Cannot read {}: {}", path, describe_io_error(err))
+}
+
+/// E.g. "Cannot write to out.png: permission denied"
+pub(crate) fn cannot_write(path: impl Display, err: &io::Error) -> String {
+    format!("Cannot write to {}: {}", path, describe_io_error(err))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn common_kinds_are_described_without_os_codes() {
+        let denied = io::Error::from_raw_os_error(13);
+        assert_eq!(denied.kind(), ErrorKind::PermissionDenied);
+        assert_eq!(
+            cannot_write("out.png", &denied),
+            "Cannot write to out.png: permission denied"
+        );
+        assert_eq!(
+            cannot_read("cover.png", &io::Error::from(ErrorKind::NotFound)),
+            "Cannot read cover.png: no such file or directory"
+        );
+        assert_eq!(
+            describe_io_error(&io::Error::other("disk on fire")),
+            "disk on fire"
+        );
+    }
+}
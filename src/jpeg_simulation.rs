@@ -0,0 +1,124 @@
+use std::io::Cursor;
+
+use image::{codecs::jpeg::JpegEncoder, ColorType, DynamicImage, ImageFormat, RgbImage};
+use rand::RngCore;
+
+use crate::{
+    buffer_modify::{checked_pixel_index, ReadImageBinary},
+    crc_spec::CrcSpec,
+    header::{generate_v3_header, try_get_header},
+    payload::write_payload,
+    prng::tool_rng,
+};
+
+/// How much of a test payload is left after a JPEG round trip, see [`simulate_jpeg`]
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct JpegSurvival {
+    pub(crate) quality: u8,
+    pub(crate) payload_bits: u64,
+    /// Payload bits read back unchanged from where they were embedded
+    pub(crate) matching_bits: u64,
+    pub(crate) header_intact: bool,
+    pub(crate) payload_intact: bool,
+}
+
+impl JpegSurvival {
+    /// Fraction of the payload bits read back unchanged. Guessing already matches half of them.
+    pub(crate) fn match_rate(&self) -> f64 {
+        match self.payload_bits {
+            0 => 0.0,
+            bits => self.matching_bits as f64 / bits as f64,
+        }
+    }
+
+    /// Fraction of the payload bits which still carry information, corrected for the half matching by chance.
+    /// 0 when the bits read back are no better than guessing.
+    pub(crate) fn survival_rate(&self) -> f64 {
+        (2.0 * self.match_rate() - 1.0).max(0.0)
+    }
+}
+
+///
+/// Embeds a random test payload into the cover, saves it as JPEG at the given quality, loads it again
+/// and compares the payload bits with the ones embedded.
+/// The payload takes half of what the cover holds at 1 bit per channel.
+pub(crate) fn simulate_jpeg(cover: &DynamicImage, quality: u8) -> Result<JpegSurvival, String> {
+    if !(1..=100).contains(&quality) {
+        return Err(format!(
+            "JPEG quality ranges from 1 to 100, but {} was given",
+            quality
+        ));
+    }
+    // JPEG has no alpha channel and 8 bits per channel
+    let mut image = cover.to_rgb8();
+    let pixel_count = image.width() as u64 * image.height() as u64;
+
+    let mut payload = vec![0u8; checked_pixel_index(pixel_count * 3 / 16)?.max(1)];
+    tool_rng().fill_bytes(&mut payload);
+    let header = generate_v3_header(
+        pixel_count,
+        &payload,
+        ColorType::Rgb8,
+        CrcSpec::default(),
+        Vec::new(),
+        true,
+    )?;
+    write_payload(&mut image, &header, &payload, None)?;
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality)
+        .encode_image(&image)
+        .map_err(|x| x.to_string())?;
+    let decoded: RgbImage = image::load(Cursor::new(&jpeg), ImageFormat::Jpeg)
+        .map_err(|x| x.to_string())?
+        .into_rgb8();
+
+    // The header is read from the original image, as it rarely survives itself
+    let read_back = decoded.read_data_with_mask(
+        header.data_mask(),
+        checked_pixel_index(header.start_offset())?,
+        payload.len(),
+    );
+    let matching_bits = payload
+        .iter()
+        .zip(&read_back)
+        .map(|(embedded, read)| (!(embedded ^ read)).count_ones() as u64)
+        .sum();
+
+    Ok(JpegSurvival {
+        quality,
+        payload_bits: payload.len() as u64 * 8,
+        matching_bits,
+        header_intact: try_get_header(&decoded).as_ref() == Ok(&header),
+        payload_intact: read_back == payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+
+    use super::*;
+
+    #[test]
+    fn next_to_nothing_survives_quality_80() {
+        let cover = ImageBuffer::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        });
+
+        let survival = simulate_jpeg(&DynamicImage::ImageRgb8(cover), 80).unwrap();
+        assert_eq!(survival.quality, 80);
+        assert_eq!(survival.payload_bits, 768 * 8);
+        assert!(survival.survival_rate() < 0.1, "{:?}", survival);
+        assert!(!survival.header_intact);
+        assert!(!survival.payload_intact);
+    }
+
+    #[test]
+    fn quality_out_of_range_is_rejected() {
+        let cover = DynamicImage::ImageRgb8(ImageBuffer::new(8, 8));
+
+        assert!(simulate_jpeg(&cover, 0).is_err());
+        assert!(simulate_jpeg(&cover, 101).is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    buffer_modify::{checked_pixel_index, PngImage},
+    crc_spec::CrcSpec,
+    header::{
+        check_low_bits_only, generate_v3_header, HeaderExtension, HeaderRaw, V1DataStuffingOptions,
+        VersionedHeader, HEADER_MAGIC,
+    },
+    payload::check_payload_crc,
+    prng::{shuffle, tool_rng},
+};
+use image::ColorType;
+
+/// Reported for every kind of failure, so a wrong key file can not be told apart from a missing payload
+const NO_PAYLOAD: &str = "No payload found for this key file";
+
+/// Order in which the bits of each byte are stored
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+impl BitOrder {
+    fn apply(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            BitOrder::MsbFirst => bytes.to_vec(),
+            BitOrder::LsbFirst => bytes.iter().map(|byte| byte.reverse_bits()).collect(),
+        }
+    }
+}
+
+///
+/// A private embedding convention, shared as a key file.
+/// Without it, the header can neither be found nor recognized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct EmbeddingParams {
+    /// Marks the header instead of the default magic
+    pub(crate) magic: u8,
+    /// Channel carrying the header, wrapped around for images with fewer channels
+    pub(crate) header_channel: u8,
+    /// Bit of that channel carrying the header, counted from the least significant one
+    pub(crate) header_bit: u8,
+    /// CRC algorithm of the payload checksum, in the format accepted by `--crc-spec`
+    pub(crate) crc_variant: String,
+    pub(crate) bit_order: BitOrder,
+    /// Seed of the order the pixels are visited in
+    pub(crate) traversal_seed: u64,
+}
+
+impl EmbeddingParams {
+    pub(crate) fn generate() -> EmbeddingParams {
+        let mut rng = tool_rng();
+        let crc_spec = CrcSpec {
+            init: rng.gen(),
+            xorout: rng.gen(),
+            ..CrcSpec::default()
+        };
+
+        EmbeddingParams {
+            magic: rng.gen(),
+            header_channel: rng.gen_range(0..4),
+            header_bit: rng.gen_range(0..2),
+            crc_variant: crc_spec.to_string(),
+            bit_order: match rng.gen() {
+                true => BitOrder::MsbFirst,
+                false => BitOrder::LsbFirst,
+            },
+            traversal_seed: rng.gen(),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("key file fields are always serializable")
+    }
+
+    pub(crate) fn from_json(json: &str) -> Result<EmbeddingParams, String> {
+        let params: EmbeddingParams =
+            serde_json::from_str(json).map_err(|x| format!("Invalid key file: {}", x))?;
+        if params.header_bit > 1 {
+            return Err("Invalid key file: header_bit has to be 0 or 1".to_string());
+        }
+        params.crc_spec()?;
+        Ok(params)
+    }
+
+    fn crc_spec(&self) -> Result<CrcSpec, String> {
+        self.crc_variant.parse()
+    }
+
+    /// The single bit of a pixel the header is stored in
+    fn header_mask(&self, color_type: ColorType) -> u64 {
+        let channels = color_type.channel_count() as u32;
+        let bits_per_channel = color_type.bits_per_pixel() as u32 / channels;
+        let channel = self.header_channel as u32 % channels;
+        let bit_from_msb =
+            channel * bits_per_channel + bits_per_channel - 1 - self.header_bit as u32;
+        1u64 << (u64::BITS - 1 - bit_from_msb)
+    }
+
+    /// All pixels, in the order derived from the key file
+    fn traversal(&self, pixel_count: u64) -> Result<Vec<usize>, String> {
+        let mut pixels: Vec<usize> = (0..checked_pixel_index(pixel_count)?).collect();
+        shuffle(self.traversal_seed, &mut pixels);
+        Ok(pixels)
+    }
+}
+
+///
+/// Embeds the payload following the convention of the key file.
+/// The header goes into the first pixels of the traversal, the payload follows.
+pub(crate) fn write_keyed_payload(
+    image: &mut dyn PngImage,
+    params: &EmbeddingParams,
+    payload: &[u8],
+    extensions: Vec<HeaderExtension>,
+    max_bits_per_channel: Option<u8>,
+) -> Result<(), String> {
+    let color_type = image.color_type();
+    let pixels = params.traversal(image.pixel_count())?;
+    let header = generate_v3_header(
+        pixels.len() as u64,
+        payload,
+        color_type,
+        params.crc_spec()?,
+        extensions,
+        true,
+    )?;
+    check_low_bits_only(header.data_mask(), color_type, max_bits_per_channel)?;
+    let start_offset = header.start_offset();
+    let header = header.with_stuffing_opts(V1DataStuffingOptions::Keyed { start_offset });
+
+    let mut raw_header: HeaderRaw = header.clone().try_into().map_err(|x| format!("{}", x))?;
+    raw_header.magic = params.magic;
+    let header_bytes = params.bit_order.apply(&raw_header.to_bytes());
+
+    image.write_data_at_pixels(
+        &header_bytes,
+        params.header_mask(color_type),
+        &pixels[..header_bytes.len() * 8],
+    );
+    image.write_data_at_pixels(
+        &params.bit_order.apply(payload),
+        header.data_mask(),
+        &pixels[checked_pixel_index(start_offset)?..],
+    );
+
+    Ok(())
+}
+
+///
+/// Reads the payload embedded with the key file, along with its header
+pub(crate) fn read_keyed_payload(
+    image: &dyn PngImage,
+    params: &EmbeddingParams,
+) -> Result<(VersionedHeader, Vec<u8>), String> {
+    let color_type = image.color_type();
+    let pixels = params.traversal(image.pixel_count())?;
+    let header_mask = params.header_mask(color_type);
+    let read = |mask: u64, pixels: &[usize], length: usize| {
+        params
+            .bit_order
+            .apply(&image.read_data_at_pixels(mask, pixels, length))
+    };
+
+    // Magic (1B), Header Len (2B), Data, CRC (4B)
+    let partial_header = read(header_mask, &pixels[..pixels.len().min(3 * 8)], 3);
+    if partial_header.first() != Some(&params.magic) {
+        return Err(NO_PAYLOAD.to_string());
+    }
+    let header_len = 3 + u16::from_be_bytes([partial_header[1], partial_header[2]]) as usize + 4;
+    if header_len * 8 > pixels.len() {
+        return Err(NO_PAYLOAD.to_string());
+    }
+    let mut raw_header = HeaderRaw::from_bytes(&read(header_mask, &pixels, header_len))?;
+    raw_header.magic = HEADER_MAGIC;
+    let header = VersionedHeader::try_from(raw_header).map_err(|_| NO_PAYLOAD.to_string())?;
+    if !matches!(header.stuffing_opts(), V1DataStuffingOptions::Keyed { .. }) {
+        return Err(NO_PAYLOAD.to_string());
+    }
+
+    let start_offset = checked_pixel_index(header.start_offset())?;
+    let data_len = checked_pixel_index(header.data_len())?;
+    let bits_per_pixel = header.data_mask().count_ones() as usize;
+    let available_pixels = pixels.len().saturating_sub(start_offset);
+    if start_offset < header_len * 8
+        || bits_per_pixel == 0
+        || (data_len * 8).div_ceil(bits_per_pixel) > available_pixels
+    {
+        return Err("Header describes more data than the image can hold".to_string());
+    }
+
+    let payload = read(header.data_mask(), &pixels[start_offset..], data_len);
+    check_payload_crc(&header, &payload)?;
+
+    Ok((header, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::header::try_get_header;
+
+    #[test]
+    fn payload_only_decodes_with_its_key_file() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(96, 96);
+        rand::thread_rng().fill_bytes(&mut image);
+        let payload = b"for the team only".repeat(30);
+        let params = EmbeddingParams::generate();
+        let other_params = EmbeddingParams::generate();
+
+        write_keyed_payload(&mut image, &params, &payload, Vec::new(), None).unwrap();
+
+        let params = EmbeddingParams::from_json(&params.to_json()).unwrap();
+        assert_eq!(read_keyed_payload(&image, &params).unwrap().1, payload);
+        assert!(read_keyed_payload(&image, &other_params).is_err());
+        assert!(try_get_header(&image).is_err());
+    }
+}
@@ -0,0 +1,58 @@
+//! In-memory encoding and decoding, without the CLI.
+//!
+//! Callers with their own image pipeline can use the bit packing on raw pixel buffers alone, see [`raw`].
+//!
+//! Build for the browser with `--no-default-features --features wasm`, see [`wasm`].
+//!
+//! Images with a crafted header claiming a longer payload than they hold make [`decode_from_slice`]
+//! and `wasm::decode` panic. The `strict-no-panic` feature checks the claimed length first and returns
+//! an error instead. It will become the default once the remaining panics are gone.
+//! The functions of [`raw`] always return errors.
+
+// The CLI binary builds on these modules. Apart from raw and wasm they are no stable interface.
+#[doc(hidden)]
+pub mod avoid_mask;
+#[doc(hidden)]
+pub mod budget;
+#[doc(hidden)]
+pub mod buffer_modify;
+#[doc(hidden)]
+pub mod color_key;
+#[doc(hidden)]
+pub mod complexity;
+#[doc(hidden)]
+pub mod crc_spec;
+mod gray_code;
+#[doc(hidden)]
+pub mod header;
+#[doc(hidden)]
+pub mod header_copies;
+mod in_memory;
+#[doc(hidden)]
+pub mod payload;
+#[doc(hidden)]
+pub mod preamble;
+#[doc(hidden)]
+pub mod prng;
+pub mod raw;
+#[doc(hidden)]
+pub mod records;
+#[doc(hidden)]
+pub mod scatter;
+#[doc(hidden)]
+pub mod signature;
+#[doc(hidden)]
+pub mod span;
+#[doc(hidden)]
+pub mod stream;
+#[doc(hidden)]
+pub mod used_regions;
+#[doc(hidden)]
+pub mod verification;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[doc(hidden)]
+pub mod ycbcr;
+
+pub use budget::PixelBudget;
+pub use in_memory::{decode_from_slice, encode_to_vec, encode_to_vec_with_budget};
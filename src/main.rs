@@ -1,44 +1,399 @@
-mod buffer_modify;
-mod header;
+mod align;
+mod analysis;
+mod archive;
+mod benchmark;
+#[cfg(feature = "tui")]
+mod browse;
+mod channel_bits;
+#[cfg(feature = "arboard")]
+mod clipboard;
+mod deniable;
+mod diffuse;
+mod downcast;
+mod explain_mask;
+mod extract;
+mod foreign;
+mod gif_palette;
+mod header_recovery;
+mod image_archive;
+mod io_errors;
+mod jpeg_simulation;
+mod keyfile;
+mod mask_display;
+mod memory_limit;
+mod output_format;
+mod png_info;
+mod profile;
+mod quality;
+mod report;
+mod rewrap;
+mod scan_dir;
+mod sidecar;
+mod size_format;
+mod stdin_input;
+mod tamper;
+mod tiff_pages;
+mod trns;
+mod visible_watermark;
 
 use clap::{Parser, Subcommand};
 use colored::*;
-use core::panic;
-use header::{try_get_header, VersionedHeader};
-use image::GenericImageView;
+use ed25519_dalek::VerifyingKey;
+use foreign::{read_foreign_payload, ForeignFormat};
+use header::try_get_header;
+use image::{ColorType, DynamicImage, GenericImageView};
+use image_hidden_message::{
+    avoid_mask, budget, buffer_modify, color_key, complexity, crc_spec, header, header_copies,
+    payload, preamble, prng, raw, records, scatter, signature, span, used_regions, verification,
+    ycbcr,
+};
+use rand::Rng;
 use std::{
-    fs::File,
-    io::{self, stdout, BufWriter, Read, Write},
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, stdout, BufWriter, Write},
     path::Path,
     process::exit,
 };
+use tracing::{debug, info, info_span, level_filters::LevelFilter, warn};
+use tracing_subscriber::EnvFilter;
 
+use crate::align::crop_to_embedded_image;
+use crate::analysis::check_cover_entropy;
+use crate::archive::pack_files;
+use crate::avoid_mask::AvoidMask;
+use crate::benchmark::Benchmark;
+use crate::budget::PixelBudget;
 use crate::buffer_modify::{convert_dynamic_image_to_png_image, PngImage};
-use crate::header::{generate_v1_header, HeaderRaw};
+use crate::channel_bits::{alpha_channel_bits, with_channel_bits, ChannelBits};
+use crate::color_key::ColorKey;
+use crate::complexity::with_complexity_placement;
+use crate::crc_spec::CrcSpec;
+use crate::deniable::{read_password_payload, write_password_payloads};
+use crate::diffuse::diffuse_dir;
+use crate::downcast::{downcast_to_8bit, Dither};
+use crate::explain_mask::{explain_mask, parse_color_type, parse_mask};
+use crate::extract::extract_to_file;
+use crate::gif_palette::{is_gif, read_gif_payload, try_get_gif_header, write_gif_payload};
+use crate::header::{
+    check_low_bits_only, generate_v3_header, generate_v3_header_with_bias, HeaderExtension,
+    OffsetBias, PayloadContainer, V1DataStuffingOptions, VersionedHeader,
+    DEFAULT_MAX_BITS_PER_CHANNEL, HIGH_BITS_PER_CHANNEL,
+};
+use crate::header_copies::{pixels_between_copies, MAX_HEADER_COPIES};
+use crate::header_recovery::try_all_headers;
+use crate::image_archive::{archive_kind, read_entry};
+use crate::io_errors::{cannot_read, cannot_write, describe_io_error};
+use crate::jpeg_simulation::simulate_jpeg;
+use crate::keyfile::{read_keyed_payload, write_keyed_payload, EmbeddingParams};
+use crate::mask_display::format_data_mask;
+use crate::memory_limit::{check_memory, decoded_image_bytes, MemoryEstimate, MemoryLimit};
+use crate::output_format::OutputFormat;
+use crate::payload::{
+    read_payload, scrub_stale_payload, verify_payload, write_payload, write_payload_with_stats,
+    PayloadSummary,
+};
+use crate::png_info::check_supported_bit_depth;
+use crate::preamble::{find_preamble, Preamble};
+use crate::prng::{enable_deterministic_mode, tool_rng, DETERMINISTIC_SEED};
+use crate::profile::CoverProfile;
+use crate::quality::{change_rate, check_change_budget, sweep_bits_per_pixel, DEFAULT_TARGET_PSNR};
+use crate::records::LengthEndian;
+use crate::report::EncodeReport;
+use crate::rewrap::rewrap;
+use crate::scan_dir::scan_dir;
+use crate::scatter::max_reserved_pixels;
+use crate::sidecar::Sidecar;
+use crate::signature::{
+    load_signing_key, load_verifying_key, public_key_base64, sign_payload, verify_signature,
+};
+use crate::size_format::format_byte_size;
+use crate::span::{join_chunks, split_payload, SpanInfo};
+use crate::stdin_input::{is_stdin_path, is_stdout_path, read_stdin, StdinInput, StdinOptions};
+use crate::tamper::compare_copies;
+use crate::tiff_pages::{
+    check_pages_lossless, is_tiff, read_pages_payload, read_tiff_pages, write_pages_payload,
+    write_tiff_pages,
+};
+use crate::trns::{read_trns_payload, try_get_trns_header, write_trns_payload, EmbedChannel};
+use crate::used_regions::free_capacity;
+use crate::verification::{stat_image, verify, Outcome};
+use crate::ycbcr::with_luma_placement;
 
 #[derive(Parser)]
 struct Cli {
     /// Enable verbose logging
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// Only log errors. Reports asked for by a flag, like `--benchmark`, are still printed.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Refuse operations whose estimated peak memory use exceeds this many MiB.
+    /// The estimate is checked before images or payloads are loaded.
+    #[arg(long, global = true, value_name = "MIB")]
+    max_memory: Option<u64>,
+
+    /// Fail right away instead of waiting for input if nothing is piped into STDIN
+    #[arg(long, global = true)]
+    no_interactive: bool,
+
+    /// Refuse to read more than this many bytes from STDIN
+    #[arg(long, global = true, value_name = "BYTES")]
+    stdin_limit: Option<u64>,
+
+    /// For testing only: draw all randomness from a fixed seed, so the output is reproducible.
+    /// Makes the payload location predictable.
+    #[arg(long, global = true, hide = true)]
+    deterministic: bool,
+
+    /// Number of threads used to read and write large payloads. 0 uses all cores.
+    #[arg(long, global = true, value_name = "N", default_value_t = 0)]
+    threads: usize,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+// Parsed once at startup, so the size of the Encode variant does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Write a hidden message to a PNG Image
     #[command(visible_aliases=["e", "enc"])]
     Encode {
         /// Path to the image you want to encode the message into
-        source: String,
+        #[arg(required_unless_present = "span")]
+        source: Option<String>,
         /// The message you want to hide. If this is not set, the message will be read from STDIN instead. The message can be binary.
         #[arg(short, long)]
         message: Option<String>,
+        /// Path to a file you want to hide. Its file name is stored alongside the payload.
+        #[arg(long, conflicts_with = "message")]
+        file: Option<String>,
+        /// The length of the message piped into STDIN. Encoding fails if the pipe delivers more or fewer bytes,
+        /// instead of embedding a truncated message.
+        #[arg(long, value_name = "BYTES", conflicts_with_all = ["message", "message_file"])]
+        size: Option<u64>,
+        /// Path to a file to hide alongside others, can be repeated. The files are packed into an archive
+        /// together with their names, which `decode --extract-all` unpacks again.
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["message", "file", "span", "password", "params", "channel", "page"])]
+        message_file: Vec<String>,
+        /// Store the message as the first of a sequence of records, which `append-record` extends later on.
+        /// The payload starts right after the header, leaving the rest of the image to the records to come.
+        #[arg(long, conflicts_with_all = ["message_file", "span", "password", "params", "channel", "page", "avoid_mask", "scatter_header", "preamble", "sign", "header_copies", "ycbcr", "complexity_weighted", "color_key", "channel_bits", "compare_covers"])]
+        record: bool,
+        /// Byte order of the length in front of every record, `big` or `little`. Stored in the header.
+        #[arg(long, value_name = "ORDER", default_value = "big", requires = "record")]
+        length_endian: LengthEndian,
+        /// Keep this many bytes free behind the first record, for the records `append-record` adds later on.
+        /// Every record takes 4 more bytes for its length. Appends are limited to the reserved bytes.
+        #[arg(long, value_name = "BYTES", requires = "record")]
+        reserve: Option<u64>,
         /// The output path of the modified Image. If this is not set, the message will be written to STDOUT.
         #[arg(short, long)]
         out: Option<String>,
+        /// Path to a PNG of the same size as the source. Black pixels mark regions which must not carry any data.
+        /// The same mask is needed to decode the image.
+        #[arg(long)]
+        avoid_mask: Option<String>,
+        /// The format of the modified Image. If this is not set, it is derived from the output path, defaulting to PNG.
+        #[arg(short, long, value_enum)]
+        format: Option<OutputFormat>,
+        /// Output the modified Image as a `data:` URI with base64 content instead of raw bytes
+        #[arg(long, conflicts_with = "span")]
+        data_uri: bool,
+        /// Convert covers with 16 bits per channel to 8 bits per channel before embedding, e.g. for a smaller file
+        #[arg(long)]
+        convert_8bit: bool,
+        /// How --convert-8bit spreads the rounding error, to avoid banding in smooth gradients
+        #[arg(long, value_enum, default_value_t, requires = "convert_8bit")]
+        downcast_dither: Dither,
+        /// Refuse to encode into images where hidden data would be easy to spot, instead of only warning
+        #[arg(long)]
+        strict: bool,
+        /// Before embedding, write the cover in the output format and read it back, refusing to embed unless
+        /// every sample survives bit-exact
+        #[arg(long)]
+        verify_cover_lossless: bool,
+        /// Parameters of the CRC-32 algorithm used for the payload checksum, e.g. "poly=0x04c11db7,init=0xffffffff,refin=true,refout=true,xorout=0xffffffff".
+        /// Omitted parameters default to CRC-32/CKSUM. The spec is stored in the header.
+        #[arg(long)]
+        crc_spec: Option<CrcSpec>,
+        /// Scatter the header among the payload pixels instead of storing it at the start of the image.
+        /// Only a small bootstrap record remains at a fixed location.
+        #[arg(long, conflicts_with = "avoid_mask")]
+        scatter_header: bool,
+        /// Store a 4 byte preamble (header version and payload size in KiB) ahead of the header,
+        /// so `stat --fast` can triage the image without parsing the header
+        #[arg(long, conflicts_with_all = ["scatter_header", "span", "password", "channel", "params", "page"])]
+        preamble: bool,
+        /// Store the payload bits of every channel Gray-coded, so neighbouring symbol values differ in a single bit.
+        /// Recorded in the header, decoding reverses it automatically.
+        #[arg(long, conflicts_with_all = ["span", "password", "channel", "params", "page", "ycbcr", "complexity_weighted", "color_key", "emit_sidecar", "report_change_rate", "max_bits_changed"])]
+        gray: bool,
+        /// Split the message across several images instead of a single source image.
+        /// The modified images are written into the directory given by --out, keeping their file names.
+        #[arg(long, num_args = 1.., conflicts_with_all = ["source", "avoid_mask"], requires = "out")]
+        span: Vec<String>,
+        /// Place the message in pixels derived from this password. Nothing is stored at a fixed location,
+        /// so the image does not reveal that it carries a message.
+        #[arg(long, conflicts_with_all = ["avoid_mask", "scatter_header", "span"])]
+        password: Option<String>,
+        /// Password of a decoy message, which is embedded alongside the real one.
+        /// Either password only reveals its own message.
+        #[arg(long, requires_all = ["password", "decoy"])]
+        decoy_password: Option<String>,
+        /// Salt mixed into the derivation of the password pixels. It is stored in the image and read back
+        /// automatically when decoding. Defaults to a random salt, so each encode differs.
+        #[arg(long, requires = "password")]
+        salt: Option<u64>,
+        /// The decoy message
+        #[arg(long, group = "decoy", requires = "decoy_password")]
+        decoy_message: Option<String>,
+        /// Path to a file used as the decoy message
+        #[arg(long, group = "decoy", requires = "decoy_password")]
+        decoy_file: Option<String>,
+        /// Where the message is embedded. `trns` only changes the transparency entries of a palette PNG,
+        /// which holds a few dozen bytes at most. `gif-palette` only changes the palette entries no frame
+        /// of a GIF shows, and is picked for GIF sources. Decoding detects the channel automatically.
+        #[arg(long, value_enum, default_value_t, conflicts_with_all = ["avoid_mask", "scatter_header", "span", "password"])]
+        channel: EmbedChannel,
+        /// Try every bits per pixel setting the payload fits with, and use the one giving the smallest file
+        /// while the PSNR stays above --target-psnr
+        #[arg(long, conflicts_with_all = ["avoid_mask", "scatter_header", "span", "password"])]
+        compare_covers: bool,
+        /// Lowest acceptable PSNR (in dB) for --compare-covers
+        #[arg(long, default_value_t = DEFAULT_TARGET_PSNR, requires = "compare_covers")]
+        target_psnr: f64,
+        /// Allow the payload to use more than the 2 least significant bits of each channel, up to half of its bits.
+        /// Changing higher bits visibly alters the image.
+        #[arg(long)]
+        allow_high_bits: bool,
+        /// Allow the payload to use more than half of the bits of each channel, up to all of them.
+        /// The image is destroyed rather than altered, so this is only useful if the cover does not matter.
+        #[arg(long)]
+        allow_destructive: bool,
+        /// Bits of a channel carrying the payload, e.g. `--channel-bits r=2 --channel-bits b=1`. Can be repeated,
+        /// channels which are not given carry no payload. Replaces the evenly spread bits picked by default.
+        #[arg(long, value_name = "CHANNEL=BITS", conflicts_with_all = ["span", "password", "channel", "params", "compare_covers", "page"])]
+        channel_bits: Vec<ChannelBits>,
+        /// Start the payload right after the header instead of at a random pixel. No randomness is needed,
+        /// but the payload is easier to find.
+        #[arg(long, conflicts_with_all = ["scatter_header", "span", "password"])]
+        no_randomize_offset: bool,
+        /// How the random start offset of the payload is drawn: `uniform`, `center` (mostly around the middle
+        /// of the image) or `edges` (mostly near its start or end). Only the drawn offset is stored.
+        #[arg(long, value_name = "BIAS", default_value = "uniform", conflicts_with_all = ["no_randomize_offset", "span", "password", "channel", "params", "page", "record", "ycbcr", "complexity_weighted"])]
+        offset_bias: OffsetBias,
+        /// Overwrite the low bits of the whole image with noise before embedding, so no header or payload
+        /// the image already carries is found again. Bits above the ones the payload may use are kept.
+        #[arg(long, conflicts_with = "channel")]
+        clean_slate: bool,
+        /// Counter the brightness shift caused by the payload, by also adjusting bits of the changed
+        /// samples which are not read when decoding. Keeps the histogram closer to the original.
+        #[arg(long, conflicts_with_all = ["span", "password", "channel", "params"])]
+        dither_compensate: bool,
+        /// Keep the perceived brightness (luma) of every changed pixel close to the original, by also adjusting
+        /// bits of its color channels which are not read when decoding. Suited for photographs.
+        #[arg(long, conflicts_with_all = ["span", "password", "channel", "params", "dither_compensate"])]
+        preserve_luma: bool,
+        /// Embed the payload and its header into the alpha channel only, leaving the color channels bit-exact.
+        /// Suited for images with a (mostly) opaque alpha channel. Fails for images without one.
+        #[arg(long, conflicts_with_all = ["scatter_header", "span", "password", "channel", "params", "compare_covers", "page", "channel_bits", "profile", "clean_slate", "dither_compensate", "preserve_luma", "color_key"])]
+        alpha_only: bool,
+        /// Number of least significant bits of the alpha channel used by --alpha-only
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_BITS_PER_CHANNEL, requires = "alpha_only")]
+        alpha_bits: u8,
+        /// Store this many copies of the header, spread evenly across the image. Decoding uses the first copy
+        /// which is intact, so the payload survives damage to the start of the image.
+        #[arg(long, value_name = "N", default_value_t = 1, value_parser = parse_header_copies, conflicts_with_all = ["avoid_mask", "scatter_header", "span", "password", "channel", "params", "page", "alpha_only", "color_key", "emit_sidecar", "report_change_rate", "max_bits_changed"])]
+        header_copies: u8,
+        /// Embed the payload into the luma (Y) of the image's YCbCr representation, using the reversible transform
+        /// of JPEG 2000. Every channel of a changed pixel shifts by the same amount, so only the brightness changes.
+        /// Needs an 8-bit RGB(A) image. Groundwork for JPEG-surviving watermarks, the output still has to stay lossless.
+        #[arg(long, conflicts_with_all = ["avoid_mask", "scatter_header", "span", "password", "channel", "params", "compare_covers", "page", "channel_bits", "profile", "dither_compensate", "preserve_luma", "alpha_only", "color_key", "emit_sidecar", "report_change_rate", "max_bits_changed", "header_copies"])]
+        ycbcr: bool,
+        /// Number of least significant bits of the luma used by --ycbcr
+        #[arg(long, value_name = "N", default_value_t = 1, requires = "ycbcr")]
+        y_bits: u8,
+        /// Spread the payload according to the local complexity of the image. Samples in textured areas
+        /// carry more bits, samples in flat areas fewer or none. Decoding derives the same distribution from the image.
+        #[arg(long, conflicts_with_all = ["avoid_mask", "scatter_header", "span", "password", "channel", "params", "compare_covers", "page", "channel_bits", "profile", "dither_compensate", "preserve_luma", "alpha_only", "color_key", "emit_sidecar", "report_change_rate", "max_bits_changed", "header_copies", "ycbcr"])]
+        complexity_weighted: bool,
+        /// Most low bits a sample carries with --complexity-weighted
+        #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_BITS_PER_CHANNEL, requires = "complexity_weighted")]
+        complexity_bits: u8,
+        /// Only embed into pixels of this color, given as RRGGBB or RRGGBBAA, e.g. a green screen background.
+        /// The key is stored in the header, so decoding selects the same pixels.
+        #[arg(long, value_name = "RRGGBB[AA]", conflicts_with_all = ["avoid_mask", "scatter_header", "span", "password", "channel", "params", "dither_compensate", "preserve_luma", "allow_high_bits", "allow_destructive", "emit_sidecar"])]
+        color_key: Option<ColorKey>,
+        /// Largest difference per channel to the color key which still counts as a match
+        #[arg(long, default_value_t = 0, requires = "color_key")]
+        color_key_tolerance: u8,
+        /// Embed into the pixels which do not match the color key instead
+        #[arg(long, requires = "color_key")]
+        color_key_invert: bool,
+        /// Also write the header fields needed to read the payload into this JSON file.
+        /// `decode --sidecar` can read the payload with it, even if the header in the image is damaged.
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["avoid_mask", "scatter_header", "span", "password", "channel"])]
+        emit_sidecar: Option<String>,
+        /// Write a JSON summary of the chosen parameters, payload size, output size and PSNR to this file,
+        /// e.g. for provenance logs. Unlike the sidecar, it is not needed to read the payload.
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["span", "password", "channel", "params", "page"])]
+        emit_report: Option<String>,
+        /// Print the fraction of the bits carrying header and payload which had to be flipped.
+        /// Bits already matching the payload stay untouched, so a lower rate leaves fewer traces.
+        #[arg(long, conflicts_with_all = ["scatter_header", "span", "password", "channel", "params", "page"])]
+        report_change_rate: bool,
+        /// Print how the pixels of the image are split between the header, the payload and what is left free
+        #[arg(long, conflicts_with_all = ["avoid_mask", "scatter_header", "span", "password", "channel", "params", "page", "header_copies", "ycbcr", "complexity_weighted", "color_key"])]
+        print_budget: bool,
+        /// Print how long embedding the payload took to STDERR, with the throughput of payload and image.
+        /// Helps comparing settings, e.g. `--threads 1`, on the own hardware.
+        #[arg(long, conflicts_with_all = ["span", "password", "channel", "params", "page"])]
+        benchmark: bool,
+        /// Refuse to embed if more than this many of the bits carrying header and payload would have to be flipped.
+        /// Checked before the image is modified.
+        #[arg(long, value_name = "BITS", conflicts_with_all = ["scatter_header", "span", "password", "channel", "params", "page"])]
+        max_bits_changed: Option<u64>,
+        /// Draw this text faintly into the bottom right corner before embedding, e.g. a copyright notice,
+        /// so the image is marked for people as well. The payload is written into the low bits afterwards.
+        #[arg(long, value_name = "TEXT", conflicts_with_all = ["span", "channel", "page"])]
+        visible_watermark: Option<String>,
+        /// TrueType or OpenType font of --visible-watermark. Defaults to the bundled DejaVu Sans Mono.
+        #[arg(long, value_name = "PATH", requires = "visible_watermark")]
+        watermark_font: Option<String>,
+        /// Store a key/value pair alongside the payload, e.g. `--meta author=jane`. Can be repeated.
+        #[arg(long, value_name = "KEY=VALUE", value_parser = parse_meta_entry, conflicts_with_all = ["password", "channel"])]
+        meta: Vec<(String, String)>,
+        /// Identifier of the key the payload was encrypted with, e.g. a key ID or KDF parameters.
+        /// Shown by `stat`, so the recipient knows which key to use. Never pass the key itself.
+        #[arg(long, value_name = "ID", conflicts_with_all = ["password", "channel"])]
+        key_id: Option<String>,
+        /// Sign the payload with this Ed25519 private key (32 bytes, raw or base64) and store the signature
+        /// in the header. `decode --verify-sig` checks it with the public key.
+        #[arg(long, value_name = "KEYFILE", conflicts_with_all = ["span", "password", "channel", "params", "page"])]
+        sign: Option<String>,
+        /// Embed following the parameters of a key file created by `keygen`.
+        /// The payload can only be found and decoded with the same key file.
+        #[arg(long, value_name = "KEYFILE", conflicts_with_all = ["avoid_mask", "scatter_header", "span", "password", "channel", "compare_covers", "emit_sidecar", "crc_spec", "no_randomize_offset"])]
+        params: Option<String>,
+        /// Only embed into this page of a multi-page TIFF, counted from 0.
+        /// Without it, the message is spread across all pages. TIFF sources are written back as TIFF.
+        #[arg(long, value_name = "N", conflicts_with_all = ["span", "data_uri"])]
+        page: Option<usize>,
+        /// Preset of options for the kind of cover. `photo` embeds into green and blue only and preserves luma,
+        /// `screenshot` only uses the least significant bit of a channel, and `diagram` additionally refuses
+        /// covers with few distinct colors like --strict. Other flags are combined with the preset.
+        #[arg(long, value_enum, conflicts_with_all = ["span", "password", "channel", "params", "compare_covers", "page", "channel_bits", "allow_high_bits", "allow_destructive", "dither_compensate", "color_key"])]
+        profile: Option<CoverProfile>,
+        /// Write the payload a second time into the bits right above it, e.g. the LSB and the second LSB.
+        /// `decode --check-tamper` compares both copies, where they disagree the image was altered.
+        /// Takes twice the bits per pixel.
+        #[arg(long, conflicts_with_all = ["span", "password", "channel", "params", "compare_covers", "page", "channel_bits", "profile", "alpha_only", "ycbcr", "complexity_weighted", "dither_compensate", "preserve_luma", "record", "reserve", "report_change_rate", "max_bits_changed"])]
+        tamper_evident: bool,
     },
     /// Read a hidden message from a PNG Image and output to stdout
     #[command(visible_aliases=["d", "dec"])]
@@ -46,228 +401,2338 @@ enum Commands {
         /// The Path to the image you want to decode. If this is not set, the image will be read from STDIN instead.
         #[arg(short, long)]
         source: Option<String>,
+        /// Read a payload embedded by an external LSB tool instead of one created by this tool
+        #[arg(long, value_enum)]
+        foreign: Option<ForeignFormat>,
+        /// Only check that the image carries a payload with a valid checksum. Nothing is written to STDOUT.
+        /// Exits with a non-zero status if the payload cannot be verified.
+        #[arg(long, conflicts_with = "foreign")]
+        verify_only: bool,
+        /// The avoid mask the image was encoded with, if any
+        #[arg(long, conflicts_with = "foreign")]
+        avoid_mask: Option<String>,
+        /// Only report what would be extracted, without reading the payload. The report goes to STDERR.
+        #[arg(long, conflicts_with_all = ["foreign", "verify_only"])]
+        dry_run: bool,
+        /// Reassemble a message which was split across several images with `encode --span`.
+        /// The images can be given in any order.
+        #[arg(long, num_args = 1.., conflicts_with_all = ["source", "foreign", "avoid_mask", "dry_run"])]
+        span: Vec<String>,
+        /// The password the message was embedded with via `encode --password`
+        #[arg(long, conflicts_with_all = ["foreign", "avoid_mask", "dry_run", "span"])]
+        password: Option<String>,
+        /// Read the payload using the header fields from a file written by `encode --emit-sidecar`,
+        /// instead of the header in the image
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["foreign", "avoid_mask", "span", "password"])]
+        sidecar: Option<String>,
+        /// Print the metadata stored with `encode --meta` as key=value lines instead of the payload
+        #[arg(long, conflicts_with_all = ["foreign", "verify_only", "dry_run", "span", "password", "sidecar"])]
+        print_meta: bool,
+        /// The key file the message was embedded with via `encode --params`
+        #[arg(long, value_name = "KEYFILE", conflicts_with_all = ["foreign", "avoid_mask", "dry_run", "span", "password", "sidecar", "print_meta"])]
+        params: Option<String>,
+        /// Only read the message from this page of a multi-page TIFF, counted from 0
+        #[arg(long, value_name = "N", conflicts_with_all = ["foreign", "avoid_mask", "dry_run", "span", "password", "sidecar", "print_meta", "params"])]
+        page: Option<usize>,
+        /// The source is a pixel exact (1:1) screenshot showing the image on a plain background.
+        /// The image is located within it and cropped out before decoding.
+        #[arg(long, conflicts_with_all = ["span", "page"])]
+        align: bool,
+        /// Look for the header under every convention earlier versions wrote it with, e.g. in another bit
+        /// or behind a magic claiming the wrong integer encoding. Reports the one which matched on STDERR.
+        #[arg(long, conflicts_with_all = ["foreign", "span", "password", "sidecar", "params", "page"])]
+        try_all: bool,
+        /// Check the signature stored by `encode --sign` with this Ed25519 public key (32 bytes, raw or base64).
+        /// Fails without writing the payload if the signature is missing or does not match.
+        #[arg(long, value_name = "PUBKEY", conflicts_with_all = ["foreign", "verify_only", "dry_run", "span", "password", "print_meta", "params", "page"])]
+        verify_sig: Option<String>,
+        /// Unpack the files embedded with `encode --message-file` into this directory instead of writing the payload
+        /// to STDOUT. Existing files are never overwritten.
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["foreign", "verify_only", "dry_run", "span", "password", "print_meta", "params", "page"])]
+        extract_all: Option<String>,
+        /// Only write the record with this index, counted from 0, of a payload embedded with `encode --record`
+        #[arg(long, value_name = "N", conflicts_with_all = ["foreign", "verify_only", "dry_run", "span", "password", "print_meta", "params", "page", "extract_all"])]
+        record: Option<usize>,
+        /// Print how long reading the payload took to STDERR, with the throughput of payload and image
+        #[arg(long, conflicts_with_all = ["foreign", "verify_only", "dry_run", "span", "password", "print_meta", "params", "page"])]
+        benchmark: bool,
+        /// Only compare the two copies of a payload embedded with `encode --tamper-evident` and print
+        /// where they disagree. Nothing is written to STDOUT. Exits with a non-zero status if they do.
+        #[arg(long, conflicts_with_all = ["foreign", "verify_only", "dry_run", "span", "password", "print_meta", "params", "page", "extract_all", "record", "verify_sig", "benchmark"])]
+        check_tamper: bool,
+        /// The image to decode when the source is a zip or tar archive, by its path within the archive
+        #[arg(long, value_name = "NAME", conflicts_with = "span")]
+        entry: Option<String>,
+        /// Read the image from the system clipboard instead of STDIN
+        #[cfg(feature = "arboard")]
+        #[arg(long, conflicts_with_all = ["source", "span"])]
+        clipboard: bool,
+    },
+    /// Read a hidden message from an Image and write it to a file next to it.
+    /// The file is named after the stored file name, or after the image if there is none.
+    /// In that case the extension is guessed from the payload, e.g. `.pdf`, and is `.bin` for unknown ones.
+    #[command(visible_aliases=["x"])]
+    Extract {
+        /// Path to the image you want to extract the message from
+        source: String,
+        /// The directory the file is written to. Defaults to the current directory.
+        #[arg(short, long)]
+        out_dir: Option<String>,
+        /// The avoid mask the image was encoded with, if any
+        #[arg(long)]
+        avoid_mask: Option<String>,
+        /// Always name files without a stored file name `.bin` instead of guessing the extension
+        #[arg(long)]
+        no_sniff: bool,
+    },
+    /// Append a record to the payload of an image encoded with `encode --record`.
+    /// The record is written into the pixels following the ones already stored.
+    AppendRecord {
+        /// Path to the image carrying the records
+        source: String,
+        /// The record to append. If this is not set, the record will be read from STDIN instead.
+        #[arg(short, long)]
+        message: Option<String>,
+        /// Path to a file to append as the record
+        #[arg(long, conflicts_with = "message")]
+        file: Option<String>,
+        /// The output path of the modified Image. If this is not set, the image will be written to STDOUT.
+        #[arg(short, long)]
+        out: Option<String>,
+    },
+    /// Move the payload of an image to another embedding, e.g. from a contiguous to a scattered header,
+    /// without providing the payload again. The checksum is verified before and after.
+    Rewrap {
+        /// Path to the image carrying the payload. `-` reads it from STDIN.
+        source: String,
+        /// The output path of the modified Image. If this is not set, the image will be written to STDOUT.
+        #[arg(short, long)]
+        out: Option<String>,
+        /// Scatter the header among the payload pixels, like `encode --scatter-header`
+        #[arg(long)]
+        scatter_header: bool,
+        /// Store this many copies of the header, like `encode --header-copies`
+        #[arg(long, value_name = "N", default_value_t = 1, value_parser = parse_header_copies, conflicts_with = "scatter_header")]
+        header_copies: u8,
+        /// Spread the payload over this many bits per pixel instead of the fewest it needs
+        #[arg(long, value_name = "N")]
+        bits_per_pixel: Option<u8>,
+        /// Start the payload right after the header instead of at a random pixel
+        #[arg(long, conflicts_with = "scatter_header")]
+        no_randomize_offset: bool,
+        /// Allow the payload to use more than the 2 least significant bits of each channel, up to half of its bits
+        #[arg(long)]
+        allow_high_bits: bool,
     },
     /// Try to get a hidden header from a PNG Image
     #[command(visible_aliases=["s"])]
-    Stat {},
+    Stat {
+        /// Also list the metadata stored with `encode --meta`
+        #[arg(long)]
+        meta: bool,
+        /// Also report how many more payload bytes fit next to the embedded one, at the same bits per pixel
+        #[arg(long)]
+        free: bool,
+        /// Also verify the payload against its checksum. Reads the whole payload, which is slow for huge ones.
+        /// Without it, only the header is read and the checksum is reported as claimed.
+        #[arg(long)]
+        deep: bool,
+        /// Only read the preamble written by `encode --preamble`, which gives the header version and
+        /// a bound of the payload size. Images without one fall back to parsing the header.
+        #[arg(long, conflicts_with_all = ["meta", "free", "deep"])]
+        fast: bool,
+        /// The image to inspect when STDIN is a zip or tar archive, by its path within the archive
+        #[arg(long, value_name = "NAME")]
+        entry: Option<String>,
+        /// Read the image from the system clipboard instead of STDIN
+        #[cfg(feature = "arboard")]
+        #[arg(long)]
+        clipboard: bool,
+    },
+    /// Check every image in a directory for a payload and summarize which ones carry a valid one.
+    /// Files which are no supported image are skipped with a note.
+    /// A zip or tar archive is scanned like a directory, see the `zip` and `tar` features.
+    ScanDir {
+        /// The directory or archive to scan
+        dir: String,
+        /// Also scan subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+        /// Print the summary as JSON, e.g. for further tooling
+        #[arg(long)]
+        json: bool,
+    },
+    /// Randomize the low bits of every image in a directory before publishing, so no leftover structure survives.
+    /// A valid payload is kept, all other low bits are replaced with noise. The results are written as PNG
+    /// into the output directory, under the same relative path. Files which are no PNG keep their extension
+    /// in front of `.png`. Sources are never modified.
+    Diffuse {
+        /// The directory with the images
+        dir: String,
+        /// The directory the diffused images are written to
+        #[arg(short, long, value_name = "DIR")]
+        out: String,
+        /// Number of least significant bits of every channel to randomize
+        #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=8))]
+        bits: u8,
+        /// Also diffuse the images in subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Explain which bits of every channel a data mask selects, how many bits per pixel and bytes
+    /// per megapixel it gives, and whether encoding accepts it. Nothing is read or written.
+    ExplainMask {
+        /// The mask in hex, from the first bit of the pixel on in the order `stat` shows the bits, e.g. `0x0F_0F_0F`
+        mask: String,
+        /// The color type of the pixels, e.g. `rgb8` or `rgba16`
+        color_type: String,
+    },
+    /// Estimate how much of a payload survives saving the image as JPEG.
+    /// Embeds a random test payload, round-trips the image through JPEG and counts the payload bits left.
+    /// Nothing is written.
+    SimulateJpeg {
+        /// Path to the cover image
+        source: String,
+        /// JPEG quality, from 1 to 100
+        #[arg(long, default_value_t = 80)]
+        quality: u8,
+    },
+    /// Create a key file with random embedding parameters, for use with `encode --params` and `decode --params`.
+    /// Images encoded with it do not carry a recognizable header.
+    Keygen {
+        /// Path of the key file. Existing files are not overwritten.
+        out: String,
+    },
+    /// List the images in a directory, flag the ones carrying a payload and decode them interactively
+    #[cfg(feature = "tui")]
+    Browse {
+        /// The directory to list. Defaults to the current directory.
+        dir: Option<String>,
+    },
 }
 
-fn main() {
-    let cli = Cli::parse();
+fn load_image_from_memory(
+    data: &[u8],
+    memory_limit: Option<MemoryLimit>,
+) -> Result<DynamicImage, String> {
+    check_supported_bit_depth(data)?;
+    if let Some(limit) = memory_limit {
+        limit.check(&MemoryEstimate {
+            image_file: data.len() as u64,
+            image_buffer: decoded_image_bytes(data)?,
+            ..Default::default()
+        })?;
+    }
+    image::load_from_memory(data).map_err(|x| x.to_string())
+}
 
-    match cli.command {
-        Commands::Encode {
-            source,
-            message,
-            out,
-        } => {
-            let source_path = Path::new(source.as_str());
-
-            if !source_path.exists() {
-                eprintln!("Provided path {} does not exist", source.yellow());
-                panic!("Path does not exist")
-            }
+fn enforce_memory_limit(memory_limit: Option<MemoryLimit>, estimate: MemoryEstimate) {
+    if let Err(err) = check_memory(memory_limit, &estimate) {
+        eprintln!("{}", err.red());
+        exit(1);
+    }
+}
 
-            let mut image = image::open(source_path)
-                .map_err(|x| {
-                    format!(
-                        "Failed to load the image. You might find more info below: {}",
-                        x
-                    )
+fn load_avoid_mask(path: Option<String>, dimensions: (u32, u32)) -> Option<AvoidMask> {
+    let path = path?;
+    match AvoidMask::load(Path::new(path.as_str()), dimensions) {
+        Ok(val) => Some(val),
+        Err(err) => {
+            eprintln!("{}", err.red());
+            exit(1);
+        }
+    }
+}
+
+///
+/// Reads the file at the path. `-` and `/dev/stdin` read the image piped into STDIN instead.
+/// The file size is checked against the memory limit before the file is read.
+fn read_source(source: &str, stdin: StdinOptions, memory_limit: Option<MemoryLimit>) -> Vec<u8> {
+    if is_stdin_path(source) {
+        return read_stdin(StdinInput::Image, stdin).unwrap_or_else(|err| {
+            eprintln!("{}", err.red());
+            exit(1);
+        });
+    }
+    if let Ok(metadata) = fs::metadata(source) {
+        enforce_memory_limit(
+            memory_limit,
+            MemoryEstimate {
+                image_file: metadata.len(),
+                ..Default::default()
+            },
+        );
+    }
+    fs::read(source).unwrap_or_else(|err| {
+        eprintln!("{}", cannot_read(source.yellow(), &err));
+        exit(1);
+    })
+}
+
+fn load_cover(
+    data: &[u8],
+    format: OutputFormat,
+    downcast: Option<Dither>,
+    strict: bool,
+    verify_lossless: bool,
+    memory_limit: Option<MemoryLimit>,
+) -> DynamicImage {
+    let image = match load_image_from_memory(data, memory_limit) {
+        Ok(val) => val,
+        Err(err) => {
+            eprintln!(
+                "Failed to load the image. You might find more info below: {}",
+                err.red()
+            );
+            exit(1);
+        }
+    };
+    let image = match downcast {
+        Some(dither) => downcast_to_8bit(image, dither),
+        None => image,
+    };
+    let image = format.prepare_cover(image);
+
+    if let Err(err) = check_cover_entropy(&image) {
+        if strict {
+            eprintln!("{}", err.red());
+            exit(1);
+        }
+        warn!("{}", err);
+    }
+    if verify_lossless {
+        if let Err(err) = format.check_lossless(&image) {
+            eprintln!("{}", err.red());
+            exit(1);
+        }
+        debug!(?format, "Output format is lossless for the cover");
+    }
+
+    image
+}
+
+fn load_params(path: Option<String>) -> Option<EmbeddingParams> {
+    let path = path?;
+    match fs::read_to_string(&path)
+        .map_err(|x| describe_io_error(&x))
+        .and_then(|json| EmbeddingParams::from_json(&json))
+    {
+        Ok(val) => Some(val),
+        Err(err) => {
+            eprintln!(
+                "Failed to read the key file {}: {}",
+                path.yellow(),
+                err.red()
+            );
+            exit(1);
+        }
+    }
+}
+
+fn read_message(
+    message: Option<String>,
+    file: Option<&str>,
+    stdin: StdinOptions,
+    memory_limit: Option<MemoryLimit>,
+) -> Vec<u8> {
+    if let Some(metadata) = file
+        .filter(|path| !is_stdin_path(path))
+        .and_then(|path| fs::metadata(path).ok())
+    {
+        enforce_memory_limit(
+            memory_limit,
+            MemoryEstimate {
+                payload: metadata.len(),
+                ..Default::default()
+            },
+        );
+    }
+    let mut message_buf: Vec<u8> = Vec::new();
+    let message_copy_result = match (message, file) {
+        (Some(val), _) => {
+            let data = val.as_bytes();
+            message_buf.write(data).map_err(|err| format!("{}", err))
+        }
+        (None, Some(path)) if is_stdin_path(path) => {
+            read_stdin(StdinInput::Message, stdin).map(|data| {
+                message_buf = data;
+                message_buf.len()
+            })
+        }
+        (None, Some(path)) => fs::read(path)
+            .map(|data| {
+                message_buf = data;
+                message_buf.len()
+            })
+            .map_err(|err| cannot_read(path.yellow(), &err)),
+        (None, None) => read_stdin(StdinInput::Message, stdin).map(|data| {
+            message_buf = data;
+            message_buf.len()
+        }),
+    };
+
+    let buf_len: usize = message_copy_result.unwrap_or_else(|err| {
+        eprintln!("{}", err.red());
+        exit(1);
+    });
+    info!(
+        bytes = buf_len,
+        "Message received and is {} long",
+        format_byte_size(buf_len as u64)
+    );
+
+    message_buf
+}
+
+#[allow(clippy::too_many_arguments)]
+fn generate_header(
+    pixel_count: u64,
+    payload: &[u8],
+    color_space: ColorType,
+    crc_spec: CrcSpec,
+    avoid_mask: Option<&AvoidMask>,
+    scatter_header: bool,
+    header_copies: u8,
+    max_bits_per_channel: Option<u8>,
+    extensions: Vec<HeaderExtension>,
+    offset_bias: Option<OffsetBias>,
+) -> Result<VersionedHeader, String> {
+    let header = match avoid_mask {
+        Some(avoid_mask) => {
+            let allowed_pixel_count = avoid_mask.allowed_pixels().len() as u64;
+            info!(
+                allowed_pixels = allowed_pixel_count,
+                pixels = pixel_count,
+                "Pixel selection leaves {} of {}px for the payload",
+                allowed_pixel_count,
+                pixel_count
+            );
+            generate_v3_header_with_bias(
+                allowed_pixel_count,
+                payload,
+                color_space,
+                crc_spec,
+                extensions,
+                offset_bias,
+            )
+            .map(|header| {
+                let start_offset = header.start_offset();
+                header.with_stuffing_opts(V1DataStuffingOptions::AvoidMask {
+                    start_offset,
+                    mask_checksum: avoid_mask.checksum(),
                 })
-                .unwrap();
+            })
+        }
+        None if scatter_header => generate_v3_header_with_bias(
+            pixel_count.saturating_sub(max_reserved_pixels()),
+            payload,
+            color_space,
+            crc_spec,
+            extensions,
+            Some(offset_bias.unwrap_or_default()),
+        )
+        .map(|header| {
+            let start_offset = header.start_offset();
+            header.with_stuffing_opts(V1DataStuffingOptions::ScatteredHeader {
+                start_offset,
+                seed: tool_rng().gen(),
+            })
+        }),
+        None if header_copies > 1 => {
+            // Only the length of this one matters, it decides how many pixels every copy takes
+            let unplaced = generate_v3_header(
+                pixel_count,
+                payload,
+                color_space,
+                crc_spec,
+                extensions.clone(),
+                false,
+            )?;
+            let free_pixels = pixels_between_copies(&unplaced, pixel_count, header_copies)?;
+            generate_v3_header_with_bias(
+                free_pixels.len() as u64,
+                payload,
+                color_space,
+                crc_spec,
+                extensions,
+                offset_bias,
+            )
+            .map(|header| {
+                let start_offset = header.start_offset();
+                header.with_stuffing_opts(V1DataStuffingOptions::HeaderCopies {
+                    start_offset,
+                    copies: header_copies,
+                })
+            })
+        }
+        None => generate_v3_header_with_bias(
+            pixel_count,
+            payload,
+            color_space,
+            crc_spec,
+            extensions,
+            offset_bias,
+        ),
+    }?;
+
+    check_low_bits_only(
+        header.data_mask() | header.tamper_copy_mask().unwrap_or(0),
+        color_space,
+        max_bits_per_channel,
+    )?;
+    Ok(header)
+}
 
-            let color_space = image.color();
-            let channels = image.color().channel_count();
-            let bytes_per_channel = image.color().bytes_per_pixel() / channels;
-            let dimensions = image.dimensions();
+///
+/// Picks the file `entry` out of a zip or tar archive. Other sources are returned as they are.
+fn select_archive_entry(data: Vec<u8>, entry: Option<&str>) -> Vec<u8> {
+    let selected = match (archive_kind(&data), entry) {
+        (Some(kind), Some(name)) => read_entry(&data, kind, name),
+        (Some(kind), None) => Err(format!(
+            "The source is a {} archive. Pick one of its images with --entry, scan-dir lists them",
+            kind.name()
+        )),
+        (None, Some(_)) => Err("--entry needs a zip or tar archive as the source".to_string()),
+        (None, None) => return data,
+    };
+    selected.unwrap_or_else(|err| {
+        eprintln!("{}", err.red());
+        exit(1);
+    })
+}
+
+///
+/// Writes the modified image to the output path, or to STDOUT if there is none
+fn write_output(mut data: Vec<u8>, format: OutputFormat, data_uri: bool, out: Option<String>) {
+    if data_uri {
+        data = format.to_data_uri(&data).into_bytes();
+    }
+
+    match out {
+        None => stdout().write_all(&data).unwrap(),
+        Some(path) => {
+            let written = File::create(&path).and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                writer.write_all(&data)?;
+                writer.flush()
+            });
+            if let Err(err) = written {
+                eprintln!("{}", cannot_write(path.yellow(), &err));
+                exit(1);
+            }
+        }
+    }
+
+    info!(bytes = data.len(), "Modified image written");
+}
+
+fn parse_meta_entry(entry: &str) -> Result<(String, String), String> {
+    match entry.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Expected KEY=VALUE, got \"{}\"", entry)),
+    }
+}
+
+fn parse_header_copies(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(copies) if (1..=MAX_HEADER_COPIES).contains(&copies) => Ok(copies),
+        _ => Err(format!("Expected 1 to {} copies", MAX_HEADER_COPIES)),
+    }
+}
 
-            let pixel_count = dimensions.0 as u64 * dimensions.1 as u64;
+fn print_metadata(header: &VersionedHeader) {
+    match header.metadata() {
+        Some(metadata) => {
+            for (key, value) in metadata {
+                println!("{}={}", key, value);
+            }
+        }
+        None => eprintln!("The image carries no metadata"),
+    }
+}
 
-            let image: &mut dyn PngImage = convert_dynamic_image_to_png_image(&mut image).unwrap();
+fn print_preamble(preamble: &Preamble) {
+    eprintln!("--------------------------");
+    println!("Success: {}", "yes".green());
+    println!("Header Version: V{}", preamble.version);
+    match preamble.max_data_len() {
+        Some(max_data_len) => println!("Byte Length: at most {}", format_byte_size(max_data_len)),
+        None => println!(
+            "Byte Length: at least {}",
+            format_byte_size(u16::MAX as u64 * 1024)
+        ),
+    }
+}
 
+///
+/// Writes the payload to STDOUT, or only the selected record of it, or unpacks its files into a directory
+fn write_decoded_payload(
+    header: &VersionedHeader,
+    payload: &[u8],
+    extract_all: Option<String>,
+    record: Option<usize>,
+) {
+    if let Some(out_dir) = extract_all {
+        if header.container() != Some(PayloadContainer::Archive) {
             eprintln!(
-                "Loaded image. Contains {} × {} = {}px",
-                dimensions.0, dimensions.1, pixel_count
+                "{}",
+                "The payload is not an archive of files. Embed them with --message-file".red()
             );
+            exit(1);
+        }
+        match archive::extract_all(payload, Path::new(&out_dir)) {
+            Ok(paths) => {
+                for path in paths {
+                    info!(path = %path.display(), "File extracted");
+                }
+            }
+            Err(err) => {
+                eprintln!("{}", err.red());
+                exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(index) = record {
+        let Some(PayloadContainer::Records { length_endian, .. }) = header.container() else {
             eprintln!(
-                "Channels: {}, Bytes per Channel: {}",
-                channels, bytes_per_channel
+                "{}",
+                "The payload does not consist of records. Embed it with --record".red()
             );
+            exit(1);
+        };
+        match records::record_at(payload, length_endian, index) {
+            Ok(record) => stdout().write_all(&record).unwrap(),
+            Err(err) => {
+                eprintln!("{}", err.red());
+                exit(1);
+            }
+        }
+        return;
+    }
 
-            let mut message_buf: Vec<u8> = Vec::new();
-            let message_copy_result = match message {
-                Some(val) => {
-                    let data = val.as_bytes();
-                    message_buf.write(data).map_err(|err| format!("{}", err))
-                }
-                None => {
-                    eprintln!("Waiting for stdin to finish. If you are stuck here, you forgot to pipe a message. You can get a message in by:");
-                    eprintln!("- Piping a file or text, e.g. cat mySecret.tgz | ...");
-                    eprintln!("- Typing the message now, then sending EOF (usually Ctrl-D)");
-                    eprintln!("Alternatively, provide the message via the --message option");
-                    eprintln!("Ctrl-C to abort.");
-                    io::stdin()
-                        .read_to_end(&mut message_buf)
-                        .map_err(|err| format!("{}", err.to_string().red()))
-                }
-            };
-
-            let buf_len: usize = message_copy_result.unwrap();
-            eprintln!("Message received and is {} bytes long", buf_len);
-
-            // Define a Header
-            let header = generate_v1_header(pixel_count, buf_len as u64, color_space).unwrap();
-            let header_binary = {
-                let mut as_raw_header: HeaderRaw = header.try_into().unwrap();
-
-                let mut as_binary_data = Vec::new();
-                // Magic
-                as_binary_data.push(as_raw_header.magic);
-                // Header Len
-                as_binary_data.push((as_raw_header.header_len >> 8 & 0xFF) as u8);
-                as_binary_data.push((as_raw_header.header_len & 0xFF) as u8);
-                // Data
-                as_binary_data.append(&mut as_raw_header.data);
-                // CRC
-                for i in 0..4 {
-                    as_binary_data.push((as_raw_header.crc >> ((3 - i) * 8) & 0xFF) as u8)
-                }
-                //
-                as_binary_data
-            };
-
-            let (write_mask, start_offset) = match header {
-                header::VersionedHeader::V1 {
-                    stuffing_opts,
-                    data_mask,
-                    data_len,
-                } => match stuffing_opts {
-                    header::V1DataStuffingOptions::None { start_offset } => {
-                        assert_eq!(data_len, buf_len as u64);
-                        (data_mask, start_offset)
-                    }
-                },
-            };
-
-            image.write_data_with_mask(&header_binary, 0b1u64 << 63 >> 7, 0);
-            image.write_data_with_mask(&message_buf, write_mask, start_offset as usize);
-
-            let mut data = image.save_to_png_buffer().unwrap();
-
-            let out = match out {
-                Some(x) => {
-                    if x == "-" {
-                        None
-                    } else {
-                        Some(x)
-                    }
-                }
-                None => None,
-            };
-
-            eprint!("len: {}", data.len());
-
-            match out {
-                None => stdout().write_all(&mut data).unwrap(),
-                Some(path) => {
-                    let file = File::create(path).unwrap();
-                    let mut writer = BufWriter::new(file);
-                    writer.write_all(&mut data).unwrap();
-                }
+    stdout().write_all(payload).unwrap();
+}
+
+///
+/// Exits unless the payload carries a valid signature by the key. Without a key, nothing is checked.
+fn enforce_signature(
+    verifying_key: Option<&VerifyingKey>,
+    header: &VersionedHeader,
+    payload: &[u8],
+) {
+    if let Some(key) = verifying_key {
+        match verify_signature(key, header, payload) {
+            Ok(()) => eprintln!("Signature is {}", "valid".green()),
+            Err(err) => {
+                eprintln!("Signature is {}: {}", "invalid".red(), err);
+                exit(1);
             }
+        }
+    }
+}
+
+///
+/// The report goes to STDERR, so a dry run never writes anything where the payload would go
+fn print_dry_run_summary(header: &VersionedHeader) {
+    let summary = PayloadSummary::from_header(header);
+    eprintln!(
+        "Byte Length: {} ({} bytes)",
+        format_byte_size(summary.data_len),
+        summary.data_len
+    );
+    eprintln!(
+        "Pixels used: {} ({} bits per pixel)",
+        summary.pixels_used, summary.data_bits_per_pixel
+    );
+    let flags = summary.flags();
+    if flags.is_empty() {
+        eprintln!("Flags: none");
+    } else {
+        eprintln!("Flags: {}", flags.join(", "));
+    }
+    // The payload is stored as given, no header records compression, encryption or FEC
+    eprintln!("Compressed: no");
+    eprintln!("Encrypted: no");
+    eprintln!("FEC: no");
+    eprintln!(
+        "Estimated decode time: {:.3}s",
+        summary.estimated_read_time().as_secs_f64()
+    );
+}
+
+///
+/// Logs go to STDERR, as STDOUT may carry the image or payload.
+/// `RUST_LOG` directives take precedence over the level picked by `--verbose`/`--quiet`.
+/// Reports asked for by a flag, e.g. the timings of `--benchmark` or the change rate of `--report-change-rate`,
+/// bypass the logger and are printed with `eprintln!`, so `--quiet` does not hide them.
+fn init_logging(verbose: bool, quiet: bool) {
+    let level = match (verbose, quiet) {
+        (true, _) => LevelFilter::DEBUG,
+        (_, true) => LevelFilter::ERROR,
+        _ => LevelFilter::INFO,
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
 
-            eprintln!("...done")
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_target(false)
+        .without_time()
+        .init();
+}
+
+fn main() {
+    let cli = Cli::parse();
+    init_logging(cli.verbose, cli.quiet);
+    let memory_limit = cli.max_memory.map(MemoryLimit::from_mib);
+    let stdin = StdinOptions {
+        interactive: !cli.no_interactive,
+        limit: cli.stdin_limit,
+        message_size: None,
+    };
+    // A pool of our own, so the thread count does not leak into the global pool
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.threads)
+        .build()
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to start {} threads: {}", cli.threads, err);
+            std::process::exit(1);
+        });
+
+    pool.install(|| {
+        // The seed is per thread, and the command runs on a thread of the pool
+        if cli.deterministic {
+            enable_deterministic_mode(DETERMINISTIC_SEED);
         }
-        Commands::Decode { source } => {
-            let mut image = (match source {
-                Some(path) => {
-                    let source_path = Path::new(path.as_str());
-
-                    if !source_path.exists() {
-                        eprintln!("Provided path {} does not exist", path.yellow());
-                        panic!("Path does not exist")
-                    }
-                    image::open(path)
-                },
-                None => {
-                    let mut message_buf = Vec::new();
-                    eprintln!("Waiting for stdin to finish. If you are stuck here, you forgot to pipe a PNG file. You can fix this by");
-                    eprintln!("- Piping a PNG file, e.g. cat imgWithSecret.png | ...");
-                    eprintln!("Alternatively, provide the source via the --source option");
-                    eprintln!("Ctrl-C to abort.");
-                    io::stdin()
-                        .read_to_end(&mut message_buf)
-                        .map_err(|err| format!("{}", err.to_string().red()))
-                        .unwrap();
-                    image::load_from_memory_with_format(&message_buf, image::ImageFormat::Png)
-                }
-            }).map_err(|x| x.to_string()).unwrap();
-
-            let image: &mut dyn PngImage = convert_dynamic_image_to_png_image(&mut image).unwrap();
-
-            let header = match try_get_header(image) {
-                Ok(val) => val,
-                Err(err) => {
-                    eprintln!("Failed to parse Header: {}", err);
+
+        match cli.command {
+            Commands::Encode {
+                source,
+                message,
+                file,
+                size,
+                message_file,
+                record,
+                length_endian,
+                reserve,
+                out,
+                avoid_mask,
+                format,
+                data_uri,
+                convert_8bit,
+                downcast_dither,
+                strict,
+                verify_cover_lossless,
+                crc_spec,
+                scatter_header,
+                preamble,
+                gray,
+                span,
+                password,
+                decoy_password,
+                decoy_message,
+                decoy_file,
+                salt,
+                channel,
+                compare_covers,
+                target_psnr,
+                allow_high_bits,
+                allow_destructive,
+                channel_bits,
+                no_randomize_offset,
+                offset_bias,
+                clean_slate,
+                dither_compensate,
+                preserve_luma,
+                alpha_only,
+                alpha_bits,
+                header_copies,
+                ycbcr,
+                y_bits,
+                complexity_weighted,
+                complexity_bits,
+                color_key,
+                color_key_tolerance,
+                color_key_invert,
+                emit_sidecar,
+                emit_report,
+                report_change_rate,
+                print_budget,
+                benchmark,
+                max_bits_changed,
+                visible_watermark,
+                watermark_font,
+                meta,
+                key_id,
+                sign,
+                params,
+                page,
+                profile,
+                tamper_evident,
+            } => {
+                let _span = info_span!("encode").entered();
+                if size.is_some() && file.as_deref().is_some_and(|path| !is_stdin_path(path)) {
+                    eprintln!("{}", "--size only applies to messages read from STDIN".red());
+                    exit(1);
+                }
+                let stdin = StdinOptions {
+                    message_size: size,
+                    ..stdin
+                };
+                let params = load_params(params);
+                let crc_spec = crc_spec.unwrap_or_default();
+                let downcast = convert_8bit.then_some(downcast_dither);
+                let profile_options = profile.map(CoverProfile::options).unwrap_or_default();
+                let strict = strict || profile_options.strict;
+                let preserve_luma = preserve_luma || profile_options.preserve_luma;
+                let channel_bits = [channel_bits, profile_options.channel_bits].concat();
+                let max_bits_per_channel = match (allow_destructive, allow_high_bits) {
+                    (true, _) => None,
+                    (false, true) => Some(HIGH_BITS_PER_CHANNEL),
+                    (false, false) => Some(profile_options.max_bits_per_channel),
+                };
+                // Higher bits are never scrubbed, that would visibly alter the image
+                let clean_slate_bits = max_bits_per_channel
+                    .map_or(DEFAULT_MAX_BITS_PER_CHANNEL, |bits| {
+                        bits.min(DEFAULT_MAX_BITS_PER_CHANNEL)
+                    });
+                let out = out.filter(|x| !is_stdout_path(x));
+                let format = format
+                    .or_else(|| out.as_deref().and_then(OutputFormat::from_path))
+                    .unwrap_or(OutputFormat::Png);
+                let file_name = file
+                    .as_deref()
+                    .filter(|path| !is_stdin_path(path))
+                    .and_then(|path| Path::new(path).file_name())
+                    .map(|name| name.to_string_lossy().into_owned());
+                let metadata: Option<BTreeMap<String, String>> =
+                    (!meta.is_empty()).then(|| meta.into_iter().collect());
+
+                if !span.is_empty() {
+                    let out_dir = out.unwrap_or_else(|| ".".to_string());
+                    let mut covers: Vec<DynamicImage> = span
+                        .iter()
+                        .map(|path| {
+                            load_cover(
+                                &read_source(path, stdin, memory_limit),
+                                format,
+                                downcast,
+                                strict,
+                                verify_cover_lossless,
+                                memory_limit,
+                            )
+                        })
+                        .collect();
+                    let message_buf = read_message(message, file.as_deref(), stdin, memory_limit);
+
+                    let cover_buffers: Vec<u64> = covers
+                        .iter()
+                        .map(|cover| cover.as_bytes().len() as u64)
+                        .collect();
+                    enforce_memory_limit(
+                        memory_limit,
+                        MemoryEstimate {
+                            image_buffer: cover_buffers.iter().sum(),
+                            payload: message_buf.len() as u64,
+                            output: cover_buffers.iter().copied().max().unwrap_or(0),
+                            ..Default::default()
+                        },
+                    );
+
+                    let pixel_counts: Vec<u64> = covers
+                        .iter()
+                        .map(|cover| cover.width() as u64 * cover.height() as u64)
+                        .collect();
+                    let chunks = split_payload(&message_buf, &pixel_counts);
+                    let payload_id: u64 = tool_rng().gen();
+                    let payload_crc = crc_spec.checksum(&message_buf);
+
+                    for (chunk_index, ((path, cover), chunk)) in
+                        span.iter().zip(covers.iter_mut()).zip(chunks).enumerate()
+                    {
+                        let pixel_count = cover.width() as u64 * cover.height() as u64;
+                        let mut extensions = vec![HeaderExtension::Span(SpanInfo {
+                            payload_id,
+                            chunk_index: chunk_index as u32,
+                            chunk_count: span.len() as u32,
+                            payload_crc,
+                        })];
+                        extensions.extend(file_name.clone().map(HeaderExtension::FileName));
+                        extensions.extend(metadata.clone().map(HeaderExtension::Metadata));
+                        extensions.extend(key_id.clone().map(HeaderExtension::KeyId));
+                        let header = generate_header(
+                            pixel_count,
+                            chunk,
+                            cover.color(),
+                            crc_spec,
+                            None,
+                            scatter_header,
+                            1,
+                            max_bits_per_channel,
+                            extensions,
+                            Some(OffsetBias::Uniform),
+                        )
+                        .unwrap_or_else(|err| {
+                            eprintln!("Chunk for {}: {}", path.yellow(), err.red());
+                            exit(1);
+                        });
+
+                        let image: &mut dyn PngImage =
+                            convert_dynamic_image_to_png_image(cover).unwrap();
+                        if clean_slate {
+                            scrub_stale_payload(image, clean_slate_bits);
+                        }
+                        if let Err(err) = write_payload(image, &header, chunk, None) {
+                            eprintln!("{}", err.red());
+                            exit(1);
+                        }
+                        let data = image.save_to_buffer(format.image_output_format()).unwrap();
+
+                        let stem = Path::new(path)
+                            .file_stem()
+                            .map(|stem| stem.to_string_lossy().into_owned())
+                            .unwrap_or_else(|| format!("span{}", chunk_index));
+                        let out_path = Path::new(out_dir.as_str()).join(format!(
+                            "{}.{}",
+                            stem,
+                            format.extension()
+                        ));
+                        let written = fs::OpenOptions::new()
+                            .write(true)
+                            .create_new(true)
+                            .open(&out_path)
+                            .and_then(|mut file| file.write_all(&data));
+                        if let Err(err) = written {
+                            eprintln!("{}", cannot_write(out_path.display(), &err));
+                            exit(1);
+                        }
+                        info!(
+                            chunk = chunk_index + 1,
+                            chunks = span.len(),
+                            bytes = chunk.len(),
+                            path = %out_path.display(),
+                            "Chunk written"
+                        );
+                    }
+                    return;
+                }
+
+                let source = source.unwrap();
+                if is_stdin_path(&source)
+                    && message.is_none()
+                    && file.as_deref().map_or(message_file.is_empty(), is_stdin_path)
+                {
+                    eprintln!(
+                        "{}",
+                        "STDIN can only carry the image or the message. Provide the message via --message or --file"
+                            .red()
+                    );
                     exit(1);
                 }
-            };
+                let source_data = read_source(&source, stdin, memory_limit);
+                let tiff = Some(&source_data).filter(|data| is_tiff(data));
+                if tiff.is_some() || page.is_some() {
+                    let unsupported = [
+                        ("--profile", profile.is_some()),
+                        ("--convert-8bit", convert_8bit),
+                        ("--avoid-mask", avoid_mask.is_some()),
+                        ("--scatter-header", scatter_header),
+                        ("--password", password.is_some()),
+                        ("--channel", channel != EmbedChannel::default()),
+                        ("--compare-covers", compare_covers),
+                        ("--clean-slate", clean_slate),
+                        ("--dither-compensate", dither_compensate),
+                        ("--preserve-luma", preserve_luma),
+                        ("--color-key", color_key.is_some()),
+                        ("--emit-sidecar", emit_sidecar.is_some()),
+                        ("--emit-report", emit_report.is_some()),
+                        ("--report-change-rate", report_change_rate),
+                        ("--max-bits-changed", max_bits_changed.is_some()),
+                        ("--channel-bits", !channel_bits.is_empty()),
+                        ("--alpha-only", alpha_only),
+                        ("--ycbcr", ycbcr),
+                        ("--complexity-weighted", complexity_weighted),
+                        ("--header-copies", header_copies > 1),
+                        ("--preamble", preamble),
+                        ("--gray", gray),
+                        ("--tamper-evident", tamper_evident),
+                        ("--benchmark", benchmark),
+                        ("--visible-watermark", visible_watermark.is_some()),
+                        ("--offset-bias", offset_bias != OffsetBias::default()),
+                        ("--sign", sign.is_some()),
+                        ("--params", params.is_some()),
+                        ("--data-uri", data_uri),
+                    ];
+                    if let Some((flag, _)) = unsupported.iter().find(|(_, used)| *used) {
+                        eprintln!(
+                            "{}",
+                            format!("{} is not supported for TIFF sources", flag).red()
+                        );
+                        exit(1);
+                    }
+                    let mut pages = tiff
+                        .ok_or_else(|| "--page needs a TIFF source".to_string())
+                        .and_then(|data| read_tiff_pages(data))
+                        .unwrap_or_else(|err| {
+                            eprintln!("Failed to load the TIFF {}: {}", source.yellow(), err.red());
+                            exit(1);
+                        });
+                    info!(pages = pages.len(), "Loaded TIFF");
+                    if verify_cover_lossless {
+                        if let Err(err) = check_pages_lossless(&pages) {
+                            eprintln!("{}", err.red());
+                            exit(1);
+                        }
+                    }
+                    let message_buf = read_message(message, file.as_deref(), stdin, memory_limit);
+                    let extensions = file_name
+                        .map(HeaderExtension::FileName)
+                        .into_iter()
+                        .chain(metadata.map(HeaderExtension::Metadata))
+                        .chain(key_id.map(HeaderExtension::KeyId))
+                        .collect();
+
+                    let data = write_pages_payload(
+                        &mut pages,
+                        page,
+                        &message_buf,
+                        crc_spec,
+                        max_bits_per_channel,
+                        extensions,
+                    )
+                    .and_then(|()| write_tiff_pages(&pages))
+                    .unwrap_or_else(|err| {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    });
+                    write_output(data, format, false, out);
+                    return;
+                }
 
-            let payload = match header {
-                VersionedHeader::V1 {
-                    stuffing_opts,
-                    data_mask,
-                    data_len,
-                } => {
-                    let start_offset = match stuffing_opts {
-                        header::V1DataStuffingOptions::None { start_offset } => start_offset,
+                let channel = match is_gif(&source_data) {
+                    true if channel == EmbedChannel::Pixels => EmbedChannel::GifPalette,
+                    _ => channel,
+                };
+                if tamper_evident && channel != EmbedChannel::Pixels {
+                    eprintln!(
+                        "{}",
+                        "--tamper-evident is only available for payloads in the pixels".red()
+                    );
+                    exit(1);
+                }
+                if channel == EmbedChannel::GifPalette {
+                    if format != OutputFormat::Png || data_uri {
+                        eprintln!(
+                            "{}",
+                            "The GIF palette channel writes a GIF, --format and --data-uri are not available"
+                                .red()
+                        );
+                        exit(1);
+                    }
+                    let message_buf = read_message(message, file.as_deref(), stdin, memory_limit);
+                    enforce_memory_limit(
+                        memory_limit,
+                        MemoryEstimate {
+                            image_file: source_data.len() as u64,
+                            payload: message_buf.len() as u64,
+                            output: source_data.len() as u64,
+                            ..Default::default()
+                        },
+                    );
+
+                    match write_gif_payload(&source_data, &message_buf, crc_spec) {
+                        Ok(data) => write_output(data, format, false, out),
+                        Err(err) => {
+                            eprintln!("{}", err.red());
+                            exit(1);
+                        }
+                    }
+                    return;
+                }
+                if channel == EmbedChannel::Trns {
+                    if format != OutputFormat::Png {
+                        eprintln!(
+                            "{}",
+                            "The tRNS channel is only available for PNG output".red()
+                        );
+                        exit(1);
+                    }
+                    let cover = source_data;
+                    let message_buf = read_message(message, file.as_deref(), stdin, memory_limit);
+                    enforce_memory_limit(
+                        memory_limit,
+                        MemoryEstimate {
+                            image_file: cover.len() as u64,
+                            payload: message_buf.len() as u64,
+                            output: cover.len() as u64,
+                            ..Default::default()
+                        },
+                    );
+
+                    match write_trns_payload(&cover, &message_buf, crc_spec) {
+                        Ok(data) => write_output(data, format, data_uri, out),
+                        Err(err) => {
+                            eprintln!("{}", err.red());
+                            exit(1);
+                        }
+                    }
+                    return;
+                }
+
+                let mut image = load_cover(
+                    &source_data,
+                    format,
+                    downcast,
+                    strict,
+                    verify_cover_lossless,
+                    memory_limit,
+                );
+                if let Some(text) = visible_watermark {
+                    let font = watermark_font
+                        .map(|path| {
+                            fs::read(&path).unwrap_or_else(|err| {
+                                eprintln!("{}", cannot_read(path.yellow(), &err));
+                                exit(1);
+                            })
+                        })
+                        .unwrap_or_else(|| visible_watermark::DEFAULT_FONT.to_vec());
+                    match visible_watermark::draw_visible_watermark(&mut image, &text, &font) {
+                        Ok(changed) => debug!(changed, "Drew the visible watermark"),
+                        Err(err) => {
+                            eprintln!("{}", err.red());
+                            exit(1);
+                        }
+                    }
+                }
+                let image_buffer = image.as_bytes().len() as u64;
+
+                let color_space = image.color();
+                let channels = image.color().channel_count();
+                let bytes_per_channel = image.color().bytes_per_pixel() / channels;
+                let dimensions = image.dimensions();
+
+                let pixel_count = dimensions.0 as u64 * dimensions.1 as u64;
+                let avoid_mask = load_avoid_mask(avoid_mask, dimensions);
+                if clean_slate {
+                    scrub_stale_payload(
+                        convert_dynamic_image_to_png_image(&mut image).unwrap(),
+                        clean_slate_bits,
+                    );
+                }
+                // The sweep embeds into copies of the untouched cover
+                let cover = compare_covers.then(|| image.clone());
+                let mut original = (dither_compensate
+                    || preserve_luma
+                    || emit_report.is_some()
+                    || report_change_rate)
+                    .then(|| image.clone());
+
+                let image: &mut dyn PngImage =
+                    convert_dynamic_image_to_png_image(&mut image).unwrap();
+                let color_key = color_key.map(|key| ColorKey {
+                    tolerance: color_key_tolerance,
+                    invert: color_key_invert,
+                    ..key
+                });
+                // The pixels matching the color key are placed like the ones left by an avoid mask
+                let avoid_mask = match color_key {
+                    Some(key) => Some(key.selection(&image.rgba8_pixels())),
+                    None => avoid_mask,
+                };
+
+                info!(
+                    width = dimensions.0,
+                    height = dimensions.1,
+                    "Loaded image. Contains {} × {} = {}px",
+                    dimensions.0,
+                    dimensions.1,
+                    pixel_count
+                );
+                debug!(channels, bytes_per_channel, "Pixel layout");
+
+                let message_buf = match message_file.is_empty() {
+                    true => read_message(message, file.as_deref(), stdin, memory_limit),
+                    false => pack_files(&message_file).unwrap_or_else(|err| {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    }),
+                };
+                let message_buf = match record {
+                    true => records::frame(&message_buf, length_endian).unwrap_or_else(|err| {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    }),
+                    false => message_buf,
+                };
+                enforce_memory_limit(
+                    memory_limit,
+                    MemoryEstimate {
+                        image_buffer,
+                        payload: message_buf.len() as u64,
+                        output: image_buffer,
+                        ..Default::default()
+                    },
+                );
+
+                // Only set for payloads described by a single header
+                let mut written_header = None;
+                if let Some(password) = &password {
+                    let decoy = match (decoy_message, &decoy_file) {
+                        (Some(val), _) => Some(val.into_bytes()),
+                        (None, Some(path)) => Some(fs::read(path).unwrap_or_else(|err| {
+                            eprintln!("{}", cannot_read(path.yellow(), &err));
+                            exit(1);
+                        })),
+                        (None, None) => None,
+                    };
+                    let mut entries = vec![(password.as_str(), message_buf.as_slice())];
+                    if let (Some(decoy_password), Some(decoy)) = (&decoy_password, &decoy) {
+                        entries.push((decoy_password.as_str(), decoy.as_slice()));
+                    }
+
+                    if let Err(err) = write_password_payloads(
+                        image,
+                        &entries,
+                        crc_spec,
+                        max_bits_per_channel,
+                        salt,
+                    ) {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    }
+                } else if let Some(params) = &params {
+                    let extensions = file_name
+                        .map(HeaderExtension::FileName)
+                        .into_iter()
+                        .chain(metadata.map(HeaderExtension::Metadata))
+                        .chain(key_id.map(HeaderExtension::KeyId))
+                        .collect();
+                    if let Err(err) = write_keyed_payload(
+                        image,
+                        params,
+                        &message_buf,
+                        extensions,
+                        max_bits_per_channel,
+                    ) {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    }
+                } else {
+                    // Define a Header
+                    let extensions = file_name
+                        .map(HeaderExtension::FileName)
+                        .into_iter()
+                        .chain(metadata.map(HeaderExtension::Metadata))
+                        .chain(key_id.map(HeaderExtension::KeyId))
+                        .chain(preamble.then_some(HeaderExtension::Preamble))
+                        .chain(gray.then_some(HeaderExtension::GrayCode))
+                        .chain(tamper_evident.then_some(HeaderExtension::TamperCopy(0)))
+                        .chain(
+                            (!message_file.is_empty())
+                                .then_some(HeaderExtension::Container(PayloadContainer::Archive)),
+                        )
+                        .chain(record.then_some(HeaderExtension::Container(
+                            PayloadContainer::Records {
+                                count: 1,
+                                length_endian,
+                            },
+                        )))
+                        .chain(reserve.map(HeaderExtension::Reserved))
+                        .chain(sign.as_deref().map(|path| {
+                            let key = fs::read(path)
+                                .map_err(|x| describe_io_error(&x))
+                                .and_then(|data| load_signing_key(&data))
+                                .unwrap_or_else(|err| {
+                                    eprintln!(
+                                        "Failed to read the signing key {}: {}",
+                                        path.yellow(),
+                                        err.red()
+                                    );
+                                    exit(1);
+                                });
+                            info!(public_key = public_key_base64(&key), "Signing the payload");
+                            sign_payload(&key, &message_buf)
+                        }))
+                        .collect();
+                    let header = generate_header(
+                        pixel_count,
+                        &message_buf,
+                        color_space,
+                        crc_spec,
+                        avoid_mask.as_ref(),
+                        scatter_header,
+                        header_copies,
+                        // The data mask is dropped again when the payload moves into the luma
+                        // or is weighted by complexity
+                        max_bits_per_channel.filter(|_| !ycbcr && !complexity_weighted),
+                        extensions,
+                        (!no_randomize_offset && !record).then_some(offset_bias),
+                    )
+                    .unwrap_or_else(|err| {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    });
+                    let header = match record {
+                        true => records::with_room_for_records(header, color_space),
+                        false => header,
+                    };
+                    let channel_bits = match alpha_only {
+                        true => alpha_channel_bits(alpha_bits, color_space).unwrap_or_else(|err| {
+                            eprintln!("{}", err.red());
+                            exit(1);
+                        }),
+                        false => channel_bits,
+                    };
+                    let header = match channel_bits.is_empty() {
+                        true => header,
+                        false => with_channel_bits(
+                            header,
+                            &channel_bits,
+                            color_space,
+                            max_bits_per_channel,
+                        )
+                        .unwrap_or_else(|err| {
+                            eprintln!("{}", err.red());
+                            exit(1);
+                        }),
+                    };
+                    let header = match (color_key, &avoid_mask) {
+                        (Some(key), Some(selection)) => {
+                            let start_offset = header.start_offset();
+                            header.with_stuffing_opts(V1DataStuffingOptions::ColorKey {
+                                start_offset,
+                                key,
+                                selection_checksum: selection.checksum(),
+                            })
+                        }
+                        _ => header,
+                    };
+                    let header = match ycbcr {
+                        true => with_luma_placement(image, header, y_bits, !no_randomize_offset)
+                            .unwrap_or_else(|err| {
+                                eprintln!("{}", err.red());
+                                exit(1);
+                            }),
+                        false => header,
+                    };
+                    let header = match complexity_weighted {
+                        true => with_complexity_placement(
+                            image,
+                            header,
+                            complexity_bits,
+                            !no_randomize_offset,
+                        )
+                        .unwrap_or_else(|err| {
+                            eprintln!("{}", err.red());
+                            exit(1);
+                        }),
+                        false => header,
+                    };
+                    debug!(?header, "Generated header");
+
+                    let header = match &cover {
+                        Some(cover) => {
+                            let (header, results) = sweep_bits_per_pixel(
+                                cover,
+                                &header,
+                                &message_buf,
+                                format,
+                                target_psnr,
+                                max_bits_per_channel,
+                            )
+                            .unwrap_or_else(|err| {
+                                eprintln!("{}", err.red());
+                                exit(1);
+                            });
+                            for result in &results {
+                                debug!(
+                                    bits_per_pixel = result.bits_per_pixel,
+                                    psnr = format!("{:.2}", result.psnr),
+                                    file_size = result.file_size,
+                                    "Compared setting"
+                                );
+                            }
+                            info!(
+                                bits_per_pixel = header.data_mask().count_ones(),
+                                "Chose {} bits per pixel",
+                                header.data_mask().count_ones()
+                            );
+                            header
+                        }
+                        None => header,
                     };
 
-                    image.read_data_with_mask(data_mask, start_offset as usize, data_len as usize)
+                    if let Some(max_changed_bits) = max_bits_changed {
+                        match check_change_budget(
+                            image,
+                            &header,
+                            &message_buf,
+                            avoid_mask.as_ref(),
+                            max_changed_bits,
+                        ) {
+                            Ok(planned) => info!(
+                                changed_bits = planned.changed_bits,
+                                max_changed_bits, "Embedding stays within the change budget"
+                            ),
+                            Err(err) => {
+                                eprintln!("{}", err.red());
+                                exit(1);
+                            }
+                        }
+                    }
+                    let (written, timing) = Benchmark::measure(
+                        "embedding",
+                        message_buf.len() as u64,
+                        pixel_count,
+                        || {
+                            write_payload_with_stats(
+                                image,
+                                &header,
+                                &message_buf,
+                                avoid_mask.as_ref(),
+                            )
+                        },
+                    );
+                    let written = written.unwrap_or_else(|err| {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    });
+                    if benchmark {
+                        eprintln!("{}", timing);
+                    }
+                    if let Some(original) = &original {
+                        if dither_compensate {
+                            image.compensate_mean_shift(original.as_bytes(), header.data_mask());
+                        }
+                        if preserve_luma {
+                            image.preserve_luma(original.as_bytes(), header.data_mask());
+                        }
+                    }
+                    if let (true, Some(original)) = (report_change_rate, &mut original) {
+                        let cover = convert_dynamic_image_to_png_image(original).unwrap();
+                        match change_rate(cover, image, &header, avoid_mask.as_ref()) {
+                            Ok(rate) => eprintln!(
+                                "Change rate: {:.2}% ({} of {} used bits flipped in {} of {} pixels)",
+                                rate.rate() * 100.0,
+                                rate.changed_bits,
+                                rate.used_bits,
+                                written.changed_pixels,
+                                pixel_count
+                            ),
+                            Err(err) => {
+                                eprintln!("{}", err.red());
+                                exit(1);
+                            }
+                        }
+                    }
+                    if print_budget {
+                        match PixelBudget::new(&header, pixel_count) {
+                            Ok(budget) => eprintln!(
+                                "Pixel budget: {} pixels, {} for the header, {} for the payload from pixel {} at {} bits per pixel, {} free",
+                                budget.total_pixels,
+                                budget.header_pixels,
+                                budget.payload_pixels,
+                                budget.start_offset,
+                                budget.bits_per_pixel,
+                                budget.free_pixels
+                            ),
+                            Err(err) => {
+                                eprintln!("{}", err.red());
+                                exit(1);
+                            }
+                        }
+                    }
+
+                    if let Some(path) = &emit_sidecar {
+                        let written = Sidecar::from_header(&header).and_then(|sidecar| {
+                            fs::write(path, sidecar.to_json()).map_err(|x| describe_io_error(&x))
+                        });
+                        if let Err(err) = written {
+                            eprintln!(
+                                "Failed to write the sidecar {}: {}",
+                                path.yellow(),
+                                err.red()
+                            );
+                            exit(1);
+                        }
+                        info!(path, "Sidecar written");
+                    }
+                    written_header = Some(header);
                 }
-            };
 
-            stdout().write(&payload).unwrap();
-        }
-        Commands::Stat {} => {
-            let mut image = {
-                let mut message_buf = Vec::new();
-                eprintln!("Waiting for stdin to finish. If you are stuck here, you forgot to pipe a PNG file. You can fix this by");
-                eprintln!("- Piping a PNG file, e.g. cat imgWithSecret.png | ...");
-                eprintln!("Ctrl-C to abort.");
-                io::stdin()
-                    .read_to_end(&mut message_buf)
-                    .map_err(|err| format!("{}", err.to_string().red()))
+                let data = image.save_to_buffer(format.image_output_format()).unwrap();
+                if let (Some(path), Some(header), Some(original)) =
+                    (&emit_report, &written_header, &original)
+                {
+                    let written = EncodeReport::new(header, original, &data).and_then(|report| {
+                        fs::write(path, report.to_json()).map_err(|x| describe_io_error(&x))
+                    });
+                    if let Err(err) = written {
+                        eprintln!(
+                            "Failed to write the report {}: {}",
+                            path.yellow(),
+                            err.red()
+                        );
+                        exit(1);
+                    }
+                    info!(path, "Report written");
+                }
+                write_output(data, format, data_uri, out);
+            }
+            Commands::Decode {
+                source,
+                foreign,
+                verify_only,
+                avoid_mask,
+                dry_run,
+                span,
+                password,
+                sidecar,
+                print_meta,
+                params,
+                page,
+                align,
+                try_all,
+                verify_sig,
+                extract_all,
+                record,
+                benchmark,
+                check_tamper,
+                entry,
+                #[cfg(feature = "arboard")]
+                clipboard,
+            } => {
+                let _span = info_span!("decode").entered();
+                let params = load_params(params);
+                let verifying_key = verify_sig.map(|path| {
+                    fs::read(&path)
+                        .map_err(|x| describe_io_error(&x))
+                        .and_then(|data| load_verifying_key(&data))
+                        .unwrap_or_else(|err| {
+                            eprintln!(
+                                "Failed to read the public key {}: {}",
+                                path.yellow(),
+                                err.red()
+                            );
+                            exit(1);
+                        })
+                });
+                let sidecar = sidecar.map(|path| {
+                    fs::read_to_string(&path)
+                        .map_err(|x| describe_io_error(&x))
+                        .and_then(|json| Sidecar::from_json(&json))
+                        .and_then(|sidecar| sidecar.to_header())
+                        .unwrap_or_else(|err| {
+                            eprintln!(
+                                "Failed to read the sidecar {}: {}",
+                                path.yellow(),
+                                err.red()
+                            );
+                            exit(1);
+                        })
+                });
+                if !span.is_empty() {
+                    let chunks = span
+                        .iter()
+                        .map(|path| {
+                            let image_file = fs::metadata(path).map_or(0, |x| x.len());
+                            check_memory(
+                                memory_limit,
+                                &MemoryEstimate {
+                                    image_file,
+                                    ..Default::default()
+                                },
+                            )?;
+                            let mut image = fs::read(path)
+                                .map_err(|x| describe_io_error(&x))
+                                .and_then(|data| load_image_from_memory(&data, memory_limit))
+                                .map_err(|err| format!("Failed to load {}: {}", path, err))?;
+                            let image_buffer = image.as_bytes().len() as u64;
+                            let image: &mut dyn PngImage =
+                                convert_dynamic_image_to_png_image(&mut image).unwrap();
+                            let header = try_get_header(image).map_err(|err| {
+                                format!("Failed to parse Header of {}: {}", path, err)
+                            })?;
+                            check_memory(
+                                memory_limit,
+                                &MemoryEstimate {
+                                    image_buffer,
+                                    payload: header.data_len(),
+                                    ..Default::default()
+                                },
+                            )?;
+                            let chunk = read_payload(image, &header, None).map_err(|err| {
+                                format!("Failed to read chunk of {}: {}", path, err)
+                            })?;
+                            Ok((header, chunk))
+                        })
+                        .collect::<Result<Vec<_>, String>>();
+
+                    match chunks.and_then(join_chunks) {
+                        Ok(_) if verify_only => eprintln!("Payload is {}", "valid".green()),
+                        Ok(payload) => stdout().write_all(&payload).unwrap(),
+                        Err(err) if verify_only => {
+                            eprintln!("Payload is {}: {}", "invalid".red(), err);
+                            exit(1);
+                        }
+                        Err(err) => {
+                            eprintln!("Failed to read payload: {}", err);
+                            exit(1);
+                        }
+                    }
+                    return;
+                }
+
+                let data = (match source.filter(|path| !is_stdin_path(path)) {
+                    Some(path) => Ok(read_source(&path, stdin, memory_limit)),
+                    #[cfg(feature = "arboard")]
+                    None if clipboard => clipboard::read_system_clipboard_png(),
+                    None => read_stdin(StdinInput::Image, stdin),
+                })
+                .unwrap_or_else(|err| {
+                    eprintln!("Failed to load the image: {}", err.red());
+                    exit(1);
+                });
+                let data = select_archive_entry(data, entry.as_deref());
+
+                if is_tiff(&data) || page.is_some() {
+                    let unsupported = [
+                        ("--foreign", foreign.is_some()),
+                        ("--avoid-mask", avoid_mask.is_some()),
+                        ("--dry-run", dry_run),
+                        ("--password", password.is_some()),
+                        ("--sidecar", sidecar.is_some()),
+                        ("--print-meta", print_meta),
+                        ("--params", params.is_some()),
+                        ("--try-all", try_all),
+                        ("--verify-sig", verifying_key.is_some()),
+                        ("--benchmark", benchmark),
+                    ];
+                    if let Some((flag, _)) = unsupported.iter().find(|(_, used)| *used) {
+                        eprintln!(
+                            "{}",
+                            format!("{} is not supported for TIFF sources", flag).red()
+                        );
+                        exit(1);
+                    }
+                    let payload = read_tiff_pages(&data)
+                        .and_then(|mut pages| read_pages_payload(&mut pages, page));
+                    match payload {
+                        Ok(_) if verify_only => eprintln!("Payload is {}", "valid".green()),
+                        Ok(payload) => stdout().write_all(&payload).unwrap(),
+                        Err(err) if verify_only => {
+                            eprintln!("Payload is {}: {}", "invalid".red(), err);
+                            exit(1);
+                        }
+                        Err(err) => {
+                            eprintln!("Failed to read payload: {}", err);
+                            exit(1);
+                        }
+                    }
+                    return;
+                }
+
+                // Payloads in the tRNS chunk or a GIF palette are read without decoding the image
+                type ChannelReader = fn(&[u8]) -> Result<(VersionedHeader, Vec<u8>), String>;
+                let (channel_header, read_channel_payload): (_, ChannelReader) =
+                    match is_gif(&data) {
+                        true => (try_get_gif_header(&data), read_gif_payload),
+                        false => (try_get_trns_header(&data), read_trns_payload),
+                    };
+                if let (Err(err), true) = (&channel_header, is_gif(&data)) {
+                    eprintln!("Failed to read payload: {}", err);
+                    exit(1);
+                }
+                if let (Ok(header), None, None, None) =
+                    (channel_header, &password, &params, foreign)
+                {
+                    let unsupported = [
+                        ("--avoid-mask", avoid_mask.is_some()),
+                        ("--sidecar", sidecar.is_some()),
+                        ("--try-all", try_all),
+                        ("--align", align),
+                        ("--benchmark", benchmark),
+                        ("--check-tamper", check_tamper),
+                    ];
+                    if let Some((flag, _)) = unsupported.iter().find(|(_, used)| *used) {
+                        eprintln!(
+                            "{}",
+                            format!(
+                                "{} is not supported for payloads in the tRNS chunk or a GIF palette",
+                                flag
+                            )
+                            .red()
+                        );
+                        exit(1);
+                    }
+                    if dry_run {
+                        print_dry_run_summary(&header);
+                        return;
+                    }
+                    if print_meta {
+                        print_metadata(&header);
+                        return;
+                    }
+                    // The header is not trusted yet, it might claim a gigantic payload
+                    enforce_memory_limit(
+                        memory_limit,
+                        MemoryEstimate {
+                            image_file: data.len() as u64,
+                            payload: header.data_len(),
+                            ..Default::default()
+                        },
+                    );
+                    match read_channel_payload(&data) {
+                        Ok(_) if verify_only => eprintln!("Payload is {}", "valid".green()),
+                        Ok((header, payload)) => {
+                            enforce_signature(verifying_key.as_ref(), &header, &payload);
+                            write_decoded_payload(&header, &payload, extract_all, record);
+                        }
+                        Err(err) if verify_only => {
+                            eprintln!("Payload is {}: {}", "invalid".red(), err);
+                            exit(1);
+                        }
+                        Err(err) => {
+                            eprintln!("Failed to read payload: {}", err);
+                            exit(1);
+                        }
+                    }
+                    return;
+                }
+
+                let mut image = load_image_from_memory(&data, memory_limit).unwrap_or_else(|err| {
+                    eprintln!("Failed to load the image: {}", err.red());
+                    exit(1);
+                });
+                if align {
+                    let (region, cropped) = crop_to_embedded_image(&image).unwrap_or_else(|err| {
+                        eprintln!("Failed to locate the image: {}", err.red());
+                        exit(1);
+                    });
+                    info!(
+                        x = region.x,
+                        y = region.y,
+                        width = region.width,
+                        height = region.height,
+                        "Located the image in the screenshot"
+                    );
+                    image = cropped;
+                }
+
+                let avoid_mask = load_avoid_mask(avoid_mask, image.dimensions());
+                let image_buffer = image.as_bytes().len() as u64;
+                let image: &mut dyn PngImage =
+                    convert_dynamic_image_to_png_image(&mut image).unwrap();
+
+                if let Some(password) = password {
+                    match read_password_payload(image, &password) {
+                        Ok(_) if verify_only => eprintln!("Payload is {}", "valid".green()),
+                        Ok((_, payload)) => stdout().write_all(&payload).unwrap(),
+                        Err(err) => {
+                            eprintln!("Failed to read payload: {}", err);
+                            exit(1);
+                        }
+                    }
+                    return;
+                }
+
+                if let Some(params) = params {
+                    match read_keyed_payload(image, &params) {
+                        Ok(_) if verify_only => eprintln!("Payload is {}", "valid".green()),
+                        Ok((_, payload)) => stdout().write_all(&payload).unwrap(),
+                        Err(err) => {
+                            eprintln!("Failed to read payload: {}", err);
+                            exit(1);
+                        }
+                    }
+                    return;
+                }
+
+                if let Some(format) = foreign {
+                    match read_foreign_payload(image, format) {
+                        Ok(payload) => stdout().write_all(&payload).unwrap(),
+                        Err(err) => {
+                            eprintln!("Failed to read foreign payload: {}", err);
+                            exit(1);
+                        }
+                    }
+                    return;
+                }
+
+                if verify_only && sidecar.is_none() && !try_all {
+                    let report = verify(image, avoid_mask.as_ref());
+                    for (check, outcome) in &report.checks {
+                        match outcome {
+                            Outcome::Passed => eprintln!("{}: {}", check, "passed".green()),
+                            Outcome::Failed(err) => {
+                                eprintln!("{}: {} ({})", check, "failed".red(), err)
+                            }
+                            Outcome::Skipped(reason) => {
+                                eprintln!("{}: skipped ({})", check, reason)
+                            }
+                        }
+                    }
+                    if !report.is_valid() {
+                        eprintln!("Payload is {}", "invalid".red());
+                        exit(1);
+                    }
+                    eprintln!("Payload is {}", "valid".green());
+                    return;
+                }
+
+                let header = if try_all {
+                    try_all_headers(image).map(|found| {
+                        eprintln!("Found a {}", found);
+                        found.header
+                    })
+                } else {
+                    sidecar.map_or_else(|| try_get_header(image), Ok)
+                };
+                let header = match header {
+                    Ok(val) => val,
+                    Err(err) => {
+                        eprintln!("Failed to parse Header: {}", err);
+                        exit(1);
+                    }
+                };
+
+                if dry_run {
+                    print_dry_run_summary(&header);
+                    return;
+                }
+                if print_meta {
+                    print_metadata(&header);
+                    return;
+                }
+
+                // The header is not trusted yet, it might claim a gigantic payload
+                enforce_memory_limit(
+                    memory_limit,
+                    MemoryEstimate {
+                        image_buffer,
+                        payload: header.data_len(),
+                        ..Default::default()
+                    },
+                );
+
+                if check_tamper || header.tamper_copy_mask().is_some() {
+                    match (compare_copies(image, &header, avoid_mask.as_ref()), check_tamper) {
+                        (Ok(report), true) => {
+                            eprintln!("{}", report);
+                            if !report.is_intact() {
+                                exit(1);
+                            }
+                            return;
+                        }
+                        (Err(err), true) => {
+                            eprintln!("{}", err.red());
+                            exit(1);
+                        }
+                        (Ok(report), false) if !report.is_intact() => {
+                            eprintln!("{}", report.to_string().yellow())
+                        }
+                        // Reading the payload reports why it does not fit
+                        _ => {}
+                    }
+                }
+
+                if verify_only {
+                    match verify_payload(image, &header, avoid_mask.as_ref()) {
+                        Ok(()) => eprintln!("Payload is {}", "valid".green()),
+                        Err(err) => {
+                            eprintln!("Payload is {}: {}", "invalid".red(), err);
+                            exit(1);
+                        }
+                    }
+                    return;
+                }
+
+                let (payload, timing) = Benchmark::measure(
+                    "extracting",
+                    header.data_len(),
+                    image.pixel_count(),
+                    || read_payload(image, &header, avoid_mask.as_ref()),
+                );
+                let payload = match payload {
+                    Ok(val) => val,
+                    Err(err) => {
+                        eprintln!("Failed to read payload: {}", err);
+                        exit(1);
+                    }
+                };
+                if benchmark {
+                    eprintln!("{}", timing);
+                }
+                enforce_signature(verifying_key.as_ref(), &header, &payload);
+                write_decoded_payload(&header, &payload, extract_all, record);
+            }
+            Commands::Rewrap {
+                source,
+                out,
+                scatter_header,
+                header_copies,
+                bits_per_pixel,
+                no_randomize_offset,
+                allow_high_bits,
+            } => {
+                let _span = info_span!("rewrap").entered();
+                let out = out.filter(|x| !is_stdout_path(x));
+                let format = out
+                    .as_deref()
+                    .and_then(OutputFormat::from_path)
+                    .unwrap_or(OutputFormat::Png);
+                let data = read_source(&source, stdin, memory_limit);
+                let mut image = load_image_from_memory(&data, memory_limit).unwrap_or_else(|err| {
+                    eprintln!("Failed to load the image: {}", err.red());
+                    exit(1);
+                });
+                let image: &mut dyn PngImage =
+                    convert_dynamic_image_to_png_image(&mut image).unwrap();
+                let pixel_count = image.pixel_count();
+                let color_type = image.color_type();
+                let max_bits_per_channel = match allow_high_bits {
+                    true => Some(HIGH_BITS_PER_CHANNEL),
+                    false => Some(DEFAULT_MAX_BITS_PER_CHANNEL),
+                };
+
+                let rewrapped = rewrap(image, |payload, crc_spec, extensions| {
+                    let header = generate_header(
+                        pixel_count,
+                        payload,
+                        color_type,
+                        crc_spec,
+                        None,
+                        scatter_header,
+                        header_copies,
+                        max_bits_per_channel,
+                        extensions,
+                        (!no_randomize_offset).then_some(OffsetBias::Uniform),
+                    )?;
+                    let Some(bits_per_pixel) = bits_per_pixel else {
+                        return Ok(header);
+                    };
+                    // The start offset is kept, so the payload may only get denser
+                    let needed = header.data_mask().count_ones();
+                    if (bits_per_pixel as u32) < needed {
+                        return Err(format!(
+                            "The payload needs at least {} bits per pixel, more than --bits-per-pixel {}",
+                            needed, bits_per_pixel
+                        ));
+                    }
+                    let header = header.with_bits_per_pixel(bits_per_pixel, color_type);
+                    check_low_bits_only(header.data_mask(), color_type, max_bits_per_channel)?;
+                    Ok(header)
+                })
+                .unwrap_or_else(|err| {
+                    eprintln!("{}", err.red());
+                    exit(1);
+                });
+                info!(
+                    bits_per_pixel = rewrapped.data_mask().count_ones(),
+                    scattered_header = rewrapped.scatter_seed().is_some(),
+                    "Payload rewrapped"
+                );
+                let data = image
+                    .save_to_buffer(format.image_output_format())
                     .unwrap();
-                image::load_from_memory_with_format(&message_buf, image::ImageFormat::Png).unwrap()
-            };
-            let image: &mut dyn PngImage = convert_dynamic_image_to_png_image(&mut image).unwrap();
-
-            match try_get_header(image) {
-                Ok(val) => match val {
-                    VersionedHeader::V1 {
-                        stuffing_opts,
-                        data_mask,
-                        data_len,
-                    } => {
+                write_output(data, format, false, out);
+            }
+            Commands::AppendRecord {
+                source,
+                message,
+                file,
+                out,
+            } => {
+                let _span = info_span!("append-record").entered();
+                if is_stdin_path(&source) && message.is_none() && file.is_none() {
+                    eprintln!(
+                        "{}",
+                        "STDIN can only carry the image or the record. Provide the record via --message or --file"
+                            .red()
+                    );
+                    exit(1);
+                }
+                let out = out.filter(|x| !is_stdout_path(x));
+                let format = out
+                    .as_deref()
+                    .and_then(OutputFormat::from_path)
+                    .unwrap_or(OutputFormat::Png);
+                let data = read_source(&source, stdin, memory_limit);
+                let mut image = load_image_from_memory(&data, memory_limit).unwrap_or_else(|err| {
+                    eprintln!("Failed to load the image: {}", err.red());
+                    exit(1);
+                });
+                let image: &mut dyn PngImage =
+                    convert_dynamic_image_to_png_image(&mut image).unwrap();
+                let record = read_message(message, file.as_deref(), stdin, memory_limit);
+
+                let header = records::append_record(image, &record).unwrap_or_else(|err| {
+                    eprintln!("{}", err.red());
+                    exit(1);
+                });
+                if let Some(PayloadContainer::Records { count, .. }) = header.container() {
+                    info!(records = count, bytes = record.len(), "Record appended");
+                }
+                let data = image
+                    .save_to_buffer(format.image_output_format())
+                    .unwrap();
+                write_output(data, format, false, out);
+            }
+            Commands::Extract {
+                source,
+                out_dir,
+                avoid_mask,
+                no_sniff,
+            } => {
+                let _span = info_span!("extract").entered();
+                let source_path = Path::new(source.as_str());
+                let data = read_source(&source, stdin, memory_limit);
+                let mut image = load_image_from_memory(&data, memory_limit).unwrap_or_else(|err| {
+                    eprintln!("Failed to load the image: {}", err.red());
+                    exit(1);
+                });
+
+                let avoid_mask = load_avoid_mask(avoid_mask, image.dimensions());
+                let image_buffer = image.as_bytes().len() as u64;
+                let image: &mut dyn PngImage =
+                    convert_dynamic_image_to_png_image(&mut image).unwrap();
+
+                let header = match try_get_header(image) {
+                    Ok(val) => val,
+                    Err(err) => {
+                        eprintln!("Failed to parse Header: {}", err);
+                        exit(1);
+                    }
+                };
+                enforce_memory_limit(
+                    memory_limit,
+                    MemoryEstimate {
+                        image_buffer,
+                        payload: header.data_len(),
+                        ..Default::default()
+                    },
+                );
+
+                let out_dir = out_dir.unwrap_or_else(|| ".".to_string());
+                match extract_to_file(
+                    image,
+                    &header,
+                    avoid_mask.as_ref(),
+                    source_path,
+                    Path::new(out_dir.as_str()),
+                    !no_sniff,
+                ) {
+                    Ok(path) => info!(path = %path.display(), "Payload written"),
+                    Err(err) => {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    }
+                }
+            }
+            Commands::Stat {
+                meta,
+                free,
+                deep,
+                fast,
+                entry,
+                #[cfg(feature = "arboard")]
+                clipboard,
+            } => {
+                #[cfg(feature = "arboard")]
+                let message_buf = match clipboard {
+                    true => clipboard::read_system_clipboard_png(),
+                    false => read_stdin(StdinInput::Image, stdin),
+                };
+                #[cfg(not(feature = "arboard"))]
+                let message_buf = read_stdin(StdinInput::Image, stdin);
+                let message_buf = message_buf.unwrap_or_else(|err| {
+                    eprintln!("{}", err.red());
+                    exit(1);
+                });
+                let message_buf = select_archive_entry(message_buf, entry.as_deref());
+
+                // Each tRNS entry is a single 8-bit value, as is each byte of an unused GIF palette entry
+                let channel_header = match is_gif(&message_buf) {
+                    true => try_get_gif_header(&message_buf).map(|header| (header, "GIF palette")),
+                    false => try_get_trns_header(&message_buf).map(|header| (header, "tRNS")),
+                };
+                let header = match channel_header {
+                    Ok((header, channel)) => {
+                        let free_capacity = free
+                            .then(|| Err(format!("not available for the {} channel", channel)));
+                        let verified = deep.then(|| {
+                            match is_gif(&message_buf) {
+                                true => read_gif_payload(&message_buf),
+                                false => read_trns_payload(&message_buf),
+                            }
+                            .map(|_| ())
+                        });
+                        Ok((header, ColorType::L8, free_capacity, verified))
+                    }
+                    Err(err) if is_gif(&message_buf) => Err(err),
+                    Err(_) => {
+                        let mut image = load_image_from_memory(&message_buf, memory_limit)
+                            .unwrap_or_else(|err| {
+                                eprintln!("Failed to load the image: {}", err.red());
+                                exit(1);
+                            });
+                        let color_type = image.color();
+                        let image: &mut dyn PngImage =
+                            convert_dynamic_image_to_png_image(&mut image).unwrap();
+                        let preamble = fast.then(|| find_preamble(image)).flatten();
+                        if let Some(preamble) = preamble {
+                            print_preamble(&preamble);
+                            return;
+                        }
+                        if fast {
+                            info!("The image has no preamble, parsing the header");
+                        }
+                        stat_image(image, deep).map(|(header, report)| {
+                            let free_capacity = free.then(|| free_capacity(image, &header));
+                            let verified =
+                                report.map(|report| report.failure().map_or(Ok(()), Err));
+                            (header, color_type, free_capacity, verified)
+                        })
+                    }
+                };
+
+                match header {
+                    Ok((val, color_type, free_capacity, verified)) => {
                         eprintln!("--------------------------");
                         println!("Success: {}", "yes".green());
-                        match stuffing_opts {
-                            header::V1DataStuffingOptions::None { start_offset } => {
-                                println!("Pixel Offset: {}", start_offset)
+                        match val.stuffing_opts() {
+                            V1DataStuffingOptions::Trns { .. } => println!("Channel: tRNS"),
+                            V1DataStuffingOptions::GifPalette { .. } => {
+                                println!("Channel: GIF palette")
+                            }
+                            _ => {}
+                        }
+                        println!("Pixel Offset: {}", val.start_offset());
+                        if let Some(key) = val.color_key() {
+                            println!(
+                                "Color Key: #{} ± {}{}",
+                                key.color[..if key.compare_alpha { 4 } else { 3 }]
+                                    .iter()
+                                    .map(|channel| format!("{:02x}", channel))
+                                    .collect::<String>(),
+                                key.tolerance,
+                                if key.invert { " (inverted)" } else { "" }
+                            );
+                        }
+                        println!(
+                            "Byte Length: {} ({} bytes)",
+                            format_byte_size(val.data_len()),
+                            val.data_len()
+                        );
+                        match val.stuffing_opts() {
+                            V1DataStuffingOptions::Luma { y_bits, .. } => {
+                                println!("Luma Bits: {} (YCbCr)", y_bits)
                             }
-                        };
-                        println!("Byte Length: {}", data_len);
-                        println!("Data Mask: {:#066b}", data_mask);
-                        println!("         :  |0      |8      |16     |24     |32     |40     |48     |56     |64");
+                            V1DataStuffingOptions::Complexity { max_bits, .. } => {
+                                println!("Complexity Weighted: up to {} bits per channel", max_bits)
+                            }
+                            _ => {
+                                let (mask, ruler) = format_data_mask(val.data_mask(), color_type);
+                                println!("Data Mask: {}", mask);
+                                println!("         : {}", ruler);
+                            }
+                        }
+                        match (val.data_crc(), &verified) {
+                            (Some(data_crc), None) => {
+                                println!("Payload CRC: {:#010x} (not verified)", data_crc)
+                            }
+                            (Some(data_crc), Some(_)) => {
+                                println!("Payload CRC: {:#010x}", data_crc)
+                            }
+                            (None, _) => {}
+                        }
+                        match verified {
+                            Some(Ok(())) => println!("Payload: {}", "valid".green()),
+                            Some(Err(err)) => println!("Payload: {}, {}", "invalid".red(), err),
+                            None => {}
+                        }
+                        if let Some(file_name) = val.file_name() {
+                            println!("File Name: {}", file_name);
+                        }
+                        if let Some(key_id) = val.key_id() {
+                            println!("Key ID: {}", key_id);
+                        }
+                        match val.container() {
+                            Some(PayloadContainer::Archive) => {
+                                println!("Container: archive of files, see decode --extract-all")
+                            }
+                            Some(PayloadContainer::Records {
+                                count,
+                                length_endian,
+                            }) => println!(
+                                "Container: {} records with {}-endian lengths, see decode --record",
+                                count,
+                                length_endian.name()
+                            ),
+                            None => {}
+                        }
+                        if val
+                            .extensions()
+                            .iter()
+                            .any(|extension| matches!(extension, HeaderExtension::Reserved(_)))
+                        {
+                            println!(
+                                "Reserved: {} left for appends",
+                                format_byte_size(val.reserved_bytes())
+                            );
+                        }
+                        if val.is_gray_coded() {
+                            println!("Gray Coded: yes");
+                        }
+                        if let Some(mask) = val.tamper_copy_mask() {
+                            println!(
+                                "Tamper Copy: {:#018x}, see decode --check-tamper",
+                                mask
+                            );
+                        }
+                        if let Some(span) = val.span_info() {
+                            println!(
+                                "Span: chunk {} of {} (payload id {:#018x})",
+                                span.chunk_index + 1,
+                                span.chunk_count,
+                                span.payload_id
+                            );
+                        }
+                        match val.tool_version() {
+                            Some(version) => println!("Created by v{}", version),
+                            None => println!("Created by: unknown version"),
+                        }
+                        if meta {
+                            match val.metadata() {
+                                Some(metadata) => {
+                                    println!("Metadata:");
+                                    for (key, value) in metadata {
+                                        println!("  {}={}", key, value);
+                                    }
+                                }
+                                None => println!("Metadata: none"),
+                            }
+                        }
+                        match free_capacity {
+                            Some(Ok(bytes)) => {
+                                println!("Free Capacity: {}", format_byte_size(bytes))
+                            }
+                            Some(Err(err)) => println!("Free Capacity: unknown, {}", err),
+                            None => {}
+                        }
+                    }
+                    Err(err) => {
+                        println!("Success: {}", "no".red());
+                        println!("Reason: {}", err.italic());
+                    }
+                };
+            }
+            #[cfg(feature = "tui")]
+            Commands::Browse { dir } => {
+                let dir = dir.unwrap_or_else(|| ".".to_string());
+                if let Err(err) = browse::run_browse(Path::new(&dir)) {
+                    eprintln!("{}", err.red());
+                    exit(1);
+                }
+            }
+            Commands::ScanDir {
+                dir,
+                recursive,
+                json,
+            } => {
+                let _span = info_span!("scan-dir").entered();
+                match scan_dir(Path::new(&dir), recursive) {
+                    Ok(summary) if json => println!("{}", summary.to_json()),
+                    Ok(summary) => println!("{}", summary.to_text()),
+                    Err(err) => {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    }
+                }
+            }
+            Commands::Diffuse {
+                dir,
+                out,
+                bits,
+                recursive,
+            } => {
+                let _span = info_span!("diffuse").entered();
+                let entries = diffuse_dir(Path::new(&dir), Path::new(&out), bits, recursive)
+                    .unwrap_or_else(|err| {
+                        eprintln!("{}", err.red());
+                        exit(1);
+                    });
+                for entry in &entries {
+                    println!("{}", entry.to_text());
+                }
+            }
+            Commands::ExplainMask { mask, color_type } => {
+                let explanation = parse_mask(&mask).and_then(|mask| {
+                    parse_color_type(&color_type)
+                        .and_then(|color_type| explain_mask(mask, color_type))
+                });
+                match explanation {
+                    Ok(explanation) => println!("{}", explanation),
+                    Err(err) => {
+                        eprintln!("{}", err.red());
+                        exit(1);
                     }
-                },
-                Err(err) => {
-                    println!("Success: {}", "no".red());
-                    println!("Reason: {}", err.italic());
                 }
-            };
+            }
+            Commands::SimulateJpeg { source, quality } => {
+                let _span = info_span!("simulate-jpeg").entered();
+                let data = read_source(&source, stdin, memory_limit);
+                let image = load_image_from_memory(&data, memory_limit).unwrap_or_else(|err| {
+                    eprintln!("Failed to load the image: {}", err.red());
+                    exit(1);
+                });
+
+                let survival = simulate_jpeg(&image, quality).unwrap_or_else(|err| {
+                    eprintln!("{}", err.red());
+                    exit(1);
+                });
+                let intact = |intact: bool| match intact {
+                    true => "intact".green(),
+                    false => "destroyed".red(),
+                };
+                println!("JPEG Quality: {}", survival.quality);
+                println!(
+                    "Matching Bits: {} of {} ({:.2}%, guessing matches 50%)",
+                    survival.matching_bits,
+                    survival.payload_bits,
+                    survival.match_rate() * 100.0
+                );
+                println!(
+                    "Surviving Information: {:.2}%",
+                    survival.survival_rate() * 100.0
+                );
+                println!("Header: {}", intact(survival.header_intact));
+                println!("Payload: {}", intact(survival.payload_intact));
+                if !survival.payload_intact {
+                    println!(
+                        "{}",
+                        "JPEG rounds away the low bits the payload is stored in. Use a lossless format like PNG."
+                            .italic()
+                    );
+                }
+            }
+            Commands::Keygen { out } => {
+                let written = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&out)
+                    .and_then(|mut file| {
+                        file.write_all(EmbeddingParams::generate().to_json().as_bytes())
+                    });
+                if let Err(err) = written {
+                    eprintln!("{}", cannot_write(out.yellow(), &err));
+                    exit(1);
+                }
+                info!(path = out, "Key file written");
+            }
         }
-    }
+    });
 }
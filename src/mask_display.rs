@@ -0,0 +1,65 @@
+use colored::*;
+use image::ColorType;
+
+fn channel_names(color_type: ColorType) -> &'static [&'static str] {
+    match color_type.channel_count() {
+        1 => &["L"],
+        2 => &["L", "A"],
+        3 => &["R", "G", "B"],
+        _ => &["R", "G", "B", "A"],
+    }
+}
+
+///
+/// Renders the data mask as bits, split into the channels of a pixel, along with a ruler naming the channels.
+/// Bits beyond the pixel width can never carry data and are dimmed.
+pub(crate) fn format_data_mask(data_mask: u64, color_type: ColorType) -> (String, String) {
+    let bits = format!("{:064b}", data_mask);
+    let pixel_bits = (color_type.bits_per_pixel() as usize).min(bits.len());
+    let channel_bits = pixel_bits / color_type.channel_count() as usize;
+
+    let mut mask = String::new();
+    let mut ruler = String::new();
+    for (i, name) in channel_names(color_type).iter().enumerate() {
+        mask.push_str(&bits[i * channel_bits..(i + 1) * channel_bits]);
+        mask.push('|');
+        ruler.push_str(&format!("{:<width$}|", name, width = channel_bits));
+    }
+    if pixel_bits < bits.len() {
+        mask.push_str(&bits[pixel_bits..].dimmed().to_string());
+        ruler.push_str("unused");
+    }
+
+    (mask, ruler)
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn rgb8_mask_marks_unused_bits() {
+        colored::control::set_override(false);
+        let (mask, ruler) = format_data_mask(0x01_01_01_00_00_00_00_00, ColorType::Rgb8);
+
+        assert_eq!(
+            mask,
+            format!("00000001|00000001|00000001|{}", "0".repeat(40))
+        );
+        assert_eq!(ruler, "R       |G       |B       |unused");
+    }
+
+    #[test]
+    fn rgba8_mask_has_alpha_channel() {
+        colored::control::set_override(false);
+        let (mask, ruler) = format_data_mask(0x03_03_03_03_00_00_00_00, ColorType::Rgba8);
+
+        assert_eq!(
+            mask,
+            format!("00000011|00000011|00000011|00000011|{}", "0".repeat(32))
+        );
+        assert_eq!(ruler, "R       |G       |B       |A       |unused");
+    }
+}
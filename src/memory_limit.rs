@@ -0,0 +1,137 @@
+use std::io::Cursor;
+
+use crate::{png_info::PngBitDepth, size_format::format_byte_size};
+
+/// Decoded size of a pixel for formats other than PNG. Farbfeld is always 16-bit RGBA.
+const FALLBACK_BYTES_PER_PIXEL: u64 = 8;
+
+///
+/// Upper bound for the memory a command may allocate, set via `--max-memory`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct MemoryLimit {
+    max_bytes: u64,
+}
+
+impl MemoryLimit {
+    pub(crate) fn from_mib(mib: u64) -> MemoryLimit {
+        MemoryLimit {
+            max_bytes: mib.saturating_mul(1024 * 1024),
+        }
+    }
+
+    ///
+    /// Fails if the estimated peak allocation exceeds the limit.
+    /// Has to be called before the memory is allocated.
+    pub(crate) fn check(&self, estimate: &MemoryEstimate) -> Result<(), String> {
+        let total = estimate.total();
+        if total > self.max_bytes {
+            return Err(format!(
+                "Estimated peak memory use is {} (image file: {}, image: {}, payload: {}, output: {}), \
+                which exceeds the limit of {}",
+                format_byte_size(total),
+                format_byte_size(estimate.image_file),
+                format_byte_size(estimate.image_buffer),
+                format_byte_size(estimate.payload),
+                format_byte_size(estimate.output),
+                format_byte_size(self.max_bytes)
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// Checks the estimate against the limit, if there is one
+pub(crate) fn check_memory(
+    limit: Option<MemoryLimit>,
+    estimate: &MemoryEstimate,
+) -> Result<(), String> {
+    match limit {
+        Some(limit) => limit.check(estimate),
+        None => Ok(()),
+    }
+}
+
+///
+/// Allocations which are alive at the same time, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) struct MemoryEstimate {
+    /// The encoded image file, as read from disk or STDIN
+    pub(crate) image_file: u64,
+    /// The decoded pixel buffer
+    pub(crate) image_buffer: u64,
+    pub(crate) payload: u64,
+    /// The encoded modified image
+    pub(crate) output: u64,
+}
+
+impl MemoryEstimate {
+    pub(crate) fn total(&self) -> u64 {
+        self.image_file
+            .saturating_add(self.image_buffer)
+            .saturating_add(self.payload)
+            .saturating_add(self.output)
+    }
+}
+
+///
+/// Size of the pixel buffer the image file decodes to, read from the file header without decoding it
+pub(crate) fn decoded_image_bytes(data: &[u8]) -> Result<u64, String> {
+    let (width, height) = image::io::Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|x| x.to_string())?
+        .into_dimensions()
+        .map_err(|x| x.to_string())?;
+    let bytes_per_pixel = PngBitDepth::from_png_bytes(data)
+        .map(|info| info.decoded_bytes_per_pixel())
+        .unwrap_or(FALLBACK_BYTES_PER_PIXEL);
+
+    Ok((width as u64 * height as u64).saturating_mul(bytes_per_pixel))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, ImageOutputFormat, Rgb};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        buffer_modify::PngImageSaveable,
+        header::{V1DataStuffingOptions, VersionedHeader},
+    };
+
+    #[test]
+    fn decoded_size_of_png() {
+        let image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(30, 20);
+        let data = image.save_to_buffer(ImageOutputFormat::Png).unwrap();
+
+        assert_eq!(decoded_image_bytes(&data).unwrap(), 30 * 20 * 3);
+    }
+
+    #[test]
+    fn huge_claimed_data_len_is_rejected() {
+        let header = VersionedHeader::V1 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 100 },
+            data_mask: 0x01_00_00_00_00_00_00_00,
+            data_len: 1 << 40,
+        };
+        let limit = MemoryLimit::from_mib(64);
+        let image_buffer = 512 * 512 * 3;
+
+        let honest = MemoryEstimate {
+            image_buffer,
+            payload: 1024,
+            ..Default::default()
+        };
+        assert!(limit.check(&honest).is_ok());
+
+        let claimed = MemoryEstimate {
+            image_buffer,
+            payload: header.data_len(),
+            ..Default::default()
+        };
+        assert!(limit.check(&claimed).is_err());
+        assert!(check_memory(None, &claimed).is_ok());
+    }
+}
@@ -0,0 +1,294 @@
+use std::{io::Cursor, path::Path};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+
+/// Lossless formats the modified image can be written as
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OutputFormat {
+    Png,
+    /// 16-bit RGBA. The source image is converted to 16-bit RGBA before encoding.
+    Farbfeld,
+    /// 8-bit RGB(A), fast to write and read. Other sources are converted to 8-bit RGB(A) before encoding.
+    Qoi,
+}
+
+impl OutputFormat {
+    ///
+    /// Guesses the format from the extension of the output path
+    pub(crate) fn from_path(path: &str) -> Option<OutputFormat> {
+        let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "png" => Some(OutputFormat::Png),
+            "ff" | "farbfeld" => Some(OutputFormat::Farbfeld),
+            "qoi" => Some(OutputFormat::Qoi),
+            _ => None,
+        }
+    }
+
+    /// File extension used when the output file name is chosen by this tool
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Farbfeld => "ff",
+            OutputFormat::Qoi => "qoi",
+        }
+    }
+
+    pub(crate) fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Farbfeld => "image/x-farbfeld",
+            OutputFormat::Qoi => "image/x-qoi",
+        }
+    }
+
+    ///
+    /// Wraps the encoded image into a `data:` URI, e.g. for embedding it into HTML
+    pub(crate) fn to_data_uri(self, data: &[u8]) -> String {
+        format!("data:{};base64,{}", self.mime_type(), STANDARD.encode(data))
+    }
+
+    pub(crate) fn image_output_format(&self) -> ImageOutputFormat {
+        match self {
+            OutputFormat::Png => ImageOutputFormat::Png,
+            OutputFormat::Farbfeld => ImageOutputFormat::Farbfeld,
+            OutputFormat::Qoi => ImageOutputFormat::Qoi,
+        }
+    }
+
+    ///
+    /// Converts the source image into a color type this format can store without loss.
+    /// This has to happen before encoding, as any later conversion would destroy the hidden data.
+    pub(crate) fn prepare_cover(&self, image: DynamicImage) -> DynamicImage {
+        match self {
+            OutputFormat::Png => image,
+            OutputFormat::Farbfeld => match image {
+                DynamicImage::ImageRgba16(_) => image,
+                _ => DynamicImage::ImageRgba16(image.to_rgba16()),
+            },
+            OutputFormat::Qoi => match image {
+                DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => image,
+                _ if image.color().has_alpha() => DynamicImage::ImageRgba8(image.to_rgba8()),
+                _ => DynamicImage::ImageRgb8(image.to_rgb8()),
+            },
+        }
+    }
+
+    ///
+    /// Fails unless this format stores the prepared cover without changing a single bit, see [`check_round_trip`]
+    pub(crate) fn check_lossless(&self, cover: &DynamicImage) -> Result<(), String> {
+        check_round_trip(cover, self.image_output_format())
+    }
+}
+
+///
+/// Encodes the image in the given format, decodes it again and compares every sample with the original.
+/// Catches encoders which are lossy for some covers, before a payload is embedded that would not survive.
+pub(crate) fn check_round_trip(
+    image: &DynamicImage,
+    format: ImageOutputFormat,
+) -> Result<(), String> {
+    let name = format!("{:?}", format);
+    let mut data = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut data), format)
+        .map_err(|x| format!("Failed to encode the cover as {}: {}", name, x))?;
+    let decoded = image::load_from_memory(&data)
+        .map_err(|x| format!("Failed to decode the cover from {}: {}", name, x))?;
+    check_identical(image, &decoded)
+        .map_err(|x| format!("{} is not lossless for this cover: {}", name, x))
+}
+
+///
+/// Whether both images have the same color type, dimensions and samples
+pub(crate) fn check_identical(
+    original: &DynamicImage,
+    decoded: &DynamicImage,
+) -> Result<(), String> {
+    if decoded.color() != original.color() {
+        return Err(format!(
+            "the color type changed from {:?} to {:?}",
+            original.color(),
+            decoded.color()
+        ));
+    }
+    if decoded.dimensions() != original.dimensions() {
+        return Err(format!(
+            "the size changed from {:?} to {:?}",
+            original.dimensions(),
+            decoded.dimensions()
+        ));
+    }
+    match original
+        .as_bytes()
+        .iter()
+        .zip(decoded.as_bytes())
+        .filter(|(original, decoded)| original != decoded)
+        .count()
+    {
+        0 => Ok(()),
+        changed => Err(format!("{} sample bytes changed", changed)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgba};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        buffer_modify::{convert_dynamic_image_to_png_image, PngImage, PngImageSaveable},
+        crc_spec::CrcSpec,
+        header::{generate_v1_header, try_get_header, V1DataStuffingOptions, VersionedHeader},
+        payload::{read_payload, write_payload},
+    };
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(OutputFormat::from_path("out.png"), Some(OutputFormat::Png));
+        assert_eq!(
+            OutputFormat::from_path("out.FF"),
+            Some(OutputFormat::Farbfeld)
+        );
+        assert_eq!(OutputFormat::from_path("out.qoi"), Some(OutputFormat::Qoi));
+        assert_eq!(OutputFormat::from_path("out"), None);
+        assert_eq!(OutputFormat::from_path("out.jpg"), None);
+    }
+
+    #[test]
+    fn data_uri_round_trip() {
+        let image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(8, 8);
+        let data = image
+            .save_to_buffer(OutputFormat::Png.image_output_format())
+            .unwrap();
+
+        let uri = OutputFormat::Png.to_data_uri(&data);
+
+        let encoded = uri.strip_prefix("data:image/png;base64,").unwrap();
+        assert_eq!(STANDARD.decode(encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn prepare_cover_converts_to_rgba16() {
+        let image = DynamicImage::ImageRgb8(ImageBuffer::new(4, 4));
+
+        let prepared = OutputFormat::Farbfeld.prepare_cover(image);
+
+        assert_eq!(prepared.color(), ColorType::Rgba16);
+    }
+
+    #[test]
+    fn lossy_targets_fail_the_round_trip() {
+        let mut samples = vec![0u8; 32 * 32 * 3];
+        rand::thread_rng().fill_bytes(&mut samples);
+        let cover = DynamicImage::ImageRgb8(ImageBuffer::from_raw(32, 32, samples).unwrap());
+
+        let err = check_round_trip(&cover, ImageOutputFormat::Jpeg(95)).unwrap_err();
+        assert!(err.contains("not lossless"), "{}", err);
+        for format in [OutputFormat::Png, OutputFormat::Farbfeld, OutputFormat::Qoi] {
+            format
+                .check_lossless(&format.prepare_cover(cover.clone()))
+                .unwrap();
+        }
+        // QOI has no 16-bit samples, so unprepared covers do not survive
+        assert!(OutputFormat::Qoi
+            .check_lossless(&DynamicImage::ImageRgba16(cover.to_rgba16()))
+            .is_err());
+    }
+
+    #[test]
+    fn round_trip_through_farbfeld() {
+        let mut samples = vec![0u8; 64 * 64 * 8];
+        rand::thread_rng().fill_bytes(&mut samples);
+        let cover: ImageBuffer<Rgba<u16>, Vec<u16>> = ImageBuffer::from_raw(
+            64,
+            64,
+            samples
+                .chunks_exact(2)
+                .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+                .collect(),
+        )
+        .unwrap();
+        let mut cover = OutputFormat::Farbfeld.prepare_cover(DynamicImage::ImageRgba16(cover));
+
+        // Use the low 4 bits of every 16-bit channel
+        let payload = vec![0x96; 1000];
+        let header = VersionedHeader::V2 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 1024 },
+            data_mask: 0x00_0F_00_0F_00_0F_00_0Fu64,
+            data_len: payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(&payload),
+        };
+
+        let image: &mut dyn PngImage = convert_dynamic_image_to_png_image(&mut cover).unwrap();
+        write_payload(image, &header, &payload, None).unwrap();
+        let data = image
+            .save_to_buffer(OutputFormat::Farbfeld.image_output_format())
+            .unwrap();
+        assert_eq!(&data[..8], b"farbfeld");
+
+        let mut decoded = image::load_from_memory(&data).unwrap();
+        assert_eq!(decoded.color(), ColorType::Rgba16);
+        let image: &mut dyn PngImage = convert_dynamic_image_to_png_image(&mut decoded).unwrap();
+        let header = try_get_header(image).unwrap();
+
+        assert_eq!(read_payload(image, &header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn round_trip_through_qoi() {
+        let mut samples = vec![0u8; 64 * 64 * 8];
+        rand::thread_rng().fill_bytes(&mut samples);
+        let cover: ImageBuffer<Rgba<u16>, Vec<u16>> = ImageBuffer::from_raw(
+            64,
+            64,
+            samples
+                .chunks_exact(2)
+                .map(|bytes| u16::from_ne_bytes([bytes[0], bytes[1]]))
+                .collect(),
+        )
+        .unwrap();
+        let mut cover = OutputFormat::Qoi.prepare_cover(DynamicImage::ImageRgba16(cover));
+        assert_eq!(cover.color(), ColorType::Rgba8);
+
+        let payload = vec![0x5A; 2000];
+        let header = generate_v1_header(64 * 64, payload.len() as u64, ColorType::Rgba8).unwrap();
+        let image: &mut dyn PngImage = convert_dynamic_image_to_png_image(&mut cover).unwrap();
+        write_payload(image, &header, &payload, None).unwrap();
+        let data = image
+            .save_to_buffer(OutputFormat::Qoi.image_output_format())
+            .unwrap();
+        assert_eq!(&data[..4], b"qoif");
+
+        // QOI is lossless, so every RGBA value survives
+        let mut decoded = image::load_from_memory(&data).unwrap();
+        assert_eq!(decoded.as_bytes(), cover.as_bytes());
+        let image: &mut dyn PngImage = convert_dynamic_image_to_png_image(&mut decoded).unwrap();
+        let header = try_get_header(image).unwrap();
+
+        assert_eq!(read_payload(image, &header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn rgba16_capacity_boundary() {
+        let pixel_count = 64 * 64;
+        // The remaining pixels next to the header can carry 64 bits each
+        let header_pixels = VersionedHeader::V1 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 0 },
+            data_mask: 0,
+            data_len: pixel_count * 8,
+        }
+        .max_pixel_span()
+        .unwrap();
+        let max_len = (pixel_count - header_pixels) * 8;
+
+        let header = generate_v1_header(pixel_count, max_len, ColorType::Rgba16).unwrap();
+        assert_eq!(header.data_mask(), u64::MAX);
+
+        assert!(generate_v1_header(pixel_count, max_len + 1, ColorType::Rgba16).is_err());
+    }
+}
@@ -0,0 +1,743 @@
+use std::time::Duration;
+
+use rand::RngCore;
+use tracing::debug;
+
+use crate::{
+    avoid_mask::AvoidMask,
+    buffer_modify::{checked_pixel_index, PngImage},
+    complexity::{self, read_weighted, write_weighted},
+    gray_code::{gray_decode, gray_encode, symbol_widths},
+    header::{
+        calculate_bit_mask, header_mask_for, HeaderRaw, V1DataStuffingOptions, VersionedHeader,
+        HEADER_MASK,
+    },
+    header_copies::{copy_offsets, pixels_between_copies},
+    prng::tool_rng,
+    raw::WriteStats,
+    scatter::{free_pixels, write_scattered_header},
+    ycbcr::{payload_pixels, read_luma, write_luma},
+};
+
+/// Rough number of payload bits extracted per second, used to estimate decode times
+const ESTIMATED_READ_BITS_PER_SECOND: u64 = 200_000_000;
+
+///
+/// What a header tells about the payload, without reading the payload itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayloadSummary {
+    pub data_len: u64,
+    pub data_bits_per_pixel: u32,
+    pub pixels_used: u64,
+    pub has_checksum: bool,
+    pub requires_avoid_mask: bool,
+    /// The payload is stored in the tRNS chunk instead of the pixels
+    pub in_trns: bool,
+}
+
+impl PayloadSummary {
+    pub fn from_header(header: &VersionedHeader) -> PayloadSummary {
+        let data_bits_per_pixel = match header.stuffing_opts() {
+            V1DataStuffingOptions::Luma { y_bits, .. } => y_bits as u32,
+            _ => header.data_mask().count_ones(),
+        };
+        let pixels_used = match data_bits_per_pixel {
+            0 => 0,
+            bits => (header.data_len() * 8).div_ceil(bits as u64),
+        };
+
+        PayloadSummary {
+            data_len: header.data_len(),
+            data_bits_per_pixel,
+            pixels_used,
+            has_checksum: header.data_crc().is_some(),
+            requires_avoid_mask: header.avoid_mask_checksum().is_some(),
+            in_trns: matches!(header.stuffing_opts(), V1DataStuffingOptions::Trns { .. }),
+        }
+    }
+
+    /// Names of the optional features the payload was embedded with
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = Vec::new();
+        if self.has_checksum {
+            flags.push("checksum");
+        }
+        if self.requires_avoid_mask {
+            flags.push("avoid-mask");
+        }
+        if self.in_trns {
+            flags.push("trns");
+        }
+        flags
+    }
+
+    pub fn estimated_read_time(&self) -> Duration {
+        Duration::from_secs_f64((self.data_len * 8) as f64 / ESTIMATED_READ_BITS_PER_SECOND as f64)
+    }
+}
+
+///
+/// Returns the pixels carrying the payload if the header restricts them, e.g. via an avoid mask.
+/// `None` means the payload is stored sequentially, starting at the header's start offset.
+pub fn restricted_payload_pixels(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<Option<Vec<usize>>, String> {
+    let candidate_pixels = match header.stuffing_opts() {
+        V1DataStuffingOptions::None { .. } => return Ok(None),
+        V1DataStuffingOptions::AvoidMask { mask_checksum, .. } => {
+            let avoid_mask = avoid_mask.ok_or_else(|| {
+                "The payload was embedded using an avoid mask. Provide it via --avoid-mask"
+                    .to_string()
+            })?;
+            if avoid_mask.checksum() != mask_checksum {
+                return Err(
+                    "The provided avoid mask does not match the one used to embed the payload"
+                        .to_string(),
+                );
+            }
+            avoid_mask.allowed_pixels()
+        }
+        V1DataStuffingOptions::ScatteredHeader { seed, .. } => {
+            free_pixels(seed, header.data_mask(), image.pixel_count())?
+        }
+        V1DataStuffingOptions::Password { .. } => {
+            return Err(
+                "The payload was embedded with a password. Provide it via --password".to_string(),
+            )
+        }
+        V1DataStuffingOptions::Trns { .. } => {
+            return Err(
+                "The payload is stored in the tRNS chunk and has to be read from the PNG file"
+                    .to_string(),
+            )
+        }
+        V1DataStuffingOptions::GifPalette { .. } => return Err(
+            "The payload is stored in the palette of a GIF and has to be read from the GIF file"
+                .to_string(),
+        ),
+        V1DataStuffingOptions::Keyed { .. } => {
+            return Err(
+                "The payload was embedded with a key file. Provide it via --params".to_string(),
+            )
+        }
+        V1DataStuffingOptions::ColorKey {
+            key,
+            selection_checksum,
+            ..
+        } => {
+            let selection = key.selection(&image.rgba8_pixels());
+            if selection.checksum() != selection_checksum {
+                return Err(
+                    "The pixels matching the color key differ from the ones the payload was embedded in"
+                        .to_string(),
+                );
+            }
+            selection.allowed_pixels()
+        }
+        V1DataStuffingOptions::Luma { y_bits, .. } => {
+            return payload_pixels(image, header, y_bits).map(Some)
+        }
+        V1DataStuffingOptions::HeaderCopies { copies, .. } => {
+            pixels_between_copies(header, image.pixel_count(), copies)?
+        }
+        V1DataStuffingOptions::Complexity { max_bits, .. } => {
+            return complexity::payload_pixels(image, header, max_bits).map(Some)
+        }
+    };
+
+    let start_offset = checked_pixel_index(header.start_offset())?;
+    if start_offset > candidate_pixels.len() {
+        return Err("Start offset lies outside of the pixels available to the payload".to_string());
+    }
+
+    Ok(Some(candidate_pixels[start_offset..].to_vec()))
+}
+
+///
+/// Writes the header and the payload it describes into the image.
+pub fn write_payload(
+    image: &mut dyn PngImage,
+    header: &VersionedHeader,
+    payload: &[u8],
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<(), String> {
+    write_payload_with_stats(image, header, payload, avoid_mask).map(|_| ())
+}
+
+///
+/// Like [`write_payload`], but returns how many pixels and bits of the image it changed, the header's included.
+/// Pixels written more than once, like those of a tamper copy or header copies, count once per write.
+pub fn write_payload_with_stats(
+    image: &mut dyn PngImage,
+    header: &VersionedHeader,
+    payload: &[u8],
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<WriteStats, String> {
+    if header.data_len() != payload.len() as u64 {
+        return Err(format!(
+            "Header describes {} bytes, but the payload is {} bytes long",
+            header.data_len(),
+            payload.len()
+        ));
+    }
+
+    if let (
+        Some(_),
+        V1DataStuffingOptions::Luma { .. } | V1DataStuffingOptions::Complexity { .. },
+    ) = (header.tamper_copy_mask(), header.stuffing_opts())
+    {
+        return Err("A tamper copy needs the payload to be stored with the data mask".to_string());
+    }
+
+    let as_raw_header: HeaderRaw = header.clone().try_into().map_err(|x| format!("{}", x))?;
+    let start_offset = checked_pixel_index(header.start_offset())?;
+    let pixels = restricted_payload_pixels(image, header, avoid_mask)?;
+    debug!(
+        data_len = header.data_len(),
+        bits_per_pixel = header.data_mask().count_ones(),
+        start_offset = header.start_offset(),
+        scattered_header = header.scatter_seed().is_some(),
+        "Embedding payload"
+    );
+
+    let mut stats = match header.scatter_seed() {
+        Some(seed) => write_scattered_header(image, &as_raw_header, seed, header.data_mask())?,
+        None => {
+            // The header takes the first pixels, 1 bit each. Overlapping pixels would carry both.
+            let header_bytes = header.stored_bytes()?;
+            let first_payload_pixel = match &pixels {
+                Some(pixels) => pixels.first().copied(),
+                None => Some(start_offset),
+            };
+            match first_payload_pixel {
+                Some(first) if !payload.is_empty() && first < header_bytes.len() * 8 => {
+                    return Err(format!(
+                        "The header covers the first {} pixels, but the payload starts at pixel {}",
+                        header_bytes.len() * 8,
+                        first
+                    ));
+                }
+                _ => {
+                    let header_mask = header_mask_for(header.data_mask(), image.color_type());
+                    let mut stats = image.write_data_with_mask(&header_bytes, header_mask, 0);
+                    if let V1DataStuffingOptions::HeaderCopies { copies, .. } =
+                        header.stuffing_opts()
+                    {
+                        for offset in copy_offsets(image.pixel_count(), copies) {
+                            stats += image.write_data_with_mask(
+                                &header_bytes,
+                                header_mask,
+                                checked_pixel_index(offset)?,
+                            );
+                        }
+                    }
+                    stats
+                }
+            }
+        }
+    };
+    let gray_coded;
+    let payload = match header.is_gray_coded() {
+        true => {
+            gray_coded = gray_encode(
+                payload,
+                &symbol_widths(header.data_mask(), image.color_type()),
+            );
+            &gray_coded
+        }
+        false => payload,
+    };
+    stats += match (&pixels, header.stuffing_opts()) {
+        (Some(pixels), V1DataStuffingOptions::Luma { y_bits, .. }) => {
+            write_luma(image, payload, y_bits, pixels)
+        }
+        (Some(pixels), V1DataStuffingOptions::Complexity { max_bits, .. }) => {
+            write_weighted(image, payload, max_bits, pixels)?
+        }
+        (Some(pixels), _) => image.write_data_at_pixels(payload, header.data_mask(), pixels),
+        (None, _) => image.write_data_with_mask(payload, header.data_mask(), start_offset),
+    };
+    if let Some(copy_mask) = header.tamper_copy_mask() {
+        stats += match &pixels {
+            Some(pixels) => image.write_data_at_pixels(payload, copy_mask, pixels),
+            None => image.write_data_with_mask(payload, copy_mask, start_offset),
+        };
+    }
+
+    Ok(stats)
+}
+
+///
+/// Reads the payload described by the header from the image.
+/// If the header contains a payload checksum, the payload is verified against it.
+pub fn read_payload(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<Vec<u8>, String> {
+    let start_offset = checked_pixel_index(header.start_offset())?;
+    let data_len = checked_pixel_index(header.data_len())?;
+    // Without this, a header claiming more than the image holds runs out of pixels and panics
+    #[cfg(feature = "strict-no-panic")]
+    crate::verification::check_capacity(image, header, avoid_mask)?;
+    debug!(
+        data_len,
+        bits_per_pixel = header.data_mask().count_ones(),
+        start_offset,
+        "Reading payload"
+    );
+
+    let payload = match (
+        restricted_payload_pixels(image, header, avoid_mask)?,
+        header.stuffing_opts(),
+    ) {
+        (Some(pixels), V1DataStuffingOptions::Luma { y_bits, .. }) => {
+            read_luma(image, y_bits, &pixels, data_len)
+        }
+        (Some(pixels), V1DataStuffingOptions::Complexity { max_bits, .. }) => {
+            read_weighted(image, max_bits, &pixels, data_len)?
+        }
+        (Some(pixels), _) => image.read_data_at_pixels(header.data_mask(), &pixels, data_len),
+        (None, _) => image.read_data_with_mask(header.data_mask(), start_offset, data_len),
+    };
+    let payload = match header.is_gray_coded() {
+        true => gray_decode(
+            &payload,
+            &symbol_widths(header.data_mask(), image.color_type()),
+        ),
+        false => payload,
+    };
+
+    check_payload_crc(header, &payload)?;
+
+    Ok(payload)
+}
+
+///
+/// Verifies the payload against the checksum in the header, if the header contains one.
+pub fn check_payload_crc(header: &VersionedHeader, payload: &[u8]) -> Result<(), String> {
+    if let Some(expected_crc) = header.data_crc() {
+        let crc = header.payload_crc_spec().checksum(payload);
+        if crc != expected_crc {
+            return Err(format!(
+                "Payload checksum mismatch. Expected {:#01x}, but found {:#01x}",
+                expected_crc, crc
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+///
+/// Checks that the image carries a payload which can be fully verified.
+/// Headers without a payload checksum cannot be verified and are rejected.
+pub fn verify_payload(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<(), String> {
+    if header.data_crc().is_none() {
+        return Err("Header does not contain a payload checksum. Cannot verify.".to_string());
+    }
+
+    read_payload(image, header, avoid_mask).map(|_| ())
+}
+
+///
+/// Overwrites the `bits_per_channel` least significant bits of every pixel with noise,
+/// so no header or payload stored in them survives next to the new one.
+/// Higher bits are left alone, even if an earlier payload used them, as scrubbing them would be visible.
+pub fn scrub_stale_payload(image: &mut dyn PngImage, bits_per_channel: u8) {
+    let color_type = image.color_type();
+    let mask =
+        calculate_bit_mask(bits_per_channel * color_type.channel_count(), color_type) | HEADER_MASK;
+    // Trailing bits which do not fill a whole byte stay as they are
+    let noise_len = mask.count_ones() as u64 * image.pixel_count() / 8;
+    debug!(mask = format!("{:#018x}", mask), "Scrubbing stale payload");
+
+    let mut noise = vec![0u8; noise_len as usize];
+    tool_rng().fill_bytes(&mut noise);
+    image.write_data_with_mask(&noise, mask, 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        io::{self, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use image::{ColorType, ImageBuffer, Rgba};
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        buffer_modify::ReadImageBinary,
+        crc_spec::CrcSpec,
+        header::{generate_v1_header, generate_v3_header, try_get_header, HeaderExtension},
+        prng::{enable_deterministic_mode, DETERMINISTIC_SEED},
+    };
+
+    /// Pixel offset of the payload, far enough from the header at the start of the image
+    const TEST_START_OFFSET: u64 = 1024;
+    /// 2 bits in each of the RGBA channels
+    const TEST_DATA_MASK: u64 = 0x03_03_03_03_00_00_00_00u64;
+
+    fn noisy_image() -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        image
+    }
+
+    fn test_header(payload: &[u8]) -> VersionedHeader {
+        VersionedHeader::V2 {
+            stuffing_opts: V1DataStuffingOptions::None {
+                start_offset: TEST_START_OFFSET,
+            },
+            data_mask: TEST_DATA_MASK,
+            data_len: payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(payload),
+        }
+    }
+
+    #[test]
+    fn summary_matches_header() {
+        let mut image = noisy_image();
+        let payload = vec![0x11; 700];
+        write_payload(&mut image, &test_header(&payload), &payload, None).unwrap();
+
+        let header = try_get_header(&image).unwrap();
+        let summary = PayloadSummary::from_header(&header);
+
+        assert_eq!(summary.data_len, 700);
+        assert_eq!(summary.data_bits_per_pixel, 8);
+        assert_eq!(summary.pixels_used, 700);
+        assert_eq!(summary.flags(), vec!["checksum"]);
+        assert!(summary.estimated_read_time() < Duration::from_secs(1));
+    }
+
+    /// Collects everything a `tracing` subscriber writes
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encode_emits_events() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+
+        let mut image = noisy_image();
+        let payload = vec![0x42; 100];
+        tracing::subscriber::with_default(subscriber, || {
+            write_payload(&mut image, &test_header(&payload), &payload, None).unwrap();
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("Embedding payload"), "{}", logs);
+        assert!(logs.contains("data_len=100"), "{}", logs);
+        assert!(logs.contains("start_offset=1024"), "{}", logs);
+    }
+
+    #[test]
+    fn deterministic_encode_matches_golden() {
+        enable_deterministic_mode(DETERMINISTIC_SEED);
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> =
+            ImageBuffer::from_fn(64, 64, |x, y| Rgba([x as u8, y as u8, (x ^ y) as u8, 255]));
+        let payload = b"reproducible payload".repeat(20);
+        let header = generate_v1_header(64 * 64, payload.len() as u64, ColorType::Rgba8).unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        // Golden values, these only change if the embedding itself changes
+        assert_eq!(header.start_offset(), 742);
+        assert_eq!(CrcSpec::default().checksum(&image), 0xB91B_8730);
+        assert_eq!(
+            read_payload(&image, &try_get_header(&image).unwrap(), None).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn long_header_stays_clear_of_payload() {
+        let payload = vec![0x5A; 900];
+        // Hundreds of header pixels, far more than a header without extensions needs
+        let extensions = vec![
+            HeaderExtension::FileName("a very long file name ".repeat(5)),
+            HeaderExtension::Metadata(BTreeMap::from([("note".to_string(), "x".repeat(100))])),
+        ];
+
+        for _ in 0..50 {
+            let mut image = noisy_image();
+            let header = generate_v3_header(
+                64 * 64,
+                &payload,
+                ColorType::Rgba8,
+                CrcSpec::default(),
+                extensions.clone(),
+                true,
+            )
+            .unwrap();
+            assert!(header.start_offset() >= header.pixel_span().unwrap());
+
+            write_payload(&mut image, &header, &payload, None).unwrap();
+            let header = try_get_header(&image).unwrap();
+            assert_eq!(read_payload(&image, &header, None).unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn fixed_offset_starts_right_after_header() {
+        let mut image = noisy_image();
+        let payload = b"no randomness needed".repeat(10);
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            ColorType::Rgba8,
+            CrcSpec::default(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(header.start_offset(), header.max_pixel_span().unwrap());
+        write_payload(&mut image, &header, &payload, None).unwrap();
+        let header = try_get_header(&image).unwrap();
+        assert_eq!(read_payload(&image, &header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn payload_overlapping_header_is_rejected() {
+        let mut image = noisy_image();
+        let payload = b"overlap".to_vec();
+        let header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 100 },
+            data_mask: TEST_DATA_MASK,
+            data_len: payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(&payload),
+            extensions: vec![HeaderExtension::FileName("x".repeat(100))],
+        };
+
+        let err = write_payload(&mut image, &header, &payload, None).unwrap_err();
+        assert!(err.contains("starts at pixel 100"), "{}", err);
+    }
+
+    #[test]
+    fn clean_slate_leaves_only_new_payload() {
+        let old_payload = vec![0x3C; 900];
+        let new_payload = b"the new payload".to_vec();
+        let new_header = VersionedHeader::V2 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 3000 },
+            data_mask: TEST_DATA_MASK,
+            data_len: new_payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(&new_payload),
+        };
+        let re_encode = |clean_slate: bool| {
+            let mut image = noisy_image();
+            write_payload(&mut image, &test_header(&old_payload), &old_payload, None).unwrap();
+            if clean_slate {
+                scrub_stale_payload(&mut image, 2);
+            }
+            write_payload(&mut image, &new_header, &new_payload, None).unwrap();
+            image
+        };
+        let stale_payload = |image: &ImageBuffer<Rgba<u8>, Vec<u8>>| {
+            image.read_data_with_mask(TEST_DATA_MASK, TEST_START_OFFSET as usize, 900)
+        };
+
+        // The new payload does not reach the old one, which survives a plain re-encode
+        assert_eq!(stale_payload(&re_encode(false)), old_payload);
+
+        let image = re_encode(true);
+        assert_ne!(stale_payload(&image), old_payload);
+        let header = try_get_header(&image).unwrap();
+        assert_eq!(read_payload(&image, &header, None).unwrap(), new_payload);
+    }
+
+    #[test]
+    fn clean_slate_keeps_bits_above_the_scrubbed_ones() {
+        // Like a payload embedded with --allow-high-bits, this one also takes the third bit of every channel
+        let old_payload = vec![0x3C; 900];
+        let old_header = VersionedHeader::V2 {
+            stuffing_opts: V1DataStuffingOptions::None {
+                start_offset: TEST_START_OFFSET,
+            },
+            data_mask: 0x07_07_07_07_00_00_00_00,
+            data_len: old_payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(&old_payload),
+        };
+        let mut image = noisy_image();
+        write_payload(&mut image, &old_header, &old_payload, None).unwrap();
+        let before = image.clone();
+
+        scrub_stale_payload(&mut image, 2);
+
+        for (scrubbed, before) in image.iter().zip(before.iter()) {
+            assert_eq!(scrubbed & !0b11, before & !0b11);
+        }
+        assert!(try_get_header(&image).is_err());
+    }
+
+    #[test]
+    fn write_and_read_payload() {
+        let mut image = noisy_image();
+        let payload = b"some secret payload".to_vec();
+        let header = test_header(&payload);
+
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        let header = try_get_header(&image).unwrap();
+        assert_eq!(read_payload(&image, &header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn verify_good_image() {
+        let mut image = noisy_image();
+        let payload = vec![0x5A; 500];
+        let header = test_header(&payload);
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        let header = try_get_header(&image).unwrap();
+        assert!(verify_payload(&image, &header, None).is_ok());
+    }
+
+    #[test]
+    fn verify_tampered_image() {
+        let mut image = noisy_image();
+        let payload = vec![0x5A; 500];
+        let header = test_header(&payload);
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        // Flip all data bits of the first payload pixel
+        let start_offset = header.start_offset() as usize;
+        let mask_bytes = header.data_mask().to_be_bytes();
+        for (channel, mask) in mask_bytes.iter().take(4).enumerate() {
+            image.as_mut()[start_offset * 4 + channel] ^= mask;
+        }
+
+        let header = try_get_header(&image).unwrap();
+        assert!(verify_payload(&image, &header, None).is_err());
+    }
+
+    #[test]
+    fn verify_rejects_header_without_checksum() {
+        let mut image = noisy_image();
+        let payload = vec![0x5A; 10];
+        let header = VersionedHeader::V1 {
+            stuffing_opts: V1DataStuffingOptions::None {
+                start_offset: TEST_START_OFFSET,
+            },
+            data_mask: TEST_DATA_MASK,
+            data_len: 10,
+        };
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        let header = try_get_header(&image).unwrap();
+        assert!(verify_payload(&image, &header, None).is_err());
+        assert_eq!(read_payload(&image, &header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn write_rejects_length_mismatch() {
+        let mut image = noisy_image();
+        let header = generate_v3_header(
+            64 * 64,
+            &[1, 2, 3],
+            ColorType::Rgba8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+
+        assert!(write_payload(&mut image, &header, &[1, 2], None).is_err());
+    }
+
+    #[test]
+    fn avoid_mask_keeps_masked_pixels_untouched() {
+        let mut image = noisy_image();
+        let untouched = image.clone();
+        // Block out every pixel in the left half of the image
+        let avoid_mask =
+            AvoidMask::from_allowed((0..64 * 64).map(|index| index % 64 >= 32).collect());
+        let payload = vec![0xC3; 300];
+        let header = test_header(&payload).with_stuffing_opts(V1DataStuffingOptions::AvoidMask {
+            start_offset: 300,
+            mask_checksum: avoid_mask.checksum(),
+        });
+
+        write_payload(&mut image, &header, &payload, Some(&avoid_mask)).unwrap();
+
+        // Apart from the header at the start, no masked pixel may change
+        let raw_header: HeaderRaw = header.clone().try_into().unwrap();
+        let header_pixels = raw_header.to_bytes().len() * 8;
+        for (index, (pixel, original)) in image.pixels().zip(untouched.pixels()).enumerate() {
+            if index >= header_pixels && index % 64 < 32 {
+                assert_eq!(pixel, original, "masked pixel {} was modified", index);
+            }
+        }
+
+        let header = try_get_header(&image).unwrap();
+        assert_eq!(
+            read_payload(&image, &header, Some(&avoid_mask)).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn avoid_mask_required_for_decode() {
+        let mut image = noisy_image();
+        let avoid_mask = AvoidMask::from_allowed(vec![true; 64 * 64]);
+        let payload = vec![0xC3; 30];
+        let header = test_header(&payload).with_stuffing_opts(V1DataStuffingOptions::AvoidMask {
+            start_offset: 1024,
+            mask_checksum: avoid_mask.checksum(),
+        });
+        write_payload(&mut image, &header, &payload, Some(&avoid_mask)).unwrap();
+
+        let header = try_get_header(&image).unwrap();
+        assert!(read_payload(&image, &header, None).is_err());
+
+        let other_mask = AvoidMask::from_allowed(vec![false; 64 * 64]);
+        assert!(read_payload(&image, &header, Some(&other_mask)).is_err());
+    }
+
+    #[test]
+    fn scattered_header_round_trip() {
+        let mut image = noisy_image();
+        let payload = vec![0x3C; 900];
+        let header =
+            test_header(&payload).with_stuffing_opts(V1DataStuffingOptions::ScatteredHeader {
+                start_offset: 200,
+                seed: 0xFEED_BEEF,
+            });
+
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        // Only the bootstrap is stored at the start of the image
+        assert_eq!(
+            image.read_data_with_mask(HEADER_MASK, 0, 1),
+            vec![crate::scatter::SCATTERED_MAGIC]
+        );
+        let read_header = try_get_header(&image).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(read_payload(&image, &read_header, None).unwrap(), payload);
+    }
+}
@@ -0,0 +1,114 @@
+pub(crate) const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Bit depth and color type as stored in the IHDR chunk of a PNG file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PngBitDepth {
+    pub(crate) bit_depth: u8,
+    pub(crate) color_type: u8,
+}
+
+impl PngBitDepth {
+    ///
+    /// Reads the IHDR chunk. Returns `None` if the data is not a PNG file.
+    pub(crate) fn from_png_bytes(data: &[u8]) -> Option<PngBitDepth> {
+        // Signature (8B), IHDR length (4B), "IHDR" (4B), width (4B), height (4B), bit depth, color type
+        if data.len() < 26 || data[..8] != PNG_SIGNATURE || &data[12..16] != b"IHDR" {
+            return None;
+        }
+
+        Some(PngBitDepth {
+            bit_depth: data[24],
+            color_type: data[25],
+        })
+    }
+
+    ///
+    /// Bytes per pixel once the image crate has decoded the image.
+    /// Palette images are assumed to expand to RGBA.
+    pub(crate) fn decoded_bytes_per_pixel(&self) -> u64 {
+        let channels = match self.color_type {
+            0 => 1,
+            2 => 3,
+            4 => 2,
+            _ => 4,
+        };
+        let bytes_per_channel = if self.bit_depth > 8 { 2 } else { 1 };
+        channels * bytes_per_channel
+    }
+
+    fn color_type_name(&self) -> &'static str {
+        match self.color_type {
+            0 => "grayscale",
+            2 => "RGB",
+            3 => "palette",
+            4 => "grayscale with alpha",
+            6 => "RGBA",
+            _ => "unknown",
+        }
+    }
+}
+
+///
+/// The image crate silently expands PNGs with 1, 2 or 4 bits per channel to 8 bits.
+/// Hidden data would not survive writing them back, so such images are rejected with a specific error.
+pub(crate) fn check_supported_bit_depth(data: &[u8]) -> Result<(), String> {
+    let info = match PngBitDepth::from_png_bytes(data) {
+        Some(val) => val,
+        None => return Ok(()),
+    };
+
+    if info.bit_depth < 8 {
+        return Err(format!(
+            "The PNG uses {}-bit {} channels. Channels with less than 8 bits are not supported. \
+            Convert the image to 8-bit RGB or RGBA first.",
+            info.bit_depth,
+            info.color_type_name()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn png_header(bit_depth: u8, color_type: u8) -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        data.extend_from_slice(&13u32.to_be_bytes());
+        data.extend_from_slice(b"IHDR");
+        data.extend_from_slice(&16u32.to_be_bytes()); // width
+        data.extend_from_slice(&16u32.to_be_bytes()); // height
+        data.extend_from_slice(&[bit_depth, color_type, 0, 0, 0]);
+        data
+    }
+
+    #[test]
+    fn parse_bit_depth() {
+        assert_eq!(
+            PngBitDepth::from_png_bytes(&png_header(2, 0)),
+            Some(PngBitDepth {
+                bit_depth: 2,
+                color_type: 0
+            })
+        );
+        assert_eq!(
+            PngBitDepth::from_png_bytes(b"farbfeld........................"),
+            None
+        );
+    }
+
+    #[test]
+    fn reject_2_bit_grayscale() {
+        let err = check_supported_bit_depth(&png_header(2, 0)).unwrap_err();
+
+        assert!(err.contains("2-bit grayscale"), "{}", err);
+    }
+
+    #[test]
+    fn accept_8_and_16_bit() {
+        assert!(check_supported_bit_depth(&png_header(8, 6)).is_ok());
+        assert!(check_supported_bit_depth(&png_header(16, 2)).is_ok());
+    }
+}
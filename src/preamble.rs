@@ -0,0 +1,132 @@
+use crate::{
+    buffer_modify::PngImage,
+    header::{alpha_header_mask, VersionedHeader, HEADER_MASK},
+};
+
+/// Marks a preamble in front of the header
+pub const PREAMBLE_MAGIC: u8 = 0x45;
+
+/// Magic (1B), header version (1B), length hint (2B, BE)
+pub const PREAMBLE_BYTES: usize = 4;
+
+///
+/// Fixed size summary of the header, stored in the very first pixels ahead of it.
+/// Tells whether an image is worth a full header parse without decoding the header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preamble {
+    /// Version of the header which follows
+    pub version: u8,
+    /// Payload length in KiB, rounded up. Saturates at `u16::MAX` for payloads of 64 MiB and more.
+    pub length_hint_kib: u16,
+}
+
+impl Preamble {
+    pub fn for_header(header: &VersionedHeader) -> Preamble {
+        Preamble {
+            version: header.version(),
+            length_hint_kib: u16::try_from(header.data_len().div_ceil(1024)).unwrap_or(u16::MAX),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; PREAMBLE_BYTES] {
+        let [high, low] = self.length_hint_kib.to_be_bytes();
+        [PREAMBLE_MAGIC, self.version, high, low]
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Preamble> {
+        match data {
+            [PREAMBLE_MAGIC, version, high, low] => Some(Preamble {
+                version: *version,
+                length_hint_kib: u16::from_be_bytes([*high, *low]),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Upper bound of the payload length in bytes, `None` if the hint saturated
+    pub fn max_data_len(&self) -> Option<u64> {
+        (self.length_hint_kib != u16::MAX).then_some(self.length_hint_kib as u64 * 1024)
+    }
+}
+
+///
+/// Reads the preamble from the first pixels, without touching the header behind it
+pub fn read_preamble(image: &dyn PngImage, header_mask: u64) -> Option<Preamble> {
+    if image.pixel_count() < PREAMBLE_BYTES as u64 * 8 {
+        return None;
+    }
+    Preamble::from_bytes(&image.read_data_with_mask(header_mask, 0, PREAMBLE_BYTES))
+}
+
+///
+/// Looks for a preamble where headers are stored, see [`crate::header::header_mask_for`]
+pub fn find_preamble(image: &dyn PngImage) -> Option<Preamble> {
+    read_preamble(image, HEADER_MASK).or_else(|| {
+        alpha_header_mask(image.color_type()).and_then(|mask| read_preamble(image, mask))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header, HeaderExtension},
+        payload::{read_payload, write_payload},
+    };
+
+    #[test]
+    fn preamble_matches_the_full_header() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        let payload = vec![0x5Au8; 1500];
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            vec![HeaderExtension::Preamble],
+            true,
+        )
+        .unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        let preamble = read_preamble(&image, HEADER_MASK).unwrap();
+        assert_eq!(
+            preamble,
+            Preamble {
+                version: 3,
+                length_hint_kib: 2
+            }
+        );
+        assert_eq!(preamble.max_data_len(), Some(2048));
+
+        let full_header = try_get_header(&image).unwrap();
+        assert_eq!(full_header, header);
+        assert_eq!(preamble.version, full_header.version());
+        assert!(full_header.data_len() <= preamble.max_data_len().unwrap());
+        assert_eq!(read_payload(&image, &full_header, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn headers_without_preamble_have_none() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        let payload = vec![0x5Au8; 10];
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        assert_eq!(read_preamble(&image, HEADER_MASK), None);
+    }
+}
@@ -0,0 +1,174 @@
+use std::{cell::RefCell, collections::HashSet, mem::size_of, ops::Range};
+
+use rand::{rngs::ThreadRng, thread_rng, RngCore};
+
+/// Seed used by `--deterministic`
+pub const DETERMINISTIC_SEED: u64 = 0x1D_E7E2_3141;
+
+thread_local! {
+    /// Replaces the OS seeded RNG while deterministic mode is enabled.
+    /// Thread local, so tests enabling it do not affect each other.
+    static DETERMINISTIC_RNG: RefCell<Option<SplitMix64>> = const { RefCell::new(None) };
+}
+
+///
+/// SplitMix64. Used wherever pixel positions are derived from a seed stored in an image,
+/// as the positions have to be reproducible across versions of this tool and of `rand`.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    ///
+    /// Returns a value in the given range. The tiny modulo bias is irrelevant for picking pixels.
+    pub fn next_in_range(&mut self, range: Range<usize>) -> usize {
+        let len = (range.end - range.start) as u64;
+        range.start + (self.next_u64() % len) as usize
+    }
+}
+
+///
+/// Makes all randomness of this thread reproducible. Intended for tests only:
+/// offsets, seeds and salts become predictable, which defeats their purpose.
+pub fn enable_deterministic_mode(seed: u64) {
+    DETERMINISTIC_RNG.with(|rng| *rng.borrow_mut() = Some(SplitMix64::new(seed)));
+}
+
+///
+/// Source of every random choice made while embedding (offsets, seeds, salts, ids).
+/// Backed by `thread_rng`, unless deterministic mode is enabled.
+pub struct ToolRng {
+    thread_rng: ThreadRng,
+}
+
+pub fn tool_rng() -> ToolRng {
+    ToolRng {
+        thread_rng: thread_rng(),
+    }
+}
+
+impl RngCore for ToolRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        DETERMINISTIC_RNG
+            .with(|rng| rng.borrow_mut().as_mut().map(SplitMix64::next_u64))
+            .unwrap_or_else(|| self.thread_rng.next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(size_of::<u64>()) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+///
+/// Picks `count` distinct indices from `range`, in the order they were drawn.
+pub fn pick_distinct(seed: u64, count: usize, range: Range<usize>) -> Result<Vec<usize>, String> {
+    if count > range.len() {
+        return Err(format!(
+            "Cannot pick {} distinct pixels out of {}",
+            count,
+            range.len()
+        ));
+    }
+
+    let mut rng = SplitMix64::new(seed);
+    let mut seen = HashSet::with_capacity(count);
+    let mut picked = Vec::with_capacity(count);
+    while picked.len() < count {
+        let index = rng.next_in_range(range.clone());
+        if seen.insert(index) {
+            picked.push(index);
+        }
+    }
+
+    Ok(picked)
+}
+
+///
+/// Shuffles `values` in place (Fisher-Yates), reproducibly for a given seed.
+pub fn shuffle<T>(seed: u64, values: &mut [T]) {
+    let mut rng = SplitMix64::new(seed);
+    for i in (1..values.len()).rev() {
+        let j = rng.next_in_range(0..i + 1);
+        values.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn splitmix64_reference_values() {
+        // Reference output of SplitMix64 seeded with 1234567
+        let mut rng = SplitMix64::new(1234567);
+
+        assert_eq!(rng.next_u64(), 6457827717110365317);
+        assert_eq!(rng.next_u64(), 3203168211198807973);
+    }
+
+    #[test]
+    fn pick_distinct_is_reproducible() {
+        let a = pick_distinct(42, 100, 10..1000).unwrap();
+        let b = pick_distinct(42, 100, 10..1000).unwrap();
+
+        assert_eq!(a, b);
+        assert_eq!(a.iter().collect::<HashSet<_>>().len(), 100);
+        assert!(a.iter().all(|index| (10..1000).contains(index)));
+    }
+
+    #[test]
+    fn shuffle_is_reproducible_permutation() {
+        let mut a: Vec<usize> = (0..500).collect();
+        let mut b = a.clone();
+        shuffle(42, &mut a);
+        shuffle(42, &mut b);
+
+        assert_eq!(a, b);
+        assert_ne!(a, (0..500).collect::<Vec<_>>());
+        let mut sorted = a.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..500).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn deterministic_mode_repeats_draws() {
+        enable_deterministic_mode(DETERMINISTIC_SEED);
+        let first: Vec<u64> = (0..4).map(|_| tool_rng().next_u64()).collect();
+        enable_deterministic_mode(DETERMINISTIC_SEED);
+        let second: Vec<u64> = (0..4).map(|_| tool_rng().next_u64()).collect();
+
+        assert_eq!(first, second);
+        assert_ne!(first[0], first[1]);
+    }
+
+    #[test]
+    fn pick_distinct_rejects_too_many() {
+        assert!(pick_distinct(42, 11, 0..10).is_err());
+        assert_eq!(pick_distinct(42, 10, 0..10).unwrap().len(), 10);
+    }
+}
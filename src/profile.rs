@@ -0,0 +1,109 @@
+use clap::ValueEnum;
+
+use crate::{channel_bits::ChannelBits, header::DEFAULT_MAX_BITS_PER_CHANNEL};
+
+/// Kind of cover image, selecting a preset of encode options suited for it
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CoverProfile {
+    /// Only green and blue carry the payload, and the luma of changed pixels is preserved
+    Photo,
+    /// Only the least significant bit of a channel is used. Low entropy covers are warned about.
+    Screenshot,
+    /// Like `screenshot`, but covers with few distinct colors are refused
+    Diagram,
+}
+
+///
+/// Encode options set by a profile. They are combined with the flags given explicitly.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ProfileOptions {
+    /// Used as `--channel-bits`. Empty leaves the bits to the default placement.
+    pub(crate) channel_bits: Vec<ChannelBits>,
+    pub(crate) preserve_luma: bool,
+    /// Refuse low entropy covers like `--strict`, instead of only warning
+    pub(crate) strict: bool,
+    /// Number of least significant bits of a channel the payload may use
+    pub(crate) max_bits_per_channel: u8,
+}
+
+impl Default for ProfileOptions {
+    /// Options without a profile, leaving every flag as given
+    fn default() -> Self {
+        ProfileOptions {
+            channel_bits: Vec::new(),
+            preserve_luma: false,
+            strict: false,
+            max_bits_per_channel: DEFAULT_MAX_BITS_PER_CHANNEL,
+        }
+    }
+}
+
+impl CoverProfile {
+    pub(crate) fn options(self) -> ProfileOptions {
+        match self {
+            CoverProfile::Photo => ProfileOptions {
+                channel_bits: vec![
+                    ChannelBits {
+                        channel: 'g',
+                        bits: 1,
+                    },
+                    ChannelBits {
+                        channel: 'b',
+                        bits: 2,
+                    },
+                ],
+                preserve_luma: true,
+                ..ProfileOptions::default()
+            },
+            CoverProfile::Screenshot => ProfileOptions {
+                max_bits_per_channel: 1,
+                ..ProfileOptions::default()
+            },
+            CoverProfile::Diagram => ProfileOptions {
+                strict: true,
+                max_bits_per_channel: 1,
+                ..ProfileOptions::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::ColorType;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::channel_bits::channel_bits_mask;
+
+    #[test]
+    fn profiles_set_the_documented_options() {
+        let photo = CoverProfile::Photo.options();
+        assert_eq!(
+            channel_bits_mask(&photo.channel_bits, ColorType::Rgb8).unwrap(),
+            0x00_01_03_00_00_00_00_00
+        );
+        assert!(photo.preserve_luma);
+        assert!(!photo.strict);
+        assert_eq!(photo.max_bits_per_channel, DEFAULT_MAX_BITS_PER_CHANNEL);
+
+        assert_eq!(
+            CoverProfile::Screenshot.options(),
+            ProfileOptions {
+                channel_bits: Vec::new(),
+                preserve_luma: false,
+                strict: false,
+                max_bits_per_channel: 1,
+            }
+        );
+        assert_eq!(
+            CoverProfile::Diagram.options(),
+            ProfileOptions {
+                channel_bits: Vec::new(),
+                preserve_luma: false,
+                strict: true,
+                max_bits_per_channel: 1,
+            }
+        );
+    }
+}
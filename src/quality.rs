@@ -0,0 +1,377 @@
+use image::DynamicImage;
+
+use crate::{
+    avoid_mask::AvoidMask,
+    buffer_modify::{convert_dynamic_image_to_png_image, PngImage},
+    header::{check_low_bits_only, header_mask_for, VersionedHeader},
+    output_format::OutputFormat,
+    payload::{restricted_payload_pixels, write_payload},
+};
+
+/// PSNR above which LSB changes are considered invisible
+pub(crate) const DEFAULT_TARGET_PSNR: f64 = 40.0;
+/// Output files whose sizes differ by less than this fraction count as equally large
+const FILE_SIZE_TOLERANCE: f64 = 0.02;
+
+///
+/// Peak signal-to-noise ratio between two images of the same size and color type, in dB.
+/// Identical images have an infinite PSNR.
+pub(crate) fn psnr(original: &DynamicImage, modified: &DynamicImage) -> f64 {
+    let bytes_per_channel =
+        (original.color().bytes_per_pixel() / original.color().channel_count()) as usize;
+    let samples = |image: &DynamicImage| -> Vec<f64> {
+        image
+            .as_bytes()
+            .chunks_exact(bytes_per_channel)
+            .map(|sample| match sample {
+                [value] => *value as f64,
+                [high, low] => u16::from_ne_bytes([*high, *low]) as f64,
+                _ => unreachable!("channels have 1 or 2 bytes"),
+            })
+            .collect()
+    };
+    let (original, modified) = (samples(original), samples(modified));
+
+    let squared_error: f64 = original
+        .iter()
+        .zip(&modified)
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum();
+    if squared_error == 0.0 {
+        return f64::INFINITY;
+    }
+    let mse = squared_error / original.len() as f64;
+    let max_value = ((1u32 << (8 * bytes_per_channel)) - 1) as f64;
+
+    10.0 * (max_value * max_value / mse).log10()
+}
+
+///
+/// How many of the bits carrying the header and the payload had to be flipped.
+/// Bits which already matched by chance stay as they are, so random payloads flip about half of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct ChangeRate {
+    pub(crate) changed_bits: u64,
+    pub(crate) used_bits: u64,
+}
+
+impl ChangeRate {
+    /// Fraction of the used bits which changed, from 0 to 1
+    pub(crate) fn rate(&self) -> f64 {
+        self.changed_bits as f64 / self.used_bits as f64
+    }
+}
+
+///
+/// Compares the bits carrying the header and the payload before and after embedding.
+/// Only headers stored at the start of the image are supported, not scattered ones.
+pub(crate) fn change_rate(
+    cover: &dyn PngImage,
+    encoded: &dyn PngImage,
+    header: &VersionedHeader,
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<ChangeRate, String> {
+    Ok(compare_bits(
+        &used_bits(cover, header, avoid_mask)?,
+        &used_bits(encoded, header, avoid_mask)?,
+    ))
+}
+
+///
+/// Like [`change_rate`], but before embedding: compares the cover with the header and payload
+/// which would be written, without modifying it
+pub(crate) fn planned_change_rate(
+    cover: &dyn PngImage,
+    header: &VersionedHeader,
+    payload: &[u8],
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<ChangeRate, String> {
+    let mut written = header.stored_bytes()?;
+    written.extend_from_slice(payload);
+
+    Ok(compare_bits(
+        &used_bits(cover, header, avoid_mask)?,
+        &written,
+    ))
+}
+
+///
+/// Fails if embedding would flip more than `max_changed_bits` of the bits carrying header and payload,
+/// see [`planned_change_rate`]. Nothing is written either way.
+pub(crate) fn check_change_budget(
+    cover: &dyn PngImage,
+    header: &VersionedHeader,
+    payload: &[u8],
+    avoid_mask: Option<&AvoidMask>,
+    max_changed_bits: u64,
+) -> Result<ChangeRate, String> {
+    let planned = planned_change_rate(cover, header, payload, avoid_mask)?;
+    if planned.changed_bits > max_changed_bits {
+        return Err(format!(
+            "Embedding would flip {} bits, more than the budget of {} set by --max-bits-changed",
+            planned.changed_bits, max_changed_bits
+        ));
+    }
+    Ok(planned)
+}
+
+///
+/// The header bytes followed by the payload bytes, as currently stored in the image
+fn used_bits(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<Vec<u8>, String> {
+    if header.scatter_seed().is_some() {
+        return Err("The change rate cannot be computed for scattered headers".to_string());
+    }
+    let header_len = (header.pixel_span()? / 8) as usize;
+    let data_len = header.data_len() as usize;
+    let header_mask = header_mask_for(header.data_mask(), image.color_type());
+
+    let mut bits = image.read_data_with_mask(header_mask, 0, header_len);
+    bits.extend(
+        match restricted_payload_pixels(image, header, avoid_mask)? {
+            Some(pixels) => image.read_data_at_pixels(header.data_mask(), &pixels, data_len),
+            None => image.read_data_with_mask(
+                header.data_mask(),
+                header.start_offset() as usize,
+                data_len,
+            ),
+        },
+    );
+    Ok(bits)
+}
+
+fn compare_bits(before: &[u8], after: &[u8]) -> ChangeRate {
+    ChangeRate {
+        changed_bits: before
+            .iter()
+            .zip(after)
+            .map(|(before, after)| (before ^ after).count_ones() as u64)
+            .sum(),
+        used_bits: before.len() as u64 * 8,
+    }
+}
+
+///
+/// Outcome of embedding the payload with one bits-per-pixel setting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct SweepResult {
+    pub(crate) bits_per_pixel: u8,
+    pub(crate) psnr: f64,
+    pub(crate) file_size: usize,
+}
+
+///
+/// Embeds the payload with every bits-per-pixel setting from the header's up to the full pixel.
+/// Picks the smallest output file among the settings whose PSNR reaches `target_psnr`.
+/// Settings with about the same file size are told apart by their PSNR.
+/// Returns the header of the chosen setting, along with the results of all settings.
+pub(crate) fn sweep_bits_per_pixel(
+    cover: &DynamicImage,
+    header: &VersionedHeader,
+    payload: &[u8],
+    format: OutputFormat,
+    target_psnr: f64,
+    max_bits_per_channel: Option<u8>,
+) -> Result<(VersionedHeader, Vec<SweepResult>), String> {
+    let color_type = cover.color();
+    let min_bits = header.data_mask().count_ones() as u8;
+    let max_bits = color_type.bits_per_pixel().min(u64::BITS as u16) as u8;
+
+    let mut results = Vec::new();
+    for bits_per_pixel in min_bits..=max_bits {
+        let candidate = header
+            .clone()
+            .with_bits_per_pixel(bits_per_pixel, color_type);
+        // Masks only grow with the bits per pixel, so no later setting passes either
+        if check_low_bits_only(candidate.data_mask(), color_type, max_bits_per_channel).is_err() {
+            break;
+        }
+        let mut modified = cover.clone();
+        let image = convert_dynamic_image_to_png_image(&mut modified)?;
+        write_payload(image, &candidate, payload, None)?;
+        let file_size = image.save_to_buffer(format.image_output_format())?.len();
+
+        results.push(SweepResult {
+            bits_per_pixel,
+            psnr: psnr(cover, &modified),
+            file_size,
+        });
+    }
+
+    if results.is_empty() {
+        check_low_bits_only(header.data_mask(), color_type, max_bits_per_channel)?;
+    }
+    let passing: Vec<&SweepResult> = results
+        .iter()
+        .filter(|result| result.psnr >= target_psnr)
+        .collect();
+    let Some(smallest) = passing.iter().map(|result| result.file_size).min() else {
+        let best = results
+            .iter()
+            .map(|result| result.psnr)
+            .fold(f64::NEG_INFINITY, f64::max);
+        return Err(format!(
+            "No bits per pixel setting reaches a PSNR of {:.1} dB, the best is {:.1} dB",
+            target_psnr, best
+        ));
+    };
+    // Among settings of about the smallest size, the least visible one wins
+    let chosen = passing
+        .into_iter()
+        .filter(|result| result.file_size as f64 <= smallest as f64 * (1.0 + FILE_SIZE_TOLERANCE))
+        .max_by(|a, b| a.psnr.total_cmp(&b.psnr))
+        .expect("the smallest setting is within the tolerance");
+
+    Ok((
+        header
+            .clone()
+            .with_bits_per_pixel(chosen.bits_per_pixel, color_type),
+        results,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgb};
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, HeaderRaw},
+        payload::write_payload_with_stats,
+        prng::{enable_deterministic_mode, DETERMINISTIC_SEED},
+    };
+
+    fn gradient_cover(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(ImageBuffer::from_fn(width, height, |x, y| {
+            Rgb([(x * 7 + y) as u8, (y * 5) as u8, (x ^ y) as u8])
+        }))
+    }
+
+    fn chosen_bits(cover: &DynamicImage, payload_len: usize) -> u8 {
+        // File sizes depend on where the payload lands, so fix the offset
+        enable_deterministic_mode(DETERMINISTIC_SEED);
+        let payload = vec![0xA7u8; payload_len];
+        let pixel_count = cover.width() as u64 * cover.height() as u64;
+        let header = generate_v3_header(
+            pixel_count,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+
+        let (header, results) = sweep_bits_per_pixel(
+            cover,
+            &header,
+            &payload,
+            OutputFormat::Png,
+            DEFAULT_TARGET_PSNR,
+            None,
+        )
+        .unwrap();
+        assert!(results
+            .iter()
+            .any(|result| result.psnr >= DEFAULT_TARGET_PSNR));
+        header.data_mask().count_ones() as u8
+    }
+
+    #[test]
+    fn change_rate_counts_flipped_bits_only() {
+        let payload = vec![0xFFu8; 100];
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        let raw_header: HeaderRaw = header.clone().try_into().unwrap();
+        let header_ones: u64 = raw_header
+            .to_bytes()
+            .iter()
+            .map(|byte| byte.count_ones() as u64)
+            .sum();
+        let header_bits = header.pixel_span().unwrap();
+        let rate_on = |channel_value: u8| {
+            let cover = ImageBuffer::from_pixel(64, 64, Rgb([channel_value; 3]));
+            let mut encoded = cover.clone();
+            let stats = write_payload_with_stats(&mut encoded, &header, &payload, None).unwrap();
+            let rate = change_rate(&cover, &encoded, &header, None).unwrap();
+            // Counted while writing, the flips match the comparison of the images
+            assert_eq!(stats.changed_bits, rate.changed_bits);
+            rate
+        };
+
+        // On black, exactly the set bits of header and payload flip
+        assert_eq!(
+            rate_on(0x00),
+            ChangeRate {
+                changed_bits: header_ones + 800,
+                used_bits: header_bits + 800
+            }
+        );
+        // On white, the payload bits already match, only the unset header bits flip
+        let white = rate_on(0xFF);
+        assert_eq!(white.changed_bits, header_bits - header_ones);
+        assert!(white.rate() < 0.5, "{}", white.rate());
+    }
+
+    #[test]
+    fn change_budget_rejects_payloads_flipping_too_many_bits() {
+        let cover = ImageBuffer::from_pixel(64, 64, Rgb([0u8; 3]));
+        let header_for = |payload: &[u8]| {
+            generate_v3_header(
+                64 * 64,
+                payload,
+                ColorType::Rgb8,
+                CrcSpec::default(),
+                Vec::new(),
+                true,
+            )
+            .unwrap()
+        };
+        // On black, every set bit of header and payload has to flip
+        let loud = vec![0xFFu8; 100];
+        let gentle = vec![0x01u8; 100];
+        let (loud_header, gentle_header) = (header_for(&loud), header_for(&gentle));
+        let budget = 400;
+
+        assert!(check_change_budget(&cover, &loud_header, &loud, None, budget).is_err());
+        let planned = check_change_budget(&cover, &gentle_header, &gentle, None, budget).unwrap();
+
+        // The dry pass predicts exactly what embedding flips
+        let mut encoded = cover.clone();
+        write_payload(&mut encoded, &gentle_header, &gentle, None).unwrap();
+        assert_eq!(
+            change_rate(&cover, &encoded, &gentle_header, None).unwrap(),
+            planned
+        );
+    }
+
+    #[test]
+    fn psnr_of_identical_images_is_infinite() {
+        let cover = gradient_cover(16, 16);
+
+        assert_eq!(psnr(&cover, &cover), f64::INFINITY);
+    }
+
+    #[test]
+    fn tight_capacity_needs_more_bits_per_pixel() {
+        let cover = gradient_cover(64, 64);
+
+        // 6 bits per pixel are needed to fit this payload at all
+        let tight = chosen_bits(&cover, 64 * 64 * 6 / 8 - 100);
+        let roomy = chosen_bits(&cover, 200);
+
+        assert!(tight >= 6, "{}", tight);
+        assert!(roomy < tight, "{} < {}", roomy, tight);
+    }
+}
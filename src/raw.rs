@@ -0,0 +1,864 @@
+//! Bit packing on raw pixel buffers, without depending on an image library.
+//!
+//! For callers with their own image pipeline: [`embed`] and [`extract`] hide data in the bits of any
+//! tightly packed buffer, described by a [`PixelFormat`]. Multi-byte channels are expected in big-endian order.
+//! Masks address the bits of a pixel from its first byte on, most significant bit first,
+//! so `1 << 63` is the highest bit of the first channel. Only the first 64 bits of a pixel can be addressed.
+
+use std::ops::{Add, AddAssign, Range};
+
+use rayon::prelude::*;
+
+/// Payload bytes handled by one parallel task when reading or writing a sequential run of pixels
+const PARALLEL_CHUNK_BYTES: usize = 64 * 1024;
+
+///
+/// Layout of the pixels in a buffer. Pixels follow each other without padding, as do their channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub channels: u8,
+    pub bytes_per_channel: u8,
+}
+
+impl PixelFormat {
+    pub const fn new(channels: u8, bytes_per_channel: u8) -> PixelFormat {
+        PixelFormat {
+            channels,
+            bytes_per_channel,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.channels as usize * self.bytes_per_channel as usize
+    }
+
+    pub fn bits_per_pixel(&self) -> usize {
+        self.bytes_per_pixel() * 8
+    }
+
+    /// Number of whole pixels in a buffer of this format
+    pub fn pixel_count(&self, buffer: &[u8]) -> usize {
+        match self.bytes_per_pixel() {
+            0 => 0,
+            bytes => buffer.len() / bytes,
+        }
+    }
+}
+
+///
+/// What writing into a buffer changed. Bits which already held the value written are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteStats {
+    /// Pixels with at least one flipped bit
+    pub changed_pixels: u64,
+    /// Bits which were flipped
+    pub changed_bits: u64,
+}
+
+impl Add for WriteStats {
+    type Output = WriteStats;
+
+    fn add(self, other: WriteStats) -> WriteStats {
+        WriteStats {
+            changed_pixels: self.changed_pixels + other.changed_pixels,
+            changed_bits: self.changed_bits + other.changed_bits,
+        }
+    }
+}
+
+impl AddAssign for WriteStats {
+    fn add_assign(&mut self, other: WriteStats) {
+        *self = *self + other;
+    }
+}
+
+///
+/// Writes `data` into the bits of `mask`, starting at pixel `offset`, and returns what this changed.
+/// Fails without touching the buffer if the data does not fit behind the offset.
+pub fn embed(
+    buffer: &mut [u8],
+    format: PixelFormat,
+    mask: u64,
+    offset: usize,
+    data: &[u8],
+) -> Result<WriteStats, String> {
+    check_access(buffer, format, mask, offset, data.len())?;
+    Ok(write_to_buffer(buffer, offset, mask, format, data))
+}
+
+///
+/// Reads `len` bytes from the bits of `mask`, starting at pixel `offset`
+pub fn extract(
+    buffer: &[u8],
+    format: PixelFormat,
+    mask: u64,
+    offset: usize,
+    len: usize,
+) -> Result<Vec<u8>, String> {
+    check_access(buffer, format, mask, offset, len)?;
+    Ok(read_from_buffer(buffer, offset, len, mask, format))
+}
+
+///
+/// Whether `len` bytes fit into the bits of `mask`, behind pixel `offset`
+fn check_access(
+    buffer: &[u8],
+    format: PixelFormat,
+    mask: u64,
+    offset: usize,
+    len: usize,
+) -> Result<(), String> {
+    if format.bytes_per_pixel() == 0 {
+        return Err("A pixel needs at least one channel of at least one byte".to_string());
+    }
+    let bits_per_pixel = create_offset_map(mask, format.bits_per_pixel()).len();
+    if bits_per_pixel == 0 {
+        return Err("The mask selects no bit of the pixel".to_string());
+    }
+    if bits_per_pixel != mask.count_ones() as usize {
+        return Err(format!(
+            "The mask selects bits beyond the {} bits of a pixel",
+            format.bits_per_pixel()
+        ));
+    }
+
+    let available_bits = format
+        .pixel_count(buffer)
+        .saturating_sub(offset)
+        .saturating_mul(bits_per_pixel);
+    match len.checked_mul(8) {
+        Some(needed_bits) if needed_bits <= available_bits => Ok(()),
+        _ => Err(format!(
+            "{} bytes do not fit behind pixel {}, there is room for {} bytes",
+            len,
+            offset,
+            available_bits / 8
+        )),
+    }
+}
+
+///
+/// Splits a sequential run into chunks which can be processed independently.
+/// Every chunk starts at a byte of the data and at a pixel of the image,
+/// so it spans a multiple of lcm(8, bits per pixel) bits.
+/// Returns the data bytes and the pixels per chunk.
+fn parallel_chunk_layout(bits_per_pixel: usize) -> (usize, usize) {
+    let (mut a, mut b) = (8, bits_per_pixel);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let lcm_bits = 8 * bits_per_pixel / a;
+    let units = (PARALLEL_CHUNK_BYTES * 8 / lcm_bits).max(1);
+
+    (units * lcm_bits / 8, units * lcm_bits / bits_per_pixel)
+}
+
+///
+/// read_mask is a right-padded mask defining which bits in a pixel are relevant.
+/// Large reads are split into chunks which are read in parallel.
+pub fn read_from_buffer(
+    image_buf: &[u8],
+    pixels_offset_start: usize,
+    bytes_len_read: usize,
+    read_mask: u64,
+    format: PixelFormat,
+) -> Vec<u8> {
+    let bits_per_pixel = create_offset_map(read_mask, format.bits_per_pixel()).len();
+    if bits_per_pixel == 0 || bytes_len_read <= PARALLEL_CHUNK_BYTES {
+        return read_from_buffer_at_pixels(
+            image_buf,
+            pixels_offset_start..pixel_count(image_buf, format),
+            bytes_len_read,
+            read_mask,
+            format,
+        );
+    }
+
+    let (chunk_bytes, chunk_pixels) = parallel_chunk_layout(bits_per_pixel);
+    (0..bytes_len_read.div_ceil(chunk_bytes))
+        .into_par_iter()
+        .map(|chunk| {
+            let first_byte = chunk * chunk_bytes;
+            read_from_buffer_at_pixels(
+                image_buf,
+                pixels_offset_start + chunk * chunk_pixels..pixel_count(image_buf, format),
+                chunk_bytes.min(bytes_len_read - first_byte),
+                read_mask,
+                format,
+            )
+        })
+        .collect::<Vec<Vec<u8>>>()
+        .concat()
+}
+
+///
+/// Like [`read_from_buffer`], but only visits the given pixel indices, in the given order.
+/// Reading stops with the last byte, bits of its pixel which follow it are never read.
+pub fn read_from_buffer_at_pixels(
+    image_buf: &[u8],
+    pixels: impl IntoIterator<Item = usize>,
+    bytes_len_read: usize,
+    read_mask: u64,
+    format: PixelFormat,
+) -> Vec<u8> {
+    let offset_map = create_offset_map(read_mask, format.bits_per_pixel());
+    if offset_map.is_empty() {
+        panic!("offset-map is empty. Cannot continue.");
+    }
+    if bytes_len_read == 0 {
+        return Vec::new();
+    }
+
+    let mut return_data: Vec<u8> = Vec::new();
+
+    let mut current_byte_vec: Vec<bool> = Vec::with_capacity(8);
+
+    // Loop over all pixels. This will break out once bytes_len_read is finished
+    for current_pixel_index in pixels {
+        let current_pixel_slice =
+            get_pixel_slice(image_buf, format.bytes_per_pixel(), current_pixel_index);
+
+        for in_pixel_offset in &offset_map {
+            let bit_value = current_pixel_slice[in_pixel_offset / 8]
+                & (0b1u8 << 7 >> (in_pixel_offset % 8))
+                != 0;
+            current_byte_vec.push(bit_value);
+
+            if current_byte_vec.len() == 8 {
+                // Now build the byte
+                let mut byte = 0u8;
+                for (i, bit) in current_byte_vec.iter().enumerate() {
+                    if !bit {
+                        continue;
+                    }
+                    byte |= 0b1 << 7 >> i;
+                }
+                return_data.push(byte);
+                current_byte_vec.clear();
+                if return_data.len() == bytes_len_read {
+                    return return_data;
+                }
+            }
+        }
+    }
+    panic!("Ran out of pixels before all data was read.");
+}
+
+///
+/// Large writes are split into chunks which are written in parallel.
+pub fn write_to_buffer(
+    image_buf: &mut [u8],
+    pixels_offset_start: usize,
+    write_mask: u64,
+    format: PixelFormat,
+    data_to_write: &[u8],
+) -> WriteStats {
+    let bits_per_pixel = create_offset_map(write_mask, format.bits_per_pixel()).len();
+    if bits_per_pixel == 0 || data_to_write.len() <= PARALLEL_CHUNK_BYTES {
+        let pixels = pixels_offset_start..pixel_count(image_buf, format);
+        return write_to_buffer_at_pixels(image_buf, pixels, write_mask, format, data_to_write);
+    }
+
+    let pixel_len = format.bytes_per_pixel();
+    let region = &mut image_buf[pixels_offset_start * pixel_len..];
+    if region.len() / pixel_len * bits_per_pixel < data_to_write.len() * 8 {
+        panic!("Ran out of pixels before all data was written.");
+    }
+    let (chunk_bytes, chunk_pixels) = parallel_chunk_layout(bits_per_pixel);
+    // Chunks start at a pixel, so no pixel is counted twice
+    region
+        .par_chunks_mut(chunk_pixels * pixel_len)
+        .zip(data_to_write.par_chunks(chunk_bytes))
+        .map(|(pixels, data)| write_to_buffer_at_pixels(pixels, 0.., write_mask, format, data))
+        .reduce(WriteStats::default, Add::add)
+}
+
+///
+/// Like [`write_to_buffer`], but only visits the given pixel indices, in the given order.
+pub fn write_to_buffer_at_pixels(
+    image_buf: &mut [u8],
+    pixels: impl IntoIterator<Item = usize>,
+    write_mask: u64,
+    format: PixelFormat,
+    data_to_write: &[u8],
+) -> WriteStats {
+    let offset_map = create_offset_map(write_mask, format.bits_per_pixel());
+    if offset_map.is_empty() {
+        panic!("offset-map is empty. Cannot continue.");
+    }
+    let mut stats = WriteStats::default();
+    if data_to_write.is_empty() {
+        return stats;
+    }
+    let mut current_byte_to_write: Vec<bool> = Vec::with_capacity(8);
+    let mut data_to_write_index = 0usize;
+
+    let current_byte = data_to_write[data_to_write_index];
+    for i in 0..8 {
+        current_byte_to_write.push(current_byte & (0b1u8 << 7 >> i) != 0);
+    }
+    current_byte_to_write.reverse(); // Reversed as we will just "pop" from the back
+
+    for current_pixel_index in pixels {
+        let current_pixel_slice =
+            get_pixel_slice_mut(image_buf, format.bytes_per_pixel(), current_pixel_index);
+        let mut pixel_changed = false;
+
+        for in_pixel_offset in &offset_map {
+            let local_pixel_offset = in_pixel_offset / 8;
+            let local_mask = 0b1u8 << 7 >> (in_pixel_offset % 8);
+            let before = current_pixel_slice[local_pixel_offset];
+            // inverted mask causes the value bit to be set to 0
+            current_pixel_slice[local_pixel_offset] &= !local_mask;
+            if current_byte_to_write.pop().unwrap() {
+                // set the value bit to 1
+                current_pixel_slice[local_pixel_offset] |= local_mask;
+            }
+            // Only a single bit was touched, so the byte differs exactly if that bit flipped
+            if current_pixel_slice[local_pixel_offset] != before {
+                stats.changed_bits += 1;
+                pixel_changed = true;
+            }
+            if current_byte_to_write.is_empty() {
+                data_to_write_index += 1;
+                if data_to_write_index >= data_to_write.len() {
+                    stats.changed_pixels += pixel_changed as u64;
+                    return stats;
+                }
+                let current_byte = data_to_write[data_to_write_index];
+                for i in 0..8 {
+                    current_byte_to_write.push((current_byte & (0b1u8 << 7 >> i)) != 0);
+                }
+                current_byte_to_write.reverse() // Reversed as we will just "pop" from the back
+            }
+        }
+        stats.changed_pixels += pixel_changed as u64;
+    }
+    panic!("Ran out of pixels before all data was written.");
+}
+
+///
+/// Number of whole pixels in the buffer. Pixel ranges end here, so running past the last pixel
+/// reports that the data did not fit instead of failing on the slice access.
+fn pixel_count(image_buf: &[u8], format: PixelFormat) -> usize {
+    image_buf.len() / format.bytes_per_pixel()
+}
+
+///
+/// Returns the byte range of a given pixel inside the raw image buffer.
+fn pixel_byte_range(pixel_len_bytes: usize, pixel_index: usize) -> Result<Range<usize>, String> {
+    let too_large = || {
+        format!(
+            "Image too large for this platform: pixel {} is out of addressable range",
+            pixel_index
+        )
+    };
+
+    let start = pixel_index
+        .checked_mul(pixel_len_bytes)
+        .ok_or_else(too_large)?;
+    let end = start.checked_add(pixel_len_bytes).ok_or_else(too_large)?;
+
+    Ok(start..end)
+}
+
+fn get_pixel_slice(image_buf: &[u8], pixel_len_bytes: usize, current_pixel_index: usize) -> &[u8] {
+    let range = pixel_byte_range(pixel_len_bytes, current_pixel_index)
+        .unwrap_or_else(|err| panic!("{}", err));
+    &image_buf[range]
+}
+
+fn get_pixel_slice_mut(
+    image_buf: &mut [u8],
+    pixel_len_bytes: usize,
+    current_pixel_index: usize,
+) -> &mut [u8] {
+    let range = pixel_byte_range(pixel_len_bytes, current_pixel_index)
+        .unwrap_or_else(|err| panic!("{}", err));
+    &mut image_buf[range]
+}
+
+///
+/// Returns a vec containing an "offset map" which defines the offsets of all value-bits
+pub fn create_offset_map(write_mask: u64, pixel_size: usize) -> Vec<usize> {
+    let mut return_map = Vec::new();
+    // The mask only reaches the first 64 bits of wider pixels
+    for i in 0..pixel_size.min(u64::BITS as usize) {
+        if ((0b1 << 63 >> i) & write_mask) > 0 {
+            return_map.push(i)
+        }
+    }
+
+    return_map
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    use super::*;
+
+    ///
+    /// Bit by bit reference for the packing. Deliberately simple, so optimizations of the real
+    /// implementation can be checked against it, see the `*_matches_reference` properties.
+    fn reference_write(
+        buffer: &mut [u8],
+        format: PixelFormat,
+        mask: u64,
+        pixels: &[usize],
+        data: &[u8],
+    ) {
+        let mask_bits: Vec<usize> = (0..64).filter(|bit| mask & 1 << (63 - bit) != 0).collect();
+        let data_bits = data
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |shift| byte >> shift & 1));
+        for (index, bit) in data_bits.enumerate() {
+            let pixel = pixels[index / mask_bits.len()];
+            let position = pixel * format.bits_per_pixel() + mask_bits[index % mask_bits.len()];
+            let byte = &mut buffer[position / 8];
+            *byte = *byte & !(0x80 >> (position % 8)) | bit << 7 >> (position % 8);
+        }
+    }
+
+    /// See [`reference_write`]
+    fn reference_read(
+        buffer: &[u8],
+        format: PixelFormat,
+        mask: u64,
+        pixels: &[usize],
+        len: usize,
+    ) -> Vec<u8> {
+        let mask_bits: Vec<usize> = (0..64).filter(|bit| mask & 1 << (63 - bit) != 0).collect();
+        (0..len)
+            .map(|byte| {
+                (0..8).fold(0u8, |value, bit| {
+                    let index = byte * 8 + bit;
+                    let pixel = pixels[index / mask_bits.len()];
+                    let position =
+                        pixel * format.bits_per_pixel() + mask_bits[index % mask_bits.len()];
+                    value << 1 | buffer[position / 8] >> (7 - position % 8) & 1
+                })
+            })
+            .collect()
+    }
+
+    /// A pixel format with up to 4 channels of up to 3 bytes, and a mask selecting some of its first 64 bits
+    fn format_and_mask() -> impl Strategy<Value = (PixelFormat, u64)> {
+        (1u8..=4, 1u8..=3, any::<u64>()).prop_filter_map(
+            "the mask selects no bit of the pixel",
+            |(channels, bytes_per_channel, mask)| {
+                let format = PixelFormat::new(channels, bytes_per_channel);
+                let mask = mask & u64::MAX << (64 - format.bits_per_pixel().min(64));
+                (mask != 0).then_some((format, mask))
+            },
+        )
+    }
+
+    /// A random buffer holding `len` bytes behind `offset` pixels, plus a few spare pixels
+    fn buffer_for(format: PixelFormat, mask: u64, offset: usize, len: usize, seed: u64) -> Vec<u8> {
+        let pixels = offset + (len * 8).div_ceil(mask.count_ones() as usize) + 3;
+        let mut buffer = vec![0u8; pixels * format.bytes_per_pixel()];
+        StdRng::seed_from_u64(seed).fill_bytes(&mut buffer);
+        buffer
+    }
+
+    fn sequential_matches_reference(
+        (format, mask): (PixelFormat, u64),
+        offset: usize,
+        len: usize,
+        seed: u64,
+    ) -> Result<(), TestCaseError> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut data = vec![0u8; len];
+        rng.fill_bytes(&mut data);
+        let mut buffer = buffer_for(format, mask, offset, len, rng.next_u64());
+        let mut expected = buffer.clone();
+        let pixels: Vec<usize> = (offset..format.pixel_count(&buffer)).collect();
+
+        write_to_buffer(&mut buffer, offset, mask, format, &data);
+        reference_write(&mut expected, format, mask, &pixels, &data);
+
+        prop_assert!(buffer == expected, "written buffers differ");
+        prop_assert!(read_from_buffer(&buffer, offset, len, mask, format) == data);
+        prop_assert!(reference_read(&buffer, format, mask, &pixels, len) == data);
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn packing_matches_reference(
+            format_and_mask in format_and_mask(),
+            offset in 0usize..16,
+            len in 0usize..256,
+            seed in any::<u64>(),
+        ) {
+            sequential_matches_reference(format_and_mask, offset, len, seed)?;
+        }
+
+        #[test]
+        fn packing_at_pixels_matches_reference(
+            (format, mask) in format_and_mask(),
+            len in 0usize..64,
+            order in any::<u64>(),
+            seed in any::<u64>(),
+        ) {
+            let mut data = vec![0u8; len];
+            StdRng::seed_from_u64(seed).fill_bytes(&mut data);
+            let mut buffer = buffer_for(format, mask, 0, len, seed);
+            let mut expected = buffer.clone();
+            // Every pixel once, in an order derived from the seed
+            let pixel_count = format.pixel_count(&buffer);
+            let step = (order as usize % pixel_count).max(1);
+            let step = (step..).find(|step| gcd(*step, pixel_count) == 1).unwrap();
+            let pixels: Vec<usize> = (0..pixel_count).map(|index| index * step % pixel_count).collect();
+
+            write_to_buffer_at_pixels(&mut buffer, pixels.iter().copied(), mask, format, &data);
+            reference_write(&mut expected, format, mask, &pixels, &data);
+
+            prop_assert!(buffer == expected, "written buffers differ");
+            prop_assert!(read_from_buffer_at_pixels(&buffer, pixels.iter().copied(), len, mask, format) == data);
+        }
+    }
+
+    proptest! {
+        // Large enough for the parallel chunks, which are slow to check bit by bit
+        #![proptest_config(ProptestConfig::with_cases(8))]
+        #[test]
+        fn parallel_packing_matches_reference(
+            format_and_mask in format_and_mask(),
+            offset in 0usize..16,
+            extra in 0usize..PARALLEL_CHUNK_BYTES,
+            seed in any::<u64>(),
+        ) {
+            sequential_matches_reference(format_and_mask, offset, PARALLEL_CHUNK_BYTES + 1 + extra, seed)?;
+        }
+    }
+
+    fn gcd(a: usize, b: usize) -> usize {
+        match b {
+            0 => a,
+            _ => gcd(b, a % b),
+        }
+    }
+
+    #[test]
+    fn create_offset_map_test() {
+        let input =
+            0b1000_0100_0010_0001_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0000_0001_u64;
+
+        let output = create_offset_map(input, 64);
+
+        assert_eq!(output, vec![0usize, 5usize, 10usize, 15usize, 63usize])
+    }
+
+    #[test]
+    fn encode_and_decode_into_byte_buffer() {
+        let mut image_buf = vec![0u8; 200];
+        rand::thread_rng().fill_bytes(&mut image_buf);
+
+        let data: Vec<u8> = vec![0x12, 0x34, 0x56, 0x78];
+        write_to_buffer(
+            &mut image_buf,
+            0,
+            0x01_01_01_00_00_00_00_00u64,
+            PixelFormat::new(4, 1),
+            &data,
+        );
+
+        let result = read_from_buffer(
+            &image_buf,
+            0,
+            4,
+            0x01_01_01_00_00_00_00_00u64,
+            PixelFormat::new(4, 1),
+        );
+
+        assert_eq!(data, result);
+    }
+
+    #[test]
+    fn empty_data_is_written_and_read_as_empty() {
+        let mut image_buf = vec![0u8; 12];
+        rand::thread_rng().fill_bytes(&mut image_buf);
+        let original = image_buf.clone();
+        let mask = 0x01_01_01_00_00_00_00_00u64;
+
+        // Starting right after the last pixel, no pixel is visited
+        write_to_buffer(&mut image_buf, 3, mask, PixelFormat::new(4, 1), &[]);
+        assert_eq!(image_buf, original);
+        assert!(read_from_buffer(&image_buf, 3, 0, mask, PixelFormat::new(4, 1)).is_empty());
+
+        // A single byte needs 3 pixels at 3 bits per pixel, which is exactly what is left
+        write_to_buffer(&mut image_buf, 0, mask, PixelFormat::new(4, 1), &[0xA5]);
+        assert_eq!(
+            read_from_buffer(&image_buf, 0, 1, mask, PixelFormat::new(4, 1)),
+            [0xA5]
+        );
+    }
+
+    #[test]
+    fn bits_per_pixel_not_dividing_a_byte_round_trip() {
+        let format = PixelFormat::new(4, 1);
+        // 3, 5 and 7 bits per pixel, so bytes end in the middle of a pixel
+        for mask in [
+            0x01_01_01_00_00_00_00_00u64,
+            0x03_01_03_00_00_00_00_00,
+            0x07_01_03_01_00_00_00_00,
+        ] {
+            let bits_per_pixel = mask.count_ones() as usize;
+            for len in 1..=(bits_per_pixel + 2) {
+                let mut data = vec![0u8; len];
+                rand::thread_rng().fill_bytes(&mut data);
+                let mut image_buf = vec![0u8; 4 * ((len * 8).div_ceil(bits_per_pixel) + 2)];
+                rand::thread_rng().fill_bytes(&mut image_buf);
+                let untouched = image_buf.clone();
+
+                write_to_buffer(&mut image_buf, 1, mask, format, &data);
+
+                assert_eq!(read_from_buffer(&image_buf, 1, len, mask, format), data);
+                // Shorter reads stop at their last byte, without the bits following it
+                assert_eq!(
+                    read_from_buffer(&image_buf, 1, len - 1, mask, format),
+                    data[..len - 1]
+                );
+                // The masked bits after the payload, in its last pixel and beyond, keep their values
+                let masked_bits = |buffer: &[u8]| -> Vec<u8> {
+                    buffer[4..]
+                        .chunks_exact(4)
+                        .flat_map(|pixel| {
+                            create_offset_map(mask, 32)
+                                .into_iter()
+                                .map(move |offset| pixel[offset / 8] >> (7 - offset % 8) & 1)
+                        })
+                        .collect()
+                };
+                assert_eq!(
+                    masked_bits(&image_buf)[len * 8..],
+                    masked_bits(&untouched)[len * 8..],
+                    "mask {:#x}, {} bytes",
+                    mask,
+                    len
+                );
+                assert_eq!(image_buf[..4], untouched[..4]);
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_chunks_match_sequential_write() {
+        // Odd bits per pixel, so chunks do not line up with bytes and pixels by accident
+        let mask = 0x03_01_00_00_00_00_00_00u64;
+        let mut data = vec![0u8; PARALLEL_CHUNK_BYTES * 3 + 17];
+        rand::thread_rng().fill_bytes(&mut data);
+        let mut image_buf = vec![0u8; (data.len() * 8).div_ceil(3) * 4 + 40];
+        rand::thread_rng().fill_bytes(&mut image_buf);
+        let mut sequential = image_buf.clone();
+
+        let parallel_stats =
+            write_to_buffer(&mut image_buf, 10, mask, PixelFormat::new(4, 1), &data);
+        let sequential_stats =
+            write_to_buffer_at_pixels(&mut sequential, 10.., mask, PixelFormat::new(4, 1), &data);
+
+        assert!(image_buf == sequential);
+        assert_eq!(parallel_stats, sequential_stats);
+        assert!(read_from_buffer(&image_buf, 10, data.len(), mask, PixelFormat::new(4, 1)) == data);
+    }
+
+    #[test]
+    fn write_counts_flipped_bits_and_pixels() {
+        // The least significant bit of every channel, 3 bits per pixel
+        let mask = 0x01_01_01_00_00_00_00_00u64;
+        let format = PixelFormat::new(3, 1);
+        // 24 bits over 8 pixels: 111 111 110 000 000 000 001 111
+        let data = [0xFF, 0x00, 0x0F];
+
+        let mut black = vec![0u8; 8 * 3];
+        let stats = embed(&mut black, format, mask, 0, &data).unwrap();
+        assert_eq!(
+            stats,
+            WriteStats {
+                changed_pixels: 5,
+                changed_bits: 12
+            }
+        );
+        // Writing the same data again flips nothing
+        assert_eq!(
+            embed(&mut black, format, mask, 0, &data).unwrap(),
+            WriteStats::default()
+        );
+
+        // With all low bits set, only the zeros of the data flip, in pixels 2 to 6
+        let mut white = vec![0xFFu8; 8 * 3];
+        assert_eq!(
+            embed(&mut white, format, mask, 0, &data).unwrap(),
+            WriteStats {
+                changed_pixels: 5,
+                changed_bits: 12
+            }
+        );
+    }
+
+    #[test]
+    fn single_thread_matches_multi_threaded_write() {
+        let mask = 0x03_03_03_00_00_00_00_00u64;
+        let mut data = vec![0u8; PARALLEL_CHUNK_BYTES * 4];
+        rand::thread_rng().fill_bytes(&mut data);
+        let mut cover = vec![0u8; (data.len() * 8).div_ceil(6) * 3];
+        rand::thread_rng().fill_bytes(&mut cover);
+
+        let write_with_threads = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            let mut image_buf = cover.clone();
+            pool.install(|| {
+                write_to_buffer(&mut image_buf, 0, mask, PixelFormat::new(3, 1), &data)
+            });
+            image_buf
+        };
+        let single = write_with_threads(1);
+        let multi = write_with_threads(4);
+
+        assert!(single == multi);
+        assert!(read_from_buffer(&single, 0, data.len(), mask, PixelFormat::new(3, 1)) == data);
+    }
+
+    #[test]
+    fn encode_and_decode_at_pixels() {
+        let mut image_buf = vec![0u8; 200];
+        rand::thread_rng().fill_bytes(&mut image_buf);
+        let untouched = image_buf.clone();
+        let pixels = vec![1usize, 4, 7, 10, 13, 16, 19, 22, 25, 28];
+
+        let data: Vec<u8> = vec![0x12, 0x34, 0x56];
+        write_to_buffer_at_pixels(
+            &mut image_buf,
+            pixels.iter().copied(),
+            0x01_01_01_00_00_00_00_00u64,
+            PixelFormat::new(4, 1),
+            &data,
+        );
+
+        for pixel in 0..50 {
+            if !pixels.contains(&pixel) {
+                assert_eq!(
+                    image_buf[pixel * 4..pixel * 4 + 4],
+                    untouched[pixel * 4..pixel * 4 + 4]
+                );
+            }
+        }
+
+        let result = read_from_buffer_at_pixels(
+            &image_buf,
+            pixels,
+            3,
+            0x01_01_01_00_00_00_00_00u64,
+            PixelFormat::new(4, 1),
+        );
+
+        assert_eq!(data, result);
+    }
+
+    #[test]
+    fn pixel_byte_range_test() {
+        assert_eq!(pixel_byte_range(4, 3).unwrap(), 12..16);
+    }
+
+    #[test]
+    fn pixel_byte_range_beyond_usize_errors() {
+        assert!(pixel_byte_range(4, usize::MAX).is_err());
+        assert!(pixel_byte_range(4, usize::MAX / 4).is_err());
+    }
+
+    #[test]
+    fn embed_and_extract_five_channel_pixels() {
+        // CMYK plus alpha, which no `ColorType` describes. The LSB of every channel carries data.
+        let format = PixelFormat::new(5, 1);
+        let mask = 0x01_01_01_01_01u64 << 24;
+        let mut buffer = vec![0u8; 5 * 100];
+        rand::thread_rng().fill_bytes(&mut buffer);
+        let untouched = buffer.clone();
+        let data = b"five channels".to_vec();
+
+        embed(&mut buffer, format, mask, 7, &data).unwrap();
+
+        assert_eq!(extract(&buffer, format, mask, 7, data.len()).unwrap(), data);
+        assert_eq!(buffer[..7 * 5], untouched[..7 * 5]);
+        for (byte, original) in buffer.iter().zip(&untouched) {
+            assert_eq!(byte & 0xFE, original & 0xFE);
+        }
+    }
+
+    #[test]
+    fn embed_and_extract_three_byte_channels() {
+        // 24-bit samples, big-endian. Only the first 64 of the 72 bits per pixel can be addressed.
+        let format = PixelFormat::new(3, 3);
+        let mask = 1u64 << 40 | 1u64 << 17;
+        let mut buffer = vec![0u8; 9 * 64];
+        rand::thread_rng().fill_bytes(&mut buffer);
+        let data = [0xC3u8, 0x5A, 0x0F];
+
+        embed(&mut buffer, format, mask, 0, &data).unwrap();
+
+        assert_eq!(extract(&buffer, format, mask, 0, 3).unwrap(), data);
+        // The LSB of the first and the 2nd LSB of the second channel of the first pixel
+        assert_eq!(buffer[2] & 1, 1);
+        assert_eq!(buffer[5] >> 1 & 1, 1);
+    }
+
+    #[test]
+    fn out_of_range_access_is_rejected() {
+        let format = PixelFormat::new(2, 1);
+        let mut buffer = vec![0u8; 2 * 16];
+        let original = buffer.clone();
+
+        // 16 pixels at 1 bit each hold 2 bytes
+        assert!(embed(&mut buffer, format, 1 << 48, 0, &[1, 2, 3]).is_err());
+        assert!(embed(&mut buffer, format, 1 << 48, 1, &[1, 2]).is_err());
+        assert!(extract(&buffer, format, 1 << 48, 0, 2).is_ok());
+        // No bit, or bits beyond the 16 bits of a pixel
+        assert!(extract(&buffer, format, 0, 0, 1).is_err());
+        assert!(extract(&buffer, format, 1 << 47, 0, 1).is_err());
+        assert!(extract(&buffer, PixelFormat::new(0, 1), 1 << 63, 0, 1).is_err());
+        assert_eq!(buffer, original);
+    }
+
+    #[test]
+    fn payload_ending_on_the_last_pixel_round_trips() {
+        let format = PixelFormat::new(3, 1);
+        let offset = 16;
+        // 1, 3 and 6 bits per pixel, the last case large enough to be written in parallel chunks
+        for (mask, data_len) in [
+            (1u64 << 56, 30),
+            (0x01_01_01u64 << 40, 300),
+            (0x03_03_03u64 << 40, PARALLEL_CHUNK_BYTES * 3 + 300),
+        ] {
+            let bits_per_pixel = mask.count_ones() as usize;
+            let pixels = offset + data_len * 8 / bits_per_pixel;
+            let mut buffer = vec![0u8; pixels * 3];
+            let mut data = vec![0u8; data_len];
+            rand::thread_rng().fill_bytes(&mut data);
+
+            write_to_buffer(&mut buffer, offset, mask, format, &data);
+            assert_eq!(
+                read_from_buffer(&buffer, offset, data_len, mask, format),
+                data
+            );
+
+            // One more byte does not fit and says so, rather than failing on the pixel access
+            data.push(0xFF);
+            let error = std::panic::catch_unwind(move || {
+                write_to_buffer(&mut buffer, offset, mask, format, &data)
+            })
+            .unwrap_err();
+            let message = error
+                .downcast_ref::<&str>()
+                .map(|x| x.to_string())
+                .or_else(|| error.downcast_ref::<String>().cloned())
+                .unwrap();
+            assert!(message.starts_with("Ran out of pixels"), "{}", message);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! Payloads made of records which are appended one at a time, see `encode --record`, `append-record` and `decode --record`.
+//!
+//! Every record is stored with its length in front, one after the other. The length is a 4 byte integer,
+//! big-endian unless `--length-endian little` was chosen. Appending a record writes it into the pixels
+//! following the current payload and rewrites the header with the new length, checksum and record count.
+//! Headers of such payloads carry [`crate::header::PayloadContainer::Records`].
+
+use std::str::FromStr;
+
+use bincode::{Decode, Encode};
+use image::ColorType;
+
+use crate::{
+    buffer_modify::PngImage,
+    header::{
+        try_get_header, HeaderExtension, PayloadContainer, V1DataStuffingOptions, VersionedHeader,
+        DEFAULT_MAX_BITS_PER_CHANNEL,
+    },
+    payload::{read_payload, write_payload},
+};
+
+/// Size of the length in front of every record
+const LENGTH_BYTES: usize = 4;
+
+/// Byte order of the length in front of every record. Stored in the header, so decoding picks the same one.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy, Default)]
+pub enum LengthEndian {
+    /// Like the lengths of the header itself
+    #[default]
+    Big,
+    Little,
+}
+
+impl LengthEndian {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LengthEndian::Big => "big",
+            LengthEndian::Little => "little",
+        }
+    }
+}
+
+impl FromStr for LengthEndian {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "big" => Ok(LengthEndian::Big),
+            "little" => Ok(LengthEndian::Little),
+            _ => Err(format!("Unknown byte order {}, expected big or little", s)),
+        }
+    }
+}
+
+/// The record prefixed with its length
+pub fn frame(record: &[u8], length_endian: LengthEndian) -> Result<Vec<u8>, String> {
+    let len = u32::try_from(record.len())
+        .map_err(|_| format!("A record of {} bytes is too large", record.len()))?;
+    let mut framed = match length_endian {
+        LengthEndian::Big => len.to_be_bytes(),
+        LengthEndian::Little => len.to_le_bytes(),
+    }
+    .to_vec();
+    framed.extend_from_slice(record);
+    Ok(framed)
+}
+
+/// Splits the payload into its records, in the order they were appended
+pub fn split(payload: &[u8], length_endian: LengthEndian) -> Result<Vec<Vec<u8>>, String> {
+    let mut records = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        let Some((len, data)) = rest.split_first_chunk::<LENGTH_BYTES>() else {
+            return Err(format!("Record {} is cut off in its length", records.len()));
+        };
+        let len = match length_endian {
+            LengthEndian::Big => u32::from_be_bytes(*len),
+            LengthEndian::Little => u32::from_le_bytes(*len),
+        } as usize;
+        if len > data.len() {
+            return Err(format!(
+                "Record {} claims {} bytes, but only {} are left",
+                records.len(),
+                len,
+                data.len()
+            ));
+        }
+        records.push(data[..len].to_vec());
+        rest = &data[len..];
+    }
+    Ok(records)
+}
+
+/// The record at `index`, counted from 0
+pub fn record_at(
+    payload: &[u8],
+    length_endian: LengthEndian,
+    index: usize,
+) -> Result<Vec<u8>, String> {
+    let mut records = split(payload, length_endian)?;
+    match index < records.len() {
+        true => Ok(records.swap_remove(index)),
+        false => Err(format!(
+            "There is no record {}, the payload holds {} records",
+            index,
+            records.len()
+        )),
+    }
+}
+
+///
+/// Spreads the payload over the most bits per pixel allowed by default, so the pixels after the first record
+/// leave as much room for later ones as possible. Headers already using more bits are kept.
+pub fn with_room_for_records(header: VersionedHeader, color_type: ColorType) -> VersionedHeader {
+    let bits_per_pixel = DEFAULT_MAX_BITS_PER_CHANNEL * color_type.channel_count();
+    match header.data_mask().count_ones() < bits_per_pixel as u32 {
+        true => header.with_bits_per_pixel(bits_per_pixel, color_type),
+        false => header,
+    }
+}
+
+///
+/// Appends the record to the payload of the image and returns the updated header.
+/// The records already stored are verified against the payload checksum first.
+/// If bytes were reserved with `encode --reserve`, the record is taken from them and has to fit.
+pub fn append_record(image: &mut dyn PngImage, record: &[u8]) -> Result<VersionedHeader, String> {
+    let header = try_get_header(image).map_err(|x| format!("Failed to parse Header: {}", x))?;
+    let Some(PayloadContainer::Records {
+        count,
+        length_endian,
+    }) = header.container()
+    else {
+        return Err("The payload does not consist of records. Embed it with --record".to_string());
+    };
+    let VersionedHeader::V3 {
+        stuffing_opts: stuffing_opts @ V1DataStuffingOptions::None { start_offset },
+        data_mask,
+        extensions,
+        ..
+    } = header.clone()
+    else {
+        return Err("Only records stored right after the header can be appended to".to_string());
+    };
+
+    let framed = frame(record, length_endian)?;
+    // With a reservation, records only grow into the reserved pixels, the ones behind it may be used otherwise
+    let reserved = extensions
+        .iter()
+        .any(|extension| matches!(extension, HeaderExtension::Reserved(_)))
+        .then(|| header.reserved_bytes());
+    if let Some(reserved) = reserved.filter(|reserved| framed.len() as u64 > *reserved) {
+        return Err(format!(
+            "The record takes {} bytes with its length, but only {} of the reserved bytes are left",
+            framed.len(),
+            reserved
+        ));
+    }
+
+    let mut payload = read_payload(image, &header, None)?;
+    payload.extend_from_slice(&framed);
+    let available_bits =
+        image.pixel_count().saturating_sub(start_offset) * data_mask.count_ones() as u64;
+    if payload.len() as u64 * 8 > available_bits {
+        return Err(format!(
+            "The records would take {} bytes, but only {} fit into the image",
+            payload.len(),
+            available_bits / 8
+        ));
+    }
+
+    let extensions = extensions
+        .into_iter()
+        .map(|extension| match extension {
+            HeaderExtension::Container(PayloadContainer::Records { .. }) => {
+                HeaderExtension::Container(PayloadContainer::Records {
+                    count: count + 1,
+                    length_endian,
+                })
+            }
+            HeaderExtension::Reserved(reserved) => {
+                HeaderExtension::Reserved(reserved - framed.len() as u64)
+            }
+            extension => extension,
+        })
+        .collect();
+    let header = VersionedHeader::V3 {
+        stuffing_opts,
+        data_mask,
+        data_len: payload.len() as u64,
+        data_crc: header.payload_crc_spec().checksum(&payload),
+        extensions,
+    };
+    // The records stored so far are written again unchanged, only the new one changes pixels
+    write_payload(image, &header, &payload, None)?;
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::{thread_rng, RngCore};
+
+    use super::*;
+    use crate::{crc_spec::CrcSpec, header::generate_v3_header};
+
+    #[test]
+    fn records_are_appended_and_read_back_by_index() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        thread_rng().fill_bytes(&mut image);
+        let first = frame(b"first entry", LengthEndian::Big).unwrap();
+        let header = generate_v3_header(
+            64 * 64,
+            &first,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            vec![HeaderExtension::Container(PayloadContainer::Records {
+                count: 1,
+                length_endian: LengthEndian::Big,
+            })],
+            false,
+        )
+        .unwrap();
+        let header = with_room_for_records(header, ColorType::Rgb8);
+        write_payload(&mut image, &header, &first, None).unwrap();
+        let after_first = image.clone();
+
+        let second = vec![0xA5; 700];
+        let header = append_record(&mut image, &second).unwrap();
+
+        assert_eq!(
+            header.container(),
+            Some(PayloadContainer::Records {
+                count: 2,
+                length_endian: LengthEndian::Big
+            })
+        );
+        let found = try_get_header(&image).unwrap();
+        assert_eq!(found, header);
+        let payload = read_payload(&image, &found, None).unwrap();
+        assert_eq!(
+            record_at(&payload, LengthEndian::Big, 0).unwrap(),
+            b"first entry"
+        );
+        assert_eq!(record_at(&payload, LengthEndian::Big, 1).unwrap(), second);
+        assert!(record_at(&payload, LengthEndian::Big, 2).is_err());
+
+        // The pixels holding the first record only are left as they were
+        let start = header.start_offset() as usize * 3;
+        let end = start + first.len() * 8 / header.data_mask().count_ones() as usize * 3;
+        assert_eq!(image.as_raw()[start..end], after_first.as_raw()[start..end]);
+        assert_ne!(image.as_raw()[end..], after_first.as_raw()[end..]);
+
+        // Payloads without records are left alone
+        let mut plain = after_first.clone();
+        let plain_header = generate_v3_header(
+            64 * 64,
+            b"single blob",
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+        write_payload(&mut plain, &plain_header, b"single blob", None).unwrap();
+        assert!(append_record(&mut plain, b"more").is_err());
+    }
+
+    #[test]
+    fn appends_are_limited_to_the_reserved_bytes() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        thread_rng().fill_bytes(&mut image);
+        let first = frame(b"first entry", LengthEndian::Big).unwrap();
+        let header = generate_v3_header(
+            64 * 64,
+            &first,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            vec![
+                HeaderExtension::Container(PayloadContainer::Records {
+                    count: 1,
+                    length_endian: LengthEndian::Big,
+                }),
+                HeaderExtension::Reserved(104),
+            ],
+            false,
+        )
+        .unwrap();
+        let header = with_room_for_records(header, ColorType::Rgb8);
+        write_payload(&mut image, &header, &first, None).unwrap();
+
+        // 100 bytes and their length take all of the reservation
+        let header = append_record(&mut image, &[0x5A; 100]).unwrap();
+        assert_eq!(header.reserved_bytes(), 0);
+        assert_eq!(try_get_header(&image).unwrap(), header);
+
+        let before = image.clone();
+        let err = append_record(&mut image, b"x").unwrap_err();
+        assert_eq!(
+            err,
+            "The record takes 5 bytes with its length, but only 0 of the reserved bytes are left"
+        );
+        assert_eq!(image, before);
+        let payload = read_payload(&image, &header, None).unwrap();
+        assert_eq!(
+            split(&payload, LengthEndian::Big).unwrap(),
+            [b"first entry".to_vec(), vec![0x5A; 100]]
+        );
+    }
+
+    #[test]
+    fn little_endian_lengths_only_split_as_little_endian() {
+        let payload = [
+            frame(b"one", LengthEndian::Little).unwrap(),
+            frame(&[7; 300], LengthEndian::Little).unwrap(),
+        ]
+        .concat();
+        assert_eq!(&payload[..4], [3, 0, 0, 0]);
+
+        assert_eq!(
+            split(&payload, LengthEndian::Little).unwrap(),
+            [b"one".to_vec(), vec![7; 300]]
+        );
+        assert!(split(&payload, LengthEndian::Big).is_err());
+        assert_eq!("little".parse(), Ok(LengthEndian::Little));
+        assert!("middle".parse::<LengthEndian>().is_err());
+    }
+}
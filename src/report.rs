@@ -0,0 +1,123 @@
+use image::DynamicImage;
+use serde::Serialize;
+
+use crate::{
+    header::{V1DataStuffingOptions, VersionedHeader},
+    quality::psnr,
+};
+
+///
+/// Summary of the parameters an image was encoded with, for provenance and logging.
+/// Unlike a sidecar, it is not meant to recover the payload.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct EncodeReport {
+    /// How the payload pixels are chosen, e.g. `sequential` or `scattered-header`
+    pub(crate) placement: &'static str,
+    /// Pixel the payload starts at
+    pub(crate) offset: u64,
+    pub(crate) mask: u64,
+    pub(crate) bits_per_pixel: u32,
+    /// Payload length in bytes
+    pub(crate) payload_size: u64,
+    pub(crate) crc: Option<u32>,
+    /// CRC algorithm of the checksum, in the format accepted by `--crc-spec`
+    pub(crate) crc_variant: String,
+    /// Size of the written image file in bytes
+    pub(crate) output_size: u64,
+    /// PSNR between the cover and the written image in dB. Missing if both are identical.
+    pub(crate) psnr: Option<f64>,
+}
+
+impl EncodeReport {
+    ///
+    /// Describes the encode which turned `cover` into the image file `output`
+    pub(crate) fn new(
+        header: &VersionedHeader,
+        cover: &DynamicImage,
+        output: &[u8],
+    ) -> Result<EncodeReport, String> {
+        let modified = image::load_from_memory(output).map_err(|x| x.to_string())?;
+        let psnr = psnr(cover, &modified);
+
+        Ok(EncodeReport {
+            placement: placement(&header.stuffing_opts()),
+            offset: header.start_offset(),
+            mask: header.data_mask(),
+            bits_per_pixel: header.data_mask().count_ones(),
+            payload_size: header.data_len(),
+            crc: header.data_crc(),
+            crc_variant: header.payload_crc_spec().to_string(),
+            output_size: output.len() as u64,
+            psnr: psnr.is_finite().then_some(psnr),
+        })
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("report fields are always serializable")
+    }
+}
+
+fn placement(stuffing_opts: &V1DataStuffingOptions) -> &'static str {
+    match stuffing_opts {
+        V1DataStuffingOptions::None { .. } => "sequential",
+        V1DataStuffingOptions::AvoidMask { .. } => "avoid-mask",
+        V1DataStuffingOptions::ScatteredHeader { .. } => "scattered-header",
+        V1DataStuffingOptions::Password { .. } => "password",
+        V1DataStuffingOptions::Trns { .. } => "trns",
+        V1DataStuffingOptions::Keyed { .. } => "keyed",
+        V1DataStuffingOptions::ColorKey { .. } => "color-key",
+        V1DataStuffingOptions::Luma { .. } => "luma",
+        V1DataStuffingOptions::HeaderCopies { .. } => "header-copies",
+        V1DataStuffingOptions::Complexity { .. } => "complexity",
+        V1DataStuffingOptions::GifPalette { .. } => "gif-palette",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, ImageOutputFormat, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        buffer_modify::PngImageSaveable, crc_spec::CrcSpec, header::generate_v3_header,
+        payload::write_payload,
+    };
+
+    #[test]
+    fn report_matches_encode_parameters() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        let cover = DynamicImage::ImageRgb8(image.clone());
+        let payload = b"audited payload".repeat(50);
+        let crc_spec: CrcSpec = "init=0x12345678".parse().unwrap();
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            image::ColorType::Rgb8,
+            crc_spec,
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+        let output = image.save_to_buffer(ImageOutputFormat::Png).unwrap();
+
+        let report = EncodeReport::new(&header, &cover, &output).unwrap();
+        assert_eq!(report.placement, "sequential");
+        assert_eq!(report.offset, header.start_offset());
+        assert_eq!(report.mask, header.data_mask());
+        assert_eq!(report.bits_per_pixel, header.data_mask().count_ones());
+        assert_eq!(report.payload_size, payload.len() as u64);
+        assert_eq!(report.crc, Some(crc_spec.checksum(&payload)));
+        assert_eq!(report.crc_variant, crc_spec.to_string());
+        assert_eq!(report.output_size, output.len() as u64);
+        let expected_psnr = psnr(&cover, &DynamicImage::ImageRgb8(image));
+        assert_eq!(report.psnr, Some(expected_psnr));
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["offset"], header.start_offset());
+        assert_eq!(json["payload_size"], payload.len() as u64);
+    }
+}
@@ -0,0 +1,91 @@
+use crate::{
+    buffer_modify::PngImage,
+    crc_spec::CrcSpec,
+    header::{try_get_header, HeaderExtension, VersionedHeader},
+    payload::{read_payload, scrub_stale_payload, write_payload},
+};
+
+///
+/// Moves the payload of the image to the embedding described by `new_header`, without needing the payload itself.
+/// `new_header` gets the payload, its checksum algorithm and the extensions worth keeping.
+/// The payload is checked against its checksum before, if the old header has one, and read back afterwards.
+/// The bits of the old header and payload are overwritten with noise first, so nothing of the old embedding survives.
+pub(crate) fn rewrap(
+    image: &mut dyn PngImage,
+    new_header: impl FnOnce(&[u8], CrcSpec, Vec<HeaderExtension>) -> Result<VersionedHeader, String>,
+) -> Result<VersionedHeader, String> {
+    let old_header = try_get_header(image)?;
+    let payload = read_payload(image, &old_header, None)?;
+    // Version and checksum algorithm are added again by the new header, the preamble depends on the placement
+    let extensions = old_header
+        .extensions()
+        .iter()
+        .filter(|extension| {
+            !matches!(
+                extension,
+                HeaderExtension::ToolVersion(_)
+                    | HeaderExtension::PayloadCrcSpec(_)
+                    | HeaderExtension::Preamble
+            )
+        })
+        .cloned()
+        .collect();
+    let header = new_header(&payload, old_header.payload_crc_spec(), extensions)?;
+
+    scrub_stale_payload(image, 0);
+    write_payload(image, &header, &payload, None)?;
+
+    let written_header = try_get_header(image)?;
+    match read_payload(image, &written_header, None)? == payload {
+        true => Ok(header),
+        false => Err("The payload read back differs from the one before".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::{thread_rng, Rng, RngCore};
+
+    use super::*;
+    use crate::{
+        header::{generate_v1_header, generate_v3_header, V1DataStuffingOptions},
+        scatter::max_reserved_pixels,
+    };
+
+    #[test]
+    fn contiguous_v1_payload_moves_to_a_scattered_header() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        thread_rng().fill_bytes(&mut image);
+        let payload = b"migrated without the plaintext".repeat(10);
+        let header = generate_v1_header(64 * 64, payload.len() as u64, ColorType::Rgb8).unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        let rewrapped = rewrap(&mut image, |payload, crc_spec, extensions| {
+            generate_v3_header(
+                64 * 64 - max_reserved_pixels(),
+                payload,
+                ColorType::Rgb8,
+                crc_spec,
+                extensions,
+                true,
+            )
+            .map(|header| {
+                let start_offset = header.start_offset();
+                header.with_stuffing_opts(V1DataStuffingOptions::ScatteredHeader {
+                    start_offset,
+                    seed: thread_rng().gen(),
+                })
+            })
+        })
+        .unwrap();
+
+        let found = try_get_header(&image).unwrap();
+        assert_eq!(found, rewrapped);
+        assert_eq!(found.version(), 3);
+        assert!(found.scatter_seed().is_some());
+        assert!(found.data_crc().is_some());
+        assert_eq!(read_payload(&image, &found, None).unwrap(), payload);
+    }
+}
@@ -0,0 +1,326 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{
+    buffer_modify::convert_dynamic_image_to_png_image,
+    header::try_get_header,
+    image_archive::{archive_kind, read_entries},
+    io_errors::cannot_read,
+    size_format::format_byte_size,
+    verification::verify,
+};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ScanStatus {
+    Payload,
+    NoPayload,
+    /// The file could not be read or is no supported image
+    Skipped,
+}
+
+///
+/// What `scan-dir` found in a single file
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct ScanEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) status: ScanStatus,
+    /// Header version, for files carrying a payload
+    pub(crate) version: Option<u8>,
+    /// Payload length in bytes, as claimed by the header
+    pub(crate) payload_size: Option<u64>,
+    /// Whether the payload fits into the image and matches its checksum
+    pub(crate) valid: Option<bool>,
+    /// Why the file was skipped, or why its payload is not valid
+    pub(crate) note: Option<String>,
+}
+
+impl ScanEntry {
+    fn skipped(path: PathBuf, note: String) -> ScanEntry {
+        ScanEntry {
+            path,
+            status: ScanStatus::Skipped,
+            version: None,
+            payload_size: None,
+            valid: None,
+            note: Some(note),
+        }
+    }
+}
+
+///
+/// Result of `scan-dir`, with the counts tooling usually asks for first
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct ScanSummary {
+    pub(crate) files: Vec<ScanEntry>,
+    pub(crate) carriers: usize,
+    /// Carriers whose payload is valid
+    pub(crate) valid: usize,
+    pub(crate) skipped: usize,
+}
+
+impl ScanSummary {
+    fn new(files: Vec<ScanEntry>) -> ScanSummary {
+        let with_status = |status| files.iter().filter(|entry| entry.status == status).count();
+        ScanSummary {
+            carriers: with_status(ScanStatus::Payload),
+            valid: files
+                .iter()
+                .filter(|entry| entry.valid == Some(true))
+                .count(),
+            skipped: with_status(ScanStatus::Skipped),
+            files,
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("scan fields are always serializable")
+    }
+
+    /// One line per file, followed by the counts
+    pub(crate) fn to_text(&self) -> String {
+        let mut lines: Vec<String> = self
+            .files
+            .iter()
+            .map(|entry| {
+                let details = match (entry.status, entry.valid) {
+                    (ScanStatus::Payload, valid) => format!(
+                        "payload, V{}, {}, {}",
+                        entry.version.unwrap_or_default(),
+                        format_byte_size(entry.payload_size.unwrap_or_default()),
+                        match valid {
+                            Some(true) => "valid".to_string(),
+                            _ => format!("invalid ({})", entry.note.as_deref().unwrap_or_default()),
+                        }
+                    ),
+                    (ScanStatus::NoPayload, _) => "no payload".to_string(),
+                    (ScanStatus::Skipped, _) => {
+                        format!("skipped ({})", entry.note.as_deref().unwrap_or_default())
+                    }
+                };
+                format!("{}: {}", entry.path.display(), details)
+            })
+            .collect();
+        lines.push(format!(
+            "{} files scanned: {} carry a payload ({} valid), {} skipped",
+            self.files.len(),
+            self.carriers,
+            self.valid,
+            self.skipped
+        ));
+        lines.join("\n")
+    }
+}
+
+///
+/// Checks every file of the directory for a header, sorted by path.
+/// Files which are not readable as an image are skipped with a note instead of failing the scan.
+/// A zip or tar archive instead of a directory has its files checked, in the order they are stored.
+pub(crate) fn scan_dir(dir: &Path, recursive: bool) -> Result<ScanSummary, String> {
+    if dir.is_file() {
+        return scan_archive(dir);
+    }
+    let mut files = Vec::new();
+    collect_entries(dir, recursive, &mut files)?;
+    Ok(ScanSummary::new(files))
+}
+
+fn collect_entries(dir: &Path, recursive: bool, files: &mut Vec<ScanEntry>) -> Result<(), String> {
+    let mut children: Vec<(PathBuf, bool)> = fs::read_dir(dir)
+        .map_err(|x| cannot_read(dir.display(), &x))?
+        .filter_map(|entry| entry.ok())
+        // Symbolic links to directories are not followed, so the walk always ends
+        .map(|entry| {
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            (entry.path(), is_dir)
+        })
+        .collect();
+    children.sort();
+
+    for (path, is_dir) in children {
+        match is_dir {
+            true if recursive => {
+                if let Err(err) = collect_entries(&path, recursive, files) {
+                    files.push(ScanEntry::skipped(path, err));
+                }
+            }
+            true => {}
+            false => files.push(scan_file(path)),
+        }
+    }
+    Ok(())
+}
+
+///
+/// Checks every file of the archive, named like `images.zip/dir/a.png` in the summary
+fn scan_archive(path: &Path) -> Result<ScanSummary, String> {
+    let data = fs::read(path).map_err(|x| cannot_read(path.display(), &x))?;
+    let kind = archive_kind(&data).ok_or_else(|| {
+        format!(
+            "{} is neither a directory nor a zip or tar archive",
+            path.display()
+        )
+    })?;
+    let files = read_entries(&data, kind)?
+        .into_iter()
+        .map(|(name, data)| scan_image(path.join(name), Ok(data)))
+        .collect();
+    Ok(ScanSummary::new(files))
+}
+
+fn scan_file(path: PathBuf) -> ScanEntry {
+    let data = fs::read(&path).map_err(|x| x.to_string());
+    scan_image(path, data)
+}
+
+fn scan_image(path: PathBuf, data: Result<Vec<u8>, String>) -> ScanEntry {
+    let loaded = data.and_then(|data| image::load_from_memory(&data).map_err(|x| x.to_string()));
+    let mut image = match loaded {
+        Ok(val) => val,
+        Err(err) => return ScanEntry::skipped(path, err),
+    };
+    let image = match convert_dynamic_image_to_png_image(&mut image) {
+        Ok(val) => val,
+        Err(err) => return ScanEntry::skipped(path, err),
+    };
+
+    match try_get_header(image) {
+        Ok(header) => {
+            let report = verify(image, None);
+            ScanEntry {
+                path,
+                status: ScanStatus::Payload,
+                version: Some(header.version()),
+                payload_size: Some(header.data_len()),
+                valid: Some(report.is_valid()),
+                note: report.failure(),
+            }
+        }
+        Err(_) => ScanEntry {
+            path,
+            status: ScanStatus::NoPayload,
+            version: None,
+            payload_size: None,
+            valid: None,
+            note: None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use image::{ColorType, ImageBuffer, ImageOutputFormat, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::{thread_rng, Rng, RngCore};
+
+    use super::*;
+    use crate::{
+        buffer_modify::PngImageSaveable, crc_spec::CrcSpec, header::generate_v3_header,
+        payload::write_payload,
+    };
+
+    fn noisy_png(payload: Option<&[u8]>) -> Vec<u8> {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        thread_rng().fill_bytes(&mut image);
+        if let Some(payload) = payload {
+            let header = generate_v3_header(
+                64 * 64,
+                payload,
+                ColorType::Rgb8,
+                CrcSpec::default(),
+                Vec::new(),
+                true,
+            )
+            .unwrap();
+            write_payload(&mut image, &header, payload, None).unwrap();
+        }
+        image.save_to_buffer(ImageOutputFormat::Png).unwrap()
+    }
+
+    #[test]
+    fn carriers_are_told_apart_from_clean_and_broken_files() {
+        let dir = env::temp_dir().join(format!("ihm-scan-{:x}", thread_rng().gen::<u64>()));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("a-carrier.png"), noisy_png(Some(b"scan me"))).unwrap();
+        fs::write(dir.join("b-clean.png"), noisy_png(None)).unwrap();
+        fs::write(dir.join("c-notes.txt"), b"not an image").unwrap();
+        fs::write(dir.join("nested/d-carrier.png"), noisy_png(Some(&[7; 300]))).unwrap();
+
+        let flat = scan_dir(&dir, false).unwrap();
+        let statuses: Vec<_> = flat.files.iter().map(|entry| entry.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                ScanStatus::Payload,
+                ScanStatus::NoPayload,
+                ScanStatus::Skipped
+            ]
+        );
+        assert_eq!(flat.files[0].version, Some(3));
+        assert_eq!(flat.files[0].payload_size, Some(7));
+        assert_eq!(flat.files[0].valid, Some(true));
+        assert!(flat.files[2].note.is_some());
+        assert_eq!((flat.carriers, flat.valid, flat.skipped), (1, 1, 1));
+
+        let recursive = scan_dir(&dir, true).unwrap();
+        assert_eq!(recursive.files.len(), 4);
+        assert_eq!(recursive.files[3].path, dir.join("nested/d-carrier.png"));
+        assert_eq!(recursive.files[3].payload_size, Some(300));
+        assert_eq!((recursive.carriers, recursive.valid), (2, 2));
+        let json: serde_json::Value = serde_json::from_str(&recursive.to_json()).unwrap();
+        assert_eq!(json["files"][1]["status"], "no-payload");
+        assert!(recursive
+            .to_text()
+            .ends_with("4 files scanned: 2 carry a payload (2 valid), 1 skipped"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "zip")]
+    #[test]
+    fn images_inside_a_zip_are_scanned() {
+        use std::io::{Cursor, Write};
+
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, png) in [
+            ("clean.png", noisy_png(None)),
+            ("album/carrier.png", noisy_png(Some(b"zipped"))),
+        ] {
+            writer
+                .start_file(name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(&png).unwrap();
+        }
+        let dir = env::temp_dir().join(format!("ihm-scan-zip-{:x}", thread_rng().gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("images.zip");
+        fs::write(&archive, writer.finish().unwrap().into_inner()).unwrap();
+
+        let summary = scan_dir(&archive, false).unwrap();
+        let found: Vec<_> = summary
+            .files
+            .iter()
+            .map(|entry| (entry.path.clone(), entry.status))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (archive.join("clean.png"), ScanStatus::NoPayload),
+                (archive.join("album/carrier.png"), ScanStatus::Payload)
+            ]
+        );
+        assert_eq!(summary.files[1].payload_size, Some(6));
+        assert_eq!(
+            (summary.carriers, summary.valid, summary.skipped),
+            (1, 1, 0)
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
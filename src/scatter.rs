@@ -0,0 +1,182 @@
+use std::collections::HashSet;
+
+use crate::{
+    buffer_modify::{checked_pixel_index, PngImage},
+    header::{HeaderRaw, HEADER_MASK},
+    prng::pick_distinct,
+    raw::WriteStats,
+};
+
+/// Marks a bootstrap record which points to a scattered header
+pub const SCATTERED_MAGIC: u8 = 0x43;
+/// Upper bound for the serialized header in scattered mode. Pixels for this many bytes are reserved.
+pub const MAX_SCATTERED_HEADER_BYTES: usize = 256;
+/// Magic (1B), Seed (8B), Data Mask (8B), Header Len (2B)
+const BOOTSTRAP_BYTES: usize = 19;
+/// The bootstrap is stored like the regular header, 1 bit per pixel at the start of the image
+pub const BOOTSTRAP_PIXELS: usize = BOOTSTRAP_BYTES * 8;
+
+///
+/// The only fixed-location data in scattered mode. It contains what is needed to find the header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScatterBootstrap {
+    pub seed: u64,
+    /// The header is written with the same mask as the payload
+    pub data_mask: u64,
+    /// Length of the serialized header in bytes
+    pub header_len: u16,
+}
+
+impl ScatterBootstrap {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(BOOTSTRAP_BYTES);
+        data.push(SCATTERED_MAGIC);
+        data.extend_from_slice(&self.seed.to_be_bytes());
+        data.extend_from_slice(&self.data_mask.to_be_bytes());
+        data.extend_from_slice(&self.header_len.to_be_bytes());
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<ScatterBootstrap, String> {
+        if data.len() != BOOTSTRAP_BYTES || data[0] != SCATTERED_MAGIC {
+            return Err("Not a valid scattered header bootstrap".to_string());
+        }
+        let u64_at = |start: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[start..start + 8]);
+            u64::from_be_bytes(bytes)
+        };
+
+        Ok(ScatterBootstrap {
+            seed: u64_at(1),
+            data_mask: u64_at(9),
+            header_len: u16::from_be_bytes([data[17], data[18]]),
+        })
+    }
+}
+
+///
+/// Pixels reserved for the scattered header, in the order the header bytes are written to.
+pub fn scattered_header_pixels(
+    seed: u64,
+    data_mask: u64,
+    pixel_count: u64,
+) -> Result<Vec<usize>, String> {
+    let bits_per_pixel = data_mask.count_ones() as usize;
+    if bits_per_pixel == 0 {
+        return Err("Data mask is empty".to_string());
+    }
+    let reserved = (MAX_SCATTERED_HEADER_BYTES * 8).div_ceil(bits_per_pixel);
+
+    pick_distinct(
+        seed,
+        reserved,
+        BOOTSTRAP_PIXELS..checked_pixel_index(pixel_count)?,
+    )
+}
+
+///
+/// Number of pixels which are not available to the payload in scattered mode, for the worst case of 1 data bit per pixel
+pub fn max_reserved_pixels() -> u64 {
+    (BOOTSTRAP_PIXELS + MAX_SCATTERED_HEADER_BYTES * 8) as u64
+}
+
+///
+/// All pixels which are neither part of the bootstrap nor reserved for the header, in ascending order
+pub fn free_pixels(seed: u64, data_mask: u64, pixel_count: u64) -> Result<Vec<usize>, String> {
+    let reserved: HashSet<usize> = scattered_header_pixels(seed, data_mask, pixel_count)?
+        .into_iter()
+        .collect();
+
+    Ok((BOOTSTRAP_PIXELS..checked_pixel_index(pixel_count)?)
+        .filter(|index| !reserved.contains(index))
+        .collect())
+}
+
+///
+/// Writes the bootstrap and the scattered header
+pub fn write_scattered_header(
+    image: &mut dyn PngImage,
+    raw_header: &HeaderRaw,
+    seed: u64,
+    data_mask: u64,
+) -> Result<WriteStats, String> {
+    let header_bytes = raw_header.to_bytes();
+    if header_bytes.len() > MAX_SCATTERED_HEADER_BYTES {
+        return Err(format!(
+            "Header is {} bytes long, but at most {} bytes fit in scattered mode",
+            header_bytes.len(),
+            MAX_SCATTERED_HEADER_BYTES
+        ));
+    }
+
+    let bootstrap = ScatterBootstrap {
+        seed,
+        data_mask,
+        header_len: header_bytes.len() as u16,
+    };
+    let header_pixels = scattered_header_pixels(seed, data_mask, image.pixel_count())?;
+
+    let stats = image.write_data_with_mask(&bootstrap.to_bytes(), HEADER_MASK, 0)
+        + image.write_data_at_pixels(&header_bytes, data_mask, &header_pixels);
+
+    Ok(stats)
+}
+
+///
+/// Reads the bootstrap and collects the scattered header it points to
+pub fn read_scattered_header(image: &dyn PngImage) -> Result<HeaderRaw, String> {
+    // A small image may start with the scattered magic by chance
+    if image.pixel_count() < BOOTSTRAP_PIXELS as u64 {
+        return Err("The image is too small to contain a scattered header".to_string());
+    }
+    let bootstrap =
+        ScatterBootstrap::from_bytes(&image.read_data_with_mask(HEADER_MASK, 0, BOOTSTRAP_BYTES))?;
+    if bootstrap.header_len as usize > MAX_SCATTERED_HEADER_BYTES {
+        return Err(format!(
+            "Bootstrap claims a header of {} bytes, which is more than the {} bytes scattered mode allows",
+            bootstrap.header_len, MAX_SCATTERED_HEADER_BYTES
+        ));
+    }
+
+    let header_pixels =
+        scattered_header_pixels(bootstrap.seed, bootstrap.data_mask, image.pixel_count())?;
+    let header_bytes = image.read_data_at_pixels(
+        bootstrap.data_mask,
+        &header_pixels,
+        bootstrap.header_len as usize,
+    );
+
+    HeaderRaw::from_bytes(&header_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn bootstrap_round_trip() {
+        let bootstrap = ScatterBootstrap {
+            seed: 0x0123_4567_89AB_CDEF,
+            data_mask: 0x03_03_03_00_00_00_00_00,
+            header_len: 57,
+        };
+
+        let bytes = bootstrap.to_bytes();
+
+        assert_eq!(bytes.len(), BOOTSTRAP_BYTES);
+        assert_eq!(ScatterBootstrap::from_bytes(&bytes).unwrap(), bootstrap);
+    }
+
+    #[test]
+    fn free_pixels_exclude_header_and_bootstrap() {
+        let header_pixels = scattered_header_pixels(7, 0x01_01_00_00_00_00_00_00, 10_000).unwrap();
+        let free = free_pixels(7, 0x01_01_00_00_00_00_00_00, 10_000).unwrap();
+
+        assert_eq!(header_pixels.len(), MAX_SCATTERED_HEADER_BYTES * 8 / 2);
+        assert_eq!(free.len(), 10_000 - BOOTSTRAP_PIXELS - header_pixels.len());
+        assert!(free.iter().all(|index| *index >= BOOTSTRAP_PIXELS));
+        assert!(free.iter().all(|index| !header_pixels.contains(index)));
+    }
+}
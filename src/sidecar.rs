@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crc_spec::CrcSpec,
+    header::{HeaderExtension, V1DataStuffingOptions, VersionedHeader},
+};
+
+///
+/// The header fields needed to read a payload, stored in a separate JSON file.
+/// Allows recovering the payload if the header in the image is damaged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Sidecar {
+    /// Pixel the payload starts at
+    pub(crate) offset: u64,
+    pub(crate) mask: u64,
+    /// Payload length in bytes
+    pub(crate) length: u64,
+    /// Payload checksum, if the image was written with one
+    pub(crate) crc: Option<u32>,
+    /// CRC algorithm of the checksum, in the format accepted by `--crc-spec`
+    pub(crate) crc_variant: String,
+}
+
+impl Sidecar {
+    ///
+    /// Only sequentially stored payloads can be described by a sidecar
+    pub(crate) fn from_header(header: &VersionedHeader) -> Result<Sidecar, String> {
+        if !matches!(header.stuffing_opts(), V1DataStuffingOptions::None { .. }) {
+            return Err("Sidecars are only supported for sequentially stored payloads".to_string());
+        }
+
+        Ok(Sidecar {
+            offset: header.start_offset(),
+            mask: header.data_mask(),
+            length: header.data_len(),
+            crc: header.data_crc(),
+            crc_variant: header.payload_crc_spec().to_string(),
+        })
+    }
+
+    ///
+    /// Builds a header which reads the payload described by the sidecar
+    pub(crate) fn to_header(&self) -> Result<VersionedHeader, String> {
+        let crc_spec: CrcSpec = self.crc_variant.parse()?;
+        let stuffing_opts = V1DataStuffingOptions::None {
+            start_offset: self.offset,
+        };
+
+        Ok(match self.crc {
+            Some(data_crc) => VersionedHeader::V3 {
+                stuffing_opts,
+                data_mask: self.mask,
+                data_len: self.length,
+                data_crc,
+                extensions: vec![HeaderExtension::PayloadCrcSpec(crc_spec)],
+            },
+            None => VersionedHeader::V1 {
+                stuffing_opts,
+                data_mask: self.mask,
+                data_len: self.length,
+            },
+        })
+    }
+
+    pub(crate) fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("sidecar fields are always serializable")
+    }
+
+    pub(crate) fn from_json(json: &str) -> Result<Sidecar, String> {
+        serde_json::from_str(json).map_err(|x| format!("Invalid sidecar: {}", x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        buffer_modify::ReadImageBinary,
+        header::try_get_header,
+        payload::{read_payload, write_payload},
+    };
+
+    #[test]
+    fn sidecar_recovers_payload_without_header() {
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        let payload = b"recoverable payload".repeat(10);
+        let crc_spec: CrcSpec = "init=0x12345678".parse().unwrap();
+        let header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 1024 },
+            data_mask: 0x03_03_03_03_00_00_00_00,
+            data_len: payload.len() as u64,
+            data_crc: crc_spec.checksum(&payload),
+            extensions: vec![HeaderExtension::PayloadCrcSpec(crc_spec)],
+        };
+        write_payload(&mut image, &header, &payload, None).unwrap();
+        let json = Sidecar::from_header(&header).unwrap().to_json();
+
+        // Wipe everything before the payload, including the header
+        let header_bytes =
+            header.start_offset() as usize * image.color_type().bytes_per_pixel() as usize;
+        (*image)[..header_bytes].fill(0);
+        assert!(try_get_header(&image).is_err());
+
+        let header = Sidecar::from_json(&json).unwrap().to_header().unwrap();
+        assert_eq!(read_payload(&image, &header, None).unwrap(), payload);
+    }
+}
@@ -0,0 +1,124 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SECRET_KEY_LENGTH};
+
+use crate::header::{HeaderExtension, VersionedHeader};
+
+///
+/// Reads a 32 byte Ed25519 key, stored either as raw bytes or base64 encoded
+fn key_bytes(data: &[u8]) -> Result<[u8; SECRET_KEY_LENGTH], String> {
+    let decoded = match data.len() {
+        SECRET_KEY_LENGTH => data.to_vec(),
+        _ => {
+            let text = std::str::from_utf8(data).map_err(|_| {
+                format!(
+                    "Expected {} raw bytes or base64 text, but the key is {} bytes of binary data",
+                    SECRET_KEY_LENGTH,
+                    data.len()
+                )
+            })?;
+            STANDARD
+                .decode(text.trim())
+                .map_err(|err| format!("The key is not valid base64: {}", err))?
+        }
+    };
+
+    decoded.as_slice().try_into().map_err(|_| {
+        format!(
+            "An Ed25519 key is {} bytes long, but {} bytes were given",
+            SECRET_KEY_LENGTH,
+            decoded.len()
+        )
+    })
+}
+
+pub fn load_signing_key(data: &[u8]) -> Result<SigningKey, String> {
+    Ok(SigningKey::from_bytes(&key_bytes(data)?))
+}
+
+pub fn load_verifying_key(data: &[u8]) -> Result<VerifyingKey, String> {
+    VerifyingKey::from_bytes(&key_bytes(data)?)
+        .map_err(|err| format!("Not a valid Ed25519 public key: {}", err))
+}
+
+/// The public key belonging to the private one, base64 encoded like the key files
+pub fn public_key_base64(key: &SigningKey) -> String {
+    STANDARD.encode(key.verifying_key().as_bytes())
+}
+
+///
+/// Signs the payload. The signature is detached from it and stored as a header extension.
+pub fn sign_payload(key: &SigningKey, payload: &[u8]) -> HeaderExtension {
+    HeaderExtension::Signature(key.sign(payload).to_bytes())
+}
+
+///
+/// Checks the signature stored in the header against the payload.
+/// Unlike the payload checksum, this proves the payload was signed by the holder of the private key.
+pub fn verify_signature(
+    key: &VerifyingKey,
+    header: &VersionedHeader,
+    payload: &[u8],
+) -> Result<(), String> {
+    let signature = header
+        .signature()
+        .ok_or_else(|| "The payload was not signed".to_string())?;
+
+    key.verify(payload, &Signature::from_bytes(&signature))
+        .map_err(|_| "The signature does not match the payload and public key".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header},
+        payload::{read_payload, write_payload},
+    };
+
+    #[test]
+    fn keys_are_read_raw_or_as_base64() {
+        let key = [7u8; SECRET_KEY_LENGTH];
+
+        assert_eq!(key_bytes(&key).unwrap(), key);
+        assert_eq!(
+            key_bytes(format!("{}\n", STANDARD.encode(key)).as_bytes()).unwrap(),
+            key
+        );
+        assert!(key_bytes(&[7u8; 31]).is_err());
+        assert!(key_bytes(b"not base64!").is_err());
+    }
+
+    #[test]
+    fn signed_payload_verifies_and_tampering_fails() {
+        let signing_key = SigningKey::from_bytes(&[42u8; SECRET_KEY_LENGTH]);
+        let other_key = SigningKey::from_bytes(&[43u8; SECRET_KEY_LENGTH]);
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        let payload = b"signed by the author".to_vec();
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            vec![sign_payload(&signing_key, &payload)],
+            true,
+        )
+        .unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        let header = try_get_header(&image).unwrap();
+        let decoded = read_payload(&image, &header, None).unwrap();
+        let public_key = signing_key.verifying_key();
+        assert!(verify_signature(&public_key, &header, &decoded).is_ok());
+
+        let mut tampered = decoded.clone();
+        tampered[0] ^= 0x01;
+        assert!(verify_signature(&public_key, &header, &tampered).is_err());
+        assert!(verify_signature(&other_key.verifying_key(), &header, &decoded).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+///
+/// Formats a byte count for humans, e.g. `1536` -> `1.50 KiB`.
+/// Values below 1 KiB are printed as plain bytes.
+pub(crate) fn format_byte_size(bytes: u64) -> String {
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64;
+    let mut unit = UNITS[0];
+    for current_unit in UNITS {
+        value /= 1024.0;
+        unit = current_unit;
+        if value < 1024.0 {
+            break;
+        }
+    }
+
+    format!("{:.2} {}", value, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn format_bytes_below_one_kib() {
+        assert_eq!(format_byte_size(0), "0 B");
+        assert_eq!(format_byte_size(1), "1 B");
+        assert_eq!(format_byte_size(1023), "1023 B");
+    }
+
+    #[test]
+    fn format_kib_boundaries() {
+        assert_eq!(format_byte_size(1024), "1.00 KiB");
+        assert_eq!(format_byte_size(1536), "1.50 KiB");
+        assert_eq!(format_byte_size(1024 * 1024 - 1), "1024.00 KiB");
+    }
+
+    #[test]
+    fn format_larger_units() {
+        assert_eq!(format_byte_size(1024 * 1024), "1.00 MiB");
+        assert_eq!(format_byte_size(3 * 1024 * 1024 * 1024), "3.00 GiB");
+        assert_eq!(format_byte_size(2 * 1024u64.pow(4)), "2.00 TiB");
+        assert_eq!(format_byte_size(2048 * 1024u64.pow(4)), "2048.00 TiB");
+    }
+}
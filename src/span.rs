@@ -0,0 +1,193 @@
+use bincode::{Decode, Encode};
+
+use crate::header::VersionedHeader;
+
+///
+/// Where a chunk belongs when a payload is split across several cover images.
+/// Recorded in the header of every chunk.
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct SpanInfo {
+    /// Random id shared by all chunks of the same payload
+    pub payload_id: u64,
+    /// Position of this chunk, starting at 0
+    pub chunk_index: u32,
+    pub chunk_count: u32,
+    /// Checksum of the combined payload, computed with the header's payload CRC spec
+    pub payload_crc: u32,
+}
+
+///
+/// Splits the payload into one chunk per cover, proportional to the pixel count of each cover.
+pub fn split_payload<'a>(payload: &'a [u8], pixel_counts: &[u64]) -> Vec<&'a [u8]> {
+    let total_pixels: u128 = pixel_counts.iter().map(|&count| count as u128).sum();
+    let mut chunks = Vec::with_capacity(pixel_counts.len());
+    let mut start = 0usize;
+    let mut pixels_so_far = 0u128;
+
+    for (i, &pixel_count) in pixel_counts.iter().enumerate() {
+        pixels_so_far += pixel_count as u128;
+        let end = if i + 1 == pixel_counts.len() {
+            payload.len()
+        } else {
+            (payload.len() as u128 * pixels_so_far / total_pixels.max(1)) as usize
+        };
+        chunks.push(&payload[start..end]);
+        start = end;
+    }
+
+    chunks
+}
+
+///
+/// Puts the chunks read from all images of a spanned payload back together.
+/// The chunks may be given in any order. The combined payload is verified against its checksum.
+pub fn join_chunks(chunks: Vec<(VersionedHeader, Vec<u8>)>) -> Result<Vec<u8>, String> {
+    let mut spans = Vec::with_capacity(chunks.len());
+    for (i, (header, chunk)) in chunks.into_iter().enumerate() {
+        let span = header
+            .span_info()
+            .ok_or_else(|| format!("Image {} is not part of a spanned payload", i + 1))?;
+        spans.push((span, header, chunk));
+    }
+
+    let Some((first, first_header, _)) = spans.first() else {
+        return Err("No images provided".to_string());
+    };
+    let (first, crc_spec) = (*first, first_header.payload_crc_spec());
+
+    if spans.iter().any(|(span, _, _)| {
+        span.payload_id != first.payload_id
+            || span.chunk_count != first.chunk_count
+            || span.payload_crc != first.payload_crc
+    }) {
+        return Err("The images belong to different spanned payloads".to_string());
+    }
+    if first.chunk_count as usize != spans.len() {
+        return Err(format!(
+            "The payload spans {} images, but {} were provided",
+            first.chunk_count,
+            spans.len()
+        ));
+    }
+
+    spans.sort_by_key(|(span, _, _)| span.chunk_index);
+    let mut payload = Vec::new();
+    for (expected_index, (span, _, chunk)) in spans.into_iter().enumerate() {
+        if span.chunk_index as usize != expected_index {
+            return Err(format!(
+                "Chunk {} of the payload is missing",
+                expected_index + 1
+            ));
+        }
+        payload.extend_from_slice(&chunk);
+    }
+
+    let crc = crc_spec.checksum(&payload);
+    if crc != first.payload_crc {
+        return Err(format!(
+            "Combined payload checksum mismatch. Expected {:#01x}, but found {:#01x}",
+            first.payload_crc, crc
+        ));
+    }
+
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{try_get_header, HeaderExtension, V1DataStuffingOptions},
+        payload::{read_payload, write_payload},
+    };
+
+    /// 2 bits in each of the RGBA channels
+    const TEST_DATA_MASK: u64 = 0x03_03_03_03_00_00_00_00u64;
+
+    fn chunk_header(chunk: &[u8], span: SpanInfo) -> VersionedHeader {
+        VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 1024 },
+            data_mask: TEST_DATA_MASK,
+            data_len: chunk.len() as u64,
+            data_crc: CrcSpec::default().checksum(chunk),
+            extensions: vec![HeaderExtension::Span(span)],
+        }
+    }
+
+    #[test]
+    fn split_is_proportional_to_pixel_count() {
+        let payload = vec![0u8; 1000];
+        let chunks = split_payload(&payload, &[100, 300]);
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), 250);
+        assert_eq!(chunks[1].len(), 750);
+    }
+
+    #[test]
+    fn span_round_trip_across_two_covers() {
+        let mut covers: Vec<ImageBuffer<Rgba<u8>, Vec<u8>>> =
+            vec![ImageBuffer::new(64, 64), ImageBuffer::new(64, 48)];
+        for cover in covers.iter_mut() {
+            rand::thread_rng().fill_bytes(cover);
+        }
+        let mut payload = vec![0u8; 4000];
+        rand::thread_rng().fill_bytes(&mut payload);
+
+        let pixel_counts: Vec<u64> = covers
+            .iter()
+            .map(|cover| cover.width() as u64 * cover.height() as u64)
+            .collect();
+        let chunks = split_payload(&payload, &pixel_counts);
+        for (i, (cover, chunk)) in covers.iter_mut().zip(&chunks).enumerate() {
+            let span = SpanInfo {
+                payload_id: 0xC0FFEE,
+                chunk_index: i as u32,
+                chunk_count: 2,
+                payload_crc: CrcSpec::default().checksum(&payload),
+            };
+            write_payload(cover, &chunk_header(chunk, span), chunk, None).unwrap();
+        }
+
+        // Pass the images in reverse, the chunk index decides the order
+        let read_chunks: Vec<(VersionedHeader, Vec<u8>)> = covers
+            .iter()
+            .rev()
+            .map(|cover| {
+                let header = try_get_header(cover).unwrap();
+                let chunk = read_payload(cover, &header, None).unwrap();
+                (header, chunk)
+            })
+            .collect();
+
+        assert_eq!(join_chunks(read_chunks.clone()).unwrap(), payload);
+        assert!(join_chunks(read_chunks[..1].to_vec()).is_err());
+    }
+
+    #[test]
+    fn join_rejects_mixed_payloads() {
+        let chunk = vec![1u8, 2, 3];
+        let span = SpanInfo {
+            payload_id: 1,
+            chunk_index: 0,
+            chunk_count: 2,
+            payload_crc: 0,
+        };
+        let other = SpanInfo {
+            payload_id: 2,
+            chunk_index: 1,
+            ..span
+        };
+
+        let result = join_chunks(vec![
+            (chunk_header(&chunk, span), chunk.clone()),
+            (chunk_header(&chunk, other), chunk.clone()),
+        ]);
+        assert!(result.is_err());
+    }
+}
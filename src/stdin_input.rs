@@ -0,0 +1,260 @@
+use std::io::{self, IsTerminal, Read};
+
+use image::ImageFormat;
+
+use crate::{gif_palette::is_gif, image_archive::archive_kind, size_format::format_byte_size};
+
+/// Formats of images piped into STDIN, told apart by their signature.
+/// The encoder writes PNG, farbfeld and QOI, the others can only be read.
+const STDIN_IMAGE_FORMATS: [ImageFormat; 5] = [
+    ImageFormat::Png,
+    ImageFormat::Farbfeld,
+    ImageFormat::Qoi,
+    ImageFormat::Bmp,
+    ImageFormat::WebP,
+];
+
+/// Paths which stand for STDIN, e.g. when scripts pass `/dev/stdin` instead of omitting the path
+const STDIN_PATHS: [&str; 2] = ["-", "/dev/stdin"];
+/// Paths which stand for STDOUT
+const STDOUT_PATHS: [&str; 2] = ["-", "/dev/stdout"];
+
+/// Whether the path is read from STDIN rather than from the file system
+pub(crate) fn is_stdin_path(path: &str) -> bool {
+    STDIN_PATHS.contains(&path)
+}
+
+/// Whether the path is written to STDOUT rather than to the file system
+pub(crate) fn is_stdout_path(path: &str) -> bool {
+    STDOUT_PATHS.contains(&path)
+}
+
+///
+/// What a command expects to be piped into STDIN
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum StdinInput {
+    Message,
+    Image,
+}
+
+impl StdinInput {
+    fn prompt(&self) -> &'static str {
+        match self {
+            StdinInput::Message => {
+                "Type the message, then press Ctrl-D to finish (Ctrl-C to abort):"
+            }
+            StdinInput::Image => {
+                "Waiting for an image on STDIN, e.g. cat imgWithSecret.png | ... (Ctrl-C to abort)"
+            }
+        }
+    }
+
+    fn missing_error(&self) -> &'static str {
+        match self {
+            StdinInput::Message => {
+                "No message was piped into STDIN. Pipe one in, or provide it via --message or --file"
+            }
+            StdinInput::Image => {
+                "No image was piped into STDIN. Pipe one in, e.g. cat imgWithSecret.png | ..."
+            }
+        }
+    }
+}
+
+///
+/// How STDIN is read, set once via the global CLI flags
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct StdinOptions {
+    /// Prompt for input instead of failing if STDIN is a terminal
+    pub(crate) interactive: bool,
+    /// Fail instead of reading more than this many bytes
+    pub(crate) limit: Option<u64>,
+    /// The declared length of a piped message, see `encode --size`. Any other length fails the read.
+    pub(crate) message_size: Option<u64>,
+}
+
+///
+/// Reads STDIN to the end. If STDIN is a terminal, nothing was piped in:
+/// a short prompt is shown, or the read fails right away if `interactive` is false.
+/// Images are checked for the signature of a supported format, so a missing pipe is reported before decoding.
+pub(crate) fn read_stdin(input: StdinInput, options: StdinOptions) -> Result<Vec<u8>, String> {
+    let stdin = io::stdin();
+    let is_terminal = stdin.is_terminal();
+    read_input(input, options, is_terminal, stdin.lock())
+}
+
+fn read_input(
+    input: StdinInput,
+    options: StdinOptions,
+    is_terminal: bool,
+    reader: impl Read,
+) -> Result<Vec<u8>, String> {
+    if is_terminal {
+        if !options.interactive {
+            return Err(input.missing_error().to_string());
+        }
+        eprintln!("{}", input.prompt());
+    }
+
+    let message_size = options
+        .message_size
+        .filter(|_| input == StdinInput::Message);
+    let mut data = Vec::new();
+    // One byte more than allowed is read, to tell an input of exactly the limit from a longer one
+    reader
+        .take(
+            options
+                .limit
+                .into_iter()
+                .chain(message_size)
+                .min()
+                .map_or(u64::MAX, |limit| limit.saturating_add(1)),
+        )
+        .read_to_end(&mut data)
+        .map_err(|err| format!("Failed to read STDIN: {}", err))?;
+    if let Some(limit) = options.limit.filter(|limit| data.len() as u64 > *limit) {
+        return Err(format!(
+            "STDIN is larger than the limit of {}. Raise it via --stdin-limit",
+            format_byte_size(limit)
+        ));
+    }
+    if let Some(size) = message_size.filter(|size| data.len() as u64 != *size) {
+        return Err(format!(
+            "STDIN delivered {} bytes, but --size declared {}",
+            match data.len() as u64 > size {
+                true => format!("more than {}", size),
+                false => data.len().to_string(),
+            },
+            size
+        ));
+    }
+    if input == StdinInput::Image {
+        check_image_signature(&data)?;
+    }
+    Ok(data)
+}
+
+fn check_image_signature(data: &[u8]) -> Result<(), String> {
+    // Archives are let through, the image is picked out of them by `--entry`.
+    // GIFs only carry payloads in their palette, which is read without decoding the frames.
+    if archive_kind(data).is_some() || is_gif(data) {
+        return Ok(());
+    }
+    match image::guess_format(data) {
+        Ok(format) if STDIN_IMAGE_FORMATS.contains(&format) => Ok(()),
+        _ => Err(format!(
+            "Input does not start with the signature of a supported image format (tried {}); did you forget to pipe a file?",
+            STDIN_IMAGE_FORMATS
+                .iter()
+                .map(|format| format!("{:?}", format))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::png_info::PNG_SIGNATURE;
+
+    /// Fails the test if the input is read at all
+    struct UnreadableInput;
+
+    impl Read for UnreadableInput {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            panic!("STDIN must not be read when failing fast")
+        }
+    }
+
+    const PIPED: StdinOptions = StdinOptions {
+        interactive: false,
+        limit: None,
+        message_size: None,
+    };
+
+    #[test]
+    fn terminal_fails_fast_without_interaction() {
+        let err = read_input(StdinInput::Message, PIPED, true, UnreadableInput).unwrap_err();
+
+        assert!(err.contains("--message"), "{}", err);
+    }
+
+    #[test]
+    fn piped_input_is_read() {
+        let png = [PNG_SIGNATURE.as_slice(), b"piped"].concat();
+        let data = read_input(StdinInput::Image, PIPED, false, png.as_slice()).unwrap();
+
+        assert_eq!(data, png);
+    }
+
+    #[test]
+    fn qoi_input_is_read() {
+        let qoi = [b"qoif".as_slice(), b"piped"].concat();
+
+        assert!(read_input(StdinInput::Image, PIPED, false, qoi.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn archive_input_is_read() {
+        let zip = [b"PK\x03\x04".as_slice(), b"piped"].concat();
+
+        assert!(read_input(StdinInput::Image, PIPED, false, zip.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn non_png_image_input_is_rejected() {
+        let err = read_input(
+            StdinInput::Image,
+            PIPED,
+            false,
+            b"\xFF\xD8\xFF\xE0...".as_slice(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err,
+            "Input does not start with the signature of a supported image format \
+            (tried Png, Farbfeld, Qoi, Bmp, WebP); did you forget to pipe a file?"
+        );
+    }
+
+    #[test]
+    fn input_beyond_limit_is_rejected() {
+        let options = StdinOptions {
+            limit: Some(1024),
+            ..PIPED
+        };
+        // Never ends, so reading it without a limit would run out of memory
+        let endless = io::repeat(0x41);
+
+        let err = read_input(StdinInput::Message, options, false, endless).unwrap_err();
+        assert!(err.contains("--stdin-limit"), "{}", err);
+
+        let exact = vec![0x41; 1024];
+        let data = read_input(StdinInput::Message, options, false, exact.as_slice()).unwrap();
+        assert_eq!(data, exact);
+    }
+
+    #[test]
+    fn message_of_another_length_than_declared_is_rejected() {
+        let options = StdinOptions {
+            message_size: Some(8),
+            ..PIPED
+        };
+
+        let err = read_input(StdinInput::Message, options, false, b"short".as_slice()).unwrap_err();
+        assert_eq!(err, "STDIN delivered 5 bytes, but --size declared 8");
+
+        let err = read_input(StdinInput::Message, options, false, io::repeat(0x41)).unwrap_err();
+        assert_eq!(
+            err,
+            "STDIN delivered more than 8 bytes, but --size declared 8"
+        );
+
+        let data = read_input(StdinInput::Message, options, false, b"8 bytes!".as_slice()).unwrap();
+        assert_eq!(data, b"8 bytes!");
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{self, Read, Write};
+
+use crate::buffer_modify::PngImage;
+
+///
+/// Collects everything written to it and embeds it into the image once it is finished or dropped.
+/// Lets serializers write straight into an image, e.g. `serde_json::to_writer`.
+pub struct EmbeddedWriter<'a> {
+    image: &'a mut dyn PngImage,
+    writing_mask: u64,
+    pixel_offset: usize,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl<'a> EmbeddedWriter<'a> {
+    pub fn new(
+        image: &'a mut dyn PngImage,
+        writing_mask: u64,
+        pixel_offset: usize,
+    ) -> EmbeddedWriter<'a> {
+        EmbeddedWriter {
+            image,
+            writing_mask,
+            pixel_offset,
+            buffer: Vec::new(),
+            finished: false,
+        }
+    }
+
+    ///
+    /// Embeds the collected bytes into the image. Returns how many bytes were embedded.
+    pub fn finish(mut self) -> usize {
+        self.embed()
+    }
+
+    fn embed(&mut self) -> usize {
+        if self.finished {
+            return 0;
+        }
+        self.finished = true;
+        if !self.buffer.is_empty() {
+            self.image
+                .write_data_with_mask(&self.buffer, self.writing_mask, self.pixel_offset);
+        }
+        self.buffer.len()
+    }
+}
+
+impl Write for EmbeddedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.finished {
+            return Err(io::Error::other("Data was already embedded into the image"));
+        }
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Embedding is not incremental, so there is nothing to do before the writer is finished
+        Ok(())
+    }
+}
+
+impl Drop for EmbeddedWriter<'_> {
+    fn drop(&mut self) {
+        self.embed();
+    }
+}
+
+///
+/// Reads `length` bytes from the image, chunk by chunk, as they are requested.
+pub struct EmbeddedReader<'a> {
+    image: &'a dyn PngImage,
+    reading_mask: u64,
+    pixel_offset: usize,
+    length: usize,
+    position: usize,
+    chunk: Vec<u8>,
+    chunk_position: usize,
+}
+
+/// Each chunk spans `CHUNK_PIXEL_GROUPS * 8` pixels, so chunks always start at a pixel boundary
+const CHUNK_PIXEL_GROUPS: usize = 512;
+
+impl<'a> EmbeddedReader<'a> {
+    pub fn new(
+        image: &'a dyn PngImage,
+        reading_mask: u64,
+        pixel_offset: usize,
+        length: usize,
+    ) -> EmbeddedReader<'a> {
+        EmbeddedReader {
+            image,
+            reading_mask,
+            pixel_offset,
+            length,
+            position: 0,
+            chunk: Vec::new(),
+            chunk_position: 0,
+        }
+    }
+
+    fn load_next_chunk(&mut self) {
+        // 8 pixels hold exactly `bits_per_pixel` bytes
+        let bits_per_pixel = self.reading_mask.count_ones() as usize;
+        let chunk_len = (bits_per_pixel * CHUNK_PIXEL_GROUPS).min(self.length - self.position);
+        let chunk_index = self.position / (bits_per_pixel * CHUNK_PIXEL_GROUPS);
+        let chunk_pixel_offset = self.pixel_offset + chunk_index * CHUNK_PIXEL_GROUPS * 8;
+
+        self.chunk =
+            self.image
+                .read_data_with_mask(self.reading_mask, chunk_pixel_offset, chunk_len);
+        self.chunk_position = 0;
+    }
+}
+
+impl Read for EmbeddedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.length || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chunk_position == self.chunk.len() {
+            self.load_next_chunk();
+        }
+
+        let count = buf.len().min(self.chunk.len() - self.chunk_position);
+        buf[..count].copy_from_slice(&self.chunk[self.chunk_position..self.chunk_position + count]);
+        self.chunk_position += count;
+        self.position += count;
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{copy, Cursor};
+
+    use image::{ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+
+    #[test]
+    fn copy_through_writer_and_reader() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(256, 256);
+        rand::thread_rng().fill_bytes(&mut image);
+        let mut payload = vec![0u8; 10_000];
+        rand::thread_rng().fill_bytes(&mut payload);
+        // 3 bits per pixel, so chunk boundaries do not line up with bytes of a single channel
+        let mask = 0x01_01_01_00_00_00_00_00u64;
+
+        {
+            let mut writer = EmbeddedWriter::new(&mut image, mask, 100);
+            let copied = copy(&mut Cursor::new(&payload), &mut writer).unwrap();
+            assert_eq!(copied, payload.len() as u64);
+            assert_eq!(writer.finish(), payload.len());
+        }
+
+        let mut reader = EmbeddedReader::new(&image, mask, 100, payload.len());
+        let mut result = Vec::new();
+        copy(&mut reader, &mut result).unwrap();
+
+        assert_eq!(result, payload);
+    }
+
+    #[test]
+    fn writer_embeds_on_drop() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(16, 16);
+        let mask = 0x03_03_03_00_00_00_00_00u64;
+
+        {
+            let mut writer = EmbeddedWriter::new(&mut image, mask, 0);
+            writer.write_all(b"dropped").unwrap();
+        }
+
+        let mut result = String::new();
+        EmbeddedReader::new(&image, mask, 0, 7)
+            .read_to_string(&mut result)
+            .unwrap();
+        assert_eq!(result, "dropped");
+    }
+}
@@ -0,0 +1,208 @@
+//! Tamper evidence by a second copy of the payload, written with `encode --tamper-evident`.
+//!
+//! Both copies share the pixels, but not the bits, see [`crate::header::tamper_copy_mask`].
+//! Editing the image rarely keeps them equal, so where they disagree shows where it was altered.
+//! Unlike the payload checksum, this localizes the change. It cannot repair it.
+
+use std::{fmt, ops::Range};
+
+use crate::{
+    avoid_mask::AvoidMask,
+    buffer_modify::{checked_pixel_index, PngImage},
+    header::VersionedHeader,
+    payload::restricted_payload_pixels,
+    verification::check_capacity,
+};
+
+///
+/// Where the two copies of the payload disagree
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TamperReport {
+    /// Payload bytes whose copies differ
+    pub(crate) differing_bytes: usize,
+    /// Pixels carrying the differing bytes, by index. Adjacent ranges are merged.
+    pub(crate) pixels: Vec<Range<u64>>,
+    /// Width of the image, to tell the rows of the pixels
+    width: u64,
+}
+
+impl TamperReport {
+    pub(crate) fn is_intact(&self) -> bool {
+        self.differing_bytes == 0
+    }
+}
+
+impl fmt::Display for TamperReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_intact() {
+            return write!(f, "The copies of the payload agree, no tampering found");
+        }
+        write!(
+            f,
+            "The copies of the payload disagree in {} byte{}, the image was likely altered at:",
+            self.differing_bytes,
+            if self.differing_bytes == 1 { "" } else { "s" }
+        )?;
+        for range in &self.pixels {
+            write!(
+                f,
+                "\n  pixels {} to {} (rows {} to {})",
+                range.start,
+                range.end - 1,
+                range.start / self.width,
+                (range.end - 1) / self.width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// Reads both copies of the payload and compares them bytewise.
+/// Fails if the header records no copy, or the payload does not fit into the image.
+pub(crate) fn compare_copies(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<TamperReport, String> {
+    let copy_mask = header.tamper_copy_mask().ok_or_else(|| {
+        "The header records no tamper copy. Embed with encode --tamper-evident".to_string()
+    })?;
+    check_capacity(image, header, avoid_mask)?;
+    let data_len = checked_pixel_index(header.data_len())?;
+    let start_offset = checked_pixel_index(header.start_offset())?;
+    let pixels = restricted_payload_pixels(image, header, avoid_mask)?;
+    let (original, copy) = match &pixels {
+        Some(pixels) => (
+            image.read_data_at_pixels(header.data_mask(), pixels, data_len),
+            image.read_data_at_pixels(copy_mask, pixels, data_len),
+        ),
+        None => (
+            image.read_data_with_mask(header.data_mask(), start_offset, data_len),
+            image.read_data_with_mask(copy_mask, start_offset, data_len),
+        ),
+    };
+
+    // Both masks hold the same number of bits per pixel, so byte i lies in the same pixels in both copies
+    let bits_per_pixel = header.data_mask().count_ones() as usize;
+    let pixel_at = |index: usize| match &pixels {
+        Some(pixels) => pixels[index] as u64,
+        None => (start_offset + index) as u64,
+    };
+    let mut differing_bytes = 0;
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    for (index, _) in original
+        .iter()
+        .zip(&copy)
+        .enumerate()
+        .filter(|(_, (original, copy))| original != copy)
+    {
+        differing_bytes += 1;
+        let first = index * 8 / bits_per_pixel;
+        let last = (index * 8 + 7) / bits_per_pixel;
+        for pixel in (first..=last).map(pixel_at) {
+            match ranges.last_mut() {
+                Some(range) if range.end >= pixel && range.start <= pixel => {
+                    range.end = range.end.max(pixel + 1)
+                }
+                _ => ranges.push(pixel..pixel + 1),
+            }
+        }
+    }
+
+    Ok(TamperReport {
+        differing_bytes,
+        pixels: ranges,
+        width: image.width().max(1) as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, ImageBuffer, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::{thread_rng, RngCore};
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, HeaderExtension},
+        payload::{read_payload, write_payload},
+    };
+
+    fn tamper_evident_image(payload: &[u8]) -> (ImageBuffer<Rgb<u8>, Vec<u8>>, VersionedHeader) {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        thread_rng().fill_bytes(&mut image);
+        let header = generate_v3_header(
+            64 * 64,
+            payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            vec![HeaderExtension::TamperCopy(0)],
+            false,
+        )
+        .unwrap();
+        write_payload(&mut image, &header, payload, None).unwrap();
+        (image, header)
+    }
+
+    #[test]
+    fn copies_use_the_bit_plane_above_the_payload() {
+        let (image, header) = tamper_evident_image(&[0x5A; 400]);
+
+        // 400 bytes need 2 bits per pixel for both copies: the LSB of red, and its second LSB for the copy
+        assert_eq!(header.data_mask(), 0x01 << 56);
+        assert_eq!(header.tamper_copy_mask(), Some(0x02 << 56));
+        assert_eq!(read_payload(&image, &header, None).unwrap(), [0x5A; 400]);
+        let report = compare_copies(&image, &header, None).unwrap();
+        assert!(report.is_intact(), "{}", report);
+        assert_eq!(report.pixels, []);
+    }
+
+    #[test]
+    fn altering_one_bit_plane_is_localized() {
+        let payload: Vec<u8> = (0..400).map(|index| index as u8).collect();
+        let (mut image, header) = tamper_evident_image(&payload);
+        let start = header.start_offset() as usize;
+
+        // Flip the least significant bit of red in 16 pixels, 64 pixels into the payload
+        for pixel in image.pixels_mut().skip(start + 64).take(16) {
+            pixel.0[0] ^= 1;
+        }
+
+        let report = compare_copies(&image, &header, None).unwrap();
+        assert!(!report.is_intact());
+        assert_eq!(report.differing_bytes, 2);
+        let first = (start + 64) as u64;
+        assert_eq!(
+            report.pixels,
+            vec![Range {
+                start: first,
+                end: first + 16
+            }]
+        );
+        assert!(
+            report.to_string().contains("disagree in 2 bytes"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn headers_without_a_copy_are_rejected() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        let header = generate_v3_header(
+            64 * 64,
+            b"plain",
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+        write_payload(&mut image, &header, b"plain", None).unwrap();
+
+        assert_eq!(header.tamper_copy_mask(), None);
+        assert!(compare_copies(&image, &header, None).is_err());
+    }
+}
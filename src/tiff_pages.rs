@@ -0,0 +1,284 @@
+use std::io::Cursor;
+
+use image::{DynamicImage, ImageBuffer};
+use rand::Rng;
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    encoder::{colortype, TiffEncoder},
+    ColorType as TiffColorType,
+};
+
+use crate::{
+    buffer_modify::convert_dynamic_image_to_png_image,
+    crc_spec::CrcSpec,
+    header::{check_low_bits_only, generate_v3_header, try_get_header, HeaderExtension},
+    output_format::check_identical,
+    payload::{read_payload, write_payload},
+    prng::tool_rng,
+    span::{join_chunks, split_payload, SpanInfo},
+};
+
+/// Byte order marks of little and big endian TIFF files
+const TIFF_SIGNATURES: [&[u8; 4]; 2] = [b"II*\0", b"MM\0*"];
+
+pub(crate) fn is_tiff(data: &[u8]) -> bool {
+    TIFF_SIGNATURES
+        .iter()
+        .any(|signature| data.starts_with(*signature))
+}
+
+///
+/// Decodes every page (image file directory) of a TIFF. Only RGB(A) pages with 8 or 16 bits per channel are supported.
+pub(crate) fn read_tiff_pages(data: &[u8]) -> Result<Vec<DynamicImage>, String> {
+    let mut decoder = Decoder::new(Cursor::new(data)).map_err(|x| x.to_string())?;
+    let mut pages = Vec::new();
+    loop {
+        let page = pages.len() + 1;
+        let (width, height) = decoder.dimensions().map_err(|x| x.to_string())?;
+        let color_type = decoder.colortype().map_err(|x| x.to_string())?;
+        let image = match (color_type, decoder.read_image().map_err(|x| x.to_string())?) {
+            (TiffColorType::RGB(8), DecodingResult::U8(data)) => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+            }
+            (TiffColorType::RGBA(8), DecodingResult::U8(data)) => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+            }
+            (TiffColorType::RGB(16), DecodingResult::U16(data)) => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16)
+            }
+            (TiffColorType::RGBA(16), DecodingResult::U16(data)) => {
+                ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16)
+            }
+            (color_type, _) => {
+                return Err(format!(
+                    "Page {} has the unsupported color type {:?}",
+                    page, color_type
+                ))
+            }
+        };
+        pages.push(image.ok_or_else(|| format!("Page {} is truncated", page))?);
+
+        if !decoder.more_images() {
+            return Ok(pages);
+        }
+        decoder.next_image().map_err(|x| x.to_string())?;
+    }
+}
+
+///
+/// Encodes the pages into a single TIFF, in the given order
+pub(crate) fn write_tiff_pages(pages: &[DynamicImage]) -> Result<Vec<u8>, String> {
+    let mut cursor = Cursor::new(Vec::new());
+    let mut encoder = TiffEncoder::new(&mut cursor).map_err(|x| x.to_string())?;
+    for (index, page) in pages.iter().enumerate() {
+        let (width, height) = (page.width(), page.height());
+        let written = match page {
+            DynamicImage::ImageRgb8(buffer) => {
+                encoder.write_image::<colortype::RGB8>(width, height, buffer.as_raw())
+            }
+            DynamicImage::ImageRgba8(buffer) => {
+                encoder.write_image::<colortype::RGBA8>(width, height, buffer.as_raw())
+            }
+            DynamicImage::ImageRgb16(buffer) => {
+                encoder.write_image::<colortype::RGB16>(width, height, buffer.as_raw())
+            }
+            DynamicImage::ImageRgba16(buffer) => {
+                encoder.write_image::<colortype::RGBA16>(width, height, buffer.as_raw())
+            }
+            _ => return Err(format!("Page {} has an unsupported color type", index + 1)),
+        };
+        written.map_err(|x| x.to_string())?;
+    }
+    Ok(cursor.into_inner())
+}
+
+///
+/// Fails unless every page comes back bit-exact from a TIFF written by [`write_tiff_pages`]
+pub(crate) fn check_pages_lossless(pages: &[DynamicImage]) -> Result<(), String> {
+    let decoded = read_tiff_pages(&write_tiff_pages(pages)?)?;
+    if decoded.len() != pages.len() {
+        return Err(format!(
+            "TIFF is not lossless: {} pages were written, but {} read back",
+            pages.len(),
+            decoded.len()
+        ));
+    }
+    pages
+        .iter()
+        .zip(&decoded)
+        .enumerate()
+        .try_for_each(|(index, (page, decoded))| {
+            check_identical(page, decoded)
+                .map_err(|x| format!("TIFF is not lossless for page {}: {}", index, x))
+        })
+}
+
+///
+/// Embeds the payload into a single page, or spreads it across all pages.
+/// Spread payloads record their page in the header, like payloads split with `--span`.
+pub(crate) fn write_pages_payload(
+    pages: &mut [DynamicImage],
+    page: Option<usize>,
+    payload: &[u8],
+    crc_spec: CrcSpec,
+    max_bits_per_channel: Option<u8>,
+    extensions: Vec<HeaderExtension>,
+) -> Result<(), String> {
+    let page_count = pages.len();
+    let targets: Vec<(usize, &mut DynamicImage)> = match page {
+        Some(index) => {
+            let target = pages.get_mut(index).ok_or_else(|| {
+                format!(
+                    "Page {} does not exist, the TIFF has {} pages",
+                    index, page_count
+                )
+            })?;
+            vec![(index, target)]
+        }
+        None => pages.iter_mut().enumerate().collect(),
+    };
+
+    let pixel_counts: Vec<u64> = targets
+        .iter()
+        .map(|(_, image)| image.width() as u64 * image.height() as u64)
+        .collect();
+    let chunks = match page {
+        Some(_) => vec![payload],
+        None => split_payload(payload, &pixel_counts),
+    };
+    let payload_id: u64 = tool_rng().gen();
+    let payload_crc = crc_spec.checksum(payload);
+
+    for (((index, image), chunk), pixel_count) in targets.into_iter().zip(chunks).zip(pixel_counts)
+    {
+        let mut page_extensions = extensions.clone();
+        if page.is_none() {
+            page_extensions.push(HeaderExtension::Span(SpanInfo {
+                payload_id,
+                chunk_index: index as u32,
+                chunk_count: page_count as u32,
+                payload_crc,
+            }));
+        }
+        let header = generate_v3_header(
+            pixel_count,
+            chunk,
+            image.color(),
+            crc_spec,
+            page_extensions,
+            true,
+        )
+        .map_err(|err| format!("Page {}: {}", index, err))?;
+        check_low_bits_only(header.data_mask(), image.color(), max_bits_per_channel)?;
+        write_payload(
+            convert_dynamic_image_to_png_image(image)?,
+            &header,
+            chunk,
+            None,
+        )?;
+    }
+
+    Ok(())
+}
+
+///
+/// Reads the payload of a single page. Without a page, the payload of the first page is read,
+/// and joined with the other pages if it was spread across them.
+pub(crate) fn read_pages_payload(
+    pages: &mut [DynamicImage],
+    page: Option<usize>,
+) -> Result<Vec<u8>, String> {
+    let page_count = pages.len();
+    let mut read_page = |index: usize| {
+        let image = pages.get_mut(index).ok_or_else(|| {
+            format!(
+                "Page {} does not exist, the TIFF has {} pages",
+                index, page_count
+            )
+        })?;
+        let image = convert_dynamic_image_to_png_image(image)?;
+        let header = try_get_header(image).map_err(|err| format!("Page {}: {}", index, err))?;
+        let payload = read_payload(image, &header, None)?;
+        Ok::<_, String>((header, payload))
+    };
+
+    let (header, payload) = read_page(page.unwrap_or(0))?;
+    if page.is_some() || header.span_info().is_none() {
+        return Ok(payload);
+    }
+    let mut chunks = vec![(header, payload)];
+    for index in 1..page_count {
+        chunks.push(read_page(index)?);
+    }
+    join_chunks(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, Rgba};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+
+    fn noisy_pages() -> Vec<DynamicImage> {
+        let mut first: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 48);
+        rand::thread_rng().fill_bytes(&mut first);
+        let mut second: ImageBuffer<Rgba<u16>, Vec<u16>> = ImageBuffer::new(40, 40);
+        second
+            .iter_mut()
+            .for_each(|sample| *sample = rand::thread_rng().gen());
+        vec![
+            DynamicImage::ImageRgb8(first),
+            DynamicImage::ImageRgba16(second),
+        ]
+    }
+
+    #[test]
+    fn payload_is_spread_across_pages() {
+        let tiff = write_tiff_pages(&noisy_pages()).unwrap();
+        assert!(is_tiff(&tiff));
+        let mut pages = read_tiff_pages(&tiff).unwrap();
+        assert_eq!(pages.len(), 2);
+        let payload = b"two pages, one payload".repeat(40);
+
+        write_pages_payload(
+            &mut pages,
+            None,
+            &payload,
+            CrcSpec::default(),
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        let mut pages = read_tiff_pages(&write_tiff_pages(&pages).unwrap()).unwrap();
+
+        for (index, page) in pages.iter_mut().enumerate() {
+            let header = try_get_header(convert_dynamic_image_to_png_image(page).unwrap()).unwrap();
+            let span = header.span_info().unwrap();
+            assert_eq!((span.chunk_index, span.chunk_count), (index as u32, 2));
+        }
+        assert_eq!(read_pages_payload(&mut pages, None).unwrap(), payload);
+    }
+
+    #[test]
+    fn payload_targets_a_single_page() {
+        let original = noisy_pages();
+        let mut pages = original.clone();
+        let payload = b"only on the second page".to_vec();
+
+        write_pages_payload(
+            &mut pages,
+            Some(1),
+            &payload,
+            CrcSpec::default(),
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+
+        assert_eq!(pages[0], original[0]);
+        assert_eq!(read_pages_payload(&mut pages, Some(1)).unwrap(), payload);
+        assert!(read_pages_payload(&mut pages, Some(2)).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+use std::ops::Range;
+
+use clap::ValueEnum;
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use crate::{
+    crc_spec::CrcSpec,
+    header::{HeaderExtension, HeaderRaw, V1DataStuffingOptions, VersionedHeader},
+    payload::check_payload_crc,
+    png_info::{PngBitDepth, PNG_SIGNATURE},
+    raw::{read_from_buffer, write_to_buffer, PixelFormat},
+};
+
+/// The 2 least significant bits of every transparency entry carry data.
+/// Opaque entries become at most 3/255 transparent.
+pub(crate) const TRNS_MASK: u64 = 0b11u64 << 56;
+const TRNS_BITS_PER_ENTRY: usize = 2;
+/// Every transparency entry is a single byte
+const TRNS_FORMAT: PixelFormat = PixelFormat::new(1, 1);
+/// PNG color type of palette images
+const PALETTE_COLOR_TYPE: u8 = 3;
+
+/// Where the payload is embedded
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Default)]
+pub(crate) enum EmbedChannel {
+    /// The least significant bits of the pixels
+    #[default]
+    Pixels,
+    /// The transparency entries (tRNS chunk) of a palette PNG. The pixels stay untouched.
+    Trns,
+    /// The unused entries of the global color table of a GIF. The frames stay untouched.
+    GifPalette,
+}
+
+///
+/// A chunk of a PNG file, as byte ranges into the file
+struct Chunk {
+    kind: [u8; 4],
+    /// The whole chunk, including length, type and CRC
+    span: Range<usize>,
+    data: Range<usize>,
+}
+
+fn read_chunks(png: &[u8]) -> Result<Vec<Chunk>, String> {
+    if png.len() < PNG_SIGNATURE.len() || png[..PNG_SIGNATURE.len()] != PNG_SIGNATURE {
+        return Err("Only PNG files carry transparency chunks".to_string());
+    }
+
+    let mut chunks = Vec::new();
+    let mut offset = PNG_SIGNATURE.len();
+    // Length (4B), Type (4B), Data, CRC (4B)
+    while offset + 8 <= png.len() {
+        let len = u32::from_be_bytes([
+            png[offset],
+            png[offset + 1],
+            png[offset + 2],
+            png[offset + 3],
+        ]) as usize;
+        let data = offset + 8..offset + 8 + len;
+        let end = data.end + 4;
+        if end > png.len() {
+            return Err("PNG file is truncated".to_string());
+        }
+
+        let mut kind = [0u8; 4];
+        kind.copy_from_slice(&png[offset + 4..offset + 8]);
+        chunks.push(Chunk {
+            kind,
+            span: offset..end,
+            data,
+        });
+        if &kind == b"IEND" {
+            break;
+        }
+        offset = end;
+    }
+
+    Ok(chunks)
+}
+
+///
+/// The transparency entries of a palette PNG, one per palette entry.
+/// Entries missing from the `tRNS` chunk are opaque.
+fn palette_alphas(png: &[u8], chunks: &[Chunk]) -> Result<Vec<u8>, String> {
+    let is_palette =
+        PngBitDepth::from_png_bytes(png).is_some_and(|info| info.color_type == PALETTE_COLOR_TYPE);
+    if !is_palette {
+        return Err(
+            "Only palette PNGs store a transparency value per entry which can carry data"
+                .to_string(),
+        );
+    }
+    let find = |kind: &[u8; 4]| chunks.iter().find(|chunk| &chunk.kind == kind);
+    let trns = find(b"tRNS").ok_or("The PNG has no tRNS chunk")?;
+    let palette = find(b"PLTE").ok_or("The palette PNG has no PLTE chunk")?;
+
+    let mut alphas = png[trns.data.clone()].to_vec();
+    alphas.resize(alphas.len().max(palette.data.len() / 3), 255);
+    Ok(alphas)
+}
+
+fn bytes_to_entries(len: usize) -> usize {
+    (len * 8).div_ceil(TRNS_BITS_PER_ENTRY)
+}
+
+///
+/// Embeds the payload into the `tRNS` chunk of a palette PNG. The header is stored in the chunk as well,
+/// so the image data itself stays untouched. Returns the modified PNG file.
+pub(crate) fn write_trns_payload(
+    png: &[u8],
+    payload: &[u8],
+    crc_spec: CrcSpec,
+) -> Result<Vec<u8>, String> {
+    let chunks = read_chunks(png)?;
+    let mut alphas = palette_alphas(png, &chunks)?;
+
+    let mut extensions = Vec::new();
+    if crc_spec != CrcSpec::default() {
+        extensions.push(HeaderExtension::PayloadCrcSpec(crc_spec));
+    }
+    // The chunk holds a few dozen bytes, so the header uses the shorter variable-length encoding.
+    // Its length then depends on the offset, which follows the header. Settle on a fixed point.
+    let mut start_offset = 0;
+    let header_bytes = loop {
+        let header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::Trns { start_offset },
+            data_mask: TRNS_MASK,
+            data_len: payload.len() as u64,
+            data_crc: crc_spec.checksum(payload),
+            extensions: extensions.clone(),
+        };
+        let raw_header = header.to_varint_raw().map_err(|x| format!("{}", x))?;
+        let header_bytes = raw_header.to_bytes();
+        let header_entries = bytes_to_entries(header_bytes.len()) as u64;
+        if header_entries == start_offset {
+            break header_bytes;
+        }
+        start_offset = header_entries;
+    };
+
+    let entries_needed = bytes_to_entries(header_bytes.len() + payload.len());
+    if entries_needed > alphas.len() {
+        return Err(format!(
+            "The tRNS chunk has {} entries, which hold {} bytes. The header and payload need {} bytes.",
+            alphas.len(),
+            alphas.len() * TRNS_BITS_PER_ENTRY / 8,
+            header_bytes.len() + payload.len()
+        ));
+    }
+
+    write_to_buffer(&mut alphas, 0, TRNS_MASK, TRNS_FORMAT, &header_bytes);
+    if !payload.is_empty() {
+        write_to_buffer(
+            &mut alphas,
+            start_offset as usize,
+            TRNS_MASK,
+            TRNS_FORMAT,
+            payload,
+        );
+    }
+
+    let trns = chunks
+        .iter()
+        .find(|chunk| &chunk.kind == b"tRNS")
+        .ok_or("The PNG has no tRNS chunk")?;
+    let mut chunk_body = b"tRNS".to_vec();
+    chunk_body.extend_from_slice(&alphas);
+    let chunk_crc = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(&chunk_body);
+
+    let mut output = Vec::with_capacity(png.len() + alphas.len());
+    output.extend_from_slice(&png[..trns.span.start]);
+    output.extend_from_slice(&(alphas.len() as u32).to_be_bytes());
+    output.extend_from_slice(&chunk_body);
+    output.extend_from_slice(&chunk_crc.to_be_bytes());
+    output.extend_from_slice(&png[trns.span.end..]);
+    Ok(output)
+}
+
+///
+/// Reads the header stored in the `tRNS` chunk, if the payload was embedded there
+pub(crate) fn try_get_trns_header(png: &[u8]) -> Result<VersionedHeader, String> {
+    let chunks = read_chunks(png)?;
+    let alphas = palette_alphas(png, &chunks)?;
+    read_header(&alphas)
+}
+
+fn read_header(alphas: &[u8]) -> Result<VersionedHeader, String> {
+    // Magic (1B), Header Len (2B), Data, CRC (4B)
+    if alphas.len() < bytes_to_entries(3 + 4) {
+        return Err("The tRNS chunk is too short to hold a header".to_string());
+    }
+    let partial_header = read_from_buffer(alphas, 0, 3, TRNS_MASK, TRNS_FORMAT);
+    let header_len = 3 + u16::from_be_bytes([partial_header[1], partial_header[2]]) as usize + 4;
+    if bytes_to_entries(header_len) > alphas.len() {
+        return Err("The tRNS chunk does not carry a header".to_string());
+    }
+
+    let header: VersionedHeader = HeaderRaw::from_bytes(&read_from_buffer(
+        alphas,
+        0,
+        header_len,
+        TRNS_MASK,
+        TRNS_FORMAT,
+    ))
+    .and_then(VersionedHeader::try_from)?;
+    if !matches!(header.stuffing_opts(), V1DataStuffingOptions::Trns { .. }) {
+        return Err("The header does not describe a payload in the tRNS chunk".to_string());
+    }
+
+    Ok(header)
+}
+
+///
+/// Reads the payload embedded via [`write_trns_payload`], along with its header.
+pub(crate) fn read_trns_payload(png: &[u8]) -> Result<(VersionedHeader, Vec<u8>), String> {
+    let chunks = read_chunks(png)?;
+    let alphas = palette_alphas(png, &chunks)?;
+    let header = read_header(&alphas)?;
+
+    let start_offset = header.start_offset() as usize;
+    let data_len = header.data_len() as usize;
+    if header.data_mask() != TRNS_MASK
+        || start_offset.saturating_add(bytes_to_entries(data_len)) > alphas.len()
+    {
+        return Err("Header describes more data than the tRNS chunk can hold".to_string());
+    }
+
+    let payload = match data_len {
+        0 => Vec::new(),
+        _ => read_from_buffer(&alphas, start_offset, data_len, TRNS_MASK, TRNS_FORMAT),
+    };
+    check_payload_crc(&header, &payload)?;
+
+    Ok((header, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const WIDTH: u32 = 16;
+    const HEIGHT: u32 = 16;
+
+    /// A 16×16 palette PNG with 256 colors and a transparency entry for each
+    fn palette_png() -> Vec<u8> {
+        let palette: Vec<u8> = (0..=255u8).flat_map(|i| [i, 255 - i, i / 2]).collect();
+        let alphas: Vec<u8> = (0..=255u8)
+            .map(|i| if i < 16 { i * 16 } else { 255 })
+            .collect();
+        let indices: Vec<u8> = (0..WIDTH * HEIGHT).map(|i| i as u8).collect();
+
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, WIDTH, HEIGHT);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(palette);
+        encoder.set_trns(alphas);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&indices).unwrap();
+        writer.finish().unwrap();
+        data
+    }
+
+    fn decode_indices(png: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut reader = png::Decoder::new(png).read_info().unwrap();
+        let mut indices = vec![0u8; reader.output_buffer_size()];
+        reader.next_frame(&mut indices).unwrap();
+        let trns = reader.info().trns.as_ref().unwrap().to_vec();
+        (indices, trns)
+    }
+
+    #[test]
+    fn trns_round_trip_leaves_image_data_untouched() {
+        let cover = palette_png();
+        let payload = b"hidden in alpha".to_vec();
+
+        let modified = write_trns_payload(&cover, &payload, CrcSpec::default()).unwrap();
+
+        let (header, read) = read_trns_payload(&modified).unwrap();
+        assert_eq!(read, payload);
+        assert!(matches!(
+            header.stuffing_opts(),
+            V1DataStuffingOptions::Trns { .. }
+        ));
+        assert_eq!(try_get_trns_header(&modified).unwrap(), header);
+
+        let (cover_indices, cover_alphas) = decode_indices(&cover);
+        let (indices, alphas) = decode_indices(&modified);
+        assert_eq!(indices, cover_indices);
+        for (before, after) in cover_alphas.iter().zip(&alphas) {
+            assert!(before.abs_diff(*after) <= 3, "{} -> {}", before, after);
+        }
+    }
+
+    #[test]
+    fn trns_rejects_oversized_payload() {
+        let cover = palette_png();
+
+        assert!(write_trns_payload(&cover, &[0u8; 64], CrcSpec::default()).is_err());
+        assert!(read_trns_payload(&cover).is_err());
+    }
+}
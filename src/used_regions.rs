@@ -0,0 +1,139 @@
+use std::ops::Range;
+
+use crate::{
+    buffer_modify::{checked_pixel_index, PngImage},
+    header::{V1DataStuffingOptions, VersionedHeader},
+};
+
+///
+/// Pixels taken by payloads which are already embedded into an image, see [`free_capacity`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsedRegions {
+    used: Vec<bool>,
+}
+
+impl UsedRegions {
+    pub fn new(pixel_count: u64) -> Result<UsedRegions, String> {
+        Ok(UsedRegions {
+            used: vec![false; checked_pixel_index(pixel_count)?],
+        })
+    }
+
+    pub fn mark(&mut self, pixels: Range<u64>) -> Result<(), String> {
+        let start = checked_pixel_index(pixels.start)?;
+        let end = checked_pixel_index(pixels.end)?;
+        if end > self.used.len() {
+            return Err(format!(
+                "Pixels {}..{} lie outside of the image with {} pixels",
+                start,
+                end,
+                self.used.len()
+            ));
+        }
+
+        self.used[start..end].fill(true);
+        Ok(())
+    }
+
+    ///
+    /// Marks the pixels carrying the payload described by the header, and the ones reserved behind it.
+    /// Only sequentially stored payloads are supported.
+    pub fn mark_payload(&mut self, header: &VersionedHeader) -> Result<(), String> {
+        if !matches!(header.stuffing_opts(), V1DataStuffingOptions::None { .. }) {
+            return Err("Only sequentially stored payloads can share an image".to_string());
+        }
+        let pixels = match header.data_mask().count_ones() {
+            0 => 0,
+            bits => (header.data_len().saturating_add(header.reserved_bytes()) * 8)
+                .div_ceil(bits as u64),
+        };
+
+        self.mark(header.start_offset()..header.start_offset() + pixels)
+    }
+
+    ///
+    /// Consecutive runs of unused pixels inside `range`, in ascending order
+    pub fn free_runs(&self, range: Range<u64>) -> Vec<Range<u64>> {
+        let end = range.end.min(self.used.len() as u64);
+        let mut runs = Vec::new();
+        let mut run_start = None;
+
+        for index in range.start..end {
+            match (self.used[index as usize], run_start) {
+                (false, None) => run_start = Some(index),
+                (true, Some(start)) => {
+                    runs.push(start..index);
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = run_start {
+            runs.push(start..end);
+        }
+
+        runs
+    }
+}
+
+///
+/// How many more payload bytes fit into the image next to the payload described by the header,
+/// at the same bits per pixel. Like the placement of a new payload, only the largest free run of pixels counts.
+pub fn free_capacity(image: &dyn PngImage, header: &VersionedHeader) -> Result<u64, String> {
+    let pixel_count = image.pixel_count();
+    let mut used = UsedRegions::new(pixel_count)?;
+    used.mark(0..header.pixel_span()?.min(pixel_count))?;
+    used.mark_payload(header)?;
+
+    let free_pixels = used
+        .free_runs(0..pixel_count)
+        .iter()
+        .map(|run| run.end - run.start)
+        .max()
+        .unwrap_or(0);
+    Ok(free_pixels * header.data_mask().count_ones() as u64 / 8)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{crc_spec::CrcSpec, header::HeaderExtension, payload::write_payload};
+
+    #[test]
+    fn free_runs_skip_used_pixels() {
+        let mut used = UsedRegions::new(100).unwrap();
+        used.mark(10..20).unwrap();
+        used.mark(50..60).unwrap();
+
+        assert_eq!(used.free_runs(5..100), vec![5..10, 20..50, 60..100]);
+        assert!(used.mark(90..101).is_err());
+    }
+
+    #[test]
+    fn free_capacity_counts_largest_free_run() {
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        let payload = vec![0x33u8; 500];
+        let header = |extensions| VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 1000 },
+            data_mask: 0x01_01_01_01_00_00_00_00,
+            data_len: payload.len() as u64,
+            data_crc: CrcSpec::default().checksum(&payload),
+            extensions,
+        };
+        write_payload(&mut image, &header(Vec::new()), &payload, None).unwrap();
+
+        // The payload takes pixels 1000..2000, leaving 2096 pixels at 4 bits each behind it
+        assert_eq!(
+            free_capacity(&image, &header(Vec::new())).unwrap(),
+            2096 * 4 / 8
+        );
+        // Reserved bytes count as taken
+        assert_eq!(
+            free_capacity(&image, &header(vec![HeaderExtension::Reserved(400)])).unwrap(),
+            2096 * 4 / 8 - 400
+        );
+    }
+}
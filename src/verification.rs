@@ -0,0 +1,423 @@
+use std::fmt;
+
+use crate::{
+    avoid_mask::AvoidMask,
+    buffer_modify::{checked_pixel_index, PngImage},
+    header::{
+        locate_header_mask, try_get_header, HeaderRaw, V1DataStuffingOptions, VersionedHeader,
+        HEADER_MAGIC, VARINT_HEADER_MAGIC,
+    },
+    payload::{read_payload, restricted_payload_pixels},
+    preamble::{read_preamble, PREAMBLE_BYTES},
+    scatter::{read_scattered_header, SCATTERED_MAGIC},
+};
+
+///
+/// The checks run by [`verify`], in order. Each check relies on the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Check {
+    /// The image starts with a known header magic
+    Magic,
+    /// The header fits into the image
+    HeaderLength,
+    /// The header data matches the header checksum
+    HeaderCrc,
+    /// The header data can be decoded
+    HeaderDecode,
+    /// The payload described by the header fits into the image
+    Capacity,
+    /// The payload matches the payload checksum
+    PayloadCrc,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Check::Magic => "magic",
+            Check::HeaderLength => "header length",
+            Check::HeaderCrc => "header checksum",
+            Check::HeaderDecode => "header decoding",
+            Check::Capacity => "capacity",
+            Check::PayloadCrc => "payload checksum",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    /// Not run, as an earlier check failed or the header does not allow it
+    Skipped(String),
+}
+
+///
+/// Outcome of every [`Check`], along with the header if it could be decoded
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationReport {
+    pub header: Option<VersionedHeader>,
+    pub checks: Vec<(Check, Outcome)>,
+}
+
+impl VerificationReport {
+    /// Whether every check passed. Payloads without checksum cannot be verified, so they are not valid.
+    pub fn is_valid(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|(_, outcome)| *outcome == Outcome::Passed)
+    }
+
+    /// Why the report is not valid, if it is not
+    pub fn failure(&self) -> Option<String> {
+        self.checks
+            .iter()
+            .find_map(|(check, outcome)| match outcome {
+                Outcome::Passed => None,
+                Outcome::Failed(err) => Some(format!("{} failed: {}", check, err)),
+                Outcome::Skipped(reason) => Some(format!("{} skipped: {}", check, reason)),
+            })
+    }
+}
+
+///
+/// Checks that the header and the payload of the image are consistent, without handing out the payload.
+/// Never modifies the image and never panics on corrupt headers.
+pub fn verify(image: &dyn PngImage, avoid_mask: Option<&AvoidMask>) -> VerificationReport {
+    let mut checks = Vec::new();
+    let header = run_checks(image, avoid_mask, &mut checks);
+
+    let skipped = [
+        Check::Magic,
+        Check::HeaderLength,
+        Check::HeaderCrc,
+        Check::HeaderDecode,
+        Check::Capacity,
+        Check::PayloadCrc,
+    ];
+    for check in skipped.into_iter().skip(checks.len()) {
+        checks.push((
+            check,
+            Outcome::Skipped("an earlier check failed".to_string()),
+        ));
+    }
+
+    VerificationReport { header, checks }
+}
+
+///
+/// Reads the header for `stat`. Only the header region is read, so this stays cheap for huge payloads.
+/// The payload checksum is reported as claimed by the header. With `deep`, the payload is verified as well.
+pub fn stat_image(
+    image: &dyn PngImage,
+    deep: bool,
+) -> Result<(VersionedHeader, Option<VerificationReport>), String> {
+    let header = try_get_header(image)?;
+    let report = deep.then(|| verify(image, None));
+
+    Ok((header, report))
+}
+
+///
+/// Runs the checks until one fails. Returns the header if it could be decoded.
+fn run_checks(
+    image: &dyn PngImage,
+    avoid_mask: Option<&AvoidMask>,
+    checks: &mut Vec<(Check, Outcome)>,
+) -> Option<VersionedHeader> {
+    let pixel_count = image.pixel_count();
+    if pixel_count < 3 * 8 {
+        checks.push((
+            Check::Magic,
+            Outcome::Failed("The image is too small to carry a header".to_string()),
+        ));
+        return None;
+    }
+    let header_mask = locate_header_mask(image);
+    let pixel_offset = match read_preamble(image, header_mask) {
+        Some(_) => PREAMBLE_BYTES * 8,
+        None => 0,
+    };
+    let partial_header = image.read_data_with_mask(header_mask, pixel_offset, 3);
+    let magic = partial_header[0];
+    if ![HEADER_MAGIC, VARINT_HEADER_MAGIC, SCATTERED_MAGIC].contains(&magic) {
+        checks.push((
+            Check::Magic,
+            Outcome::Failed(format!("Unknown magic {:#04x}", magic)),
+        ));
+        return None;
+    }
+    checks.push((Check::Magic, Outcome::Passed));
+
+    let raw_header = match magic {
+        SCATTERED_MAGIC => read_scattered_header(image),
+        _ => {
+            let header_len =
+                3 + u16::from_be_bytes([partial_header[1], partial_header[2]]) as u64 + 4;
+            match pixel_offset as u64 + header_len * 8 <= pixel_count {
+                true => HeaderRaw::from_bytes(&image.read_data_with_mask(
+                    header_mask,
+                    pixel_offset,
+                    header_len as usize,
+                )),
+                false => Err(format!(
+                    "The header claims {} bytes, but the image only holds {}",
+                    header_len,
+                    (pixel_count - pixel_offset as u64) / 8
+                )),
+            }
+        }
+    };
+    let raw_header = match raw_header {
+        Ok(val) => val,
+        Err(err) => {
+            checks.push((Check::HeaderLength, Outcome::Failed(err)));
+            return None;
+        }
+    };
+    checks.push((Check::HeaderLength, Outcome::Passed));
+
+    if !raw_header.has_valid_crc() {
+        checks.push((
+            Check::HeaderCrc,
+            Outcome::Failed(format!(
+                "The header data does not match its checksum {:#010x}",
+                raw_header.crc
+            )),
+        ));
+        return None;
+    }
+    checks.push((Check::HeaderCrc, Outcome::Passed));
+
+    let header = match VersionedHeader::try_from(raw_header) {
+        Ok(val) => val,
+        Err(err) => {
+            checks.push((Check::HeaderDecode, Outcome::Failed(err)));
+            return None;
+        }
+    };
+    checks.push((Check::HeaderDecode, Outcome::Passed));
+
+    if let Err(err) = check_capacity(image, &header, avoid_mask) {
+        checks.push((Check::Capacity, Outcome::Failed(err)));
+        return Some(header);
+    }
+    checks.push((Check::Capacity, Outcome::Passed));
+
+    let outcome = match header.data_crc() {
+        None => Outcome::Skipped("The header does not contain a payload checksum".to_string()),
+        Some(_) => match read_payload(image, &header, avoid_mask) {
+            Ok(_) => Outcome::Passed,
+            Err(err) => Outcome::Failed(err),
+        },
+    };
+    checks.push((Check::PayloadCrc, outcome));
+
+    Some(header)
+}
+
+///
+/// Whether all payload bits lie inside the pixels available to the payload
+pub fn check_capacity(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    avoid_mask: Option<&AvoidMask>,
+) -> Result<(), String> {
+    if let V1DataStuffingOptions::Complexity { .. } = header.stuffing_opts() {
+        // The bits per pixel vary, the payload pixels are only found if they hold all of it
+        return restricted_payload_pixels(image, header, avoid_mask).map(|_| ());
+    }
+    let bits_per_pixel = match header.stuffing_opts() {
+        V1DataStuffingOptions::Luma { y_bits, .. } => y_bits as u64,
+        _ => header.data_mask().count_ones() as u64,
+    };
+    let color_bits = image.color_type().bits_per_pixel().min(u64::BITS as u16) as u32;
+    if header.data_mask().trailing_zeros() < u64::BITS - color_bits {
+        return Err("The data mask covers bits beyond the pixel".to_string());
+    }
+    if bits_per_pixel == 0 && header.data_len() > 0 {
+        return Err("The data mask is empty".to_string());
+    }
+
+    let available_pixels = match restricted_payload_pixels(image, header, avoid_mask)? {
+        Some(pixels) => pixels.len() as u64,
+        None => image.pixel_count().saturating_sub(header.start_offset()),
+    };
+    let needed_pixels = match header.data_len().checked_mul(8) {
+        Some(bits) if bits_per_pixel > 0 => bits.div_ceil(bits_per_pixel),
+        Some(_) => 0,
+        None => u64::MAX,
+    };
+    checked_pixel_index(needed_pixels)?;
+    if header.start_offset() > image.pixel_count() || needed_pixels > available_pixels {
+        return Err(format!(
+            "The payload of {} bytes needs {} pixels, but only {} are available",
+            header.data_len(),
+            needed_pixels,
+            available_pixels
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, Rgba};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        buffer_modify::WriteImageBinary,
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, V1DataStuffingOptions, HEADER_MASK},
+        payload::write_payload,
+    };
+
+    fn image_with_payload() -> (ImageBuffer<Rgba<u8>, Vec<u8>>, VersionedHeader) {
+        let mut image: ImageBuffer<Rgba<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        let payload = vec![0x5A; 500];
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            image::ColorType::Rgba8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+        (image, header)
+    }
+
+    fn failed_check(report: &VerificationReport) -> Option<Check> {
+        report
+            .checks
+            .iter()
+            .find(|(_, outcome)| matches!(outcome, Outcome::Failed(_)))
+            .map(|(check, _)| *check)
+    }
+
+    #[test]
+    fn valid_image_passes_every_check() {
+        let (image, header) = image_with_payload();
+        let before = image.clone();
+
+        let report = verify(&image, None);
+        assert!(report.is_valid(), "{:?}", report);
+        assert!(report
+            .checks
+            .iter()
+            .all(|(_, outcome)| *outcome == Outcome::Passed));
+        assert_eq!(report.header, Some(header));
+        assert_eq!(image, before);
+    }
+
+    #[test]
+    fn bad_magic_is_reported() {
+        let (mut image, header) = image_with_payload();
+        let mut raw: HeaderRaw = header.try_into().unwrap();
+        raw.magic = 0x17;
+        image.write_data_with_mask(&raw.to_bytes(), HEADER_MASK, 0);
+
+        let report = verify(&image, None);
+        assert_eq!(failed_check(&report), Some(Check::Magic));
+        assert!(matches!(
+            report.checks.last(),
+            Some((Check::PayloadCrc, Outcome::Skipped(_)))
+        ));
+    }
+
+    #[test]
+    fn bad_header_crc_is_reported() {
+        let (mut image, header) = image_with_payload();
+        let mut raw: HeaderRaw = header.try_into().unwrap();
+        raw.crc ^= 1;
+        image.write_data_with_mask(&raw.to_bytes(), HEADER_MASK, 0);
+
+        assert_eq!(failed_check(&verify(&image, None)), Some(Check::HeaderCrc));
+    }
+
+    #[test]
+    fn oversized_header_is_reported() {
+        let (mut image, _) = image_with_payload();
+        image.write_data_with_mask(&[HEADER_MAGIC, 0xFF, 0xFF], HEADER_MASK, 0);
+
+        assert_eq!(
+            failed_check(&verify(&image, None)),
+            Some(Check::HeaderLength)
+        );
+    }
+
+    #[test]
+    fn impossible_length_is_reported() {
+        let (mut image, header) = image_with_payload();
+        let VersionedHeader::V3 {
+            data_mask,
+            data_crc,
+            extensions,
+            ..
+        } = header
+        else {
+            panic!("Expected a V3 header")
+        };
+        let header = VersionedHeader::V3 {
+            stuffing_opts: V1DataStuffingOptions::None { start_offset: 1000 },
+            data_mask,
+            data_len: u64::MAX / 4,
+            data_crc,
+            extensions,
+        };
+        let raw: HeaderRaw = header.try_into().unwrap();
+        image.write_data_with_mask(&raw.to_bytes(), HEADER_MASK, 0);
+
+        let report = verify(&image, None);
+        assert_eq!(failed_check(&report), Some(Check::Capacity));
+        assert!(report.header.is_some());
+    }
+
+    #[test]
+    fn stat_does_not_read_huge_payloads() {
+        let (mut image, header) = image_with_payload();
+        let VersionedHeader::V3 {
+            stuffing_opts,
+            data_mask,
+            data_crc,
+            extensions,
+            ..
+        } = header
+        else {
+            panic!("Expected a V3 header")
+        };
+        // Far more than could ever be read, so reading the payload would fail
+        let header = VersionedHeader::V3 {
+            stuffing_opts,
+            data_mask,
+            data_len: u64::MAX / 4,
+            data_crc,
+            extensions,
+        };
+        let raw: HeaderRaw = header.clone().try_into().unwrap();
+        image.write_data_with_mask(&raw.to_bytes(), HEADER_MASK, 0);
+
+        let (stat_header, report) = stat_image(&image, false).unwrap();
+        assert_eq!(stat_header, header);
+        assert_eq!(stat_header.data_crc(), header.data_crc());
+        assert!(report.is_none());
+
+        let (_, report) = stat_image(&image, true).unwrap();
+        assert_eq!(failed_check(&report.unwrap()), Some(Check::Capacity));
+    }
+
+    #[test]
+    fn tampered_payload_is_reported() {
+        let (mut image, header) = image_with_payload();
+        let start = header.start_offset() as usize * 4;
+        for sample in &mut image.as_mut()[start..start + 16] {
+            *sample ^= 0x03;
+        }
+
+        assert_eq!(failed_check(&verify(&image, None)), Some(Check::PayloadCrc));
+    }
+}
@@ -0,0 +1,156 @@
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use image::{DynamicImage, GenericImage, GenericImageView, Rgba};
+
+/// Font used by `--visible-watermark` unless `--watermark-font` names another one, see assets/DejaVuSansMono-LICENSE.txt
+pub(crate) const DEFAULT_FONT: &[u8] = include_bytes!("../assets/DejaVuSansMono.ttf");
+
+/// How much of the text color is blended into a pixel fully covered by a glyph
+const OPACITY: f32 = 0.35;
+
+///
+/// Draws `text` semi-transparently into the bottom right corner of the image, about a tenth of the image high
+/// and shrunk to fit its width. The text is white over dark and black over bright areas.
+/// Meant to run before embedding: the text changes the high bits of the pixels, the payload is written into
+/// their low bits afterwards. Returns the number of pixels changed.
+pub(crate) fn draw_visible_watermark(
+    image: &mut DynamicImage,
+    text: &str,
+    font: &[u8],
+) -> Result<u64, String> {
+    let font =
+        FontRef::try_from_slice(font).map_err(|err| format!("Failed to load the font: {}", err))?;
+    let (width, height) = image.dimensions();
+
+    let text_width = |scale: PxScale| {
+        let font = font.as_scaled(scale);
+        let mut previous = None;
+        text.chars()
+            .map(|c| {
+                let id = font.glyph_id(c);
+                let kern = previous.map_or(0.0, |previous| font.kern(previous, id));
+                previous = Some(id);
+                kern + font.h_advance(id)
+            })
+            .sum::<f32>()
+    };
+    let mut scale = PxScale::from((height as f32 / 10.0).max(12.0));
+    let fitting_width = width as f32 * 0.9;
+    if text_width(scale) > fitting_width {
+        scale = PxScale::from(scale.y * fitting_width / text_width(scale));
+    }
+    let scaled = font.as_scaled(scale);
+    let margin = scale.y / 2.0;
+    let mut caret = point(
+        width as f32 - margin - text_width(scale),
+        height as f32 - margin + scaled.descent(),
+    );
+
+    let mut glyphs = Vec::new();
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            caret.x += scaled.kern(previous, id);
+        }
+        previous = Some(id);
+        glyphs.extend(font.outline_glyph(id.with_scale_and_position(scale, caret)));
+        caret.x += scaled.h_advance(id);
+    }
+
+    // Coverage of every pixel touched by a glyph, clipped to the image
+    let mut coverage = Vec::new();
+    for glyph in &glyphs {
+        let bounds = glyph.px_bounds();
+        glyph.draw(|x, y, value| {
+            let x = bounds.min.x as i64 + x as i64;
+            let y = bounds.min.y as i64 + y as i64;
+            if value > 0.0 && (0..width as i64).contains(&x) && (0..height as i64).contains(&y) {
+                coverage.push((x as u32, y as u32, value.min(1.0)));
+            }
+        });
+    }
+    if coverage.is_empty() {
+        return Err(format!(
+            "The visible watermark {:?} does not fit into an image of {} × {}px",
+            text, width, height
+        ));
+    }
+
+    let luma = |pixel: Rgba<u8>| {
+        (299 * pixel[0] as u32 + 587 * pixel[1] as u32 + 114 * pixel[2] as u32) / 1000
+    };
+    let mean_luma = coverage
+        .iter()
+        .map(|&(x, y, _)| luma(image.get_pixel(x, y)) as u64)
+        .sum::<u64>()
+        / coverage.len() as u64;
+    let target = match mean_luma > 127 {
+        true => 0.0,
+        false => 255.0,
+    };
+
+    let mut changed = 0;
+    for (x, y, value) in coverage {
+        let alpha = value * OPACITY;
+        let mut pixel = image.get_pixel(x, y);
+        let original = pixel;
+        for channel in &mut pixel.0[..3] {
+            *channel = (*channel as f32 * (1.0 - alpha) + target * alpha).round() as u8;
+        }
+        if pixel != original {
+            image.put_pixel(x, y, pixel);
+            changed += 1;
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ColorType, RgbImage};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        buffer_modify::convert_dynamic_image_to_png_image,
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header},
+        payload::{read_payload, write_payload},
+    };
+
+    #[test]
+    fn visible_text_and_invisible_payload_coexist() {
+        let mut image = DynamicImage::ImageRgb8(RgbImage::from_fn(256, 128, |x, y| {
+            image::Rgb([60 + (x % 7) as u8, 70 + (y % 5) as u8, 80])
+        }));
+        let cover = image.clone();
+
+        let changed = draw_visible_watermark(&mut image, "(c) ACME 2024", DEFAULT_FONT).unwrap();
+        let payload = b"licensed to ACME, see contract 42".to_vec();
+        let header = generate_v3_header(
+            256 * 128,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            false,
+        )
+        .unwrap();
+        let png_image = convert_dynamic_image_to_png_image(&mut image).unwrap();
+        write_payload(png_image, &header, &payload, None).unwrap();
+        let found = try_get_header(png_image).unwrap();
+        assert_eq!(read_payload(png_image, &found, None).unwrap(), payload);
+
+        // The text shows in the high bits, which the payload leaves alone
+        let visible = cover
+            .as_bytes()
+            .iter()
+            .zip(image.as_bytes())
+            .filter(|(before, after)| (*before ^ *after) & 0xF0 != 0)
+            .count();
+        assert!(changed > 100, "only {} pixels changed", changed);
+        assert!(visible > 100, "only {} samples visibly changed", visible);
+
+        assert!(draw_visible_watermark(&mut image, "x", b"not a font").is_err());
+    }
+}
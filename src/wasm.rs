@@ -0,0 +1,45 @@
+//! Entry points for the browser, via `wasm-bindgen`.
+//!
+//! Randomness comes from the `js` backend of `getrandom`. Pass `randomize_offset = false`
+//! to embed without drawing any randomness.
+
+use wasm_bindgen::prelude::*;
+
+use crate::in_memory::{decode_from_slice, encode_to_vec};
+
+/// Hides `payload` in the PNG `cover` and returns the modified PNG
+#[wasm_bindgen]
+pub fn encode(cover: &[u8], payload: &[u8], randomize_offset: bool) -> Result<Vec<u8>, JsError> {
+    encode_to_vec(cover, payload, randomize_offset).map_err(|err| JsError::new(&err))
+}
+
+/// Reads the payload hidden in the PNG `image`
+#[wasm_bindgen]
+pub fn decode(image: &[u8]) -> Result<Vec<u8>, JsError> {
+    decode_from_slice(image).map_err(|err| JsError::new(&err))
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use image::{ImageBuffer, ImageOutputFormat, Rgba};
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use super::*;
+
+    #[wasm_bindgen_test]
+    fn round_trip_in_wasm() {
+        let cover = ImageBuffer::from_fn(64, 64, |x, y| Rgba([x as u8, y as u8, 0x80, 0xFF]));
+        let mut cover_png = Vec::new();
+        cover
+            .write_to(
+                &mut std::io::Cursor::new(&mut cover_png),
+                ImageOutputFormat::Png,
+            )
+            .unwrap();
+        let payload = b"round trip in the browser".to_vec();
+
+        let encoded = encode(&cover_png, &payload, true).unwrap();
+
+        assert_eq!(decode(&encoded).unwrap(), payload);
+    }
+}
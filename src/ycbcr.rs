@@ -0,0 +1,287 @@
+//! Embedding into the luma of the image instead of its RGB channels.
+//!
+//! Luma and chroma follow the reversible color transform (RCT) of JPEG 2000, which maps 8-bit RGB
+//! to integers and back without loss. Changing the luma of a pixel shifts its three channels by the same
+//! amount and leaves the chroma untouched, so decoding finds the same pixels the payload was embedded in.
+//! This does not survive JPEG compression yet, the image still has to be stored losslessly.
+
+use std::ops::RangeInclusive;
+
+use image::ColorType;
+use rand::Rng;
+
+use crate::{
+    buffer_modify::{checked_pixel_index, PngImage},
+    header::{V1DataStuffingOptions, VersionedHeader},
+    prng::tool_rng,
+    raw::WriteStats,
+};
+
+/// The color channels of an 8-bit RGB(A) pixel
+const RGB8_MASK: u64 = 0xFF_FF_FF << 40;
+
+/// Largest number of luma bits a pixel can carry
+pub const MAX_Y_BITS: u8 = 4;
+
+/// A pixel under the reversible color transform
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rct {
+    y: i32,
+    cb: i32,
+    cr: i32,
+}
+
+impl Rct {
+    fn from_rgb([r, g, b]: [u8; 3]) -> Rct {
+        let (r, g, b) = (r as i32, g as i32, b as i32);
+        Rct {
+            y: (r + 2 * g + b) >> 2,
+            cb: b - g,
+            cr: r - g,
+        }
+    }
+
+    fn to_rgb(self) -> [u8; 3] {
+        let g = self.y - (self.cb + self.cr).div_euclid(4);
+        [(self.cr + g) as u8, g as u8, (self.cb + g) as u8]
+    }
+
+    /// Luma values which keep all channels within 0..=255 at this chroma
+    fn luma_range(&self) -> RangeInclusive<i32> {
+        let offset = (self.cb + self.cr).div_euclid(4);
+        let low = 0.max(-self.cb).max(-self.cr);
+        let high = 255.min(255 - self.cb).min(255 - self.cr);
+        (low + offset)..=(high + offset)
+    }
+
+    /// Whether the luma can take every combination of its `y_bits` low bits.
+    /// Only depends on the chroma, so embedding does not change it.
+    fn carries(&self, y_bits: u8) -> bool {
+        let range = self.luma_range();
+        range.end() - range.start() + 1 >= 1 << y_bits
+    }
+
+    /// The luma closest to the current one whose low bits are `value`
+    fn with_low_bits(self, value: i32, y_bits: u8) -> Rct {
+        let step = 1 << y_bits;
+        let base = (self.y & !(step - 1)) | value;
+        let y = [base - step, base, base + step]
+            .into_iter()
+            .filter(|y| self.luma_range().contains(y))
+            .min_by_key(|y| (y - self.y).abs())
+            .expect("pixels which carry the bits have a luma for every value");
+        Rct { y, ..self }
+    }
+}
+
+fn check_color_type(color_type: ColorType) -> Result<(), String> {
+    match color_type {
+        ColorType::Rgb8 | ColorType::Rgba8 => Ok(()),
+        other => Err(format!(
+            "Embedding into the luma needs an 8-bit RGB(A) image, but the image is {:?}",
+            other
+        )),
+    }
+}
+
+///
+/// Pixels whose luma can carry `y_bits` bits, in order.
+/// The pixels the header may cover are left out, as writing the header changes their chroma.
+fn luma_pixels(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    y_bits: u8,
+) -> Result<Vec<usize>, String> {
+    check_color_type(image.color_type())?;
+    let reserved = checked_pixel_index(header.max_pixel_span()?)?;
+
+    Ok(image
+        .rgba8_pixels()
+        .into_iter()
+        .enumerate()
+        .skip(reserved)
+        .filter(|(_, [r, g, b, _])| Rct::from_rgb([*r, *g, *b]).carries(y_bits))
+        .map(|(index, _)| index)
+        .collect())
+}
+
+///
+/// Moves the payload of the header into the luma of the image, `y_bits` bits per pixel.
+/// The header itself stays where it is.
+pub fn with_luma_placement(
+    image: &dyn PngImage,
+    header: VersionedHeader,
+    y_bits: u8,
+    randomize_offset: bool,
+) -> Result<VersionedHeader, String> {
+    if !(1..=MAX_Y_BITS).contains(&y_bits) {
+        return Err(format!(
+            "The luma carries 1 to {} bits per pixel, but {} were requested",
+            MAX_Y_BITS, y_bits
+        ));
+    }
+    let pixels = luma_pixels(image, &header, y_bits)?.len() as u64;
+    let needed = (header.data_len() * 8).div_ceil(y_bits as u64);
+    if needed > pixels {
+        return Err(format!(
+            "The payload needs {} pixels at {} luma bits each, but only {} pixels can carry them",
+            needed, y_bits, pixels
+        ));
+    }
+
+    let start_offset = match randomize_offset {
+        true => tool_rng().gen_range(0..=pixels - needed),
+        false => 0,
+    };
+    Ok(header
+        .with_data_mask(0)
+        .with_stuffing_opts(V1DataStuffingOptions::Luma {
+            start_offset,
+            y_bits,
+        }))
+}
+
+///
+/// Pixels carrying the payload, see [`with_luma_placement`]
+pub fn payload_pixels(
+    image: &dyn PngImage,
+    header: &VersionedHeader,
+    y_bits: u8,
+) -> Result<Vec<usize>, String> {
+    let pixels = luma_pixels(image, header, y_bits)?;
+    let start_offset = checked_pixel_index(header.start_offset())?;
+    let needed = checked_pixel_index((header.data_len() * 8).div_ceil(y_bits as u64))?;
+    if start_offset + needed > pixels.len() {
+        return Err("Header describes more data than the luma of the image can hold".to_string());
+    }
+
+    Ok(pixels[start_offset..start_offset + needed].to_vec())
+}
+
+///
+/// Writes the payload into the luma of the given pixels
+pub fn write_luma(
+    image: &mut dyn PngImage,
+    payload: &[u8],
+    y_bits: u8,
+    pixels: &[usize],
+) -> WriteStats {
+    let colors = image.read_data_at_pixels(RGB8_MASK, pixels, pixels.len() * 3);
+    let total_bits = payload.len() * 8;
+    let modified: Vec<u8> = colors
+        .chunks_exact(3)
+        .enumerate()
+        .flat_map(|(pixel_index, rgb)| {
+            let pixel = Rct::from_rgb([rgb[0], rgb[1], rgb[2]]);
+            let value = (0..y_bits as usize).fold(0, |value, bit| {
+                let index = pixel_index * y_bits as usize + bit;
+                let shift = y_bits as usize - 1 - bit;
+                // The last pixel keeps its own low bits where the payload runs out
+                let bit = match index < total_bits {
+                    true => ((payload[index / 8] >> (7 - index % 8)) & 1) as i32,
+                    false => (pixel.y >> shift) & 1,
+                };
+                value | bit << shift
+            });
+            pixel.with_low_bits(value, y_bits).to_rgb()
+        })
+        .collect();
+    image.write_data_at_pixels(&modified, RGB8_MASK, pixels)
+}
+
+///
+/// Reads `data_len` bytes from the luma of the given pixels
+pub fn read_luma(image: &dyn PngImage, y_bits: u8, pixels: &[usize], data_len: usize) -> Vec<u8> {
+    let colors = image.read_data_at_pixels(RGB8_MASK, pixels, pixels.len() * 3);
+    let bits = colors.chunks_exact(3).flat_map(|rgb| {
+        let y = Rct::from_rgb([rgb[0], rgb[1], rgb[2]]).y;
+        (0..y_bits).rev().map(move |shift| ((y >> shift) & 1) as u8)
+    });
+
+    let mut payload = vec![0u8; data_len];
+    for (index, bit) in bits.take(data_len * 8).enumerate() {
+        payload[index / 8] |= bit << (7 - index % 8);
+    }
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageBuffer, ImageOutputFormat, Rgb};
+    use pretty_assertions::assert_eq;
+    use rand::RngCore;
+
+    use super::*;
+    use crate::{
+        crc_spec::CrcSpec,
+        header::{generate_v3_header, try_get_header},
+        payload::{read_payload, write_payload},
+    };
+
+    #[test]
+    fn transform_is_reversible_and_keeps_chroma() {
+        for rgb in [
+            [0, 0, 0],
+            [255, 255, 255],
+            [255, 0, 0],
+            [12, 200, 77],
+            [3, 1, 254],
+        ] {
+            let pixel = Rct::from_rgb(rgb);
+            assert_eq!(pixel.to_rgb(), rgb);
+            assert!(pixel.luma_range().contains(&pixel.y));
+            if pixel.carries(2) {
+                for value in 0..4 {
+                    let changed = pixel.with_low_bits(value, 2);
+                    let reread = Rct::from_rgb(changed.to_rgb());
+                    assert_eq!(reread, changed);
+                    assert_eq!(reread.y & 3, value);
+                    assert!((reread.y - pixel.y).abs() < 4);
+                }
+            }
+        }
+        // Fully saturated colors leave no room in the luma
+        assert!(!Rct::from_rgb([255, 0, 0]).carries(1));
+    }
+
+    #[test]
+    fn payload_in_the_luma_survives_a_lossless_container() {
+        let mut image: ImageBuffer<Rgb<u8>, Vec<u8>> = ImageBuffer::new(64, 64);
+        rand::thread_rng().fill_bytes(&mut image);
+        let payload = b"carried by the luma".repeat(20);
+        let header = generate_v3_header(
+            64 * 64,
+            &payload,
+            ColorType::Rgb8,
+            CrcSpec::default(),
+            Vec::new(),
+            true,
+        )
+        .unwrap();
+        let header = with_luma_placement(&image, header, 2, true).unwrap();
+        let cover = image.clone();
+        write_payload(&mut image, &header, &payload, None).unwrap();
+
+        // Only the luma changed: every channel of a payload pixel moved by the same amount
+        for (pixel, original) in image
+            .pixels()
+            .zip(cover.pixels())
+            .skip(header.max_pixel_span().unwrap() as usize)
+        {
+            let shifts: Vec<i32> = (0..3)
+                .map(|channel| pixel[channel] as i32 - original[channel] as i32)
+                .collect();
+            assert!(shifts.iter().all(|shift| *shift == shifts[0]));
+        }
+
+        let mut png = Vec::new();
+        image
+            .write_to(&mut std::io::Cursor::new(&mut png), ImageOutputFormat::Png)
+            .unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().into_rgb8();
+
+        let read_header = try_get_header(&decoded).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(read_payload(&decoded, &read_header, None).unwrap(), payload);
+    }
+}
@@ -0,0 +1,69 @@
+use std::{env, fs};
+
+use assert_cmd::Command;
+use image::{GrayImage, Luma, Rgb, RgbImage};
+use rand::{thread_rng, Rng};
+
+const SIDE: u32 = 128;
+
+/// Only the first 8 rows, which hold the header, and the last 4 rows may carry data
+fn allowed(y: u32) -> bool {
+    !(8..SIDE - 4).contains(&y)
+}
+
+#[test]
+fn random_offsets_stay_out_of_a_tight_avoid_mask() {
+    let dir = env::temp_dir().join(format!("ihm-avoid-{:x}", thread_rng().gen::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| -> String { dir.join(name).to_string_lossy().into_owned() };
+
+    let cover = RgbImage::from_fn(SIDE, SIDE, |_, _| Rgb(thread_rng().gen()));
+    cover.save(path("cover.png")).unwrap();
+    // Black marks the pixels to avoid, over 90% of the image
+    GrayImage::from_fn(SIDE, SIDE, |_, y| match allowed(y) {
+        true => Luma([255]),
+        false => Luma([0]),
+    })
+    .save(path("mask.png"))
+    .unwrap();
+    // Spills from the rows behind the header into the last rows, leaving the offset little room
+    let message: Vec<u8> = (0..400).map(|_| thread_rng().gen()).collect();
+    fs::write(path("message.bin"), &message).unwrap();
+
+    for _ in 0..10 {
+        Command::cargo_bin("image-hidden-message")
+            .unwrap()
+            .args([
+                "-q",
+                "encode",
+                &path("cover.png"),
+                "--out",
+                &path("encoded.png"),
+            ])
+            .args([
+                "--file",
+                &path("message.bin"),
+                "--avoid-mask",
+                &path("mask.png"),
+            ])
+            .assert()
+            .success();
+
+        let encoded = image::open(path("encoded.png")).unwrap().into_rgb8();
+        for (x, y, pixel) in encoded.enumerate_pixels() {
+            if !allowed(y) {
+                assert_eq!(pixel, cover.get_pixel(x, y), "masked pixel {},{}", x, y);
+            }
+        }
+
+        let decoded = Command::cargo_bin("image-hidden-message")
+            .unwrap()
+            .args(["-q", "decode", "--source", &path("encoded.png")])
+            .args(["--avoid-mask", &path("mask.png")])
+            .assert()
+            .success();
+        assert_eq!(decoded.get_output().stdout, message);
+    }
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,60 @@
+use std::{env, fs};
+
+use assert_cmd::Command;
+use image::{Rgb, RgbImage};
+use rand::{thread_rng, Rng};
+
+/// Runs the tool and returns what it wrote to STDOUT and STDERR
+fn run(args: &[&str]) -> (Vec<u8>, String) {
+    let output = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .arg("-q")
+        .args(args)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    (output.stdout, String::from_utf8(output.stderr).unwrap())
+}
+
+#[test]
+fn benchmark_prints_timings_without_changing_the_output() {
+    let dir = env::temp_dir().join(format!("ihm-benchmark-{:x}", thread_rng().gen::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    let cover = dir.join("cover.png");
+    RgbImage::from_fn(64, 64, |_, _| Rgb(thread_rng().gen()))
+        .save(&cover)
+        .unwrap();
+    let cover = cover.to_string_lossy().into_owned();
+    let encode = [
+        "encode",
+        &cover,
+        "--message",
+        "timed",
+        "--no-randomize-offset",
+    ];
+
+    let (plain, stderr) = run(&encode);
+    assert_eq!(stderr, "");
+    let (timed, stderr) = run(&[&encode[..], &["--benchmark"]].concat());
+    assert_eq!(timed, plain);
+    assert!(
+        stderr.starts_with("Benchmark: embedding 5 B with 4096 px took "),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("MB/s of payload") && stderr.contains("MP/s of image"));
+
+    let encoded = dir.join("encoded.png");
+    fs::write(&encoded, &timed).unwrap();
+    let encoded = encoded.to_string_lossy().into_owned();
+    let (payload, stderr) = run(&["decode", "--source", &encoded, "--benchmark"]);
+    assert_eq!(payload, b"timed");
+    assert!(
+        stderr.starts_with("Benchmark: extracting 5 B with 4096 px took "),
+        "{}",
+        stderr
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
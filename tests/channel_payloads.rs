@@ -0,0 +1,113 @@
+use std::{env, fs, path::PathBuf};
+
+use assert_cmd::Command;
+use ed25519_dalek::SigningKey;
+use predicates::str::{contains, is_empty};
+use rand::{thread_rng, Rng};
+
+/// Temporary directory holding a palette PNG with a payload in its tRNS chunk
+struct Workspace {
+    dir: PathBuf,
+}
+
+impl Workspace {
+    fn new() -> Workspace {
+        let dir = env::temp_dir().join(format!("ihm-channel-{:x}", thread_rng().gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("palette.png"), palette_png()).unwrap();
+        let workspace = Workspace { dir };
+
+        Command::cargo_bin("image-hidden-message")
+            .unwrap()
+            .args(["-q", "encode", &workspace.path("palette.png")])
+            .args(["--channel", "trns", "--message", "hi"])
+            .args(["--out", &workspace.path("encoded.png")])
+            .assert()
+            .success();
+        workspace
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A 16×16 palette PNG with 256 colors and a transparency entry for each
+fn palette_png() -> Vec<u8> {
+    let palette: Vec<u8> = (0..=255u8).flat_map(|i| [i, 255 - i, i / 2]).collect();
+    let indices: Vec<u8> = (0..=255u8).collect();
+
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, 16, 16);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(palette);
+    encoder.set_trns(vec![255; 256]);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&indices).unwrap();
+    writer.finish().unwrap();
+    data
+}
+
+#[test]
+fn unsigned_trns_payload_fails_signature_check() {
+    let workspace = Workspace::new();
+    let public_key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+    fs::write(workspace.path("pub.key"), public_key.to_bytes()).unwrap();
+
+    Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["decode", "--source", &workspace.path("encoded.png")])
+        .args(["--verify-sig", &workspace.path("pub.key")])
+        .assert()
+        .failure()
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("The payload was not signed"));
+}
+
+#[test]
+fn trns_payload_is_checked_like_a_pixel_payload() {
+    let workspace = Workspace::new();
+
+    Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["decode", "--source", &workspace.path("encoded.png")])
+        .args(["--record", "0"])
+        .assert()
+        .failure()
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("The payload does not consist of records"));
+
+    Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["decode", "--source", &workspace.path("encoded.png")])
+        .args(["--extract-all", &workspace.path("files")])
+        .assert()
+        .failure()
+        .code(1)
+        .stderr(contains("The payload is not an archive of files"));
+}
+
+#[test]
+fn flags_without_meaning_for_trns_payloads_are_rejected() {
+    let workspace = Workspace::new();
+
+    for flag in ["--check-tamper", "--try-all", "--benchmark"] {
+        Command::cargo_bin("image-hidden-message")
+            .unwrap()
+            .args(["decode", "--source", &workspace.path("encoded.png"), flag])
+            .assert()
+            .failure()
+            .code(1)
+            .stdout(is_empty())
+            .stderr(contains(format!("{} is not supported", flag)));
+    }
+}
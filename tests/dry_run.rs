@@ -0,0 +1,54 @@
+use std::{env, fs};
+
+use assert_cmd::Command;
+use image::{Rgb, RgbImage};
+use predicates::{prelude::PredicateBooleanExt, str::contains};
+use rand::{thread_rng, Rng};
+
+#[test]
+fn decode_dry_run_reports_the_header_without_the_payload() {
+    let dir = env::temp_dir().join(format!("ihm-dry-run-{:x}", thread_rng().gen::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| -> String { dir.join(name).to_string_lossy().into_owned() };
+
+    RgbImage::from_fn(64, 64, |_, _| Rgb(thread_rng().gen()))
+        .save(path("cover.png"))
+        .unwrap();
+    let message: Vec<u8> = (0..300).map(|_| thread_rng().gen()).collect();
+    fs::write(path("message.bin"), &message).unwrap();
+    Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args([
+            "-q",
+            "encode",
+            &path("cover.png"),
+            "--out",
+            &path("encoded.png"),
+        ])
+        .args(["--file", &path("message.bin")])
+        .assert()
+        .success();
+
+    Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args([
+            "-q",
+            "decode",
+            "--source",
+            &path("encoded.png"),
+            "--dry-run",
+        ])
+        .assert()
+        .success()
+        .stdout(predicates::str::is_empty())
+        .stderr(
+            contains("(300 bytes)")
+                .and(contains("Compressed: no"))
+                .and(contains("Encrypted: no"))
+                .and(contains("FEC: no")),
+        );
+    let files: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(files.len(), 3, "the dry run created a file");
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,100 @@
+#![cfg(unix)]
+
+use std::{env, fs, os::unix::fs::PermissionsExt, path::PathBuf};
+
+use assert_cmd::Command;
+use image::{Rgb, RgbImage};
+use rand::{thread_rng, Rng};
+
+/// Temporary directory with a noisy cover and a read-only subdirectory
+struct Workspace {
+    dir: PathBuf,
+}
+
+impl Workspace {
+    fn new() -> Workspace {
+        let dir = env::temp_dir().join(format!("ihm-file-errors-{:x}", thread_rng().gen::<u64>()));
+        fs::create_dir_all(dir.join("read-only")).unwrap();
+        RgbImage::from_fn(64, 64, |_, _| Rgb(thread_rng().gen()))
+            .save(dir.join("cover.png"))
+            .unwrap();
+        fs::set_permissions(dir.join("read-only"), fs::Permissions::from_mode(0o555)).unwrap();
+        Workspace { dir }
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = fs::set_permissions(
+            self.dir.join("read-only"),
+            fs::Permissions::from_mode(0o755),
+        );
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Runs the tool expecting it to fail with exit code 1, and returns what it wrote to STDERR
+fn failure(args: &[&str]) -> String {
+    let output = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .arg("-q")
+        .args(args)
+        .assert()
+        .code(1)
+        .get_output()
+        .clone();
+    String::from_utf8(output.stderr).unwrap()
+}
+
+#[test]
+fn unwritable_output_is_reported_without_a_panic() {
+    let workspace = Workspace::new();
+    let out = workspace.path("read-only/out.png");
+    // Permissions do not apply to root, which CI containers sometimes run as
+    if fs::write(workspace.path("read-only/probe"), b"").is_ok() {
+        return;
+    }
+
+    let stderr = failure(&[
+        "encode",
+        &workspace.path("cover.png"),
+        "--message",
+        "hi",
+        "--out",
+        &out,
+    ]);
+
+    assert!(
+        stderr.contains(&format!("Cannot write to {}: permission denied", out)),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn missing_source_is_reported_without_a_panic() {
+    let workspace = Workspace::new();
+    let missing = workspace.path("missing.png");
+
+    for args in [
+        vec!["decode", "--source", &missing],
+        vec!["encode", &missing, "--message", "hi"],
+        vec!["extract", &missing],
+    ] {
+        let stderr = failure(&args);
+        assert!(
+            stderr.contains(&format!(
+                "Cannot read {}: no such file or directory",
+                missing
+            )),
+            "{}",
+            stderr
+        );
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+    }
+}
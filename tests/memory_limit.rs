@@ -0,0 +1,81 @@
+use std::{env, fs};
+
+use assert_cmd::Command;
+use image::{Rgb, RgbImage};
+use image_hidden_message::{
+    buffer_modify::WriteImageBinary,
+    header::{HeaderRaw, V1DataStuffingOptions, VersionedHeader, HEADER_MASK},
+};
+use rand::{thread_rng, Rng};
+
+#[test]
+fn crafted_header_claiming_a_huge_payload_is_rejected() {
+    let dir = env::temp_dir().join(format!("ihm-memory-{:x}", thread_rng().gen::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("crafted.png").to_string_lossy().into_owned();
+
+    // A 1 TiB payload, which the small image cannot possibly hold
+    let header = VersionedHeader::V1 {
+        stuffing_opts: V1DataStuffingOptions::None { start_offset: 200 },
+        data_mask: 0x01_00_00_00_00_00_00_00,
+        data_len: 1 << 40,
+    };
+    let raw: HeaderRaw = header.try_into().unwrap();
+    let mut image = RgbImage::from_fn(64, 64, |_, _| Rgb(thread_rng().gen()));
+    image.write_data_with_mask(&raw.to_bytes(), HEADER_MASK, 0);
+    image.save(&path).unwrap();
+
+    let output = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["--max-memory", "16", "decode", "--source", &path])
+        .assert()
+        .failure()
+        .code(1);
+    let stderr = String::from_utf8_lossy(&output.get_output().stderr).into_owned();
+    assert!(
+        stderr.contains("Estimated peak memory use"),
+        "unexpected STDERR: {}",
+        stderr
+    );
+    assert!(output.get_output().stdout.is_empty());
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn oversized_message_file_is_rejected_before_reading() {
+    let dir = env::temp_dir().join(format!("ihm-memory-{:x}", thread_rng().gen::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| -> String { dir.join(name).to_string_lossy().into_owned() };
+
+    RgbImage::from_fn(64, 64, |_, _| Rgb(thread_rng().gen()))
+        .save(path("cover.png"))
+        .unwrap();
+    // Sparse, so the size is claimed without writing 2 MiB
+    fs::File::create(path("message.bin"))
+        .unwrap()
+        .set_len(2 * 1024 * 1024)
+        .unwrap();
+
+    let output = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["--max-memory", "1", "encode", &path("cover.png")])
+        .args([
+            "--out",
+            &path("encoded.png"),
+            "--file",
+            &path("message.bin"),
+        ])
+        .assert()
+        .failure()
+        .code(1);
+    let stderr = String::from_utf8_lossy(&output.get_output().stderr).into_owned();
+    assert!(
+        stderr.contains("Estimated peak memory use"),
+        "unexpected STDERR: {}",
+        stderr
+    );
+    assert!(!dir.join("encoded.png").exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
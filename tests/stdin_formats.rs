@@ -0,0 +1,109 @@
+use std::{env, fs, io::Cursor};
+
+use assert_cmd::Command;
+use image::{ImageOutputFormat, Rgb, RgbImage};
+use rand::{thread_rng, Rng};
+
+/// Encodes a message into a noisy PNG cover and returns the modified image as BMP
+fn bmp_with_message(message: &str) -> Vec<u8> {
+    let dir = env::temp_dir().join(format!("ihm-stdin-{:x}", thread_rng().gen::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    let cover = dir.join("cover.png");
+    RgbImage::from_fn(64, 64, |_, _| Rgb(thread_rng().gen()))
+        .save(&cover)
+        .unwrap();
+
+    let encoded = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .arg("-q")
+        .arg("encode")
+        .arg(&cover)
+        .args(["--message", message])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    fs::remove_dir_all(&dir).unwrap();
+
+    let mut bmp = Vec::new();
+    image::load_from_memory(&encoded)
+        .unwrap()
+        .write_to(&mut Cursor::new(&mut bmp), ImageOutputFormat::Bmp)
+        .unwrap();
+    bmp
+}
+
+#[test]
+fn bmp_piped_into_stat_is_read() {
+    let bmp = bmp_with_message("piped as bmp");
+
+    let stat = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["-q", "stat"])
+        .write_stdin(bmp.clone())
+        .assert()
+        .success();
+    let stdout = String::from_utf8_lossy(&stat.get_output().stdout).into_owned();
+    assert!(stdout.contains("Byte Length: 12 B"), "{}", stdout);
+
+    let decoded = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["-q", "decode"])
+        .write_stdin(bmp)
+        .assert()
+        .success();
+    assert_eq!(decoded.get_output().stdout, b"piped as bmp");
+}
+
+#[test]
+fn unsupported_format_lists_the_formats_tried() {
+    let stat = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["-q", "stat"])
+        .write_stdin(b"\xFF\xD8\xFF\xE0...".to_vec())
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&stat.get_output().stderr).into_owned();
+    assert!(
+        stderr.contains("Png, Farbfeld, Qoi, Bmp, WebP"),
+        "{}",
+        stderr
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn dev_stdin_and_dev_stdout_are_piped() {
+    let mut cover = Vec::new();
+    RgbImage::from_fn(64, 64, |_, _| Rgb(thread_rng().gen()))
+        .write_to(&mut Cursor::new(&mut cover), ImageOutputFormat::Png)
+        .unwrap();
+
+    let encoded = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["-q", "encode", "/dev/stdin", "--message", "via dev stdin"])
+        .args(["--out", "/dev/stdout"])
+        .write_stdin(cover.clone())
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    let decoded = Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["-q", "decode", "--source", "-"])
+        .write_stdin(encoded)
+        .assert()
+        .success();
+    assert_eq!(decoded.get_output().stdout, b"via dev stdin");
+
+    // The message can not be piped in as well
+    Command::cargo_bin("image-hidden-message")
+        .unwrap()
+        .args(["-q", "encode", "/dev/stdin"])
+        .write_stdin(cover)
+        .assert()
+        .failure();
+}
@@ -0,0 +1,92 @@
+use std::{env, fs, path::PathBuf};
+
+use assert_cmd::Command;
+use image::{Rgb, RgbImage};
+use rand::{thread_rng, Rng};
+
+/// Temporary directory holding a small noisy cover image
+struct Workspace {
+    dir: PathBuf,
+}
+
+impl Workspace {
+    fn new() -> Workspace {
+        let dir = env::temp_dir().join(format!("ihm-tiny-{:x}", thread_rng().gen::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let cover = RgbImage::from_fn(64, 64, |_, _| Rgb(thread_rng().gen()));
+        cover.save(dir.join("cover.png")).unwrap();
+        Workspace { dir }
+    }
+
+    fn path(&self, name: &str) -> String {
+        self.dir.join(name).to_string_lossy().into_owned()
+    }
+
+    /// Encodes with the given payload arguments and returns what `decode` writes to STDOUT
+    fn round_trip(&self, payload_args: &[&str]) -> Vec<u8> {
+        let encoded = self.path("encoded.png");
+        Command::cargo_bin("image-hidden-message")
+            .unwrap()
+            .args(["-q", "encode", &self.path("cover.png"), "--out", &encoded])
+            .args(payload_args)
+            .assert()
+            .success();
+
+        let decoded = Command::cargo_bin("image-hidden-message")
+            .unwrap()
+            .args(["-q", "decode", "--source", &encoded])
+            .assert()
+            .success();
+        decoded.get_output().stdout.clone()
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn empty_message_round_trips() {
+    let workspace = Workspace::new();
+
+    assert_eq!(workspace.round_trip(&["--message", ""]), b"");
+}
+
+#[test]
+fn empty_file_round_trips() {
+    let workspace = Workspace::new();
+    fs::write(workspace.dir.join("empty.bin"), b"").unwrap();
+
+    assert_eq!(
+        workspace.round_trip(&["--file", &workspace.path("empty.bin")]),
+        b""
+    );
+}
+
+#[test]
+fn single_byte_round_trips() {
+    let workspace = Workspace::new();
+    fs::write(workspace.dir.join("byte.bin"), [0xFF]).unwrap();
+
+    assert_eq!(workspace.round_trip(&["--message", "x"]), b"x");
+    assert_eq!(
+        workspace.round_trip(&["--file", &workspace.path("byte.bin")]),
+        [0xFF]
+    );
+}
+
+#[test]
+fn tiny_payloads_round_trip_with_a_scattered_header() {
+    let workspace = Workspace::new();
+
+    assert_eq!(
+        workspace.round_trip(&["--message", "", "--scatter-header"]),
+        b""
+    );
+    assert_eq!(
+        workspace.round_trip(&["--message", "x", "--scatter-header"]),
+        b"x"
+    );
+}